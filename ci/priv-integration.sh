@@ -47,6 +47,21 @@ ostree-ext-cli container image prune-images --sysroot "${sysroot}"
 ostree-ext-cli container image list --repo "${sysroot}/ostree/repo" > out.txt
 test $(stat -c '%s' out.txt) = 0
 
+# A deployed image is never removed by a retention policy
+ostree-ext-cli container image deploy --sysroot "${sysroot}" \
+    --stateroot "${stateroot}" --imgref "${imgref}"
+ostree-ext-cli container image prune --sysroot "${sysroot}" --older-than 0s > prune.txt
+grep "Removed images: 0 " prune.txt
+ostree-ext-cli container image list --repo "${sysroot}/ostree/repo" > out.txt
+grep "${image}" out.txt
+ostree admin --sysroot="${sysroot}" undeploy 0
+ostree-ext-cli container image prune --sysroot "${sysroot}" --older-than 0s > prune.txt
+grep "Removed images: 1 " prune.txt
+ostree-ext-cli container image list --repo "${sysroot}/ostree/repo" > out.txt
+test $(stat -c '%s' out.txt) = 0
+ostree --repo="${sysroot}/ostree/repo" refs > refs.txt
+test "$(wc -l < refs.txt)" = 0
+
 for img in "${image}"; do
     ostree-ext-cli container image deploy --sysroot "${sysroot}" \
         --stateroot "${stateroot}" --imgref ostree-unverified-registry:"${img}"
@@ -113,6 +128,12 @@ echo "ok deploy derived container identical revs"
 ostree-ext-cli container image deploy --sysroot "${sysroot}" \
         --stateroot "${stateroot}" --imgref ostree-unverified-image:"${derived_img_dir}"
 echo "ok deploy derived container from local dir"
+
+tmprepo=$(mktemp -d -p /var/tmp)
+ostree --repo="${tmprepo}" init --mode=bare-user
+ostree-ext-cli container image pull --in-process "${tmprepo}" ostree-unverified-image:"${derived_img}"
+rm -rf "${tmprepo}"
+echo "ok pull derived container in process"
 ostree-ext-cli container image remove --repo "${repo}" "${derived_img_dir}"
 rm -rf /var/tmp/derived.dir
 
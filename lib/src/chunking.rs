@@ -2,12 +2,13 @@
 
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use std::borrow::{Borrow, Cow};
+use std::borrow::Borrow;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Write;
 use std::hash::{Hash, Hasher};
 use std::num::NonZeroU32;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Instant;
 
 use crate::container::{COMPONENT_SEPARATOR, CONTENT_ANNOTATION};
@@ -15,7 +16,7 @@ use crate::objectsource::{ContentID, ObjectMeta, ObjectMetaMap, ObjectSourceMeta
 use crate::objgv::*;
 use crate::statistics;
 use anyhow::{anyhow, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use containers_image_proxy::oci_spec;
 use gvariant::aligned_bytes::TryAsAligned;
 use gvariant::{Marker, Structure};
@@ -31,7 +32,9 @@ pub(crate) const MAX_CHUNKS: u32 = 64;
 const MIN_CHUNKED_LAYERS: u32 = 4;
 
 type RcStr = Rc<str>;
-pub(crate) type ChunkMapping = BTreeMap<RcStr, (u64, Vec<Utf8PathBuf>)>;
+/// Maps content object checksums to their size and paths.  The keys are atomically
+/// reference counted so that chunks can be exported from multiple threads.
+pub(crate) type ChunkMapping = BTreeMap<Arc<str>, (u64, Vec<Utf8PathBuf>)>;
 // TODO type PackageSet = HashSet<RcStr>;
 
 const LOW_PARTITION: &str = "2ls";
@@ -108,6 +111,114 @@ impl ObjectMetaSized {
     }
 }
 
+/// A summary of a single chunk in a [`ChunkingReport`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct ChunkReport {
+    /// The name of the chunk, which is used as the layer description
+    pub name: String,
+    /// The names of the components in this chunk
+    pub packages: Vec<String>,
+    /// The number of content objects
+    pub objects: usize,
+    /// The total size of the content objects
+    pub size: u64,
+}
+
+impl From<&Chunk> for ChunkReport {
+    fn from(chunk: &Chunk) -> Self {
+        Self {
+            name: chunk.name.clone(),
+            packages: chunk.packages.clone(),
+            objects: chunk.content.len(),
+            size: chunk.size,
+        }
+    }
+}
+
+/// A component which is in a different chunk than in the prior build.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct MovedComponent {
+    /// The name of the component
+    pub name: String,
+    /// The index of its chunk in the prior build; `None` if it was added.
+    pub previous: Option<usize>,
+    /// The index of its chunk in this build; `None` if it was removed.
+    pub current: Option<usize>,
+}
+
+/// A serializable description of how a commit is split into chunks, and how that
+/// compares to a prior build.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct ChunkingReport {
+    /// Size of the ostree metadata (directory trees and metadata)
+    pub metadata_size: u64,
+    /// Number of components provided
+    pub components_provided: u32,
+    /// Number of components with a non-zero size
+    pub components_sized: u32,
+    /// The chunks, in layer order
+    pub chunks: Vec<ChunkReport>,
+    /// The content which is not in any chunk, and is exported along with the commit
+    pub remainder: ChunkReport,
+    /// Components which changed chunk compared to the prior build
+    pub moved: Vec<MovedComponent>,
+    /// The predicted (uncompressed) size of the content a client updating from the
+    /// prior build must download.  A chunk is assumed to be reused if the corresponding
+    /// layer of the prior build has exactly the same components; as changes to the
+    /// content of a component are not visible in the prior manifest, this is a lower bound.
+    /// Without a prior build, this is the size of all content.
+    pub redownload_size: u64,
+}
+
+impl ChunkingReport {
+    /// Print the report in a human readable form to standard output.
+    pub fn print(&self) {
+        println!("Metadata: {}", glib::format_size(self.metadata_size));
+        if self.components_provided > 0 {
+            println!(
+                "Components: provided={} sized={}",
+                self.components_provided, self.components_sized
+            );
+        }
+        for (n, chunk) in self.chunks.iter().enumerate() {
+            println!(
+                "Chunk {}: \"{}\": objects:{} size:{}",
+                n,
+                chunk.name,
+                chunk.objects,
+                glib::format_size(chunk.size)
+            );
+        }
+        if self.remainder.objects > 0 {
+            println!(
+                "Remainder: \"{}\": objects:{} size:{}",
+                self.remainder.name,
+                self.remainder.objects,
+                glib::format_size(self.remainder.size)
+            );
+        }
+        for moved in self.moved.iter() {
+            let chunk = |v: Option<usize>| v.map_or_else(|| "none".to_string(), |v| v.to_string());
+            println!(
+                "Moved: {}: chunk {} => {}",
+                moved.name,
+                chunk(moved.previous),
+                chunk(moved.current)
+            );
+        }
+        println!(
+            "Predicted download: {}",
+            glib::format_size(self.redownload_size)
+        );
+    }
+}
+
 /// How to split up an ostree commit into "chunks" - designed to map to container image layers.
 #[derive(Debug, Default)]
 pub struct Chunking {
@@ -177,7 +288,7 @@ fn generate_chunking_recurse(
         let checksum = std::str::from_utf8(&hexbuf)?;
         let meta = repo.query_file(checksum, gio::Cancellable::NONE)?.0;
         let size = meta.size() as u64;
-        let entry = chunk.content.entry(RcStr::from(checksum)).or_default();
+        let entry = chunk.content.entry(Arc::from(checksum)).or_default();
         entry.0 = size;
         let first = entry.1.is_empty();
         if first {
@@ -330,27 +441,7 @@ impl Chunking {
         tracing::debug!("Time elapsed in packing: {:#?}", duration);
 
         for bin in packing.into_iter() {
-            let name = match bin.len() {
-                0 => Cow::Borrowed("Reserved for new packages"),
-                1 => {
-                    let first = bin[0];
-                    let first_name = &*first.meta.identifier;
-                    Cow::Borrowed(first_name)
-                }
-                2..=5 => {
-                    let first = bin[0];
-                    let first_name = &*first.meta.identifier;
-                    let r = bin.iter().map(|v| &*v.meta.identifier).skip(1).fold(
-                        String::from(first_name),
-                        |mut acc, v| {
-                            write!(acc, " and {}", v).unwrap();
-                            acc
-                        },
-                    );
-                    Cow::Owned(r)
-                }
-                n => Cow::Owned(format!("{n} components")),
-            };
+            let name = chunk_name(bin.iter().map(|v| &*v.meta.identifier));
             let mut chunk = Chunk::new(&name);
             chunk.packages = bin.iter().map(|v| String::from(&*v.meta.name)).collect();
             for szmeta in bin {
@@ -366,40 +457,192 @@ impl Chunking {
         Ok(())
     }
 
+    /// Generate a chunking without content metadata, by grouping objects using
+    /// heuristics based on their paths: each kernel in `/usr/lib/modules`, locale data,
+    /// `/usr/bin`, `/usr/sbin`, each subdirectory of `/usr/lib`, `/usr/lib64`, `/usr/libexec`
+    /// and `/usr/share`, and the shared libraries directly in `/usr/lib` and `/usr/lib64`.
+    /// The groups are then packed into at most `max_layers` chunks by size in the same way
+    /// as packages in [`Self::from_mapping`], keeping the layout of `prior_build_metadata`
+    /// if provided so that unchanged groups produce the same layers.  Objects which are not
+    /// in any group are left in the remainder.
+    pub fn from_paths(
+        repo: &ostree::Repo,
+        rev: &str,
+        max_layers: &Option<NonZeroU32>,
+        prior_build_metadata: Option<&oci_spec::image::ImageManifest>,
+    ) -> Result<Self> {
+        let mut r = Self::new(repo, rev)?;
+        r.max = max_layers
+            .unwrap_or(NonZeroU32::new(MAX_CHUNKS).unwrap())
+            .get();
+        r.processed_mapping = true;
+
+        // Maps group name -> objects
+        let mut groups = BTreeMap::<String, Vec<Arc<str>>>::new();
+        for (checksum, (_, paths)) in r.remainder.content.iter() {
+            // Use the first path in sorted order, so the result is deterministic
+            let group = paths.iter().min().and_then(|p| path_group(p));
+            if let Some(group) = group {
+                groups.entry(group).or_default().push(Arc::clone(checksum));
+            }
+        }
+        let mut sizes: Vec<ObjectSourceMetaSized> = groups
+            .iter()
+            .map(|(name, objects)| {
+                let size = objects.iter().map(|obj| r.remainder.content[obj].0).sum();
+                path_group_meta(name, size)
+            })
+            .collect();
+        // Packing expects components in descending order of size
+        sizes.sort_by_key(|v| std::cmp::Reverse(v.size));
+        r.n_provided_components = sizes.len().try_into().unwrap();
+        r.n_sized_components = r.n_provided_components;
+        if sizes.is_empty() {
+            return Ok(r);
+        }
+
+        let packing = basic_packing(
+            &sizes,
+            NonZeroU32::new(r.max).unwrap(),
+            prior_build_metadata,
+        )?;
+        for bin in packing {
+            let mut chunk = Chunk::new(&chunk_name(bin.iter().map(|v| &*v.meta.name)));
+            chunk.packages = bin.iter().map(|v| String::from(&*v.meta.name)).collect();
+            for group in bin {
+                for obj in groups[&*group.meta.name].iter() {
+                    r.remainder.move_obj(&mut chunk, obj);
+                }
+            }
+            r.chunks.push(chunk);
+        }
+        Ok(r)
+    }
+
     pub(crate) fn take_chunks(&mut self) -> Vec<Chunk> {
         let mut r = Vec::new();
         std::mem::swap(&mut self.chunks, &mut r);
         r
     }
 
+    /// Generate a report describing the chunks, compared to the layers of `prior_build`
+    /// if provided.
+    pub fn report(
+        &self,
+        prior_build: Option<&oci_spec::image::ImageManifest>,
+    ) -> Result<ChunkingReport> {
+        let chunks: Vec<ChunkReport> = self.chunks.iter().map(ChunkReport::from).collect();
+        let content_size = self.metadata_size + self.remainder.size;
+        let (moved, redownload_size) = if let Some(prior_build) = prior_build {
+            let prior = prior_build_components(prior_build)?;
+            let prior: Vec<BTreeSet<&str>> = prior
+                .iter()
+                .map(|bin| {
+                    bin.iter()
+                        .map(|v| v.as_str())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                })
+                .collect();
+            let mut locations = BTreeMap::<&str, (Option<usize>, Option<usize>)>::new();
+            for (i, bin) in prior.iter().enumerate() {
+                for &name in bin {
+                    locations.entry(name).or_default().0 = Some(i);
+                }
+            }
+            let mut redownload_size = content_size;
+            for (i, chunk) in self.chunks.iter().enumerate() {
+                let bin: BTreeSet<&str> = chunk.packages.iter().map(|v| v.as_str()).collect();
+                for &name in &bin {
+                    locations.entry(name).or_default().1 = Some(i);
+                }
+                if prior.get(i) != Some(&bin) {
+                    redownload_size += chunk.size;
+                }
+            }
+            let moved = locations
+                .into_iter()
+                .filter(|(_, (previous, current))| previous != current)
+                .map(|(name, (previous, current))| MovedComponent {
+                    name: name.to_string(),
+                    previous,
+                    current,
+                })
+                .collect();
+            (moved, redownload_size)
+        } else {
+            let size = self.chunks.iter().map(|c| c.size).sum::<u64>();
+            (Vec::new(), content_size + size)
+        };
+        Ok(ChunkingReport {
+            metadata_size: self.metadata_size,
+            components_provided: self.n_provided_components,
+            components_sized: self.n_sized_components,
+            chunks,
+            remainder: ChunkReport::from(&self.remainder),
+            moved,
+            redownload_size,
+        })
+    }
+
     /// Print information about chunking to standard output.
-    pub fn print(&self) {
-        println!("Metadata: {}", glib::format_size(self.metadata_size));
-        if self.n_provided_components > 0 {
-            println!(
-                "Components: provided={} sized={}",
-                self.n_provided_components, self.n_sized_components
-            );
-        }
-        for (n, chunk) in self.chunks.iter().enumerate() {
-            let sz = glib::format_size(chunk.size);
-            println!(
-                "Chunk {}: \"{}\": objects:{} size:{}",
-                n,
-                chunk.name,
-                chunk.content.len(),
-                sz
-            );
-        }
-        if !self.remainder.content.is_empty() {
-            let sz = glib::format_size(self.remainder.size);
-            println!(
-                "Remainder: \"{}\": objects:{} size:{}",
-                self.remainder.name,
-                self.remainder.content.len(),
-                sz
-            );
+    pub fn print(&self) -> Result<()> {
+        self.report(None)?.print();
+        Ok(())
+    }
+}
+
+/// Generate the name of a chunk from the names of its components.
+fn chunk_name<'a>(mut names: impl ExactSizeIterator<Item = &'a str>) -> String {
+    match names.len() {
+        0 => "Reserved for new packages".to_string(),
+        1..=5 => {
+            let first = String::from(names.next().unwrap());
+            names.fold(first, |mut acc, v| {
+                write!(acc, " and {}", v).unwrap();
+                acc
+            })
         }
+        n => format!("{n} components"),
+    }
+}
+
+/// Returns true if the file name is that of a shared library, e.g. `libc.so.6`.
+fn is_shared_library(name: &str) -> bool {
+    name.ends_with(".so") || name.contains(".so.")
+}
+
+/// Return the group used by [`Chunking::from_paths`] for an absolute path.
+fn path_group(path: &Utf8Path) -> Option<String> {
+    let rest = path.strip_prefix("/usr").ok()?;
+    let components: Vec<&str> = rest.components().map(|c| c.as_str()).collect();
+    let group = match components.as_slice() {
+        ["lib", "modules", kver, _, ..] => format!("usr/lib/modules/{kver}"),
+        // Ignore files directly in the modules directory
+        ["lib", "modules", ..] => return None,
+        ["lib" | "share", "locale", _, ..] => "locale".to_string(),
+        [top @ ("bin" | "sbin"), _, ..] => format!("usr/{top}"),
+        [top @ ("lib" | "lib64" | "libexec" | "share"), dir, _, ..] => format!("usr/{top}/{dir}"),
+        [top @ ("lib" | "lib64"), name] if is_shared_library(name) => format!("usr/{top}/*.so"),
+        ["libexec", _] => "usr/libexec".to_string(),
+        _ => return None,
+    };
+    Some(group)
+}
+
+/// Create the metadata for a group of [`Chunking::from_paths`] with the total size of its
+/// objects.  Groups have no change history, so they all have the same change frequency.
+fn path_group_meta(name: &str, size: u64) -> ObjectSourceMetaSized {
+    let name: Rc<str> = Rc::from(name);
+    ObjectSourceMetaSized {
+        meta: ObjectSourceMeta {
+            identifier: Rc::clone(&name),
+            name: Rc::clone(&name),
+            srcid: name,
+            change_time_offset: 0,
+            change_frequency: 1,
+        },
+        size,
     }
 }
 
@@ -534,6 +777,29 @@ fn get_partitions_with_threshold<'a>(
     Some(partitions)
 }
 
+/// Extract the components/packages in each layer of a prior build.  The first layer is
+/// the ostree commit, which will always be different for different builds, so it is ignored.
+fn prior_build_components(
+    prior_build: &oci_spec::image::ImageManifest,
+) -> Result<Vec<Vec<String>>> {
+    prior_build
+        .layers()
+        .iter()
+        .skip(1)
+        .map(|layer| -> Result<_> {
+            let annotation_layer = layer
+                .annotations()
+                .as_ref()
+                .and_then(|annos| annos.get(CONTENT_ANNOTATION))
+                .ok_or_else(|| anyhow!("Missing {CONTENT_ANNOTATION} on prior build"))?;
+            Ok(annotation_layer
+                .split(COMPONENT_SEPARATOR)
+                .map(ToOwned::to_owned)
+                .collect())
+        })
+        .collect()
+}
+
 /// If the current rpm-ostree commit to be encapsulated is not the one in which packing structure changes, then
 ///  Flatten out prior_build_metadata to view all the packages in prior build as a single vec
 ///  Compare the flattened vector to components to see if pkgs added, updated,
@@ -553,25 +819,7 @@ fn basic_packing_with_prior_build<'a>(
 
     tracing::debug!("Keeping old package structure");
 
-    // The first layer is the ostree commit, which will always be different for different builds,
-    // so we ignore it.  For the remaining layers, extract the components/packages in each one.
-    let curr_build: Result<Vec<Vec<String>>> = prior_build
-        .layers()
-        .iter()
-        .skip(1)
-        .map(|layer| -> Result<_> {
-            let annotation_layer = layer
-                .annotations()
-                .as_ref()
-                .and_then(|annos| annos.get(CONTENT_ANNOTATION))
-                .ok_or_else(|| anyhow!("Missing {CONTENT_ANNOTATION} on prior build"))?;
-            Ok(annotation_layer
-                .split(COMPONENT_SEPARATOR)
-                .map(ToOwned::to_owned)
-                .collect())
-        })
-        .collect();
-    let mut curr_build = curr_build?;
+    let mut curr_build = prior_build_components(prior_build)?;
 
     // View the packages as unordered sets for lookups and differencing
     let prev_pkgs_set: HashSet<String> = curr_build
@@ -863,6 +1111,149 @@ mod test {
         image_manifest
     }
 
+    #[test]
+    fn test_path_group() {
+        for (path, expected) in [
+            (
+                "/usr/lib/modules/6.5.6-300.fc39.x86_64/vmlinuz",
+                Some("usr/lib/modules/6.5.6-300.fc39.x86_64"),
+            ),
+            (
+                "/usr/lib/modules/6.5.6-300.fc39.x86_64/kernel/fs/ext4.ko.xz",
+                Some("usr/lib/modules/6.5.6-300.fc39.x86_64"),
+            ),
+            ("/usr/lib/modules/README", None),
+            (
+                "/usr/lib/firmware/amdgpu/navi10_sos.bin",
+                Some("usr/lib/firmware"),
+            ),
+            ("/usr/share/locale/de/LC_MESSAGES/bash.mo", Some("locale")),
+            ("/usr/lib/locale/C.utf8/LC_CTYPE", Some("locale")),
+            ("/usr/lib/python3.12/os.py", Some("usr/lib/python3.12")),
+            (
+                "/usr/share/icons/hicolor/index.theme",
+                Some("usr/share/icons"),
+            ),
+            ("/usr/lib64/libc.so.6", Some("usr/lib64/*.so")),
+            ("/usr/lib/libfoo.so", Some("usr/lib/*.so")),
+            ("/usr/lib64/gconv/UTF-16.so", Some("usr/lib64/gconv")),
+            ("/usr/lib/os-release", None),
+            ("/usr/lib64/ld-linux-x86-64.so.2", Some("usr/lib64/*.so")),
+            ("/usr/bin/bash", Some("usr/bin")),
+            ("/usr/sbin/sshd", Some("usr/sbin")),
+            (
+                "/usr/libexec/openssh/sftp-server",
+                Some("usr/libexec/openssh"),
+            ),
+            ("/usr/libexec/grepconf.sh", Some("usr/libexec")),
+            ("/usr/bin", None),
+            ("/usr/etc/passwd", None),
+        ] {
+            assert_eq!(
+                path_group(Utf8Path::new(path)).as_deref(),
+                expected,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_pack_path_groups() -> Result<()> {
+        const MIB: u64 = 1024 * 1024;
+        let mut groups = vec![
+            path_group_meta("usr/lib/firmware", 2048 * MIB),
+            path_group_meta("usr/lib/modules/kernel", 1024 * MIB),
+        ];
+        for i in 0..20 {
+            groups.push(path_group_meta(&format!("usr/share/g{i}"), (20 - i) * MIB));
+        }
+        let bins = NonZeroU32::new(8).unwrap();
+        let packing = basic_packing(&groups, bins, None)?;
+        assert!(packing.len() <= 8);
+        assert_eq!(packing.iter().map(|b| b.len()).sum::<usize>(), groups.len());
+        // The largest groups each get a layer of their own, and all other layers are smaller
+        let sizes: Vec<u64> = packing.iter().map(|b| components_size(b)).collect();
+        assert_eq!(&sizes[..2], [2048 * MIB, 1024 * MIB]);
+        assert!(sizes[2..].iter().all(|&v| v < 1024 * MIB));
+        assert_eq!(packing_size(&packing), sizes.iter().sum::<u64>());
+
+        // Given the prior build, groups stay in the same layer even if their size changes
+        let structure: Vec<Vec<String>> = packing
+            .iter()
+            .map(|bin| bin.iter().map(|v| v.meta.name.to_string()).collect())
+            .collect();
+        let prior = create_manifest(
+            structure
+                .iter()
+                .map(|bin| bin.iter().map(|v| v.as_str()).collect())
+                .collect(),
+        );
+        groups[2].size = 512 * MIB;
+        groups.push(path_group_meta("usr/share/new", MIB));
+        let packing = basic_packing(&groups, bins, Some(&prior))?;
+        let new_structure: Vec<Vec<String>> = packing
+            .iter()
+            .map(|bin| bin.iter().map(|v| v.meta.name.to_string()).collect())
+            .collect();
+        let (last, rest) = new_structure.split_last().unwrap();
+        assert_eq!(rest, &structure[..structure.len() - 1]);
+        assert_eq!(last, &["usr/share/new"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_name() {
+        assert_eq!(chunk_name(std::iter::empty()), "Reserved for new packages");
+        assert_eq!(chunk_name(["a"].into_iter()), "a");
+        assert_eq!(chunk_name(["a", "b", "c"].into_iter()), "a and b and c");
+        assert_eq!(chunk_name(["a"; 6].into_iter()), "6 components");
+    }
+
+    #[test]
+    fn test_report() -> Result<()> {
+        let chunk = |packages: &[&str], size: u64| {
+            let mut chunk = Chunk::new(&packages.join(" and "));
+            chunk.packages = packages.iter().map(|v| v.to_string()).collect();
+            chunk
+                .content
+                .insert(Arc::from(packages[0]), (size, Vec::new()));
+            chunk.size = size;
+            chunk
+        };
+        let mut remainder = Chunk::new("remainder");
+        remainder.size = 3;
+        let chunking = Chunking {
+            metadata_size: 1,
+            remainder,
+            chunks: vec![chunk(&["a"], 10), chunk(&["b", "c"], 20), chunk(&["d"], 5)],
+            ..Default::default()
+        };
+
+        let report = chunking.report(None)?;
+        assert_eq!(report.chunks.len(), 3);
+        assert_eq!(report.chunks[1].name, "b and c");
+        assert_eq!(report.chunks[1].objects, 1);
+        assert_eq!(report.remainder.objects, 0);
+        assert!(report.moved.is_empty());
+        assert_eq!(report.redownload_size, 39);
+
+        let prior = create_manifest(vec![vec!["a"], vec!["b"], vec!["c", "d"], vec!["e"]]);
+        let report = chunking.report(Some(&prior))?;
+        let moved: Vec<_> = report
+            .moved
+            .iter()
+            .map(|m| (m.name.as_str(), m.previous, m.current))
+            .collect();
+        assert_eq!(moved, [("c", Some(2), Some(1)), ("e", Some(3), None)]);
+        // The chunk containing only "a" is unchanged
+        assert_eq!(report.redownload_size, 29);
+
+        let v = serde_json::to_value(&report)?;
+        assert_eq!(v["redownload-size"], 29);
+        assert_eq!(v["chunks"][1]["packages"], serde_json::json!(["b", "c"]));
+        Ok(())
+    }
+
     #[test]
     fn test_advanced_packing() -> Result<()> {
         // Step1 : Initial build (Packing sructure computed)
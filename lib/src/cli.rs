@@ -15,18 +15,22 @@ use fn_error_context::context;
 use io_lifetimes::AsFd;
 use ostree::{gio, glib};
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::io::{BufWriter, Write};
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
 use std::process::Command;
 use tokio::sync::mpsc::Receiver;
 
+use crate::chunking::ObjectMetaSized;
 use crate::commit::container_commit;
-use crate::container::store::{ImportProgress, LayerProgress, PreparedImport};
+use crate::container::store::{ImportProgress, LayerProgress, PreparedImport, ProgressEvent};
 use crate::container::{self as ostree_container, ManifestDiff};
-use crate::container::{Config, ImageReference, OstreeImageReference};
+use crate::container::{Config, ExportCompression, ImageReference, OstreeImageReference};
+use crate::objectsource::ObjectMeta;
 use crate::sysroot::SysrootLock;
+use containers_image_proxy::oci_spec::image::Platform;
 use ostree_container::store::{ImageImporter, PrepareResult};
 
 /// Parse an [`OstreeImageReference`] from a CLI arguemnt.
@@ -39,6 +43,43 @@ pub fn parse_base_imgref(s: &str) -> Result<ImageReference> {
     ImageReference::try_from(s)
 }
 
+/// Parse a [`crate::tar::ContentPolicy`] from a CLI argument.
+pub fn parse_content_policy(s: &str) -> Result<crate::tar::ContentPolicy> {
+    crate::tar::ContentPolicy::try_from(s)
+}
+
+/// Parse an [`ExportCompression`] from a CLI arguemnt.
+pub fn parse_compression(s: &str) -> Result<ExportCompression> {
+    ExportCompression::try_from(s)
+}
+
+/// Parse a platform of the form `os/arch[/variant]` (e.g. `linux/arm64/v8`) from a CLI argument.
+pub fn parse_platform(s: &str) -> Result<Platform> {
+    let mut parts = s.splitn(3, '/');
+    let (os, arch) = match (parts.next(), parts.next()) {
+        (Some(os), Some(arch)) if !os.is_empty() && !arch.is_empty() => (os, arch),
+        _ => anyhow::bail!("Invalid platform '{s}', expected os/arch[/variant]"),
+    };
+    let mut platform = Platform::default();
+    platform.set_os(os.into());
+    platform.set_architecture(arch.into());
+    platform.set_variant(parts.next().map(ToOwned::to_owned));
+    Ok(platform)
+}
+
+/// Parse a set of `KEY=VALUE` arguments, such as labels.
+fn parse_key_values(kind: &str, items: Vec<String>) -> Result<BTreeMap<String, String>> {
+    items
+        .into_iter()
+        .map(|l| {
+            let (k, v) = l
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Missing '=' in {} {}", kind, l))?;
+            Ok((k.to_string(), v.to_string()))
+        })
+        .collect()
+}
+
 /// Parse an [`ostree::Repo`] from a CLI arguemnt.
 pub fn parse_repo(s: &Utf8Path) -> Result<ostree::Repo> {
     let repofd = cap_std::fs::Dir::open_ambient_dir(s, cap_std::ambient_authority())
@@ -130,6 +171,12 @@ pub(crate) enum ContainerOpts {
         #[clap(value_parser = parse_base_imgref)]
         imgref: ImageReference,
 
+        /// An ostree ref or commit for another architecture; if specified, an image index
+        /// is generated with one image per platform, as determined from the kernel version
+        /// in the `ostree.linux` commit metadata key of each commit (e.g. `6.5.6-300.fc39.aarch64`).
+        #[clap(name = "additional-rev", long)]
+        additional_revs: Vec<String>,
+
         /// Additional labels for the container
         #[clap(name = "label", long, short)]
         labels: Vec<String>,
@@ -150,9 +197,94 @@ pub(crate) enum ContainerOpts {
         #[clap(long)]
         cmd: Option<Vec<String>>,
 
+        /// Corresponds to the Dockerfile `ENTRYPOINT` instruction.
+        #[clap(long)]
+        entrypoint: Option<Vec<String>>,
+
+        /// Environment variable for the container, in KEY=VALUE form
+        #[clap(long)]
+        env: Option<Vec<String>>,
+
+        /// Corresponds to the Dockerfile `WORKDIR` instruction.
+        #[clap(long)]
+        working_dir: Option<String>,
+
+        /// Corresponds to the Dockerfile `USER` instruction.
+        #[clap(long)]
+        user: Option<String>,
+
+        /// Port to expose, e.g. 8080/tcp; corresponds to the Dockerfile `EXPOSE` instruction.
+        #[clap(name = "expose", long)]
+        exposed_ports: Option<Vec<String>>,
+
+        /// Corresponds to the Dockerfile `VOLUME` instruction.
+        #[clap(name = "volume", long)]
+        volumes: Option<Vec<String>>,
+
+        /// Corresponds to the Dockerfile `STOPSIGNAL` instruction.
+        #[clap(long)]
+        stop_signal: Option<String>,
+
+        /// Annotation for the image manifest, in KEY=VALUE form
+        #[clap(name = "annotation", long)]
+        annotations: Vec<String>,
+
         /// Compress at the fastest level (e.g. gzip level 1)
         #[clap(long)]
         compression_fast: bool,
+
+        /// Layer compression algorithm: gzip, zstd or none
+        #[clap(long, default_value = "gzip", value_parser = parse_compression)]
+        compression: ExportCompression,
+
+        /// Embed a table of contents in content layers, allowing clients with random
+        /// access to the layers to read only missing objects (implies zstd compression).
+        /// Images pulled from registries are still fetched in full.
+        #[clap(long)]
+        content_toc: bool,
+
+        /// Number of layers to generate concurrently (defaults to the number of CPUs)
+        #[clap(long)]
+        jobs: Option<NonZeroUsize>,
+
+        /// Generate a reproducible image; all timestamps are taken from `SOURCE_DATE_EPOCH`
+        /// if set, and otherwise from the commit.
+        #[clap(long)]
+        reproducible: bool,
+
+        /// When pushing to a registry or OCI directory, upload each layer as soon as it is generated;
+        /// the registry must support deleting manifests
+        #[clap(long)]
+        incremental_push: bool,
+
+        /// Path to JSON content metadata, mapping content objects to the components
+        /// (e.g. packages) which provide them; used to split the image into layers.
+        /// If `auto`, it is generated from the package database in the commit.
+        #[clap(long, conflicts_with = "additional-rev")]
+        contentmeta: Option<Utf8PathBuf>,
+
+        /// Without content metadata, split the commit into layers using heuristics
+        /// based on paths, e.g. one layer per kernel
+        #[clap(long, conflicts_with = "contentmeta")]
+        chunk_by_path: bool,
+
+        /// Maximum number of layers to use with `--contentmeta` or `--chunk-by-path`
+        #[clap(long)]
+        max_layers: Option<NonZeroU32>,
+
+        /// Image reference of a previous build, e.g. registry:quay.io/exampleos/exampleos:latest;
+        /// its packing structure is reused to minimize layer changes.  Requires `--contentmeta`
+        /// or `--chunk-by-path`.
+        #[clap(long, value_parser = parse_base_imgref)]
+        previous_build: Option<ImageReference>,
+
+        /// Do not generate an image; instead print how the commit would be split into layers
+        #[clap(long, conflicts_with = "additional-rev")]
+        dry_run: bool,
+
+        /// With `--dry-run`, output the layer report in this format instead of as text
+        #[clap(long, value_parser = ["json"], requires = "dry_run")]
+        report: Option<String>,
     },
 
     /// Perform build-time checking and canonicalization.
@@ -196,6 +328,40 @@ pub(crate) struct ContainerProxyOpts {
     insecure_skip_tls_verification: bool,
 }
 
+/// Options for machine-readable progress output.
+#[derive(Debug, Parser)]
+pub(crate) struct ProgressJsonOpts {
+    /// Write progress events as JSON Lines to standard output, instead of other output.
+    #[clap(long, conflicts_with = "progress_fd")]
+    json: bool,
+
+    /// Write progress events as JSON Lines to this file descriptor.
+    #[clap(long)]
+    progress_fd: Option<i32>,
+}
+
+impl ProgressJsonOpts {
+    /// Open the destination for progress events, if any.
+    #[allow(unsafe_code)]
+    fn open(self) -> Result<Option<Box<dyn Write + Send>>> {
+        use std::os::fd::{BorrowedFd, FromRawFd};
+        if self.json {
+            return Ok(Some(Box::new(std::io::stdout())));
+        }
+        let fd = match self.progress_fd {
+            Some(fd) if fd >= 0 => fd,
+            Some(fd) => anyhow::bail!("Invalid --progress-fd {fd}"),
+            None => return Ok(None),
+        };
+        // SAFETY: We only check whether the file descriptor is open.
+        rustix::io::fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) })
+            .with_context(|| format!("Invalid --progress-fd {fd}"))?;
+        // SAFETY: The caller passed us ownership of the file descriptor.
+        let f = unsafe { std::fs::File::from_raw_fd(fd) };
+        Ok(Some(Box::new(f)))
+    }
+}
+
 /// Options for import/export to tar archives.
 #[derive(Debug, Subcommand)]
 pub(crate) enum ContainerImageOpts {
@@ -204,6 +370,10 @@ pub(crate) enum ContainerImageOpts {
         /// Path to the repository
         #[clap(long, value_parser)]
         repo: Utf8PathBuf,
+
+        /// Only list pinned images
+        #[clap(long)]
+        pinned: bool,
     },
 
     /// Pull (or update) a container image.
@@ -219,10 +389,33 @@ pub(crate) enum ContainerImageOpts {
         #[clap(flatten)]
         proxyopts: ContainerProxyOpts,
 
-        /// Don't display progress
+        /// Select the image for this platform (e.g. `linux/arm64`) from an image index,
+        /// instead of the host platform.
+        #[clap(long, value_parser = parse_platform)]
+        platform: Option<Platform>,
+
+        /// Read an `oci:` or `oci-archive:` image in process instead of via skopeo, which
+        /// then does not need to be installed.  Signature verification via
+        /// containers-policy.json is not supported in this mode.
+        #[clap(long, conflicts_with_all = ["auth_anonymous", "authfile", "cert_dir", "insecure_skip_tls_verification"])]
+        in_process: bool,
+
+        /// Number of layers to fetch concurrently
         #[clap(long)]
+        jobs: Option<NonZeroUsize>,
+
+        /// How to handle content outside of /usr and /etc in derived layers: error, warn
+        /// (discard it) or translate (convert /var content into tmpfiles.d entries)
+        #[clap(long, default_value = "warn", value_parser = parse_content_policy)]
+        content_policy: crate::tar::ContentPolicy,
+
+        /// Don't display progress
+        #[clap(long, conflicts_with = "json")]
         quiet: bool,
 
+        #[clap(flatten)]
+        progressopts: ProgressJsonOpts,
+
         /// Just check for an updated manifest, but do not download associated container layers.
         /// If an updated manifest is found, a file at the provided path will be created and contain
         /// the new manifest.
@@ -303,6 +496,28 @@ pub(crate) enum ContainerImageOpts {
         skip_gc: bool,
     },
 
+    /// Pin container images, so that they are not pruned.
+    Pin {
+        /// Path to the repository
+        #[clap(long, value_parser)]
+        repo: Utf8PathBuf,
+
+        /// Image reference, e.g. quay.io/exampleos/exampleos:latest
+        #[clap(value_parser = parse_base_imgref)]
+        imgrefs: Vec<ImageReference>,
+    },
+
+    /// Unpin container images.
+    Unpin {
+        /// Path to the repository
+        #[clap(long, value_parser)]
+        repo: Utf8PathBuf,
+
+        /// Image reference, e.g. quay.io/exampleos/exampleos:latest
+        #[clap(value_parser = parse_base_imgref)]
+        imgrefs: Vec<ImageReference>,
+    },
+
     /// Garbage collect unreferenced image layer references.
     PruneLayers {
         /// Path to the repository
@@ -321,6 +536,24 @@ pub(crate) enum ContainerImageOpts {
         and_layers: bool,
     },
 
+    /// Remove container images according to a retention policy.  Images are grouped
+    /// by repository (ignoring the tag or digest); deployed and pinned images are
+    /// always kept.
+    Prune {
+        /// Path to the system root
+        #[clap(long)]
+        sysroot: Utf8PathBuf,
+
+        /// Keep this many of the most recently created images of each repository
+        #[clap(long, required_unless_present = "older_than")]
+        keep_last: Option<usize>,
+
+        /// Remove images created longer ago than this, e.g. `30d`; with `--keep-last`,
+        /// the most recent images are kept regardless of their age
+        #[clap(long, value_parser = crate::container::retention::parse_duration)]
+        older_than: Option<std::time::Duration>,
+    },
+
     /// Perform initial deployment for a container image
     Deploy {
         /// Path to the system root
@@ -356,6 +589,11 @@ pub(crate) enum ContainerImageOpts {
         #[clap(long)]
         ostree_remote: Option<String>,
 
+        /// With `--ostree-remote`, also verify the image (including any derived layers)
+        /// using `containers-policy.json`
+        #[clap(long, requires = "ostree_remote")]
+        enforce_container_sigpolicy: bool,
+
         #[clap(flatten)]
         proxyopts: ContainerProxyOpts,
 
@@ -378,9 +616,18 @@ pub(crate) enum ContainerImageOpts {
         /// Add a kernel argument
         karg: Option<Vec<String>>,
 
+        /// Read an `oci:` or `oci-archive:` image in process instead of via skopeo, which
+        /// then does not need to be installed.  Signature verification via
+        /// containers-policy.json is not supported in this mode.
+        #[clap(long, conflicts_with_all = ["auth_anonymous", "authfile", "cert_dir", "insecure_skip_tls_verification"])]
+        in_process: bool,
+
         /// Write the deployed checksum to this file
         #[clap(long)]
         write_commitid_to: Option<Utf8PathBuf>,
+
+        #[clap(flatten)]
+        progressopts: ProgressJsonOpts,
     },
 }
 
@@ -550,25 +797,19 @@ pub async fn handle_layer_progress_print(
     mut layers: Receiver<ImportProgress>,
     mut layer_bytes: tokio::sync::watch::Receiver<Option<LayerProgress>>,
 ) {
-    let style = indicatif::ProgressStyle::default_bar();
-    let pb = indicatif::ProgressBar::new(100);
-    pb.set_style(
-        style
-            .template("{prefix} {bytes} [{bar:20}] ({eta}) {msg}")
-            .unwrap(),
-    );
+    let style = indicatif::ProgressStyle::default_bar()
+        .template("{prefix} {bytes} [{bar:20}] ({eta}) {msg}")
+        .unwrap();
+    // Layers may be fetched concurrently, so track a progress bar per layer.
+    let bars = indicatif::MultiProgress::new();
+    let mut layer_bars = HashMap::new();
     loop {
         tokio::select! {
             // Always handle layer changes first.
             biased;
             layer = layers.recv() => {
                 if let Some(l) = layer {
-                    if l.is_starting() {
-                        pb.set_position(0);
-                    } else {
-                        pb.finish();
-                    }
-                    pb.set_message(layer_progress_format(&l));
+                    let _ = bars.println(layer_progress_format(&l));
                 } else {
                     // If the receiver is disconnected, then we're done
                     break
@@ -581,13 +822,44 @@ pub async fn handle_layer_progress_print(
                 }
                 let bytes = layer_bytes.borrow();
                 if let Some(bytes) = &*bytes {
+                    let pb = layer_bars.entry(bytes.layer_index).or_insert_with(|| {
+                        let pb = bars.add(indicatif::ProgressBar::new(bytes.total));
+                        pb.set_style(style.clone());
+                        pb.set_message(format!("layer {}", bytes.layer_index));
+                        pb
+                    });
                     pb.set_length(bytes.total);
                     pb.set_position(bytes.fetched);
+                    if bytes.fetched >= bytes.total {
+                        pb.finish_and_clear();
+                        layer_bars.remove(&bytes.layer_index);
+                    }
                 }
             }
 
         }
     }
+    for pb in layer_bars.into_values() {
+        pb.finish_and_clear();
+    }
+}
+
+/// Write a progress event as a line of JSON.
+fn write_progress_event(out: &mut impl Write, event: &ProgressEvent) -> Result<()> {
+    serde_json::to_writer(&mut *out, event)?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Write progress events as JSON Lines, until the sender is disconnected.
+///
+/// This blocks, so should be run via e.g. [`tokio::task::spawn_blocking`].
+pub fn write_progress_json(mut events: Receiver<ProgressEvent>, mut out: impl Write) -> Result<()> {
+    while let Some(event) = events.blocking_recv() {
+        write_progress_event(&mut out, &event)?;
+    }
+    Ok(())
 }
 
 /// Write the status of layers to download.
@@ -651,34 +923,39 @@ async fn container_import(
 }
 
 /// Export a container image with an encapsulated ostree commit.
-#[allow(clippy::too_many_arguments)]
 async fn container_export(
     repo: &ostree::Repo,
     rev: &str,
+    additional_revs: Vec<String>,
     imgref: &ImageReference,
-    labels: BTreeMap<String, String>,
-    authfile: Option<PathBuf>,
-    copy_meta_keys: Vec<String>,
-    copy_meta_opt_keys: Vec<String>,
-    cmd: Option<Vec<String>>,
-    compression_fast: bool,
+    config: Config,
+    opts: crate::container::ExportOpts<'_, '_>,
 ) -> Result<()> {
-    let config = Config {
-        labels: Some(labels),
-        cmd,
-    };
-    let opts = crate::container::ExportOpts {
-        copy_meta_keys,
-        copy_meta_opt_keys,
-        authfile,
-        skip_compression: compression_fast, // TODO rename this in the struct at the next semver break
-        ..Default::default()
+    let pushed = if additional_revs.is_empty() {
+        crate::container::encapsulate(repo, rev, &config, Some(opts), imgref).await?
+    } else {
+        let revs: Vec<_> = std::iter::once(rev)
+            .chain(additional_revs.iter().map(|r| r.as_str()))
+            .collect();
+        crate::container::encapsulate_multiarch(repo, &revs, &config, Some(opts), imgref).await?
     };
-    let pushed = crate::container::encapsulate(repo, rev, &config, Some(opts), imgref).await?;
     println!("{}", pushed);
     Ok(())
 }
 
+/// Load content metadata from a JSON file, or if the path is `auto` generate it
+/// from the commit, and compute the size of each component.
+#[context("Loading content metadata from {path}")]
+fn load_contentmeta(repo: &ostree::Repo, rev: &str, path: &Utf8Path) -> Result<ObjectMetaSized> {
+    let meta = if path.as_str() == "auto" {
+        ObjectMeta::from_commit(repo, rev)?
+    } else {
+        let f = std::fs::File::open(path)?;
+        ObjectMeta::from_json(std::io::BufReader::new(f))?
+    };
+    ObjectMetaSized::compute_sizes(repo, meta)
+}
+
 /// Load metadata for a container image with an encapsulated ostree commit.
 async fn container_info(imgref: &OstreeImageReference) -> Result<()> {
     let (_, digest) = crate::container::fetch_manifest(imgref).await?;
@@ -687,17 +964,42 @@ async fn container_info(imgref: &OstreeImageReference) -> Result<()> {
 }
 
 /// Write a layered container image into an OSTree commit.
+#[allow(clippy::too_many_arguments)]
 async fn container_store(
     repo: &ostree::Repo,
     imgref: &OstreeImageReference,
     proxyopts: ContainerProxyOpts,
+    platform: Option<&Platform>,
+    in_process: bool,
+    jobs: Option<NonZeroUsize>,
+    content_policy: crate::tar::ContentPolicy,
     quiet: bool,
+    progressopts: ProgressJsonOpts,
     check: Option<Utf8PathBuf>,
 ) -> Result<()> {
-    let mut imp = ImageImporter::new(repo, imgref, proxyopts.into()).await?;
+    // When writing JSON to standard output, don't mix in other output.
+    let print = !progressopts.json;
+    let json = progressopts.open()?;
+    let mut imp = if in_process {
+        ImageImporter::new_in_process(repo, imgref, platform).await?
+    } else {
+        ImageImporter::new_with_platform(repo, imgref, proxyopts.into(), platform).await?
+    };
+    if let Some(jobs) = jobs {
+        imp.set_jobs(jobs);
+    }
+    imp.set_content_policy(content_policy);
     let prep = match imp.prepare().await? {
         PrepareResult::AlreadyPresent(c) => {
-            println!("No changes in {} => {}", imgref, c.merge_commit);
+            if let Some(mut out) = json {
+                let event = ProgressEvent::Imported {
+                    merge_commit: c.merge_commit.clone(),
+                };
+                write_progress_event(&mut out, &event)?;
+            }
+            if print {
+                println!("No changes in {} => {}", imgref, c.merge_commit);
+            }
             return Ok(());
         }
         PrepareResult::Ready(r) => r,
@@ -713,12 +1015,18 @@ async fn container_store(
         // In check mode, we're done
         return Ok(());
     }
-    if let Some(previous_state) = prep.previous_state.as_ref() {
-        let diff = ManifestDiff::new(&previous_state.manifest, &prep.manifest);
-        diff.print();
+    if print {
+        if let Some(previous_state) = prep.previous_state.as_ref() {
+            let diff = ManifestDiff::new(&previous_state.manifest, &prep.manifest);
+            diff.print();
+        }
+        print_layer_status(&prep);
     }
-    print_layer_status(&prep);
-    let printer = (!quiet).then(|| {
+    let json_writer = json.map(|out| {
+        let events = imp.request_progress_events();
+        tokio::task::spawn_blocking(move || write_progress_json(events, out))
+    });
+    let printer = (!quiet && print).then(|| {
         let layer_progress = imp.request_progress();
         let layer_byte_progress = imp.request_layer_progress();
         tokio::task::spawn(async move {
@@ -729,13 +1037,18 @@ async fn container_store(
     if let Some(printer) = printer {
         let _ = printer.await;
     }
+    if let Some(json_writer) = json_writer {
+        let _ = json_writer.await;
+    }
     let import = import?;
     if let Some(msg) =
         ostree_container::store::image_filtered_content_warning(repo, &imgref.imgref)?
     {
         eprintln!("{msg}")
     }
-    println!("Wrote: {} => {}", imgref, import.merge_commit);
+    if print {
+        println!("Wrote: {} => {}", imgref, import.merge_commit);
+    }
     Ok(())
 }
 
@@ -829,7 +1142,8 @@ async fn testing(opts: &TestingOpts) -> Result<()> {
         TestingOpts::Run => crate::integrationtest::run_tests(),
         TestingOpts::RunIMA => crate::integrationtest::test_ima(),
         TestingOpts::FilterTar => {
-            crate::tar::filter_tar(std::io::stdin(), std::io::stdout()).map(|_| {})
+            crate::tar::filter_tar(std::io::stdin(), std::io::stdout(), Default::default())
+                .map(|_| {})
         }
     }
 }
@@ -892,40 +1206,109 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 repo,
                 rev,
                 imgref,
+                additional_revs,
                 labels,
                 authfile,
                 copy_meta_keys,
                 copy_meta_opt_keys,
                 cmd,
+                entrypoint,
+                env,
+                working_dir,
+                user,
+                exposed_ports,
+                volumes,
+                stop_signal,
+                annotations,
                 compression_fast,
+                compression,
+                content_toc,
+                jobs,
+                reproducible,
+                incremental_push,
+                contentmeta,
+                chunk_by_path,
+                max_layers,
+                previous_build,
+                dry_run,
+                report,
             } => {
-                let labels: Result<BTreeMap<_, _>> = labels
-                    .into_iter()
-                    .map(|l| {
-                        let (k, v) = l
-                            .split_once('=')
-                            .ok_or_else(|| anyhow::anyhow!("Missing '=' in label {}", l))?;
-                        Ok((k.to_string(), v.to_string()))
-                    })
-                    .collect();
+                let labels = parse_key_values("label", labels)?;
+                let annotations = (!annotations.is_empty())
+                    .then(|| parse_key_values("annotation", annotations))
+                    .transpose()?;
+                let config = Config {
+                    labels: Some(labels),
+                    cmd,
+                    entrypoint,
+                    env,
+                    working_dir,
+                    user,
+                    exposed_ports,
+                    volumes,
+                    stop_signal,
+                    annotations,
+                };
                 let repo = parse_repo(&repo)?;
-                container_export(
-                    &repo,
-                    &rev,
-                    &imgref,
-                    labels?,
-                    authfile,
+                let contentmeta = contentmeta
+                    .map(|path| load_contentmeta(&repo, &rev, &path))
+                    .transpose()?;
+                if previous_build.is_some() && contentmeta.is_none() && !chunk_by_path {
+                    anyhow::bail!("--previous-build requires --contentmeta or --chunk-by-path");
+                }
+                let prior_build = if let Some(imgref) = previous_build {
+                    let imgref = OstreeImageReference {
+                        sigverify: ostree_container::SignatureSource::ContainerPolicyAllowInsecure,
+                        imgref,
+                    };
+                    // Use the same credentials as for the push
+                    let config = ostree_container::store::ImageProxyConfig {
+                        authfile: authfile.clone(),
+                        ..Default::default()
+                    };
+                    let (manifest, _) =
+                        crate::container::fetch_manifest_with_config(&imgref, config).await?;
+                    Some(manifest)
+                } else {
+                    None
+                };
+                let opts = crate::container::ExportOpts {
                     copy_meta_keys,
                     copy_meta_opt_keys,
-                    cmd,
-                    compression_fast,
-                )
-                .await
+                    authfile,
+                    skip_compression: compression_fast, // TODO rename this in the struct at the next semver break
+                    compression,
+                    content_toc,
+                    jobs,
+                    reproducible,
+                    incremental_push,
+                    max_layers,
+                    prior_build: prior_build.as_ref(),
+                    contentmeta: contentmeta.as_ref(),
+                    chunk_by_path,
+                    ..Default::default()
+                };
+                if dry_run {
+                    let r = crate::container::chunking_report(&repo, &rev, Some(opts))?;
+                    if report.is_some() {
+                        serde_json::to_writer_pretty(std::io::stdout().lock(), &r)?;
+                        println!();
+                    } else {
+                        r.print();
+                    }
+                    return Ok(());
+                }
+                container_export(&repo, &rev, additional_revs, &imgref, config, opts).await
             }
             ContainerOpts::Image(opts) => match opts {
-                ContainerImageOpts::List { repo } => {
+                ContainerImageOpts::List { repo, pinned } => {
                     let repo = parse_repo(&repo)?;
-                    for image in crate::container::store::list_images(&repo)? {
+                    let images = if pinned {
+                        crate::container::store::list_pinned_images(&repo)?
+                    } else {
+                        crate::container::store::list_images(&repo)?
+                    };
+                    for image in images {
                         println!("{}", image);
                     }
                     Ok(())
@@ -934,11 +1317,28 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     repo,
                     imgref,
                     proxyopts,
+                    platform,
+                    in_process,
+                    jobs,
+                    content_policy,
                     quiet,
+                    progressopts,
                     check,
                 } => {
                     let repo = parse_repo(&repo)?;
-                    container_store(&repo, &imgref, proxyopts, quiet, check).await
+                    container_store(
+                        &repo,
+                        &imgref,
+                        proxyopts,
+                        platform.as_ref(),
+                        in_process,
+                        jobs,
+                        content_policy,
+                        quiet,
+                        progressopts,
+                        check,
+                    )
+                    .await
                 }
                 ContainerImageOpts::History { repo, imgref } => {
                     let repo = parse_repo(&repo)?;
@@ -981,6 +1381,25 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     }
                     Ok(())
                 }
+                ContainerImageOpts::Pin { repo, imgrefs } => {
+                    let repo = parse_repo(&repo)?;
+                    for imgref in imgrefs {
+                        crate::container::store::pin_image(&repo, &imgref)?;
+                        println!("Pinned: {imgref}");
+                    }
+                    Ok(())
+                }
+                ContainerImageOpts::Unpin { repo, imgrefs } => {
+                    let repo = parse_repo(&repo)?;
+                    for imgref in imgrefs {
+                        if crate::container::store::unpin_image(&repo, &imgref)? {
+                            println!("Unpinned: {imgref}");
+                        } else {
+                            println!("Not pinned: {imgref}");
+                        }
+                    }
+                    Ok(())
+                }
                 ContainerImageOpts::PruneLayers { repo } => {
                     let repo = parse_repo(&repo)?;
                     let nlayers = crate::container::store::gc_image_layers(&repo)?;
@@ -1037,6 +1456,30 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     println!("Pushed: {}", digest);
                     Ok(())
                 }
+                ContainerImageOpts::Prune {
+                    sysroot,
+                    keep_last,
+                    older_than,
+                } => {
+                    let sysroot = &ostree::Sysroot::new(Some(&gio::File::for_path(&sysroot)));
+                    sysroot.load(gio::Cancellable::NONE)?;
+                    let sysroot = &SysrootLock::new_from_sysroot(sysroot).await?;
+                    let policy = crate::container::retention::RetentionPolicy {
+                        keep_last,
+                        older_than,
+                    };
+                    let report = crate::container::retention::prune_images(sysroot, &policy)?;
+                    for imgref in report.removed.iter() {
+                        println!("Removed: {imgref}");
+                    }
+                    let size = glib::format_size(report.bytes_freed);
+                    println!(
+                        "Removed images: {} layers: {} freed: {size}",
+                        report.removed.len(),
+                        report.layers_removed
+                    );
+                    Ok(())
+                }
                 ContainerImageOpts::Deploy {
                     sysroot,
                     stateroot,
@@ -1045,11 +1488,14 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     transport,
                     no_signature_verification,
                     ostree_remote,
+                    enforce_container_sigpolicy,
                     target_imgref,
                     no_imgref,
                     karg,
                     proxyopts,
+                    in_process,
                     write_commitid_to,
+                    progressopts,
                 } => {
                     let sysroot = &if let Some(sysroot) = sysroot {
                         ostree::Sysroot::new(Some(&gio::File::for_path(&sysroot)))
@@ -1088,7 +1534,13 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                         let sigverify = if no_signature_verification {
                             ostree_container::SignatureSource::ContainerPolicyAllowInsecure
                         } else if let Some(remote) = ostree_remote.as_ref() {
-                            ostree_container::SignatureSource::OstreeRemote(remote.to_string())
+                            if enforce_container_sigpolicy {
+                                ostree_container::SignatureSource::OstreeRemoteAndContainerPolicy(
+                                    remote.to_string(),
+                                )
+                            } else {
+                                ostree_container::SignatureSource::OstreeRemote(remote.to_string())
+                            }
                         } else {
                             ostree_container::SignatureSource::ContainerPolicy
                         };
@@ -1100,12 +1552,23 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                         imgref.as_str().try_into()?
                     };
 
+                    let (progress, json_writer) = match progressopts.open()? {
+                        Some(out) => {
+                            let (s, r) = tokio::sync::mpsc::channel(32);
+                            let w =
+                                tokio::task::spawn_blocking(move || write_progress_json(r, out));
+                            (Some(s), Some(w))
+                        }
+                        None => (None, None),
+                    };
                     #[allow(clippy::needless_update)]
                     let options = crate::container::deploy::DeployOpts {
                         kargs: kargs.as_deref(),
                         target_imgref: target_imgref.as_ref(),
                         proxy_cfg: Some(proxyopts.into()),
+                        in_process,
                         no_imgref,
+                        progress,
                         ..Default::default()
                     };
                     let state = crate::container::deploy::deploy(
@@ -1114,7 +1577,11 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                         &imgref,
                         Some(options),
                     )
-                    .await?;
+                    .await;
+                    if let Some(json_writer) = json_writer {
+                        let _ = json_writer.await;
+                    }
+                    let state = state?;
                     let wrote_imgref = target_imgref.as_ref().unwrap_or(&imgref);
                     if let Some(msg) = ostree_container::store::image_filtered_content_warning(
                         repo,
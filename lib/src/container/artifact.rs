@@ -0,0 +1,79 @@
+//! # OCI artifacts
+//!
+//! APIs to attach [OCI 1.1 artifacts] such as SBOMs, provenance or signatures to an
+//! image in an OCI directory (for example, one generated by [`super::encapsulate()`]),
+//! and to find them again via their `subject`.
+//!
+//! Artifacts are written into the same OCI layout as the image, and referenced
+//! from its top level index, tagged after their digest.  Because the index then has
+//! multiple entries, the image must be tagged (e.g. `oci:/path/to/dir:latest`) so that
+//! it can be referenced unambiguously; attaching to an untagged image is an error.
+//!
+//! [OCI 1.1 artifacts]: https://github.com/opencontainers/image-spec/blob/v1.1.0/manifest.md#guidelines-for-artifact-usage
+
+use super::ocidir::OciDir;
+use anyhow::{anyhow, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use containers_image_proxy::oci_spec::image::{Descriptor, DescriptorBuilder, MediaType};
+use fn_error_context::context;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+pub use super::ocidir::{ArtifactManifest, Referrer, MEDIA_TYPE_EMPTY};
+
+/// Attach an artifact of the given type (e.g. `application/spdx+json`) to the manifest
+/// or image index with digest `subject` in the OCI directory.  Each blob is provided
+/// as its media type and contents.
+///
+/// Returns the digest of the artifact manifest.
+#[context("Attaching artifact to {subject}")]
+pub fn attach<R: Read>(
+    ocidir: &Dir,
+    subject: &str,
+    artifact_type: &str,
+    blobs: impl IntoIterator<Item = (MediaType, R)>,
+    annotations: Option<HashMap<String, String>>,
+) -> Result<String> {
+    let ocidir = OciDir::open(ocidir)?;
+    let subject = ocidir
+        .find_descriptor(subject)?
+        .ok_or_else(|| anyhow!("No manifest found for {subject}"))?;
+    let layers = blobs
+        .into_iter()
+        .map(|(media_type, contents)| {
+            let blob = ocidir.write_blob(contents)?;
+            Ok(blob.descriptor().media_type(media_type).build().unwrap())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let desc = ocidir.write_artifact(artifact_type, Some(subject), layers, annotations)?;
+    Ok(desc.digest().to_string())
+}
+
+/// List the artifacts in the OCI directory which refer to the manifest or image index
+/// with digest `subject`, optionally only those of the given artifact type.
+#[context("Listing referrers of {subject}")]
+pub fn referrers(
+    ocidir: &Dir,
+    subject: &str,
+    artifact_type: Option<&str>,
+) -> Result<Vec<Referrer>> {
+    OciDir::open(ocidir)?.referrers(subject, artifact_type)
+}
+
+/// Fetch the manifest of an artifact.
+#[context("Fetching artifact {}", referrer.digest)]
+pub fn fetch(ocidir: &Dir, referrer: &Referrer) -> Result<ArtifactManifest> {
+    let desc = DescriptorBuilder::default()
+        .media_type(referrer.media_type.clone())
+        .digest(referrer.digest.clone())
+        .size(referrer.size)
+        .build()
+        .unwrap();
+    OciDir::open(ocidir)?.read_artifact(&desc)
+}
+
+/// Open a blob of an artifact, as referenced by [`ArtifactManifest::layers`].
+pub fn open_blob(ocidir: &Dir, desc: &Descriptor) -> Result<File> {
+    OciDir::open(ocidir)?.read_blob(desc)
+}
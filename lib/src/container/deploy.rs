@@ -4,7 +4,7 @@ use std::collections::HashSet;
 
 use super::store::LayeredImageState;
 use super::{ImageReference, OstreeImageReference};
-use crate::container::store::PrepareResult;
+use crate::container::store::{ImportPhase, PrepareResult, ProgressEvent};
 use crate::keyfileext::KeyFileExt;
 use crate::sysroot::SysrootLock;
 use anyhow::Result;
@@ -37,6 +37,11 @@ pub struct DeployOpts<'a> {
     /// Configuration for fetching containers.
     pub proxy_cfg: Option<super::store::ImageProxyConfig>,
 
+    /// Read the image (which must use the `oci:` or `oci-archive:` transport) in process,
+    /// rather than via the containers-image-proxy; `proxy_cfg` is then unused.
+    /// See [`super::store::ImageImporter::new_in_process`].
+    pub in_process: bool,
+
     /// If true, then no image reference will be written; but there will be refs
     /// for the fetched layers.  This ensures that if the machine is later updated
     /// to a different container image, the fetch process will reuse shared layers, but
@@ -45,6 +50,9 @@ pub struct DeployOpts<'a> {
 
     /// Do not cleanup deployments
     pub no_clean: bool,
+
+    /// Send progress events for the import and deployment to this channel.
+    pub progress: Option<tokio::sync::mpsc::Sender<ProgressEvent>>,
 }
 
 /// Write a container image to an OSTree deployment.
@@ -61,9 +69,12 @@ pub async fn deploy(
     let options = options.unwrap_or_default();
     let repo = &sysroot.repo();
     let merge_deployment = sysroot.merge_deployment(Some(stateroot));
-    let mut imp =
+    let mut imp = if options.in_process {
+        super::store::ImageImporter::new_in_process(repo, imgref, None).await?
+    } else {
         super::store::ImageImporter::new(repo, imgref, options.proxy_cfg.unwrap_or_default())
-            .await?;
+            .await?
+    };
     imp.require_bootable();
     if let Some(target) = options.target_imgref {
         imp.set_target(target);
@@ -71,8 +82,23 @@ pub async fn deploy(
     if options.no_imgref {
         imp.set_no_imgref();
     }
+    if let Some(progress) = options.progress.clone() {
+        imp.set_progress_events(progress);
+    }
+    // It's OK if the caller is no longer listening for progress.
+    let send_progress = |event| async {
+        if let Some(progress) = options.progress.as_ref() {
+            let _ = progress.send(event).await;
+        }
+    };
     let state = match imp.prepare().await? {
-        PrepareResult::AlreadyPresent(r) => r,
+        PrepareResult::AlreadyPresent(r) => {
+            send_progress(ProgressEvent::Imported {
+                merge_commit: r.merge_commit.clone(),
+            })
+            .await;
+            r
+        }
         PrepareResult::Ready(prep) => {
             if let Some(warning) = prep.deprecated_warning() {
                 crate::cli::print_deprecated_warning(warning).await;
@@ -86,6 +112,10 @@ pub async fn deploy(
     let target_imgref = options.target_imgref.unwrap_or(imgref);
     origin.set_string("origin", ORIGIN_CONTAINER, &target_imgref.to_string());
 
+    send_progress(ProgressEvent::Phase {
+        phase: ImportPhase::Deploy,
+    })
+    .await;
     let opts = ostree::SysrootDeployTreeOpts {
         override_kernel_argv: options.kargs,
         ..Default::default()
@@ -125,6 +155,11 @@ pub async fn deploy(
             sysroot.cleanup(cancellable)?;
         }
     }
+    send_progress(ProgressEvent::Deployed {
+        stateroot: stateroot.to_string(),
+        commit: commit.to_string(),
+    })
+    .await;
 
     Ok(state)
 }
@@ -144,14 +179,9 @@ fn deployment_origin_container(
     Ok(r)
 }
 
-/// Remove all container images which are not the target of a deployment.
-/// This acts equivalently to [`super::store::remove_images()`] - the underlying layers
-/// are not pruned.
-///
-/// The set of removed images is returned.
-pub fn remove_undeployed_images(sysroot: &SysrootLock) -> Result<Vec<ImageReference>> {
-    let repo = &sysroot.repo();
-    let deployment_origins: Result<HashSet<_>> = sysroot
+/// Return the container images which are the origin of a deployment.
+pub(crate) fn deployed_images(sysroot: &ostree::Sysroot) -> Result<HashSet<ImageReference>> {
+    sysroot
         .deployments()
         .into_iter()
         .filter_map(|deploy| {
@@ -159,15 +189,25 @@ pub fn remove_undeployed_images(sysroot: &SysrootLock) -> Result<Vec<ImageRefere
                 .map(|v| v.map(|v| v.imgref))
                 .transpose()
         })
-        .collect();
-    let deployment_origins = deployment_origins?;
+        .collect()
+}
+
+/// Remove all container images which are not the target of a deployment,
+/// and are not pinned via [`super::store::pin_image`].
+/// This acts equivalently to [`super::store::remove_images()`] - the underlying layers
+/// are not pruned.
+///
+/// The set of removed images is returned.
+pub fn remove_undeployed_images(sysroot: &SysrootLock) -> Result<Vec<ImageReference>> {
+    let repo = &sysroot.repo();
+    let deployment_origins = deployed_images(sysroot)?;
     // TODO add an API that returns ImageReference instead
     let all_images = super::store::list_images(&sysroot.repo())?
         .into_iter()
         .filter_map(|img| ImageReference::try_from(img.as_str()).ok());
     let mut removed = Vec::new();
     for image in all_images {
-        if !deployment_origins.contains(&image) {
+        if !deployment_origins.contains(&image) && !super::store::image_is_pinned(repo, &image)? {
             super::store::remove_image(repo, &image)?;
             removed.push(image);
         }
@@ -1,25 +1,30 @@
 //! APIs for creating container images from OSTree commits
 
-use super::ocidir::{Layer, OciDir};
-use super::{ocidir, OstreeImageReference, Transport, COMPONENT_SEPARATOR, CONTENT_ANNOTATION};
-use super::{ImageReference, SignatureSource, OSTREE_COMMIT_LABEL};
-use crate::chunking::{Chunk, Chunking, ObjectMetaSized};
+use super::ocidir::{Layer, LayerCompression, OciDir};
+use super::push::{LayerSink, Uploader};
+use super::toc::{self, TocPosition, TOC_ANNOTATION};
+use super::{
+    ocidir, Transport, COMPONENT_SEPARATOR, CONTENT_ANNOTATION, UNCOMPRESSED_SIZE_ANNOTATION,
+};
+use super::{ImageReference, OSTREE_COMMIT_LABEL};
+use crate::chunking::{Chunk, Chunking, ChunkingReport, ObjectMetaSized};
 use crate::container::skopeo;
 use crate::tar as ostree_tar;
 use anyhow::{anyhow, Context, Result};
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use containers_image_proxy::oci_spec;
-use flate2::Compression;
 use fn_error_context::context;
 use gio::glib;
 use oci_spec::image as oci_image;
 use ostree::gio;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
-use std::num::NonZeroU32;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use tracing::instrument;
 
 /// The label which may be used in addition to the standard OCI label.
@@ -55,13 +60,66 @@ impl ExportLayout {
 /// schema, it's not actually useful today.  But, we keep it
 /// out of principle.
 const BLOB_OSTREE_ANNOTATION: &str = "ostree.encapsulated";
+
+/// Commit metadata key (`as`) for the container `ENTRYPOINT`.
+pub const COMMIT_META_CONTAINER_ENTRYPOINT: &str = "ostree.container-entrypoint";
+/// Commit metadata key (`as`) for the container environment, in `KEY=VALUE` form.
+pub const COMMIT_META_CONTAINER_ENV: &str = "ostree.container-env";
+/// Commit metadata key (`s`) for the container working directory.
+pub const COMMIT_META_CONTAINER_WORKDIR: &str = "ostree.container-workdir";
+/// Commit metadata key (`s`) for the container user.
+pub const COMMIT_META_CONTAINER_USER: &str = "ostree.container-user";
+/// Commit metadata key (`as`) for the container exposed ports, e.g. `8080/tcp`.
+pub const COMMIT_META_CONTAINER_EXPOSED_PORTS: &str = "ostree.container-exposed-ports";
+/// Commit metadata key (`as`) for the container volumes.
+pub const COMMIT_META_CONTAINER_VOLUMES: &str = "ostree.container-volumes";
+/// Commit metadata key (`s`) for the container stop signal.
+pub const COMMIT_META_CONTAINER_STOP_SIGNAL: &str = "ostree.container-stop-signal";
+/// Commit metadata key (`a{ss}`) for manifest annotations.
+pub const COMMIT_META_CONTAINER_ANNOTATIONS: &str = "ostree.container-annotations";
+
 /// Configuration for the generated container.
+///
+/// Each value (other than labels) may also be provided by commit metadata,
+/// e.g. [`COMMIT_META_CONTAINER_ENTRYPOINT`]; values set here take precedence.
+/// Like `ostree.container-cmd` ([`ostree::COMMIT_META_CONTAINER_CMD`]), the
+/// `ostree.container-*` commit metadata keys and their types are a stable interface
+/// for build tools, and will not be renamed or reinterpreted.
 #[derive(Debug, Default)]
 pub struct Config {
     /// Additional labels.
     pub labels: Option<BTreeMap<String, String>>,
     /// The equivalent of a `Dockerfile`'s `CMD` instruction.
     pub cmd: Option<Vec<String>>,
+    /// The equivalent of a `Dockerfile`'s `ENTRYPOINT` instruction.
+    pub entrypoint: Option<Vec<String>>,
+    /// Environment variables, in `KEY=VALUE` form.
+    pub env: Option<Vec<String>>,
+    /// The working directory.
+    pub working_dir: Option<String>,
+    /// The user (and optionally group) to run as.
+    pub user: Option<String>,
+    /// Exposed ports, in `port/protocol` form (e.g. `8080/tcp`).
+    pub exposed_ports: Option<Vec<String>>,
+    /// Paths which should be volumes.
+    pub volumes: Option<Vec<String>>,
+    /// The signal used to stop the container.
+    pub stop_signal: Option<String>,
+    /// Annotations for the image manifest.
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+/// Return the configured value if set, otherwise look it up in commit metadata.
+fn config_or_commit_meta<T: glib::FromVariant + Clone>(
+    configured: &Option<T>,
+    meta: &glib::VariantDict,
+    key: &str,
+) -> Result<Option<T>> {
+    if let Some(v) = configured {
+        return Ok(Some(v.clone()));
+    }
+    meta.lookup::<T>(key)
+        .with_context(|| format!("Parsing commit metadata key {key}"))
 }
 
 fn commit_meta_to_labels<'a>(
@@ -100,24 +158,112 @@ fn commit_meta_to_labels<'a>(
     Ok(())
 }
 
+/// A content layer generated from a chunk.
+struct ChunkLayer {
+    layer: Layer,
+    name: String,
+    packages: Vec<String>,
+    toc: Option<TocPosition>,
+}
+
+/// Export a single chunk as a layer.
+fn export_chunk(
+    repo: &ostree::Repo,
+    commit: &str,
+    ociw: &OciDir,
+    chunk: Chunk,
+    compression: LayerCompression,
+    content_toc: bool,
+    sink: Option<&LayerSink>,
+) -> Result<ChunkLayer> {
+    if let (true, LayerCompression::Zstd(level)) = (content_toc, compression) {
+        let (layer, toc) = toc::export_chunk_with_toc(repo, commit, chunk.content, ociw, level)?;
+        if let Some(sink) = sink {
+            sink.send(&layer)?;
+        }
+        return Ok(ChunkLayer {
+            layer,
+            name: chunk.name,
+            packages: chunk.packages,
+            toc: Some(toc),
+        });
+    }
+    let mut w = ociw.create_layer_with(compression)?;
+    ostree_tar::export_chunk(repo, commit, chunk.content, &mut w)?;
+    let layer = w.into_inner()?.complete()?;
+    if let Some(sink) = sink {
+        sink.send(&layer)?;
+    }
+    Ok(ChunkLayer {
+        layer,
+        name: chunk.name,
+        packages: chunk.packages,
+        toc: None,
+    })
+}
+
+/// Export chunks as layers, using multiple threads if configured.  The returned
+/// layers are in the same order as the input chunks.
 fn export_chunks(
     repo: &ostree::Repo,
     commit: &str,
     ociw: &mut OciDir,
     chunks: Vec<Chunk>,
     opts: &ExportOpts,
-) -> Result<Vec<(Layer, String, Vec<String>)>> {
-    chunks
-        .into_iter()
-        .enumerate()
-        .map(|(i, chunk)| -> Result<_> {
-            let mut w = ociw.create_layer(Some(opts.compression()))?;
-            ostree_tar::export_chunk(repo, commit, chunk.content, &mut w)
-                .with_context(|| format!("Exporting chunk {i}"))?;
-            let w = w.into_inner()?;
-            Ok((w.complete()?, chunk.name, chunk.packages))
-        })
-        .collect()
+) -> Result<Vec<ChunkLayer>> {
+    let compression = opts.compression();
+    let content_toc = opts.content_toc;
+    let sink = opts.layer_sink.as_ref();
+    let jobs = opts.jobs().min(chunks.len());
+    if jobs <= 1 {
+        return chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                export_chunk(repo, commit, ociw, chunk, compression, content_toc, sink)
+                    .with_context(|| format!("Exporting chunk {i}"))
+            })
+            .collect();
+    }
+
+    let ociw = &*ociw;
+    let repofd = repo.dfd_borrow();
+    let queue = Mutex::new(chunks.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        for _ in 0..jobs {
+            let (queue, results, failed) = (&queue, &results, &failed);
+            s.spawn(move || {
+                // Each worker uses its own repository instance
+                let repo = match ostree::Repo::open_at_dir(repofd, ".") {
+                    Ok(r) => r,
+                    Err(e) => {
+                        failed.store(true, Ordering::SeqCst);
+                        results.lock().unwrap().push((0, Err(e.into())));
+                        return;
+                    }
+                };
+                while !failed.load(Ordering::SeqCst) {
+                    let next = queue.lock().unwrap().next();
+                    let (i, chunk) = match next {
+                        Some(v) => v,
+                        None => break,
+                    };
+                    let r =
+                        export_chunk(&repo, commit, ociw, chunk, compression, content_toc, sink)
+                            .with_context(|| format!("Exporting chunk {i}"));
+                    if r.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap().push((i, r));
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
 }
 
 /// Write an ostree commit to an OCI blob
@@ -133,41 +279,73 @@ fn export_chunked(
     mut chunking: Chunking,
     opts: &ExportOpts,
     description: &str,
+    created: Option<DateTime<Utc>>,
 ) -> Result<()> {
+    let created = created.unwrap_or_else(Utc::now);
     let layers = export_chunks(repo, commit, ociw, chunking.take_chunks(), opts)?;
-    let compression = Some(opts.compression());
-
     // In V1, the ostree layer comes first
-    let mut w = ociw.create_layer(compression)?;
+    let mut w = ociw.create_layer_with(opts.compression())?;
     ostree_tar::export_final_chunk(repo, commit, chunking.remainder, &mut w)?;
     let w = w.into_inner()?;
     let ostree_layer = w.complete()?;
+    if let Some(sink) = opts.layer_sink.as_ref() {
+        sink.send(&ostree_layer)?;
+    }
 
     // Then, we have a label that points to the last chunk.
     // Note in the pathological case of a single layer chunked v1 image, this could be the ostree layer.
     let last_digest = layers
         .last()
-        .map(|v| &v.0)
+        .map(|v| &v.layer)
         .unwrap_or(&ostree_layer)
         .uncompressed_sha256
         .clone();
 
     // Add the ostree layer
-    ociw.push_layer(manifest, imgcfg, ostree_layer, description, None);
+    let uncompressed_size = ostree_layer.uncompressed_size;
+    let annotation_ostree_layer = HashMap::from([(
+        UNCOMPRESSED_SIZE_ANNOTATION.to_string(),
+        uncompressed_size.to_string(),
+    )]);
+    ociw.push_layer_full(
+        manifest,
+        imgcfg,
+        ostree_layer,
+        Some(annotation_ostree_layer),
+        description,
+        created,
+    );
+    push_history_uncompressed_size(imgcfg, uncompressed_size);
     // Add the component/content layers
     let mut buf = [0; 8];
     let sep = COMPONENT_SEPARATOR.encode_utf8(&mut buf);
-    for (layer, name, mut packages) in layers {
+    for ChunkLayer {
+        layer,
+        name,
+        mut packages,
+        toc,
+    } in layers
+    {
         let mut annotation_component_layer = HashMap::new();
         packages.sort();
         annotation_component_layer.insert(CONTENT_ANNOTATION.to_string(), packages.join(sep));
-        ociw.push_layer(
+        if let Some(toc) = toc {
+            annotation_component_layer.insert(TOC_ANNOTATION.to_string(), toc.to_string());
+        }
+        let uncompressed_size = layer.uncompressed_size;
+        annotation_component_layer.insert(
+            UNCOMPRESSED_SIZE_ANNOTATION.to_string(),
+            uncompressed_size.to_string(),
+        );
+        ociw.push_layer_full(
             manifest,
             imgcfg,
             layer,
-            name.as_str(),
             Some(annotation_component_layer),
+            name.as_str(),
+            created,
         );
+        push_history_uncompressed_size(imgcfg, uncompressed_size);
     }
 
     // This label (mentioned above) points to the last layer that is part of
@@ -179,31 +357,123 @@ fn export_chunked(
     Ok(())
 }
 
-/// Generate an OCI image from a given ostree root
-#[context("Building oci")]
-#[allow(clippy::too_many_arguments)]
-fn build_oci(
+/// Also record the uncompressed size of the layer just pushed in its history entry,
+/// as layer annotations may be lost.
+fn push_history_uncompressed_size(imgcfg: &mut oci_image::ImageConfiguration, size: u64) {
+    if let Some(h) = imgcfg.history_mut().last_mut() {
+        super::set_history_uncompressed_size(h, size);
+    }
+}
+
+/// Translate an architecture name as used by e.g. `uname -m` or RPM into
+/// the Go naming and CPU variant used for OCI platforms.
+fn goarch(arch: &str) -> Option<(&'static str, Option<&'static str>)> {
+    let r = match arch {
+        "x86_64" | "amd64" => ("amd64", None),
+        "aarch64" | "arm64" => ("arm64", None),
+        "i386" | "i686" => ("386", None),
+        "armv7hl" | "armv7l" | "armhf" | "armhfp" => ("arm", Some("v7")),
+        "ppc64le" => ("ppc64le", None),
+        "s390x" => ("s390x", None),
+        "riscv64" => ("riscv64", None),
+        _ => return None,
+    };
+    Some(r)
+}
+
+/// Compute the platform of a commit from the architecture suffix of its kernel version
+/// in the standard `ostree.linux` metadata key (e.g. `6.5.6-300.fc39.aarch64` or
+/// `6.1.0-13-arm64`), defaulting to the host.
+fn platform_for_commit(meta: &glib::VariantDict) -> Result<oci_image::Platform> {
+    let mut platform = oci_image::Platform::default();
+    let kver = meta.lookup::<String>(&ostree::METADATA_KEY_LINUX)?;
+    let arch = kver
+        .as_deref()
+        .and_then(|kver| kver.rsplit(['.', '-']).next())
+        .and_then(goarch);
+    if let Some((arch, variant)) = arch {
+        platform.set_architecture(oci_image::Arch::from(arch));
+        platform.set_variant(variant.map(ToOwned::to_owned));
+    }
+    Ok(platform)
+}
+
+/// Compute the timestamp used for all generated metadata in reproducible mode; this is
+/// [`SOURCE_DATE_EPOCH`] if provided, otherwise the commit timestamp.
+///
+/// [`SOURCE_DATE_EPOCH`]: https://reproducible-builds.org/specs/source-date-epoch/
+fn reproducible_timestamp(
+    source_date_epoch: Option<&str>,
+    commit_timestamp: u64,
+) -> Result<DateTime<Utc>> {
+    let ts = if let Some(epoch) = source_date_epoch {
+        epoch
+            .trim()
+            .parse::<i64>()
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH: {epoch}"))?
+    } else {
+        commit_timestamp.try_into()?
+    };
+    Utc.timestamp_opt(ts, 0)
+        .single()
+        .ok_or_else(|| anyhow!("Invalid timestamp {ts}"))
+}
+
+/// Split a commit into chunks as configured by the export options.
+fn chunking_for_commit(repo: &ostree::Repo, commit: &str, opts: &ExportOpts) -> Result<Chunking> {
+    match opts.contentmeta {
+        Some(meta) => {
+            Chunking::from_mapping(repo, commit, meta, &opts.max_layers, opts.prior_build)
+        }
+        None if opts.chunk_by_path => {
+            Chunking::from_paths(repo, commit, &opts.max_layers, opts.prior_build)
+        }
+        // If no chunking was provided, create a logical single chunk.
+        None => Chunking::new(repo, commit),
+    }
+}
+
+/// Compute how an ostree commit would be split into layers by [`encapsulate`], without
+/// generating an image.  If the options include a prior build, the report describes
+/// the components which changed layer and the content clients are predicted to download.
+#[context("Computing chunking report")]
+pub fn chunking_report(
     repo: &ostree::Repo,
     rev: &str,
-    ocidir_path: &Path,
-    tag: Option<&str>,
-    config: &Config,
-    opts: ExportOpts,
-) -> Result<ImageReference> {
-    if !ocidir_path.exists() {
-        std::fs::create_dir(ocidir_path).context("Creating OCI dir")?;
-    }
-    let ocidir = Dir::open_ambient_dir(ocidir_path, cap_std::ambient_authority())?;
-    let mut writer = ocidir::OciDir::create(&ocidir)?;
+    opts: Option<ExportOpts<'_, '_>>,
+) -> Result<ChunkingReport> {
+    let opts = opts.unwrap_or_default();
+    let commit = repo.require_rev(rev)?;
+    let chunking = chunking_for_commit(repo, commit.as_str(), &opts)?;
+    chunking.report(opts.prior_build)
+}
 
+/// Generate an OCI manifest (and its layers and config) from a given ostree root,
+/// along with the platform it targets.
+#[context("Building manifest")]
+fn build_manifest(
+    repo: &ostree::Repo,
+    rev: &str,
+    writer: &mut OciDir,
+    config: &Config,
+    opts: &ExportOpts,
+) -> Result<(oci_image::ImageManifest, oci_image::Platform)> {
     let commit = repo.require_rev(rev)?;
     let commit = commit.as_str();
     let (commit_v, _) = repo.load_commit(commit)?;
-    let commit_timestamp = NaiveDateTime::from_timestamp_opt(
-        ostree::commit_get_timestamp(&commit_v).try_into().unwrap(),
-        0,
-    )
-    .unwrap();
+    let commit_timestamp = ostree::commit_get_timestamp(&commit_v);
+    // In reproducible mode, all timestamps are derived from the environment or the commit
+    let reproducible_timestamp = opts
+        .reproducible
+        .then(|| {
+            let epoch = std::env::var("SOURCE_DATE_EPOCH").ok();
+            reproducible_timestamp(epoch.as_deref(), commit_timestamp)
+        })
+        .transpose()?;
+    let commit_timestamp = match reproducible_timestamp {
+        Some(ts) => ts.naive_utc(),
+        None => NaiveDateTime::from_timestamp_opt(commit_timestamp.try_into().unwrap(), 0).unwrap(),
+    };
     let commit_subject = commit_v.child_value(3);
     let commit_subject = commit_subject.str().ok_or_else(|| {
         anyhow::anyhow!(
@@ -215,7 +485,11 @@ fn build_oci(
     let commit_meta = glib::VariantDict::new(Some(commit_meta));
 
     let mut ctrcfg = oci_image::Config::default();
+    let platform = platform_for_commit(&commit_meta)?;
     let mut imgcfg = oci_image::ImageConfiguration::default();
+    imgcfg.set_architecture(platform.architecture().clone());
+    imgcfg.set_os(platform.os().clone());
+    imgcfg.set_variant(platform.variant().clone());
     imgcfg.set_created(Some(
         commit_timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
     ));
@@ -230,23 +504,7 @@ fn build_oci(
 
     let mut manifest = ocidir::new_empty_manifest().build().unwrap();
 
-    let chunking = opts
-        .contentmeta
-        .as_ref()
-        .map(|meta| {
-            crate::chunking::Chunking::from_mapping(
-                repo,
-                commit,
-                meta,
-                &opts.max_layers,
-                opts.prior_build,
-            )
-        })
-        .transpose()?;
-    // If no chunking was provided, create a logical single chunk.
-    let chunking = chunking
-        .map(Ok)
-        .unwrap_or_else(|| crate::chunking::Chunking::new(repo, commit))?;
+    let chunking = chunking_for_commit(repo, commit, opts)?;
 
     if let Some(version) = commit_meta.lookup::<String>("version")? {
         if !opts.no_legacy_version_label {
@@ -271,40 +529,136 @@ fn build_oci(
     export_chunked(
         repo,
         commit,
-        &mut writer,
+        writer,
         &mut manifest,
         &mut imgcfg,
         labels,
         chunking,
-        &opts,
+        opts,
         &description,
+        reproducible_timestamp,
     )?;
 
-    // Lookup the cmd embedded in commit metadata
-    let cmd = commit_meta.lookup::<Vec<String>>(ostree::COMMIT_META_CONTAINER_CMD)?;
-    // But support it being overridden by CLI options
-
-    // https://github.com/rust-lang/rust-clippy/pull/7639#issuecomment-1050340564
-    #[allow(clippy::unnecessary_lazy_evaluations)]
-    let cmd = config.cmd.as_ref().or_else(|| cmd.as_ref());
-    if let Some(cmd) = cmd {
-        ctrcfg.set_cmd(Some(cmd.clone()));
+    // Lookup the runtime configuration embedded in commit metadata,
+    // but support it being overridden by CLI options
+    let meta = &commit_meta;
+    ctrcfg.set_cmd(config_or_commit_meta(
+        &config.cmd,
+        meta,
+        ostree::COMMIT_META_CONTAINER_CMD,
+    )?);
+    ctrcfg.set_entrypoint(config_or_commit_meta(
+        &config.entrypoint,
+        meta,
+        COMMIT_META_CONTAINER_ENTRYPOINT,
+    )?);
+    ctrcfg.set_env(config_or_commit_meta(
+        &config.env,
+        meta,
+        COMMIT_META_CONTAINER_ENV,
+    )?);
+    ctrcfg.set_working_dir(config_or_commit_meta(
+        &config.working_dir,
+        meta,
+        COMMIT_META_CONTAINER_WORKDIR,
+    )?);
+    ctrcfg.set_user(config_or_commit_meta(
+        &config.user,
+        meta,
+        COMMIT_META_CONTAINER_USER,
+    )?);
+    ctrcfg.set_exposed_ports(config_or_commit_meta(
+        &config.exposed_ports,
+        meta,
+        COMMIT_META_CONTAINER_EXPOSED_PORTS,
+    )?);
+    ctrcfg.set_volumes(config_or_commit_meta(
+        &config.volumes,
+        meta,
+        COMMIT_META_CONTAINER_VOLUMES,
+    )?);
+    ctrcfg.set_stop_signal(config_or_commit_meta(
+        &config.stop_signal,
+        meta,
+        COMMIT_META_CONTAINER_STOP_SIGNAL,
+    )?);
+    let annotations =
+        config_or_commit_meta(&config.annotations, meta, COMMIT_META_CONTAINER_ANNOTATIONS)?;
+    if let Some(annotations) = annotations {
+        manifest.set_annotations(Some(annotations.into_iter().collect()));
     }
 
     imgcfg.set_config(Some(ctrcfg));
     let ctrcfg = writer.write_config(imgcfg)?;
     manifest.set_config(ctrcfg);
-    let platform = oci_image::Platform::default();
+    Ok((manifest, platform))
+}
+
+/// Generate an OCI image from the given ostree roots.  If `index` is set, an image index
+/// with one manifest per commit platform is written; otherwise there must be exactly
+/// one commit.  Returns the descriptor for the manifest or index.
+#[context("Building oci")]
+fn build_oci(
+    repo: &ostree::Repo,
+    revs: &[&str],
+    index: bool,
+    ocidir_path: &Path,
+    tag: Option<&str>,
+    config: &Config,
+    opts: ExportOpts,
+) -> Result<oci_image::Descriptor> {
+    if !ocidir_path.exists() {
+        std::fs::create_dir(ocidir_path).context("Creating OCI dir")?;
+    }
+    let ocidir = Dir::open_ambient_dir(ocidir_path, cap_std::ambient_authority())?;
+    let mut writer = ocidir::OciDir::create(&ocidir)?;
+    build_oci_with(repo, revs, index, &mut writer, tag, config, opts)
+}
+
+/// Generate an image (or an image index) using the provided writer; see [`build_oci`].
+fn build_oci_with(
+    repo: &ostree::Repo,
+    revs: &[&str],
+    index: bool,
+    writer: &mut OciDir,
+    tag: Option<&str>,
+    config: &Config,
+    opts: ExportOpts,
+) -> Result<oci_image::Descriptor> {
+    if !index {
+        let rev = match revs {
+            [rev] => rev,
+            _ => anyhow::bail!("Expected a single commit, found {}", revs.len()),
+        };
+        let (manifest, platform) = build_manifest(repo, rev, writer, config, &opts)?;
+        return if let Some(tag) = tag {
+            writer.insert_manifest(manifest, Some(tag), platform)
+        } else {
+            writer.replace_with_single_manifest(manifest, platform)?;
+            writer.read_manifest_and_descriptor().map(|(_, desc)| desc)
+        };
+    }
+
+    let mut manifests: Vec<oci_image::Descriptor> = Vec::new();
+    for rev in revs {
+        let (manifest, platform) = build_manifest(repo, rev, writer, config, &opts)?;
+        if manifests
+            .iter()
+            .any(|m| m.platform().as_ref() == Some(&platform))
+        {
+            anyhow::bail!(
+                "Duplicate platform {}/{} for {rev}",
+                platform.os(),
+                platform.architecture()
+            );
+        }
+        manifests.push(writer.write_manifest(manifest, platform)?);
+    }
     if let Some(tag) = tag {
-        writer.insert_manifest(manifest, Some(tag), platform)?;
+        writer.insert_index(manifests, Some(tag))
     } else {
-        writer.replace_with_single_manifest(manifest, platform)?;
+        writer.replace_with_single_index(manifests)
     }
-
-    Ok(ImageReference {
-        transport: Transport::OciDir,
-        name: ocidir_path.to_str().unwrap().to_string(),
-    })
 }
 
 /// Interpret a filesystem path as optionally including a tag.  Paths
@@ -317,25 +671,65 @@ pub(crate) fn parse_oci_path_and_tag(path: &str) -> (&str, Option<&str>) {
     }
 }
 
+/// Generate an image as an `oci-archive:`, atomically replacing `dest`.  Layers are
+/// streamed into the archive as they are generated; only the configuration, manifests
+/// and index are written to a temporary directory next to it.
+#[context("Writing {}", dest.display())]
+#[allow(clippy::too_many_arguments)]
+fn build_oci_archive(
+    repo: &ostree::Repo,
+    revs: &[&str],
+    index: bool,
+    dest: &Path,
+    tag: Option<&str>,
+    config: &Config,
+    mut opts: ExportOpts,
+) -> Result<oci_image::Descriptor> {
+    let parent = match dest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let td = tempfile::tempdir_in(parent)?;
+    let tempdir = Dir::open_ambient_dir(td.path(), cap_std::ambient_authority())?;
+    let tmpf = tempfile::NamedTempFile::new_in(parent)?;
+    let mut writer = OciDir::create_for_archive(&tempdir, tmpf.as_file().try_clone()?)?;
+    // Layers are written to the archive one at a time, in order
+    opts.jobs = Some(NonZeroUsize::MIN);
+    let desc = build_oci_with(repo, revs, index, &mut writer, tag, config, opts)?;
+    writer.finish_archive()?;
+    tmpf.as_file()
+        .set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o644))?;
+    tmpf.persist(dest)?;
+    Ok(desc)
+}
+
 /// Helper for `build()` that avoids generics
 #[instrument(level = "debug", skip_all)]
 async fn build_impl(
     repo: &ostree::Repo,
-    ostree_ref: &str,
+    revs: &[&str],
+    index: bool,
     config: &Config,
     opts: Option<ExportOpts<'_, '_>>,
     dest: &ImageReference,
 ) -> Result<String> {
     let mut opts = opts.unwrap_or_default();
+    if opts.incremental_push && !matches!(dest.transport, Transport::Registry | Transport::OciDir) {
+        anyhow::bail!("Incremental push requires a registry or OCI directory destination");
+    }
     if dest.transport == Transport::ContainerStorage {
         opts.skip_compression = true;
     }
-    let digest = if dest.transport == Transport::OciDir {
+    if dest.transport == Transport::OciDir && !opts.incremental_push {
         let (path, tag) = parse_oci_path_and_tag(dest.name.as_str());
         tracing::debug!("using OCI path={path} tag={tag:?}");
-        let _copied: ImageReference =
-            build_oci(repo, ostree_ref, Path::new(path), tag, config, opts)?;
-        None
+        let desc = build_oci(repo, revs, index, Path::new(path), tag, config, opts)?;
+        Ok(desc.digest().to_string())
+    } else if dest.transport == Transport::OciArchive {
+        let (path, tag) = parse_oci_path_and_tag(dest.name.as_str());
+        tracing::debug!("using OCI archive path={path} tag={tag:?}");
+        let desc = build_oci_archive(repo, revs, index, Path::new(path), tag, config, opts)?;
+        Ok(desc.digest().to_string())
     } else {
         let tempdir = tempfile::tempdir_in("/var/tmp")?;
         let tempdest = tempdir.path().join("d");
@@ -343,39 +737,108 @@ async fn build_impl(
 
         // Minor TODO: refactor to avoid clone
         let authfile = opts.authfile.clone();
-        let tempoci = build_oci(repo, ostree_ref, Path::new(tempdest), None, config, opts)?;
+        let uploader = if opts.incremental_push {
+            let uploader = Uploader::new(
+                tempdir.path(),
+                Path::new(tempdest),
+                dest,
+                authfile.as_deref(),
+            )?;
+            opts.layer_sink = Some(uploader.sink());
+            Some(uploader)
+        } else {
+            None
+        };
+        let r = build_oci(repo, revs, index, Path::new(tempdest), None, config, opts);
+        // Prefer reporting upload errors, as they also cause the build to fail
+        let carriers = uploader.map(|u| u.finish()).transpose()?;
+        let _: oci_image::Descriptor = r?;
+        let tempoci = ImageReference {
+            transport: Transport::OciDir,
+            name: tempdest.to_string(),
+        };
 
-        let digest = skopeo::copy(&tempoci, dest, authfile.as_deref()).await?;
-        Some(digest)
-    };
-    if let Some(digest) = digest {
-        Ok(digest)
-    } else {
-        // If `skopeo copy` doesn't have `--digestfile` yet, then fall back
-        // to running an inspect cycle.
-        let imgref = OstreeImageReference {
-            sigverify: SignatureSource::ContainerPolicyAllowInsecure,
-            imgref: dest.to_owned(),
+        // When pushing an image index, copy all of the images it references.
+        let r = skopeo::copy(&tempoci, dest, authfile.as_deref(), index).await;
+        // Remove the carriers even if the copy failed, but prefer reporting its error
+        let removed = match carriers {
+            Some(carriers) => carriers.remove().await,
+            None => Ok(()),
         };
-        let (_, digest) = super::unencapsulate::fetch_manifest(&imgref)
-            .await
-            .context("Querying manifest after push")?;
+        let digest = r?;
+        removed?;
         Ok(digest)
     }
 }
 
+/// The compression algorithm used for generated layers.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ExportCompression {
+    /// `application/vnd.oci.image.layer.v1.tar+gzip`
+    #[default]
+    Gzip,
+    /// `application/vnd.oci.image.layer.v1.tar+zstd`
+    Zstd,
+    /// Uncompressed `application/vnd.oci.image.layer.v1.tar`
+    None,
+}
+
+impl TryFrom<&str> for ExportCompression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        Ok(match value {
+            "gzip" => Self::Gzip,
+            "zstd" => Self::Zstd,
+            "none" => Self::None,
+            o => return Err(anyhow!("Unknown compression '{}'", o)),
+        })
+    }
+}
+
+impl std::fmt::Display for ExportCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ExportCompression::Gzip => "gzip",
+            ExportCompression::Zstd => "zstd",
+            ExportCompression::None => "none",
+        };
+        f.write_str(s)
+    }
+}
+
 /// Options controlling commit export into OCI
 #[derive(Clone, Debug, Default)]
 #[non_exhaustive]
 pub struct ExportOpts<'m, 'o> {
-    /// If true, do not perform gzip compression of the tar layers.
+    /// If true, compress at the fastest level (the name is historical).
     pub skip_compression: bool,
+    /// The compression algorithm to use for layers.
+    pub compression: ExportCompression,
+    /// Embed a table of contents in each content layer, keyed by ostree
+    /// content checksum, so that clients can fetch only missing objects.
+    /// This implies zstd compression.  Currently only images read in process
+    /// from a local OCI layout make use of it; registries do not.
+    pub content_toc: bool,
     /// A set of commit metadata keys to copy as image labels.
     pub copy_meta_keys: Vec<String>,
     /// A set of optionally-present commit metadata keys to copy as image labels.
     pub copy_meta_opt_keys: Vec<String>,
     /// Maximum number of layers to use
     pub max_layers: Option<NonZeroU32>,
+    /// Generate a bit-for-bit reproducible image, by using the `SOURCE_DATE_EPOCH`
+    /// environment variable if set (or otherwise the commit timestamp) for all timestamps,
+    /// including the history entries for each layer.
+    pub reproducible: bool,
+    /// Number of layers to generate concurrently; defaults to the available parallelism.
+    /// The generated image is the same regardless of this value.  Layers of `oci-archive:`
+    /// images are always generated one at a time, as they are written directly to it.
+    pub jobs: Option<NonZeroUsize>,
+    /// When pushing to a registry or OCI directory, upload each layer as soon as it is
+    /// written, instead of writing the full image before copying it.  Each layer is
+    /// uploaded via a carrier image referencing only it, which is removed once the full
+    /// image has been copied; the destination must support deleting manifests.
+    pub incremental_push: bool,
     /// Path to Docker-formatted authentication file.
     pub authfile: Option<std::path::PathBuf>,
     // TODO semver-break: remove this
@@ -387,15 +850,37 @@ pub struct ExportOpts<'m, 'o> {
     /// Metadata mapping between objects and their owning component/package;
     /// used to optimize packing.
     pub contentmeta: Option<&'o ObjectMetaSized>,
+    /// If no content metadata is provided, split the commit into layers using
+    /// heuristics based on paths instead of generating a single layer.
+    pub chunk_by_path: bool,
+    /// Queue for layers to be uploaded incrementally.
+    pub(crate) layer_sink: Option<LayerSink>,
 }
 
 impl<'m, 'o> ExportOpts<'m, 'o> {
-    /// Return the gzip compression level to use, as configured by the export options.
-    fn compression(&self) -> Compression {
-        if self.skip_compression {
-            Compression::fast()
+    /// Return the number of layers to generate concurrently.
+    fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map(|v| v.get())
+            .unwrap_or(1)
+    }
+
+    /// Return the layer compression to use, as configured by the export options.
+    fn compression(&self) -> LayerCompression {
+        let compression = if self.content_toc {
+            ExportCompression::Zstd
         } else {
-            Compression::default()
+            self.compression
+        };
+        match (compression, self.skip_compression) {
+            (ExportCompression::Gzip, false) => LayerCompression::Gzip(Default::default()),
+            (ExportCompression::Gzip, true) => LayerCompression::Gzip(flate2::Compression::fast()),
+            (ExportCompression::Zstd, false) => {
+                LayerCompression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+            }
+            (ExportCompression::Zstd, true) => LayerCompression::Zstd(1),
+            (ExportCompression::None, _) => LayerCompression::None,
         }
     }
 }
@@ -410,7 +895,59 @@ pub async fn encapsulate<S: AsRef<str>>(
     opts: Option<ExportOpts<'_, '_>>,
     dest: &ImageReference,
 ) -> Result<String> {
-    build_impl(repo, ostree_ref.as_ref(), config, opts, dest).await
+    build_impl(repo, &[ostree_ref.as_ref()], false, config, opts, dest).await
+}
+
+/// Given an OSTree repository and a set of refs for different architectures, generate
+/// a container image index with one image per platform.  The platform of each commit
+/// is derived from the architecture suffix of its kernel version (the standard `ostree.linux`
+/// metadata key), defaulting to the host architecture; each platform may appear at most once.
+///
+/// The returned string is the digest of the image index.
+pub async fn encapsulate_multiarch<S: AsRef<str>>(
+    repo: &ostree::Repo,
+    ostree_refs: &[S],
+    config: &Config,
+    opts: Option<ExportOpts<'_, '_>>,
+    dest: &ImageReference,
+) -> Result<String> {
+    let revs: Vec<_> = ostree_refs.iter().map(|r| r.as_ref()).collect();
+    if revs.is_empty() {
+        anyhow::bail!("No commits provided");
+    }
+    build_impl(repo, &revs, true, config, opts, dest).await
+}
+
+#[test]
+fn test_export_compression() {
+    for c in [
+        ExportCompression::Gzip,
+        ExportCompression::Zstd,
+        ExportCompression::None,
+    ] {
+        assert_eq!(
+            ExportCompression::try_from(c.to_string().as_str()).unwrap(),
+            c
+        );
+    }
+    assert!(ExportCompression::try_from("bzip2").is_err());
+    let opts = ExportOpts {
+        compression: ExportCompression::Zstd,
+        ..Default::default()
+    };
+    assert_eq!(
+        opts.compression().media_type(),
+        oci_image::MediaType::ImageLayerZstd
+    );
+}
+
+#[test]
+fn test_reproducible_timestamp() {
+    let ts = reproducible_timestamp(None, 872879442).unwrap();
+    assert_eq!(ts.to_rfc3339(), "1997-08-29T18:30:42+00:00");
+    let ts = reproducible_timestamp(Some("1700000000"), 872879442).unwrap();
+    assert_eq!(ts.timestamp(), 1700000000);
+    assert!(reproducible_timestamp(Some("yesterday"), 872879442).is_err());
 }
 
 #[test]
@@ -425,3 +962,24 @@ fn test_parse_ocipath() {
     );
     assert_eq!(parse_oci_path_and_tag(untagged), ("/foo/bar", Some("baz")));
 }
+
+#[test]
+fn test_platform_for_commit() {
+    let meta = glib::VariantDict::new(None);
+    let host = platform_for_commit(&meta).unwrap();
+    assert_eq!(host, oci_image::Platform::default());
+    for (kver, arch, variant) in [
+        ("6.5.6-300.fc39.x86_64", "amd64", None),
+        ("6.5.6-300.fc39.aarch64", "arm64", None),
+        ("6.1.0-13-arm64", "arm64", None),
+        ("6.5.6-300.fc39.armv7hl", "arm", Some("v7")),
+    ] {
+        meta.insert("ostree.linux", kver);
+        let platform = platform_for_commit(&meta).unwrap();
+        assert_eq!(platform.architecture().to_string(), arch);
+        assert_eq!(platform.variant().as_deref(), variant);
+    }
+    // An unknown suffix falls back to the host
+    meta.insert("ostree.linux", "6.5.6-custom");
+    assert_eq!(platform_for_commit(&meta).unwrap(), host);
+}
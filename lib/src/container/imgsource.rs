@@ -0,0 +1,606 @@
+//! Sources for fetching container images.
+//!
+//! By default images are fetched via the containers-image-proxy (skopeo).
+//! However, images in a local OCI directory (`oci:`) or OCI archive (`oci-archive:`)
+//! are simple on-disk formats, and can optionally be read in process instead; this
+//! allows pulling them without skopeo installed.  The size and digest of every blob
+//! read this way is verified against its descriptor.
+//!
+//! Note that the in-process backend does not implement `containers-policy.json`
+//! signature verification, so it cannot be used for images using [`SignatureSource::ContainerPolicy`]
+//! or [`SignatureSource::OstreeRemoteAndContainerPolicy`].
+
+use super::encapsulate::parse_oci_path_and_tag;
+use super::ocidir::OCI_TAG_ANNOTATION;
+use super::*;
+use anyhow::Context;
+use camino::Utf8PathBuf;
+use cap_std_ext::cap_std::{self, fs::Dir};
+use containers_image_proxy::{ConvertedLayerInfo, ImageProxy, ImageProxyConfig, OpenedImage};
+use fn_error_context::context;
+use futures_util::future::Either;
+use futures_util::Future;
+use oci_spec::image::{
+    Descriptor, ImageConfiguration, ImageIndex, ImageManifest, MediaType, Platform,
+};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The maximum size of a JSON document (index, manifest or configuration) we will read.
+const MAX_JSON_SIZE: u64 = 16 * 1024 * 1024;
+
+/// An opened container image.
+#[derive(Debug)]
+pub(crate) enum ImageSource {
+    /// An image fetched via the containers-image-proxy.
+    Proxy(ImageProxy, OpenedImage),
+    /// An image read in process from a local OCI layout.
+    Local(Box<LocalImage>),
+}
+
+/// The size of the buffer used to stream local blobs.
+const LOCAL_BLOB_BUFSIZE: usize = 64 * 1024;
+
+/// The future which reads a local blob.
+type LocalDriver = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+impl ImageSource {
+    /// Open an image via the proxy; if it is an image index, select the image for
+    /// the provided platform instead of the one matching the host.
+    pub(crate) async fn open(
+        imgref: &OstreeImageReference,
+        mut config: ImageProxyConfig,
+        platform: Option<&Platform>,
+    ) -> Result<Self> {
+        if imgref.imgref.transport == Transport::ContainerStorage {
+            // Fetching from containers-storage, may require privileges to read files
+            merge_default_container_proxy_opts_with_isolation(&mut config, None)?;
+        } else {
+            // Apply our defaults to the proxy config
+            merge_default_container_proxy_opts(&mut config)?;
+        }
+        if let Some(platform) = platform {
+            set_container_proxy_platform(&mut config, platform);
+        }
+        let proxy = ImageProxy::new_with_config(config).await?;
+        let img = proxy.open_image(&imgref.imgref.to_string()).await?;
+        Ok(Self::Proxy(proxy, img))
+    }
+
+    /// Open an image in a local OCI directory or archive in process.
+    pub(crate) async fn open_in_process(
+        imgref: &OstreeImageReference,
+        platform: Option<&Platform>,
+    ) -> Result<Self> {
+        if !matches!(
+            imgref.imgref.transport,
+            Transport::OciDir | Transport::OciArchive
+        ) {
+            anyhow::bail!(
+                "Cannot read images using transport {} in process",
+                imgref.imgref.transport
+            );
+        }
+        if imgref.sigverify.requires_container_policy() {
+            anyhow::bail!("Cannot verify signatures via containers-policy.json in process");
+        }
+        let img = LocalImage::open_async(&imgref.imgref, platform).await?;
+        Ok(Self::Local(Box::new(img)))
+    }
+
+    /// Fetch the manifest and its digest.
+    pub(crate) async fn fetch_manifest(&self) -> Result<(String, ImageManifest)> {
+        match self {
+            Self::Proxy(proxy, img) => proxy.fetch_manifest(img).await,
+            Self::Local(img) => Ok((img.manifest_digest.clone(), img.manifest.clone())),
+        }
+    }
+
+    /// Fetch the image configuration.
+    pub(crate) async fn fetch_config(&self) -> Result<ImageConfiguration> {
+        match self {
+            Self::Proxy(proxy, img) => proxy.fetch_config(img).await,
+            Self::Local(img) => img.fetch_config(),
+        }
+    }
+
+    /// Fetch information about the uncompressed layers; only available for `containers-storage`.
+    pub(crate) async fn get_layer_info(&self) -> Result<Option<Vec<ConvertedLayerInfo>>> {
+        match self {
+            Self::Proxy(proxy, img) => proxy.get_layer_info(img).await,
+            Self::Local(_) => Ok(None),
+        }
+    }
+
+    /// Fetch a blob.  The returned future must be awaited concurrently with reading the blob.
+    pub(crate) async fn get_blob(
+        &self,
+        digest: &str,
+        size: u64,
+    ) -> Result<(
+        Box<dyn AsyncBufRead + Send + Unpin>,
+        impl Future<Output = Result<()>> + Unpin + '_,
+    )> {
+        match self {
+            Self::Proxy(proxy, img) => {
+                let (blob, driver) = proxy.get_blob(img, digest, size).await?;
+                let (blob, driver) = ProxyBlob::new(blob, driver);
+                Ok((Box::new(blob), Either::Left(driver)))
+            }
+            Self::Local(img) => {
+                let (blob, driver) = img.get_blob(digest, size)?;
+                Ok((Box::new(blob), Either::Right(driver)))
+            }
+        }
+    }
+
+    /// Whether [`Self::get_blob_at`] is supported; only images read in process
+    /// support ranged fetches.
+    pub(crate) fn supports_ranged_fetch(&self) -> bool {
+        matches!(self, Self::Local(_))
+    }
+
+    /// Fetch a blob starting at `offset`; see [`Self::supports_ranged_fetch`].
+    /// Unlike [`Self::get_blob`], the digest is not verified.
+    pub(crate) fn get_blob_at(
+        &self,
+        digest: &str,
+        size: u64,
+        offset: u64,
+    ) -> Result<Box<dyn AsyncBufRead + Send + Unpin>> {
+        match self {
+            Self::Proxy(..) => {
+                anyhow::bail!("Ranged fetches are not supported via the containers-image-proxy")
+            }
+            Self::Local(img) => Ok(Box::new(img.get_blob_at(digest, size, offset)?)),
+        }
+    }
+
+    /// Open a blob for random access, if supported by this source; only images read
+    /// in process support this.  Returns the file and the offset of the blob in it.
+    /// The digest of the blob is not verified; callers must verify the content they
+    /// read by other means, as done for tables of contents.
+    pub(crate) fn open_blob_file(
+        &self,
+        digest: &str,
+        size: u64,
+    ) -> Result<Option<(std::fs::File, u64)>> {
+        match self {
+            Self::Proxy(..) => Ok(None),
+            Self::Local(img) => img.open_blob_file(digest, size).map(Some),
+        }
+    }
+
+    /// Close the image.
+    pub(crate) async fn close(&self) -> Result<()> {
+        match self {
+            Self::Proxy(proxy, img) => proxy.close_image(img).await,
+            Self::Local(_) => Ok(()),
+        }
+    }
+
+    /// Close the image, and if using the proxy, shut it down and check for errors.
+    pub(crate) async fn finalize(self) -> Result<()> {
+        self.close().await?;
+        match self {
+            Self::Proxy(proxy, _) => proxy.finalize().await,
+            Self::Local(_) => Ok(()),
+        }
+    }
+}
+
+/// A blob being read via the proxy.
+///
+/// The proxy holds a lock for the duration of each request, and the request which
+/// finishes a blob only completes once the blob has been written in full.  Sending
+/// it as soon as the blob is opened would block all other requests, and so serialize
+/// concurrent fetches; instead, it is deferred until the blob has been read to the
+/// end or the reader is dropped.
+struct ProxyBlob<R> {
+    inner: R,
+    done: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl<R: AsyncBufRead + Unpin> ProxyBlob<R> {
+    /// Wrap a blob and the future returned by the proxy which finishes it.
+    fn new<'a>(
+        inner: R,
+        finish: impl Future<Output = Result<()>> + Unpin + 'a,
+    ) -> (Self, Pin<Box<dyn Future<Output = Result<()>> + 'a>>) {
+        let (done, wait) = tokio::sync::oneshot::channel();
+        let driver = async move {
+            // An error means the reader was dropped, which is handled the same way
+            let _ = wait.await;
+            finish.await
+        };
+        let blob = Self {
+            inner,
+            done: Some(done),
+        };
+        (blob, Box::pin(driver))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for ProxyBlob<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(r, Poll::Ready(Ok(()))) && buf.remaining() > 0 && buf.filled().len() == filled {
+            self.done.take();
+        }
+        r
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for ProxyBlob<R> {
+    fn poll_fill_buf(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let r = Pin::new(&mut this.inner).poll_fill_buf(cx);
+        if let Poll::Ready(Ok(buf)) = &r {
+            if buf.is_empty() {
+                this.done.take();
+            }
+        }
+        r
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+/// The storage of a local OCI image layout.
+#[derive(Debug)]
+enum Layout {
+    /// An OCI directory.
+    Dir(Dir),
+    /// An OCI archive; the offset and size of each regular file in the tarball.
+    Archive {
+        path: Utf8PathBuf,
+        entries: HashMap<String, (u64, u64)>,
+    },
+}
+
+impl Layout {
+    fn open_dir(path: &str) -> Result<Self> {
+        let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())
+            .with_context(|| format!("Opening {path}"))?;
+        Ok(Self::Dir(d))
+    }
+
+    /// Scan the archive, recording the location of each file.  This only
+    /// reads the tar headers; the archive must be uncompressed.
+    fn open_archive(path: &str) -> Result<Self> {
+        let f = std::fs::File::open(path).with_context(|| format!("Opening {path}"))?;
+        let mut archive = tar::Archive::new(std::io::BufReader::new(f));
+        let mut entries = HashMap::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if entry.header().entry_type() != tar::EntryType::Regular {
+                continue;
+            }
+            let name = entry.path()?;
+            let name = name.strip_prefix("./").unwrap_or(&name);
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid non-UTF8 path in archive: {name:?}"))?;
+            entries.insert(name.to_string(), (entry.raw_file_position(), entry.size()));
+        }
+        Ok(Self::Archive {
+            path: path.into(),
+            entries,
+        })
+    }
+
+    /// Open a file, returning a reader positioned at its start and its size.
+    fn open(&self, name: &str) -> Result<(std::fs::File, u64)> {
+        match self {
+            Self::Dir(d) => {
+                let f = d
+                    .open(name)
+                    .with_context(|| format!("Opening {name}"))?
+                    .into_std();
+                let size = f.metadata()?.len();
+                Ok((f, size))
+            }
+            Self::Archive { path, entries } => {
+                let (offset, size) = entries
+                    .get(name)
+                    .ok_or_else(|| anyhow!("{name} not found in archive"))?;
+                let mut f = std::fs::File::open(path)?;
+                f.seek(SeekFrom::Start(*offset))?;
+                Ok((f, *size))
+            }
+        }
+    }
+
+    /// Read a (small) file into memory.
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let (f, size) = self.open(name)?;
+        if size > MAX_JSON_SIZE {
+            anyhow::bail!("{name} is too large ({size} bytes)");
+        }
+        let mut buf = Vec::with_capacity(size as usize);
+        f.take(size).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Compute the path to a blob, validating its digest.
+fn blob_path(digest: &str) -> Result<String> {
+    let hash = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow!("Unsupported digest: {digest}"))?;
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid digest: {digest}");
+    }
+    Ok(format!("blobs/sha256/{hash}"))
+}
+
+fn verify_digest(digest: &str, actual: [u8; 32]) -> std::io::Result<()> {
+    let actual = format!("sha256:{}", hex::encode(actual));
+    if actual != digest {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Digest mismatch; expected {digest} found {actual}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Copy a blob to `dest`, verifying its sha256 digest.  Readers of a blob commonly stop
+/// before its end, e.g. at the end-of-archive marker of a tarball; if `dest` is closed,
+/// the rest of the blob is still read so that the digest is always verified.
+async fn copy_verified(
+    mut src: impl AsyncRead + Unpin,
+    mut dest: impl AsyncWrite + Unpin,
+    digest: String,
+) -> Result<()> {
+    let mut hasher = openssl::sha::Sha256::new();
+    let mut buf = vec![0u8; LOCAL_BLOB_BUFSIZE];
+    let mut dest_open = true;
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let buf = &buf[..n];
+        hasher.update(buf);
+        if dest_open {
+            match dest.write_all(buf).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => dest_open = false,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    verify_digest(&digest, hasher.finish())?;
+    Ok(())
+}
+
+/// An image in a local OCI directory or archive.
+#[derive(Debug)]
+pub(crate) struct LocalImage {
+    layout: Layout,
+    manifest_digest: String,
+    manifest: ImageManifest,
+}
+
+impl LocalImage {
+    /// Open an image in an OCI layout, selecting it by tag if one is provided.
+    #[context("Opening {imgref}")]
+    pub(crate) fn open(imgref: &ImageReference, platform: Option<&Platform>) -> Result<Self> {
+        let (path, tag) = parse_oci_path_and_tag(&imgref.name);
+        let layout = match imgref.transport {
+            Transport::OciDir => Layout::open_dir(path)?,
+            Transport::OciArchive => Layout::open_archive(path)?,
+            o => anyhow::bail!("Unsupported transport: {o}"),
+        };
+        let index: ImageIndex =
+            serde_json::from_slice(&layout.read("index.json")?).context("Parsing index.json")?;
+        let mut desc = select_manifest(&index, tag)?.clone();
+        if desc.media_type() == &MediaType::ImageIndex {
+            let index: ImageIndex = read_json_blob(&layout, &desc)?;
+            desc = select_platform(&index, platform)?.clone();
+        }
+        if desc.media_type() != &MediaType::ImageManifest {
+            anyhow::bail!("Unsupported manifest media type: {}", desc.media_type());
+        }
+        let manifest = read_json_blob(&layout, &desc)?;
+        Ok(Self {
+            layout,
+            manifest_digest: desc.digest().to_string(),
+            manifest,
+        })
+    }
+
+    /// Open an image in a worker thread.
+    pub(crate) async fn open_async(
+        imgref: &ImageReference,
+        platform: Option<&Platform>,
+    ) -> Result<Self> {
+        let imgref = imgref.clone();
+        let platform = platform.cloned();
+        crate::tokio_util::spawn_blocking_cancellable_flatten(move |_| {
+            Self::open(&imgref, platform.as_ref())
+        })
+        .await
+    }
+
+    /// Read the image configuration.
+    pub(crate) fn fetch_config(&self) -> Result<ImageConfiguration> {
+        read_json_blob(&self.layout, self.manifest.config())
+    }
+
+    /// Open a blob.  The returned future reads the blob, and must be awaited concurrently
+    /// with reading it; the blob is read in full even if the reader is dropped early, and
+    /// the future returns an error if its digest does not match.
+    fn get_blob(
+        &self,
+        digest: &str,
+        size: u64,
+    ) -> Result<(impl AsyncBufRead + Send + Unpin, LocalDriver)> {
+        let (f, actual_size) = self.layout.open(&blob_path(digest)?)?;
+        if actual_size != size {
+            anyhow::bail!("Blob {digest} has size {actual_size}, expected {size}");
+        }
+        let (send, recv) = tokio::io::duplex(LOCAL_BLOB_BUFSIZE);
+        let driver = copy_verified(
+            tokio::fs::File::from_std(f).take(size),
+            send,
+            digest.to_string(),
+        );
+        Ok((tokio::io::BufReader::new(recv), Box::pin(driver)))
+    }
+
+    /// Open the file holding a blob, returning it along with the offset of the blob.
+    fn open_blob_file(&self, digest: &str, size: u64) -> Result<(std::fs::File, u64)> {
+        let (mut f, actual_size) = self.layout.open(&blob_path(digest)?)?;
+        if actual_size != size {
+            anyhow::bail!("Blob {digest} has size {actual_size}, expected {size}");
+        }
+        let offset = f.stream_position()?;
+        Ok((f, offset))
+    }
+
+    /// Open a blob for reading from `offset`, without verifying its digest.
+    fn get_blob_at(
+        &self,
+        digest: &str,
+        size: u64,
+        offset: u64,
+    ) -> Result<impl AsyncBufRead + Send + Unpin> {
+        let (mut f, actual_size) = self.layout.open(&blob_path(digest)?)?;
+        if actual_size != size {
+            anyhow::bail!("Blob {digest} has size {actual_size}, expected {size}");
+        }
+        if offset > size {
+            anyhow::bail!("Offset {offset} exceeds size {size} of blob {digest}");
+        }
+        f.seek(SeekFrom::Current(offset as i64))?;
+        let reader = tokio::fs::File::from_std(f).take(size - offset);
+        Ok(tokio::io::BufReader::new(reader))
+    }
+}
+
+/// Read and parse a JSON blob, verifying its size and digest.
+fn read_json_blob<T: serde::de::DeserializeOwned>(layout: &Layout, desc: &Descriptor) -> Result<T> {
+    let digest = desc.digest().as_str();
+    let buf = layout.read(&blob_path(digest)?)?;
+    if buf.len() as i64 != desc.size() {
+        anyhow::bail!(
+            "Blob {digest} has size {}, expected {}",
+            buf.len(),
+            desc.size()
+        );
+    }
+    verify_digest(digest, openssl::sha::sha256(&buf))?;
+    serde_json::from_slice(&buf).with_context(|| format!("Parsing {digest}"))
+}
+
+/// Find the manifest with the provided tag, or if none is provided, the single manifest.
+fn select_manifest<'a>(index: &'a ImageIndex, tag: Option<&str>) -> Result<&'a Descriptor> {
+    let manifests = index.manifests();
+    match tag {
+        Some(tag) => manifests
+            .iter()
+            .find(|desc| {
+                desc.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+                    .is_some_and(|v| v == tag)
+            })
+            .ok_or_else(|| anyhow!("No image tagged {tag}")),
+        None => match manifests.as_slice() {
+            [] => Err(anyhow!("No manifests found")),
+            [desc] => Ok(desc),
+            o => Err(anyhow!("Expected exactly 1 manifest, found {}", o.len())),
+        },
+    }
+}
+
+/// Find the manifest for the provided platform in an image index, defaulting to the host.
+fn select_platform<'a>(
+    index: &'a ImageIndex,
+    platform: Option<&Platform>,
+) -> Result<&'a Descriptor> {
+    let host = Platform::default();
+    let platform = platform.unwrap_or(&host);
+    index
+        .manifests()
+        .iter()
+        .find(|desc| {
+            desc.platform().as_ref().is_some_and(|p| {
+                p.os() == platform.os()
+                    && p.architecture() == platform.architecture()
+                    && (platform.variant().is_none() || p.variant() == platform.variant())
+            })
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "No image found for platform {}/{}",
+                platform.os(),
+                platform.architecture()
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_path() {
+        let digest = "sha256:a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7";
+        assert_eq!(
+            blob_path(digest).unwrap(),
+            "blobs/sha256/a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7"
+        );
+        for invalid in [
+            "",
+            "sha256:",
+            "sha512:a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7",
+            "sha256:../../../../../../../../../../../../../../../../../../etc/passwd",
+            "sha256:a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc",
+        ] {
+            assert!(blob_path(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_verified() -> Result<()> {
+        let data = vec![42u8; 3 * LOCAL_BLOB_BUFSIZE];
+        let digest = format!("sha256:{}", hex::encode(openssl::sha::sha256(&data)));
+        for (digest, valid) in [(digest.as_str(), true), ("sha256:0000", false)] {
+            let mut buf = Vec::new();
+            let r = copy_verified(data.as_slice(), &mut buf, digest.to_string()).await;
+            assert_eq!(r.is_ok(), valid);
+            assert_eq!(buf, data);
+            // The digest is verified even if the reader stops early
+            let (send, mut recv) = tokio::io::duplex(16);
+            let reader = async move {
+                let mut buf = [0u8; 8];
+                recv.read_exact(&mut buf).await?;
+                drop(recv);
+                Ok::<_, anyhow::Error>(())
+            };
+            let (r, driver) = tokio::join!(
+                reader,
+                copy_verified(data.as_slice(), send, digest.to_string())
+            );
+            r?;
+            assert_eq!(driver.is_ok(), valid);
+        }
+        Ok(())
+    }
+}
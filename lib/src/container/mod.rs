@@ -42,6 +42,29 @@ pub const OSTREE_COMMIT_LABEL: &str = "ostree.commit";
 pub(crate) const CONTENT_ANNOTATION: &str = "ostree.components";
 /// The character we use to separate values in [`CONTENT_ANNOTATION`].
 pub(crate) const COMPONENT_SEPARATOR: char = ',';
+/// The name of an annotation attached to a layer with the size of its uncompressed
+/// content, used to estimate the space required to import it.
+pub(crate) const UNCOMPRESSED_SIZE_ANNOTATION: &str = "ostree.uncompressed-size";
+
+/// Record the uncompressed size of a layer in the comment of its history entry, in the
+/// form `ostree.uncompressed-size=<bytes>`.  Unlike layer annotations, this is preserved
+/// when an image is converted to the Docker schema.
+pub(crate) fn set_history_uncompressed_size(history: &mut oci_spec::image::History, size: u64) {
+    history.set_comment(Some(format!("{UNCOMPRESSED_SIZE_ANNOTATION}={size}")));
+}
+
+/// Parse the uncompressed size of a layer recorded via [`set_history_uncompressed_size`].
+pub(crate) fn history_uncompressed_size(history: &oci_spec::image::History) -> Option<u64> {
+    history
+        .comment()
+        .as_deref()?
+        .split_whitespace()
+        .find_map(|w| {
+            w.strip_prefix(UNCOMPRESSED_SIZE_ANNOTATION)?
+                .strip_prefix('=')
+        })
+        .and_then(|v| v.parse().ok())
+}
 
 /// Our generic catchall fatal error, expected to be converted
 /// to a string to output to a terminal or logs.
@@ -82,6 +105,28 @@ pub enum SignatureSource {
     ContainerPolicy,
     /// NOT RECOMMENDED.  Fetches will defer to the `containers-policy.json` default which is usually `insecureAcceptAnything`.
     ContainerPolicyAllowInsecure,
+    /// The ostree commit is verified using the named ostree remote, and the image (including
+    /// any derived layers) using `containers-policy.json`, as for [`Self::ContainerPolicy`].
+    OstreeRemoteAndContainerPolicy(String),
+}
+
+impl SignatureSource {
+    /// The ostree remote used to verify the ostree commit, if any.
+    pub(crate) fn ostree_remote(&self) -> Option<&str> {
+        match self {
+            Self::OstreeRemote(r) | Self::OstreeRemoteAndContainerPolicy(r) => Some(r.as_str()),
+            Self::ContainerPolicy | Self::ContainerPolicyAllowInsecure => None,
+        }
+    }
+
+    /// Whether the image must be verified by `containers-policy.json`, which must
+    /// therefore not accept everything by default.
+    pub(crate) fn requires_container_policy(&self) -> bool {
+        matches!(
+            self,
+            Self::ContainerPolicy | Self::OstreeRemoteAndContainerPolicy(_)
+        )
+    }
 }
 
 /// A commonly used pre-OCI label for versions.
@@ -169,14 +214,29 @@ impl TryFrom<&str> for SignatureSource {
         match value {
             "ostree-image-signed" => Ok(Self::ContainerPolicy),
             "ostree-unverified-image" => Ok(Self::ContainerPolicyAllowInsecure),
-            o => match o.strip_prefix("ostree-remote-image:") {
-                Some(rest) => Ok(Self::OstreeRemote(rest.to_string())),
-                _ => Err(anyhow!("Invalid signature source: {}", o)),
-            },
+            o => {
+                if let Some(rest) = o.strip_prefix("ostree-remote-image:") {
+                    Ok(Self::OstreeRemote(rest.to_string()))
+                } else if let Some(rest) = o.strip_prefix("ostree-remote-signed-image:") {
+                    Ok(Self::OstreeRemoteAndContainerPolicy(rest.to_string()))
+                } else {
+                    Err(anyhow!("Invalid signature source: {}", o))
+                }
+            }
         }
     }
 }
 
+/// Strip the tag or digest from an image name, e.g. `quay.io/exampleos/blah:latest`
+/// becomes `quay.io/exampleos/blah`.
+pub(crate) fn repository_name(name: &str) -> &str {
+    let name = name.split_once('@').map(|v| v.0).unwrap_or(name);
+    match name.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => name,
+    }
+}
+
 impl FromStr for SignatureSource {
     type Err = anyhow::Error;
 
@@ -222,6 +282,25 @@ impl TryFrom<&str> for OstreeImageReference {
                     Cow::Borrowed(rest),
                 )
             }
+            // Shorthand for ostree-remote-signed-image with registry:
+            "ostree-remote-signed-registry" => {
+                let (remote, rest) = second
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Missing second ':' in {}", value))?;
+                (
+                    SignatureSource::OstreeRemoteAndContainerPolicy(remote.to_string()),
+                    Cow::Owned(format!("registry:{rest}")),
+                )
+            }
+            "ostree-remote-signed-image" => {
+                let (remote, rest) = second
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Missing second ':' in {}", value))?;
+                (
+                    SignatureSource::OstreeRemoteAndContainerPolicy(remote.to_string()),
+                    Cow::Borrowed(rest),
+                )
+            }
             o => {
                 return Err(anyhow!("Invalid ostree image reference scheme: {}", o));
             }
@@ -267,6 +346,9 @@ impl std::fmt::Display for SignatureSource {
             SignatureSource::ContainerPolicyAllowInsecure => {
                 write!(f, "ostree-unverified-image")
             }
+            SignatureSource::OstreeRemoteAndContainerPolicy(r) => {
+                write!(f, "ostree-remote-signed-image:{r}")
+            }
         }
     }
 }
@@ -431,6 +513,39 @@ pub fn merge_default_container_proxy_opts_with_isolation(
     Ok(())
 }
 
+/// Configure the proxy to select the image for the provided platform from an
+/// image index, rather than the one matching the host.  The proxy has no option
+/// for this, so it is passed as global options to skopeo.  This should be invoked
+/// after [`merge_default_container_proxy_opts`], which may set up an explicit skopeo
+/// command; otherwise one equivalent to the proxy default is used.
+#[allow(unsafe_code)]
+pub(crate) fn set_container_proxy_platform(
+    config: &mut containers_image_proxy::ImageProxyConfig,
+    platform: &oci_spec::image::Platform,
+) {
+    let cmd = config.skopeo_cmd.get_or_insert_with(|| {
+        use std::os::unix::process::CommandExt;
+        let mut cmd = std::process::Command::new("skopeo");
+        // Match the default lifecycle binding of the proxy to this process.
+        // SAFETY: This only invokes prctl(), which is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(|| {
+                rustix::process::set_parent_process_death_signal(Some(
+                    rustix::process::Signal::Term,
+                ))
+                .map_err(Into::into)
+            });
+        }
+        cmd
+    });
+    cmd.arg("--override-os").arg(platform.os().to_string());
+    cmd.arg("--override-arch")
+        .arg(platform.architecture().to_string());
+    if let Some(variant) = platform.variant() {
+        cmd.arg("--override-variant").arg(variant);
+    }
+}
+
 /// Convenience helper to return the labels, if present.
 pub(crate) fn labels_of(
     config: &oci_spec::image::ImageConfiguration,
@@ -450,9 +565,11 @@ pub fn version_for_config(config: &oci_spec::image::ImageConfiguration) -> Optio
     None
 }
 
+pub mod artifact;
 pub mod deploy;
 mod encapsulate;
 pub use encapsulate::*;
+mod imgsource;
 mod unencapsulate;
 pub use unencapsulate::*;
 // We have this trick of compiling ourself with integration testing
@@ -468,8 +585,12 @@ pub mod ocidir;
 #[cfg(not(feature = "internal-testing-api"))]
 #[allow(dead_code)]
 mod ocidir;
+mod push;
+pub mod retention;
 mod skopeo;
+mod staging;
 pub mod store;
+mod toc;
 mod update_detachedmeta;
 pub use update_detachedmeta::*;
 
@@ -501,6 +622,22 @@ mod tests {
         "docker://quay.io/exampleos/blah:sometag",
     ];
 
+    #[test]
+    fn test_repository_name() {
+        for (name, expected) in [
+            ("quay.io/exampleos/blah", "quay.io/exampleos/blah"),
+            ("quay.io/exampleos/blah:latest", "quay.io/exampleos/blah"),
+            ("localhost:5000/blah", "localhost:5000/blah"),
+            ("localhost:5000/blah:v1", "localhost:5000/blah"),
+            (
+                "quay.io/exampleos/blah@sha256:a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7",
+                "quay.io/exampleos/blah",
+            ),
+        ] {
+            assert_eq!(repository_name(name), expected);
+        }
+    }
+
     #[test]
     fn test_imagereference() {
         let ir: ImageReference = "registry:quay.io/exampleos/blah".try_into().unwrap();
@@ -602,6 +739,31 @@ mod tests {
             OstreeImageReference::try_from("ostree-unverified-registry:quay.io/exampleos/blah")
                 .unwrap();
         assert_eq!(&ir_shorthand, &ir);
+
+        let ir_s = "ostree-remote-signed-image:myremote:registry:quay.io/exampleos/blah";
+        let ir_registry = "ostree-remote-signed-registry:myremote:quay.io/exampleos/blah";
+        for &ir_s in &[ir_s, ir_registry] {
+            let ir: OstreeImageReference = ir_s.try_into().unwrap();
+            assert_eq!(
+                ir.sigverify,
+                SignatureSource::OstreeRemoteAndContainerPolicy("myremote".to_string())
+            );
+            assert_eq!(ir.imgref.transport, Transport::Registry);
+            assert_eq!(ir.imgref.name, "quay.io/exampleos/blah");
+            let reserialized = ir.to_string();
+            assert_eq!(
+                reserialized,
+                "ostree-remote-signed-image:myremote:docker://quay.io/exampleos/blah"
+            );
+            assert_eq!(
+                OstreeImageReference::try_from(reserialized.as_str()).unwrap(),
+                ir
+            );
+        }
+        let sigverify = SignatureSource::try_from("ostree-remote-signed-image:myremote").unwrap();
+        assert_eq!(sigverify.to_string(), "ostree-remote-signed-image:myremote");
+        assert_eq!(sigverify.ostree_remote(), Some("myremote"));
+        assert!(sigverify.requires_container_policy());
     }
 
     #[test]
@@ -629,4 +791,52 @@ mod tests {
         super::merge_default_container_proxy_opts_with_isolation(&mut c, Some("foo")).unwrap();
         assert_eq!(c.skopeo_cmd.unwrap().get_program(), "skopeo");
     }
+
+    #[test]
+    fn test_history_uncompressed_size() {
+        let mut h = oci_spec::image::History::default();
+        assert_eq!(super::history_uncompressed_size(&h), None);
+        super::set_history_uncompressed_size(&mut h, 4096);
+        assert_eq!(super::history_uncompressed_size(&h), Some(4096));
+        h.set_comment(Some("FROM foo ostree.uncompressed-size=42".into()));
+        assert_eq!(super::history_uncompressed_size(&h), Some(42));
+        h.set_comment(Some("ostree.uncompressed-size=junk".into()));
+        assert_eq!(super::history_uncompressed_size(&h), None);
+    }
+
+    #[test]
+    fn test_proxy_platform() {
+        let mut platform = oci_spec::image::Platform::default();
+        platform.set_architecture(oci_spec::image::Arch::ARM64);
+        platform.set_variant(Some("v8".into()));
+        let mut c = ImageProxyConfig::default();
+        super::set_container_proxy_platform(&mut c, &platform);
+        let cmd = c.skopeo_cmd.unwrap();
+        assert_eq!(cmd.get_program(), "skopeo");
+        let args: Vec<_> = cmd.get_args().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            [
+                "--override-os",
+                "linux",
+                "--override-arch",
+                "arm64",
+                "--override-variant",
+                "v8"
+            ]
+        );
+
+        // An existing command is extended
+        let mut c = ImageProxyConfig {
+            skopeo_cmd: Some(crate::isolation::unprivileged_subprocess("skopeo", "foo")),
+            ..Default::default()
+        };
+        super::set_container_proxy_platform(&mut c, &platform);
+        let cmd = c.skopeo_cmd.unwrap();
+        let args: Vec<_> = cmd.get_args().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(
+            &args[args.len() - 6..args.len() - 4],
+            ["--override-os", "linux"]
+        );
+    }
 }
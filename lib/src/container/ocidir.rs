@@ -17,20 +17,22 @@ use oci_spec::image::{self as oci_image, Descriptor};
 use olpc_cjson::CanonicalFormatter;
 use openssl::hash::{Hasher, MessageDigest};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{prelude::*, BufReader};
-use std::os::unix::fs::DirBuilderExt;
+use std::io::{prelude::*, BufReader, SeekFrom};
+use std::os::unix::fs::{DirBuilderExt, FileExt};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
 
 /// Path inside an OCI directory to the blobs
-const BLOBDIR: &str = "blobs/sha256";
+pub(crate) const BLOBDIR: &str = "blobs/sha256";
 
-const OCI_TAG_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+/// The annotation holding the tag of an image in the index.
+pub(crate) const OCI_TAG_ANNOTATION: &str = "org.opencontainers.image.ref.name";
 
 /// Completed blob metadata
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Blob {
     /// SHA-256 digest
     pub sha256: String,
@@ -53,18 +55,56 @@ impl Blob {
 }
 
 /// Completed layer metadata
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Layer {
     /// The underlying blob (usually compressed)
     pub blob: Blob,
     /// The uncompressed digest, which will be used for "diffid"s
     pub uncompressed_sha256: String,
+    /// The size of the uncompressed content
+    pub uncompressed_size: u64,
+    /// The media type of the blob, which reflects its compression
+    pub media_type: MediaType,
 }
 
 impl Layer {
     /// Return the descriptor for this layer
     pub fn descriptor(&self) -> oci_image::DescriptorBuilder {
-        self.blob.descriptor()
+        self.blob.descriptor().media_type(self.media_type.clone())
+    }
+}
+
+/// The compression algorithm (and level) used for a layer blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    /// gzip compression at the given level
+    Gzip(flate2::Compression),
+    /// zstd compression at the given level
+    Zstd(i32),
+    /// Store the tar stream uncompressed
+    None,
+}
+
+impl Default for LayerCompression {
+    fn default() -> Self {
+        Self::Gzip(flate2::Compression::default())
+    }
+}
+
+impl From<flate2::Compression> for LayerCompression {
+    fn from(c: flate2::Compression) -> Self {
+        Self::Gzip(c)
+    }
+}
+
+impl LayerCompression {
+    /// The OCI media type for a tar layer using this compression.
+    pub fn media_type(&self) -> MediaType {
+        match self {
+            LayerCompression::Gzip(_) => MediaType::ImageLayerGzip,
+            LayerCompression::Zstd(_) => MediaType::ImageLayerZstd,
+            LayerCompression::None => MediaType::ImageLayer,
+        }
     }
 }
 
@@ -73,10 +113,28 @@ pub struct BlobWriter<'a> {
     /// Compute checksum
     pub hash: Hasher,
     /// Target file
-    pub target: Option<cap_tempfile::TempFile<'a>>,
+    target: Option<BlobTarget<'a>>,
     size: u64,
 }
 
+/// Where a [`BlobWriter`] writes to.
+#[derive(Debug)]
+enum BlobTarget<'a> {
+    /// A temporary file in the OCI directory
+    File(cap_tempfile::TempFile<'a>),
+    /// An archive, following a placeholder header at the provided offset; the
+    /// archive is locked until the blob is complete.
+    Archive(MutexGuard<'a, ArchiveState>, u64),
+}
+
+/// An `oci-archive:` being written by [`OciDir::create_for_archive`].
+#[derive(Debug)]
+struct ArchiveState {
+    f: File,
+    /// The digests of the blobs written to the archive.
+    blobs: HashSet<String>,
+}
+
 impl<'a> Debug for BlobWriter<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BlobWriter")
@@ -86,11 +144,31 @@ impl<'a> Debug for BlobWriter<'a> {
     }
 }
 
+/// The compression stream for a layer; compressed output is buffered
+/// in memory and then drained into the blob.
+enum Compressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>, i32),
+    None,
+}
+
+impl Debug for Compressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compressor::Gzip(c) => f.debug_tuple("Gzip").field(c).finish(),
+            Compressor::Zstd(_, level) => f.debug_tuple("Zstd").field(level).finish(),
+            Compressor::None => f.write_str("None"),
+        }
+    }
+}
+
 /// Create an OCI layer (also a blob).
 pub struct RawLayerWriter<'a> {
     bw: BlobWriter<'a>,
     uncompressed_hash: Hasher,
-    compressor: GzEncoder<Vec<u8>>,
+    uncompressed_size: u64,
+    compressor: Compressor,
+    media_type: MediaType,
 }
 
 impl<'a> Debug for RawLayerWriter<'a> {
@@ -107,6 +185,8 @@ impl<'a> Debug for RawLayerWriter<'a> {
 pub struct OciDir {
     /// The underlying directory.
     pub dir: std::sync::Arc<Dir>,
+    /// If set, layer blobs are written to this archive instead of the directory.
+    archive: Option<std::sync::Arc<Mutex<ArchiveState>>>,
 }
 
 /// Write a serializable data (JSON) as an OCI blob
@@ -130,6 +210,19 @@ fn parse_one_filename(s: &str) -> Result<&str> {
         .ok_or_else(|| anyhow!("Invalid filename {}", s))
 }
 
+/// Create a header for an entry in an `oci-archive:`, with fixed metadata so that the
+/// archive only depends on its content.
+fn archive_header(ty: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(ty);
+    h.set_mode(mode);
+    h.set_size(size);
+    h.set_mtime(0);
+    h.set_uid(0);
+    h.set_gid(0);
+    h
+}
+
 /// Create a dummy config descriptor.
 /// Our API right now always mutates a manifest, which means we need
 /// a "valid" manifest, which requires a "valid" config descriptor.
@@ -143,6 +236,15 @@ fn empty_config_descriptor() -> oci_image::Descriptor {
         .unwrap()
 }
 
+/// Create an image index with the provided manifests.
+fn new_index(manifests: Vec<Descriptor>) -> oci_image::ImageIndex {
+    oci_image::ImageIndexBuilder::default()
+        .schema_version(oci_image::SCHEMA_VERSION)
+        .manifests(manifests)
+        .build()
+        .unwrap()
+}
+
 /// Generate a "valid" empty manifest.  See above.
 pub fn new_empty_manifest() -> oci_image::ImageManifestBuilder {
     oci_image::ImageManifestBuilder::default()
@@ -151,6 +253,62 @@ pub fn new_empty_manifest() -> oci_image::ImageManifestBuilder {
         .layers(Vec::new())
 }
 
+/// The media type of the empty JSON object `{}`, used as the config of artifacts.
+pub const MEDIA_TYPE_EMPTY: &str = "application/vnd.oci.empty.v1+json";
+
+/// An OCI 1.1 artifact manifest: an image manifest with an `artifactType`,
+/// an empty config, and usually a `subject` referencing another manifest.
+///
+/// This is defined here as the version of oci-spec we use does not support these fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactManifest {
+    /// Always 2.
+    pub schema_version: u32,
+    /// Always [`MediaType::ImageManifest`].
+    pub media_type: MediaType,
+    /// The type of the artifact, e.g. `application/spdx+json`.
+    pub artifact_type: String,
+    /// The config, which is the empty descriptor.
+    pub config: Descriptor,
+    /// The blobs holding the artifact contents.
+    pub layers: Vec<Descriptor>,
+    /// The manifest this artifact refers to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Descriptor>,
+    /// Arbitrary annotations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// A descriptor for a manifest referring to another, as returned by the
+/// OCI 1.1 referrers API; unlike [`Descriptor`] this includes the artifact type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Referrer {
+    /// The media type of the referring manifest.
+    pub media_type: MediaType,
+    /// The digest of the referring manifest.
+    pub digest: String,
+    /// The size of the referring manifest.
+    pub size: i64,
+    /// The artifact type of the referring manifest.
+    pub artifact_type: String,
+    /// The annotations of the referring manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// The subset of a manifest needed to determine whether it is a referrer.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestSubject {
+    artifact_type: Option<String>,
+    config: Option<Descriptor>,
+    subject: Option<Descriptor>,
+    annotations: Option<HashMap<String, String>>,
+}
+
 impl OciDir {
     /// Create a new, empty OCI directory at the target path, which should be empty.
     pub fn create(dir: &Dir) -> Result<Self> {
@@ -176,15 +334,87 @@ impl OciDir {
         Ok(cloned)
     }
 
+    /// Create a new, empty OCI directory at the target path, which should be empty, for
+    /// writing an uncompressed tarball in the format used by the `oci-archive:` transport
+    /// to `f`.  Layer blobs are written directly to the archive, and only the other blobs
+    /// and the index are written to the directory; [`Self::finish_archive`] appends them.
+    /// Only one layer can be written at a time; creating another waits for it to complete.
+    pub fn create_for_archive(dir: &Dir, mut f: File) -> Result<Self> {
+        let mut r = Self::create(dir)?;
+        for path in ["blobs", BLOBDIR] {
+            let mut h = archive_header(tar::EntryType::Directory, 0o755, 0);
+            h.set_path(path)?;
+            h.set_cksum();
+            f.write_all(h.as_bytes())?;
+        }
+        let state = ArchiveState {
+            f,
+            blobs: Default::default(),
+        };
+        r.archive = Some(std::sync::Arc::new(Mutex::new(state)));
+        Ok(r)
+    }
+
     /// Open an existing OCI directory.
     pub fn open(dir: &Dir) -> Result<Self> {
         let dir = std::sync::Arc::new(dir.try_clone()?);
-        Ok(Self { dir })
+        Ok(Self { dir, archive: None })
+    }
+
+    /// Complete an archive created by [`Self::create_for_archive`], appending the blobs
+    /// in the directory in sorted order, then `index.json` and `oci-layout`.
+    #[context("Writing OCI archive")]
+    pub fn finish_archive(&self) -> Result<()> {
+        let archive = self
+            .archive
+            .as_ref()
+            .ok_or_else(|| anyhow!("Not writing an archive"))?;
+        let mut state = archive
+            .lock()
+            .map_err(|_| anyhow!("Failed to lock archive"))?;
+        let mut blobs = self
+            .dir
+            .read_dir(BLOBDIR)?
+            .map(|e| e.map(|e| Path::new(BLOBDIR).join(e.file_name())))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        blobs.sort();
+        let f = &mut state.f;
+        f.seek(SeekFrom::End(0))?;
+        let mut builder = tar::Builder::new(f);
+        for path in blobs
+            .iter()
+            .map(|p| p.as_path())
+            .chain(["index.json", "oci-layout"].map(Path::new))
+        {
+            let f = self
+                .dir
+                .open(path)
+                .with_context(|| format!("Opening {}", path.display()))?;
+            let size = f.metadata()?.len();
+            let mut h = archive_header(tar::EntryType::Regular, 0o644, size);
+            builder.append_data(&mut h, path, BufReader::new(f))?;
+        }
+        builder.finish()?;
+        Ok(())
+    }
+
+    /// Create a writer for a layer blob, in the archive if writing one.
+    fn create_layer_blob(&self) -> Result<BlobWriter<'_>> {
+        match self.archive.as_deref() {
+            Some(archive) => BlobWriter::new_in_archive(archive),
+            None => BlobWriter::new(&self.dir),
+        }
     }
 
     /// Create a writer for a new blob (expected to be a tar stream)
     pub fn create_raw_layer(&self, c: Option<flate2::Compression>) -> Result<RawLayerWriter> {
-        RawLayerWriter::new(&self.dir, c)
+        self.create_raw_layer_with(c.map(Into::into).unwrap_or_default())
+    }
+
+    /// Create a writer for a new blob (expected to be a tar stream), using
+    /// the provided compression.
+    pub fn create_raw_layer_with(&self, c: LayerCompression) -> Result<RawLayerWriter<'_>> {
+        RawLayerWriter::new(self.create_layer_blob()?, c)
     }
 
     /// Create a tar output stream, backed by a blob
@@ -195,6 +425,14 @@ impl OciDir {
         Ok(tar::Builder::new(self.create_raw_layer(c)?))
     }
 
+    /// Create a tar output stream, backed by a blob using the provided compression.
+    pub fn create_layer_with(
+        &self,
+        c: LayerCompression,
+    ) -> Result<tar::Builder<RawLayerWriter<'_>>> {
+        Ok(tar::Builder::new(self.create_raw_layer_with(c)?))
+    }
+
     /// Add a layer to the top of the image stack.  The firsh pushed layer becomes the root.
 
     pub fn push_layer(
@@ -219,7 +457,24 @@ impl OciDir {
         annotations: Option<impl Into<HashMap<String, String>>>,
         description: &str,
     ) {
-        let mut builder = layer.descriptor().media_type(MediaType::ImageLayerGzip);
+        let now = chrono::offset::Utc::now();
+        self.push_layer_full(manifest, config, layer, annotations, description, now)
+    }
+
+    /// Add a layer to the top of the image stack with optional annotations, using
+    /// the provided timestamp for the history entry.
+    ///
+    /// This is otherwise equivalent to [`Self::push_layer_annotated`].
+    pub fn push_layer_full(
+        &self,
+        manifest: &mut oci_image::ImageManifest,
+        config: &mut oci_image::ImageConfiguration,
+        layer: Layer,
+        annotations: Option<impl Into<HashMap<String, String>>>,
+        description: &str,
+        created: chrono::DateTime<chrono::Utc>,
+    ) {
+        let mut builder = layer.descriptor();
         if let Some(annotations) = annotations {
             builder = builder.annotations(annotations);
         }
@@ -230,9 +485,8 @@ impl OciDir {
             .diff_ids_mut()
             .push(format!("sha256:{}", layer.uncompressed_sha256));
         config.set_rootfs(rootfs);
-        let now = chrono::offset::Utc::now();
         let h = oci_image::HistoryBuilder::default()
-            .created(now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .created(created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
             .created_by(description.to_string())
             .build()
             .unwrap();
@@ -280,6 +534,21 @@ impl OciDir {
             .unwrap())
     }
 
+    /// Write a manifest as a blob, returning a descriptor for it with the provided platform.
+    /// This does not modify the index.
+    pub fn write_manifest(
+        &self,
+        manifest: oci_image::ImageManifest,
+        platform: oci_image::Platform,
+    ) -> Result<Descriptor> {
+        Ok(
+            write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
+                .platform(platform)
+                .build()
+                .unwrap(),
+        )
+    }
+
     /// Write a manifest as a blob, and replace the index with a reference to it.
     pub fn insert_manifest(
         &self,
@@ -287,32 +556,119 @@ impl OciDir {
         tag: Option<&str>,
         platform: oci_image::Platform,
     ) -> Result<Descriptor> {
-        let mut manifest = write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
-            .platform(platform)
+        let manifest = self.write_manifest(manifest, platform)?;
+        self.append_to_index(manifest, tag)
+    }
+
+    /// Write a manifest as a blob, and replace the index with a reference to it.
+    pub fn replace_with_single_manifest(
+        &self,
+        manifest: oci_image::ImageManifest,
+        platform: oci_image::Platform,
+    ) -> Result<()> {
+        let manifest = self.write_manifest(manifest, platform)?;
+        self.write_index(&new_index(vec![manifest]))
+    }
+
+    /// Write an image index referencing the provided manifests (typically one per platform)
+    /// as a blob, and add a reference to it to the top level index.
+    pub fn insert_index(
+        &self,
+        manifests: Vec<Descriptor>,
+        tag: Option<&str>,
+    ) -> Result<Descriptor> {
+        let index = write_json_blob(&self.dir, &new_index(manifests), MediaType::ImageIndex)?
             .build()
             .unwrap();
+        self.append_to_index(index, tag)
+    }
+
+    /// Write an image index referencing the provided manifests (typically one per platform)
+    /// as a blob, and replace the top level index with a reference to it.
+    pub fn replace_with_single_index(&self, manifests: Vec<Descriptor>) -> Result<Descriptor> {
+        let index = write_json_blob(&self.dir, &new_index(manifests), MediaType::ImageIndex)?
+            .build()
+            .unwrap();
+        self.write_index(&new_index(vec![index.clone()]))?;
+        Ok(index)
+    }
+
+    /// Remove the image manifest with the provided tag from the index, along with its
+    /// manifest and configuration blobs if no other image references them.  Its layers
+    /// are not removed.
+    #[context("Removing manifest tagged {tag}")]
+    pub(crate) fn remove_tagged_manifest(&self, tag: &str) -> Result<()> {
+        let f = self
+            .dir
+            .open("index.json")
+            .context("Failed to open index.json")?;
+        let mut index = oci_image::ImageIndex::from_reader(BufReader::new(f))?;
+        let (removed, manifests): (Vec<_>, Vec<_>) =
+            index.manifests().iter().cloned().partition(|desc| {
+                desc.annotations()
+                    .as_ref()
+                    .and_then(|a| a.get(OCI_TAG_ANNOTATION))
+                    .is_some_and(|v| v == tag)
+            });
+        let desc = match removed.as_slice() {
+            [desc] => desc,
+            [] => anyhow::bail!("No manifest tagged {tag}"),
+            o => anyhow::bail!("Found {} manifests tagged {tag}", o.len()),
+        };
+        let manifest: oci_image::ImageManifest = self.read_json_blob(desc)?;
+        // Find the blobs still referenced; if there is anything other than a plain
+        // image manifest, conservatively keep the configuration.
+        let mut referenced = HashSet::new();
+        let mut complete = true;
+        for d in manifests.iter() {
+            referenced.insert(d.digest().to_string());
+            if d.media_type() != &MediaType::ImageManifest {
+                complete = false;
+                continue;
+            }
+            let m: oci_image::ImageManifest = self.read_json_blob(d)?;
+            referenced.insert(m.config().digest().to_string());
+        }
+        let mut unreferenced = vec![desc];
+        if complete {
+            unreferenced.push(manifest.config());
+        }
+        for blob in unreferenced {
+            if !referenced.contains(blob.digest()) {
+                self.dir
+                    .remove_file(Self::parse_descriptor_to_path(blob)?)?;
+            }
+        }
+        index.set_manifests(manifests);
+        self.write_index(&index)?;
+        Ok(())
+    }
+
+    /// Add a descriptor to the top level index, optionally annotated with a tag.
+    fn append_to_index(&self, mut desc: Descriptor, tag: Option<&str>) -> Result<Descriptor> {
         if let Some(tag) = tag {
             let annotations: HashMap<_, _> = [(OCI_TAG_ANNOTATION.to_string(), tag.to_string())]
                 .into_iter()
                 .collect();
-            manifest.set_annotations(Some(annotations));
+            desc.set_annotations(Some(annotations));
         }
 
         let index = self.dir.open_optional("index.json")?.map(BufReader::new);
         let index =
             if let Some(mut index) = index.map(oci_image::ImageIndex::from_reader).transpose()? {
                 let mut manifests = index.manifests().clone();
-                manifests.push(manifest.clone());
+                manifests.push(desc.clone());
                 index.set_manifests(manifests);
                 index
             } else {
-                oci_image::ImageIndexBuilder::default()
-                    .schema_version(oci_image::SCHEMA_VERSION)
-                    .manifests(vec![manifest.clone()])
-                    .build()
-                    .unwrap()
+                new_index(vec![desc.clone()])
             };
+        self.write_index(&index)?;
+        Ok(desc)
+    }
 
+    /// Replace the top level index.
+    fn write_index(&self, index: &oci_image::ImageIndex) -> Result<()> {
         self.dir
             .atomic_replace_with("index.json", |mut w| -> Result<()> {
                 let mut ser =
@@ -320,35 +676,158 @@ impl OciDir {
                 index.serialize(&mut ser).context("Failed to serialize")?;
                 Ok(())
             })?;
-        Ok(manifest)
+        Ok(())
     }
 
-    /// Write a manifest as a blob, and replace the index with a reference to it.
-    pub fn replace_with_single_manifest(
+    /// Read the image index referenced by the provided descriptor.
+    pub fn read_index(&self, desc: &Descriptor) -> Result<oci_image::ImageIndex> {
+        self.read_json_blob(desc)
+    }
+
+    /// Write a blob with the provided contents.
+    pub fn write_blob(&self, mut r: impl Read) -> Result<Blob> {
+        let mut w = BlobWriter::new(&self.dir)?;
+        std::io::copy(&mut r, &mut w)?;
+        w.complete()
+    }
+
+    /// Find the descriptor for a manifest or index with the provided digest, which is
+    /// either referenced by the top level index or by an image index it references.
+    /// Only the media type, digest and size are returned, not e.g. the platform or the
+    /// annotations of the index entry, so it is suitable as the `subject` of an artifact.
+    pub fn find_descriptor(&self, digest: &str) -> Result<Option<Descriptor>> {
+        let f = self
+            .dir
+            .open("index.json")
+            .context("Failed to open index.json")?;
+        let idx: oci_image::ImageIndex = serde_json::from_reader(BufReader::new(f))?;
+        let plain = |desc: &Descriptor| {
+            oci_image::DescriptorBuilder::default()
+                .media_type(desc.media_type().clone())
+                .digest(desc.digest())
+                .size(desc.size())
+                .build()
+                .unwrap()
+        };
+        for desc in idx.manifests() {
+            if desc.digest() == digest {
+                return Ok(Some(plain(desc)));
+            }
+            if desc.media_type() == &MediaType::ImageIndex {
+                let nested = self.read_index(desc)?;
+                if let Some(desc) = nested.manifests().iter().find(|d| d.digest() == digest) {
+                    return Ok(Some(plain(desc)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write an OCI 1.1 artifact manifest with the provided blobs (which should
+    /// already have been written), and add a reference to it to the index.
+    ///
+    /// The artifact is tagged `<algorithm>-<hex>` after its own digest.  As the index
+    /// then has multiple entries, the existing manifests must also be tagged so they can
+    /// still be referenced unambiguously; otherwise an error is returned.
+    #[context("Writing artifact")]
+    pub fn write_artifact(
         &self,
-        manifest: oci_image::ImageManifest,
-        platform: oci_image::Platform,
-    ) -> Result<()> {
-        let manifest = write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
-            .platform(platform)
+        artifact_type: &str,
+        subject: Option<Descriptor>,
+        layers: Vec<Descriptor>,
+        annotations: Option<HashMap<String, String>>,
+    ) -> Result<Descriptor> {
+        let index = self
+            .dir
+            .open_optional("index.json")?
+            .map(|f| oci_image::ImageIndex::from_reader(BufReader::new(f)))
+            .transpose()?;
+        let manifests = index.as_ref().map(|i| i.manifests().as_slice());
+        for desc in manifests.unwrap_or_default() {
+            let tagged = desc
+                .annotations()
+                .as_ref()
+                .is_some_and(|a| a.contains_key(OCI_TAG_ANNOTATION));
+            if !tagged {
+                anyhow::bail!(
+                    "Manifest {} is not tagged; tag it to add artifacts to the same OCI directory",
+                    desc.digest()
+                );
+            }
+        }
+        let config = write_json_blob(
+            &self.dir,
+            &serde_json::Map::new(),
+            MediaType::Other(MEDIA_TYPE_EMPTY.to_string()),
+        )?
+        .build()
+        .unwrap();
+        let manifest = ArtifactManifest {
+            schema_version: oci_image::SCHEMA_VERSION,
+            media_type: MediaType::ImageManifest,
+            artifact_type: artifact_type.to_string(),
+            config,
+            layers,
+            subject,
+            annotations,
+        };
+        let desc = write_json_blob(&self.dir, &manifest, MediaType::ImageManifest)?
             .build()
             .unwrap();
+        if let Some(existing) = manifests
+            .unwrap_or_default()
+            .iter()
+            .find(|d| d.digest() == desc.digest())
+        {
+            return Ok(existing.clone());
+        }
+        let tag = desc.digest().replacen(':', "-", 1);
+        self.append_to_index(desc, Some(&tag))
+    }
 
-        let index_data = oci_image::ImageIndexBuilder::default()
-            .schema_version(oci_image::SCHEMA_VERSION)
-            .manifests(vec![manifest])
-            .build()
-            .unwrap();
-        self.dir
-            .atomic_replace_with("index.json", |mut w| -> Result<()> {
-                let mut ser =
-                    serde_json::Serializer::with_formatter(&mut w, CanonicalFormatter::new());
-                index_data
-                    .serialize(&mut ser)
-                    .context("Failed to serialize")?;
-                Ok(())
-            })?;
-        Ok(())
+    /// Read an OCI 1.1 artifact manifest.
+    pub fn read_artifact(&self, desc: &Descriptor) -> Result<ArtifactManifest> {
+        self.read_json_blob(desc)
+    }
+
+    /// List the manifests in the index which refer to the manifest with the provided
+    /// digest, optionally filtering by artifact type.
+    pub fn referrers(&self, digest: &str, artifact_type: Option<&str>) -> Result<Vec<Referrer>> {
+        let f = self
+            .dir
+            .open("index.json")
+            .context("Failed to open index.json")?;
+        let idx: oci_image::ImageIndex = serde_json::from_reader(BufReader::new(f))?;
+        let mut r = Vec::new();
+        for desc in idx.manifests() {
+            if desc.media_type() != &MediaType::ImageManifest {
+                continue;
+            }
+            let manifest: ManifestSubject = self.read_json_blob(desc)?;
+            let is_referrer = manifest
+                .subject
+                .as_ref()
+                .is_some_and(|s| s.digest() == digest);
+            if !is_referrer {
+                continue;
+            }
+            // Per the specification, fall back to the config media type
+            let manifest_artifact_type = manifest
+                .artifact_type
+                .or_else(|| manifest.config.map(|c| c.media_type().to_string()))
+                .unwrap_or_default();
+            if artifact_type.is_some_and(|t| t != manifest_artifact_type) {
+                continue;
+            }
+            r.push(Referrer {
+                media_type: desc.media_type().clone(),
+                digest: desc.digest().clone(),
+                size: desc.size(),
+                artifact_type: manifest_artifact_type,
+                annotations: manifest.annotations,
+            });
+        }
+        Ok(r)
     }
 
     /// If this OCI directory has a single manifest, return it.  Otherwise, an error is returned.
@@ -378,6 +857,7 @@ impl OciDir {
     }
 
     /// If this OCI directory has a single manifest, return it.  Otherwise, an error is returned.
+    /// Artifacts which refer to another manifest (see [`Self::write_artifact`]) are ignored.
     pub fn read_manifest_and_descriptor(&self) -> Result<(oci_image::ImageManifest, Descriptor)> {
         let f = self
             .dir
@@ -387,7 +867,19 @@ impl OciDir {
         let desc = match idx.manifests().as_slice() {
             [] => anyhow::bail!("No manifests found"),
             [desc] => desc.clone(),
-            manifests => anyhow::bail!("Expected exactly 1 manifest, found {}", manifests.len()),
+            manifests => {
+                let mut images = Vec::new();
+                for desc in manifests {
+                    let manifest: ManifestSubject = self.read_json_blob(desc)?;
+                    if manifest.subject.is_none() {
+                        images.push(desc);
+                    }
+                }
+                match images.as_slice() {
+                    [desc] => (*desc).clone(),
+                    _ => anyhow::bail!("Expected exactly 1 manifest, found {}", manifests.len()),
+                }
+            }
         };
         Ok((self.read_json_blob(&desc)?, desc))
     }
@@ -399,7 +891,24 @@ impl<'a> BlobWriter<'a> {
         Ok(Self {
             hash: Hasher::new(MessageDigest::sha256())?,
             // FIXME add ability to choose filename after completion
-            target: Some(cap_tempfile::TempFile::new(ocidir)?),
+            target: Some(BlobTarget::File(cap_tempfile::TempFile::new(ocidir)?)),
+            size: 0,
+        })
+    }
+
+    /// Create a writer for a blob appended to an archive, waiting for any other blob
+    /// being written to it.  A placeholder header is written, which is replaced once
+    /// the digest and size are known.
+    #[context("Creating blob writer in archive")]
+    fn new_in_archive(archive: &'a Mutex<ArchiveState>) -> Result<Self> {
+        let mut state = archive
+            .lock()
+            .map_err(|_| anyhow!("Failed to lock archive"))?;
+        let start = state.f.seek(SeekFrom::End(0))?;
+        state.f.write_all(&[0u8; 512])?;
+        Ok(Self {
+            hash: Hasher::new(MessageDigest::sha256())?,
+            target: Some(BlobTarget::Archive(state, start)),
             size: 0,
         })
     }
@@ -409,8 +918,22 @@ impl<'a> BlobWriter<'a> {
     pub fn complete(mut self) -> Result<Blob> {
         let sha256 = hex::encode(self.hash.finish()?);
         let destname = &format!("{}/{}", BLOBDIR, sha256);
-        let target = self.target.take().unwrap();
-        target.replace(destname)?;
+        match self.target.take().unwrap() {
+            BlobTarget::File(target) => target.replace(destname)?,
+            BlobTarget::Archive(mut state, start) => {
+                if state.blobs.insert(sha256.clone()) {
+                    let mut h = archive_header(tar::EntryType::Regular, 0o644, self.size);
+                    h.set_path(destname)?;
+                    h.set_cksum();
+                    state.f.write_all_at(h.as_bytes(), start)?;
+                    let padding = (512 - self.size % 512) % 512;
+                    state.f.write_all(&vec![0u8; padding as usize])?;
+                } else {
+                    // The same content was already written
+                    state.f.set_len(start)?;
+                }
+            }
+        }
         Ok(Blob {
             sha256,
             size: self.size,
@@ -418,14 +941,22 @@ impl<'a> BlobWriter<'a> {
     }
 }
 
+impl<'a> Drop for BlobWriter<'a> {
+    fn drop(&mut self) {
+        // Discard an incomplete blob so that it is not left in an archive
+        if let Some(BlobTarget::Archive(state, start)) = self.target.take() {
+            let _ = state.f.set_len(start);
+        }
+    }
+}
+
 impl<'a> std::io::Write for BlobWriter<'a> {
     fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
         self.hash.update(srcbuf)?;
-        self.target
-            .as_mut()
-            .unwrap()
-            .as_file_mut()
-            .write_all(srcbuf)?;
+        match self.target.as_mut().unwrap() {
+            BlobTarget::File(f) => f.as_file_mut().write_all(srcbuf)?,
+            BlobTarget::Archive(state, _) => state.f.write_all(srcbuf)?,
+        }
         self.size += srcbuf.len() as u64;
         Ok(srcbuf.len())
     }
@@ -436,37 +967,104 @@ impl<'a> std::io::Write for BlobWriter<'a> {
 }
 
 impl<'a> RawLayerWriter<'a> {
-    /// Create a writer for a gzip compressed layer blob.
-    fn new(ocidir: &'a Dir, c: Option<flate2::Compression>) -> Result<Self> {
-        let bw = BlobWriter::new(ocidir)?;
+    /// Create a writer for a layer blob with the given compression.
+    fn new(bw: BlobWriter<'a>, c: LayerCompression) -> Result<Self> {
+        let buf = Vec::with_capacity(8192);
+        let compressor = match c {
+            // Pin the header fields so that the output depends only on the input
+            LayerCompression::Gzip(level) => Compressor::Gzip(
+                flate2::GzBuilder::new()
+                    .mtime(0)
+                    .operating_system(255)
+                    .write(buf, level),
+            ),
+            LayerCompression::Zstd(level) => {
+                Compressor::Zstd(zstd::stream::write::Encoder::new(buf, level)?, level)
+            }
+            LayerCompression::None => Compressor::None,
+        };
         Ok(Self {
             bw,
             uncompressed_hash: Hasher::new(MessageDigest::sha256())?,
-            compressor: GzEncoder::new(Vec::with_capacity(8192), c.unwrap_or_default()),
+            uncompressed_size: 0,
+            compressor,
+            media_type: c.media_type(),
         })
     }
 
     #[context("Completing layer")]
     /// Consume this writer, flushing buffered data and put the blob in place.
     pub fn complete(mut self) -> Result<Layer> {
-        self.compressor.get_mut().clear();
-        let buf = self.compressor.finish()?;
+        let buf = match self.compressor {
+            Compressor::Gzip(mut c) => {
+                c.get_mut().clear();
+                c.finish()?
+            }
+            Compressor::Zstd(mut c, _) => {
+                c.get_mut().clear();
+                c.finish()?
+            }
+            Compressor::None => Vec::new(),
+        };
         self.bw.write_all(&buf)?;
         let blob = self.bw.complete()?;
         let uncompressed_sha256 = hex::encode(self.uncompressed_hash.finish()?);
         Ok(Layer {
             blob,
             uncompressed_sha256,
+            uncompressed_size: self.uncompressed_size,
+            media_type: self.media_type,
         })
     }
+
+    /// For zstd compressed layers, complete the current compressed frame so that
+    /// data written afterwards can be decompressed independently.  Returns the
+    /// offset in the blob at which the next frame starts.
+    pub fn end_frame(&mut self) -> Result<u64> {
+        if let Compressor::Zstd(c, level) = &mut self.compressor {
+            let next = zstd::stream::write::Encoder::new(Vec::with_capacity(8192), *level)?;
+            let mut prev = std::mem::replace(c, next);
+            prev.get_mut().clear();
+            let buf = prev.finish()?;
+            self.bw.write_all(&buf)?;
+        } else {
+            anyhow::bail!("Layer is not zstd compressed");
+        }
+        Ok(self.bw.size)
+    }
+
+    /// Append a zstd skippable frame containing `data` to the blob; this is ignored
+    /// by decompressors and does not contribute to the uncompressed digest.
+    /// Returns the offset in the blob of `data`.
+    pub fn write_skippable_frame(&mut self, data: &[u8]) -> Result<u64> {
+        // https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#skippable-frames
+        const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+        let offset = self.end_frame()?;
+        let len = u32::try_from(data.len())?;
+        self.bw.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+        self.bw.write_all(&len.to_le_bytes())?;
+        self.bw.write_all(data)?;
+        Ok(offset + 8)
+    }
 }
 
 impl<'a> std::io::Write for RawLayerWriter<'a> {
     fn write(&mut self, srcbuf: &[u8]) -> std::io::Result<usize> {
-        self.compressor.get_mut().clear();
-        self.compressor.write_all(srcbuf).unwrap();
         self.uncompressed_hash.update(srcbuf)?;
-        let compressed_buf = self.compressor.get_mut().as_slice();
+        self.uncompressed_size += srcbuf.len() as u64;
+        let compressed_buf = match &mut self.compressor {
+            Compressor::Gzip(c) => {
+                c.get_mut().clear();
+                c.write_all(srcbuf)?;
+                c.get_mut().as_slice()
+            }
+            Compressor::Zstd(c, _) => {
+                c.get_mut().clear();
+                c.write_all(srcbuf)?;
+                c.get_mut().as_slice()
+            }
+            Compressor::None => srcbuf,
+        };
         self.bw.write_all(compressed_buf)?;
         Ok(srcbuf.len())
     }
@@ -527,6 +1125,7 @@ mod tests {
             root_layer.uncompressed_sha256,
             "349438e5faf763e8875b43de4d7101540ef4d865190336c2cc549a11f33f8d7c"
         );
+        assert_eq!(root_layer.uncompressed_size, 25);
         let mut manifest = new_empty_manifest().build().unwrap();
         let mut config = oci_image::ImageConfigurationBuilder::default()
             .build()
@@ -550,4 +1149,185 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_index() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::create(&td)?;
+        let manifests = [oci_image::Arch::Amd64, oci_image::Arch::ARM64]
+            .into_iter()
+            .map(|arch| {
+                let mut platform = oci_image::Platform::default();
+                platform.set_architecture(arch);
+                let manifest = new_empty_manifest().build().unwrap();
+                w.write_manifest(manifest, platform)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let index = w.replace_with_single_index(manifests.clone())?;
+        assert_eq!(index.media_type(), &MediaType::ImageIndex);
+        // The top level index references only the nested index
+        let toplevel = oci_image::ImageIndex::from_reader(w.dir.open("index.json")?)?;
+        assert_eq!(toplevel.manifests(), std::slice::from_ref(&index));
+        let read_index = w.read_index(&index)?;
+        assert_eq!(read_index.manifests(), &manifests);
+        let archs: Vec<_> = read_index
+            .manifests()
+            .iter()
+            .map(|m| m.platform().as_ref().unwrap().architecture().to_string())
+            .collect();
+        assert_eq!(archs, ["amd64", "arm64"]);
+
+        let _: Descriptor = w.insert_index(manifests, Some("latest"))?;
+        assert!(w.read_manifest().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let mut f = tempfile::tempfile()?;
+        let w = OciDir::create_for_archive(&td, f.try_clone()?)?;
+        let mut layers = Vec::new();
+        for content in [&b"layer content"[..], b"more", b"layer content"] {
+            let mut layerw = w.create_raw_layer_with(LayerCompression::None)?;
+            layerw.write_all(content)?;
+            layers.push(layerw.complete()?);
+        }
+        // An incomplete layer is discarded
+        let mut layerw = w.create_raw_layer_with(LayerCompression::None)?;
+        layerw.write_all(b"incomplete")?;
+        drop(layerw);
+        assert_eq!(layers[0].blob.sha256, layers[2].blob.sha256);
+        let manifest = new_empty_manifest().build().unwrap();
+        w.replace_with_single_manifest(manifest, oci_image::Platform::default())?;
+        let (_, desc) = w.read_manifest_and_descriptor()?;
+        w.finish_archive()?;
+        // Layers are only written to the archive
+        assert_eq!(w.dir.read_dir(BLOBDIR)?.count(), 1);
+
+        f.seek(SeekFrom::Start(0))?;
+        let mut archive = tar::Archive::new(f);
+        let mut entries = Vec::new();
+        for e in archive.entries()? {
+            let mut e = e?;
+            let path = e
+                .path()?
+                .to_str()
+                .unwrap()
+                .trim_end_matches('/')
+                .to_string();
+            let mut buf = Vec::new();
+            e.read_to_end(&mut buf)?;
+            entries.push((path, buf));
+        }
+        let paths: Vec<_> = entries.iter().map(|e| e.0.as_str()).collect();
+        let blobpath = |sha256: &str| format!("{BLOBDIR}/{sha256}");
+        let expected = [
+            "blobs".to_string(),
+            BLOBDIR.to_string(),
+            blobpath(&layers[0].blob.sha256),
+            blobpath(&layers[1].blob.sha256),
+            blobpath(desc.digest().strip_prefix("sha256:").unwrap()),
+            "index.json".to_string(),
+            "oci-layout".to_string(),
+        ];
+        assert_eq!(paths, expected);
+        assert_eq!(entries[2].1, b"layer content");
+        assert_eq!(entries[3].1, b"more");
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifacts() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::create(&td)?;
+        let manifest = new_empty_manifest().build().unwrap();
+        w.replace_with_single_manifest(manifest, oci_image::Platform::default())?;
+        let (_, desc) = w.read_manifest_and_descriptor()?;
+        assert!(desc.platform().is_some());
+        // The platform of the index entry is not included
+        let subject = w.find_descriptor(desc.digest())?.unwrap();
+        assert_eq!(
+            (subject.media_type(), subject.digest(), subject.size()),
+            (desc.media_type(), desc.digest(), desc.size())
+        );
+        assert!(subject.platform().is_none() && subject.annotations().is_none());
+        assert!(w
+            .find_descriptor(&format!("sha256:{}", "0".repeat(64)))?
+            .is_none());
+        assert!(w.referrers(subject.digest(), None)?.is_empty());
+
+        let sbom_type = "application/spdx+json";
+        let blob = w.write_blob(&b"{}"[..])?;
+        let blob = blob
+            .descriptor()
+            .media_type(MediaType::Other(sbom_type.into()))
+            .build()
+            .unwrap();
+        let sbom = w.write_artifact(sbom_type, Some(subject.clone()), vec![blob.clone()], None)?;
+        let sig = w.write_artifact(
+            "application/example.sig",
+            Some(subject.clone()),
+            vec![],
+            None,
+        )?;
+        // Unrelated artifacts are not referrers
+        let _ = w.write_artifact(sbom_type, None, vec![], None)?;
+
+        let referrers = w.referrers(subject.digest(), None)?;
+        let digests: Vec<_> = referrers.iter().map(|r| r.digest.as_str()).collect();
+        assert_eq!(digests, [sbom.digest().as_str(), sig.digest().as_str()]);
+        let referrers = w.referrers(subject.digest(), Some(sbom_type))?;
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].artifact_type, sbom_type);
+
+        let artifact = w.read_artifact(&sbom)?;
+        assert_eq!(artifact.artifact_type, sbom_type);
+        assert_eq!(artifact.subject.as_ref(), Some(&subject));
+        assert_eq!(artifact.layers, [blob]);
+        assert_eq!(
+            artifact.config.media_type(),
+            &MediaType::Other(MEDIA_TYPE_EMPTY.into())
+        );
+        assert_eq!(artifact.config.size(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_compression() -> Result<()> {
+        let td = cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let w = OciDir::create(&td)?;
+        let contents = b"pretend this is a tarball";
+        for (c, media_type) in [
+            (LayerCompression::default(), MediaType::ImageLayerGzip),
+            (LayerCompression::Zstd(3), MediaType::ImageLayerZstd),
+            (LayerCompression::None, MediaType::ImageLayer),
+        ] {
+            let mut layerw = w.create_raw_layer_with(c)?;
+            layerw.write_all(contents)?;
+            let layer = layerw.complete()?;
+            // The diffid is independent of the compression
+            assert_eq!(
+                layer.uncompressed_sha256,
+                "349438e5faf763e8875b43de4d7101540ef4d865190336c2cc549a11f33f8d7c"
+            );
+            let desc = layer.descriptor().build().unwrap();
+            assert_eq!(desc.media_type(), &media_type);
+            let mut blob = Vec::new();
+            let f = w.read_blob(&desc)?;
+            match c {
+                LayerCompression::Gzip(_) => {
+                    flate2::read::GzDecoder::new(f).read_to_end(&mut blob)?;
+                }
+                LayerCompression::Zstd(_) => {
+                    zstd::stream::read::Decoder::new(f)?.read_to_end(&mut blob)?;
+                }
+                LayerCompression::None => {
+                    BufReader::new(f).read_to_end(&mut blob)?;
+                }
+            }
+            assert_eq!(blob.as_slice(), contents);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,202 @@
+//! Incremental upload of layers to a registry or OCI directory.
+//!
+//! Normally, an image is written in full to a temporary OCI directory and then
+//! copied to the destination by skopeo.  With incremental pushing, each layer is
+//! instead uploaded as soon as it is written, which allows generating later layers
+//! while earlier ones are uploaded.
+//!
+//! As skopeo can only copy whole images, each layer is uploaded as part of a
+//! single-layer "carrier" image.  For a registry, carriers are pushed by digest so
+//! that no tag is modified; for an OCI directory, they use a temporary tag.
+//! The final copy of the full image then finds the layers already present in
+//! the destination, and only uploads the configuration and manifest.  The layers
+//! are kept in the temporary directory until then, so the final copy never depends
+//! on skopeo skipping blobs which are already present.  After it completes, the
+//! carriers are removed with [`Carriers::remove`]; the layers remain referenced by
+//! the final image.  Failing to remove a carrier is an error, so pushing incrementally
+//! requires a registry which supports deleting manifests.
+
+use super::encapsulate::parse_oci_path_and_tag;
+use super::ocidir::{self, Layer, OciDir, BLOBDIR};
+use super::{repository_name, skopeo, ImageReference, Transport};
+use anyhow::{anyhow, Context, Result};
+use cap_std::fs::Dir;
+use cap_std_ext::cap_std;
+use containers_image_proxy::oci_spec::image as oci_image;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// The prefix of the tags of carrier images in an OCI directory.
+const CARRIER_TAG_PREFIX: &str = "ostree-ext-carrier-";
+
+/// A handle used to queue layers for upload once they have been written.
+#[derive(Debug, Clone)]
+pub(crate) struct LayerSink(UnboundedSender<Layer>);
+
+impl LayerSink {
+    /// Queue a written layer for upload.
+    pub(crate) fn send(&self, layer: &Layer) -> Result<()> {
+        self.0
+            .send(layer.clone())
+            .map_err(|_| anyhow!("Layer upload failed"))
+    }
+}
+
+/// Uploads layers in a background thread as they are written.
+#[derive(Debug)]
+pub(crate) struct Uploader {
+    sink: LayerSink,
+    worker: std::thread::JoinHandle<Result<Carriers>>,
+}
+
+impl Uploader {
+    /// Start uploading layers written to the OCI directory at `ocidir` to the repository
+    /// of `dest`.  Temporary files are written to `workdir`, which must be on the
+    /// same filesystem.
+    pub(crate) fn new(
+        workdir: &Path,
+        ocidir: &Path,
+        dest: &ImageReference,
+        authfile: Option<&Path>,
+    ) -> Result<Self> {
+        let name = match dest.transport {
+            Transport::Registry => repository_name(&dest.name),
+            Transport::OciDir => parse_oci_path_and_tag(&dest.name).0,
+            _ => anyhow::bail!(
+                "Incremental push requires a registry or OCI directory destination, not {dest}"
+            ),
+        };
+        let worker = Worker {
+            workdir: workdir.to_owned(),
+            blobdir: ocidir.join(BLOBDIR),
+            transport: dest.transport,
+            name: name.to_string(),
+            authfile: authfile.map(ToOwned::to_owned),
+        };
+        let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+        let worker = std::thread::Builder::new()
+            .name("layer-upload".into())
+            .spawn(move || worker.run(recv))?;
+        Ok(Self {
+            sink: LayerSink(send),
+            worker,
+        })
+    }
+
+    /// Return a handle for queueing layers.
+    pub(crate) fn sink(&self) -> LayerSink {
+        self.sink.clone()
+    }
+
+    /// Wait for all queued layers to be uploaded, returning the carrier images to
+    /// remove once the final image has been copied.  All other handles returned by
+    /// [`Self::sink`] must have been dropped.
+    pub(crate) fn finish(self) -> Result<Carriers> {
+        drop(self.sink);
+        self.worker
+            .join()
+            .map_err(|_| anyhow!("Layer upload thread panicked"))?
+    }
+}
+
+/// Carrier images uploaded to the destination.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct Carriers {
+    images: Vec<ImageReference>,
+    authfile: Option<PathBuf>,
+}
+
+impl Carriers {
+    /// Remove the carrier images.  This must only be done after the final image has
+    /// been copied, as otherwise the layers may be garbage collected.
+    pub(crate) async fn remove(self) -> Result<()> {
+        for imgref in self.images {
+            match imgref.transport {
+                Transport::OciDir => {
+                    let (path, tag) = parse_oci_path_and_tag(&imgref.name);
+                    let tag = tag.ok_or_else(|| anyhow!("Missing tag in {imgref}"))?;
+                    let path = path.to_string();
+                    let tag = tag.to_string();
+                    let r = tokio::task::spawn_blocking(move || {
+                        let d = Dir::open_ambient_dir(&path, cap_std::ambient_authority())?;
+                        OciDir::open(&d)?.remove_tagged_manifest(&tag)
+                    })
+                    .await;
+                    crate::tokio_util::flatten_anyhow(r)?;
+                }
+                _ => skopeo::delete(&imgref, self.authfile.as_deref())
+                    .await
+                    .with_context(|| format!("Removing carrier image {imgref}"))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Worker {
+    workdir: PathBuf,
+    blobdir: PathBuf,
+    transport: Transport,
+    /// The repository, or the path of the OCI directory
+    name: String,
+    authfile: Option<PathBuf>,
+}
+
+impl Worker {
+    fn run(self, mut recv: UnboundedReceiver<Layer>) -> Result<Carriers> {
+        let mut uploaded = HashSet::new();
+        let mut images = Vec::new();
+        while let Some(layer) = recv.blocking_recv() {
+            let digest = layer.blob.digest_id();
+            let blobpath = self.blobdir.join(&layer.blob.sha256);
+            // The same content may be written more than once
+            if !uploaded.contains(&digest) {
+                let carrier = self
+                    .upload(&blobpath, layer)
+                    .with_context(|| format!("Uploading layer {digest}"))?;
+                images.push(carrier);
+                uploaded.insert(digest);
+            }
+        }
+        Ok(Carriers {
+            images,
+            authfile: self.authfile,
+        })
+    }
+
+    /// Upload a layer via a carrier image referencing only it, returning the
+    /// reference to the carrier.
+    fn upload(&self, blobpath: &Path, layer: Layer) -> Result<ImageReference> {
+        let tempdir = tempfile::tempdir_in(&self.workdir)?;
+        let d = Dir::open_ambient_dir(tempdir.path(), cap_std::ambient_authority())?;
+        let carrier = OciDir::create(&d)?;
+        std::fs::hard_link(
+            blobpath,
+            tempdir.path().join(BLOBDIR).join(&layer.blob.sha256),
+        )?;
+        let tag = format!("{CARRIER_TAG_PREFIX}{}", layer.blob.sha256);
+        let mut manifest = ocidir::new_empty_manifest().build().unwrap();
+        let mut config = oci_image::ImageConfiguration::default();
+        carrier.push_layer(&mut manifest, &mut config, layer, "", None);
+        manifest.set_config(carrier.write_config(config)?);
+        carrier.replace_with_single_manifest(manifest, oci_image::Platform::default())?;
+        let (_, desc) = carrier.read_manifest_and_descriptor()?;
+        let src = ImageReference {
+            transport: Transport::OciDir,
+            name: tempdir.path().to_str().unwrap().to_string(),
+        };
+        let name = match self.transport {
+            Transport::OciDir => format!("{}:{tag}", self.name),
+            _ => format!("{}@{}", self.name, desc.digest()),
+        };
+        let dest = ImageReference {
+            transport: self.transport,
+            name,
+        };
+        tracing::debug!("Uploading {src} to {dest}");
+        skopeo::copy_blocking(&src, &dest, self.authfile.as_deref())?;
+        Ok(dest)
+    }
+}
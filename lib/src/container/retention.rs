@@ -0,0 +1,259 @@
+//! Retention policies for the container image store.
+//!
+//! Stored images are grouped by repository, i.e. their name without any tag or
+//! digest; for example `quay.io/exampleos/os:41` and `quay.io/exampleos/os:42` are
+//! in the same group.  Within each group, images are ordered by their creation time,
+//! and removed according to a [`RetentionPolicy`].  Images which are the origin of a
+//! deployment or which are pinned via [`super::store::pin_image`] are never removed.
+
+use super::{repository_name, store, ImageReference};
+use crate::sysroot::SysrootLock;
+use anyhow::Result;
+use fn_error_context::context;
+use ostree::gio;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Which stored images to retain.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct RetentionPolicy {
+    /// Keep this many of the most recently created images of each repository.
+    pub keep_last: Option<usize>,
+    /// Remove images created longer ago than this.  If `keep_last` is also set,
+    /// the most recent images are kept regardless of their age.
+    pub older_than: Option<Duration>,
+}
+
+/// The result of [`prune_images`].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct PruneReport {
+    /// The images which were removed.
+    pub removed: Vec<ImageReference>,
+    /// The number of layers which were removed.
+    pub layers_removed: u32,
+    /// The number of bytes freed in the repository by pruning the objects of the
+    /// removed images and layers.
+    pub bytes_freed: u64,
+}
+
+/// A stored image considered for removal.
+#[derive(Debug)]
+struct Candidate {
+    imgref: ImageReference,
+    /// The creation time of the image, in seconds since the epoch
+    created: Option<u64>,
+    /// If true, the image is deployed or pinned
+    protected: bool,
+}
+
+/// Select the images to remove according to the policy.  Images without a known
+/// creation time are always retained.
+fn select(candidates: Vec<Candidate>, policy: &RetentionPolicy, now: u64) -> Vec<ImageReference> {
+    let mut groups: HashMap<_, Vec<_>> = HashMap::new();
+    for c in candidates {
+        let created = match c.created {
+            Some(v) => v,
+            None => continue,
+        };
+        let repository = ImageReference {
+            transport: c.imgref.transport,
+            name: repository_name(&c.imgref.name).to_string(),
+        };
+        groups.entry(repository).or_default().push((created, c));
+    }
+    let mut removed = Vec::new();
+    for mut group in groups.into_values() {
+        // Most recent first
+        group.sort_by_key(|(created, _)| std::cmp::Reverse(*created));
+        for (i, (created, c)) in group.into_iter().enumerate() {
+            if c.protected || policy.keep_last.is_some_and(|n| i < n) {
+                continue;
+            }
+            let remove = match policy.older_than {
+                Some(age) => now.saturating_sub(created) > age.as_secs(),
+                None => policy.keep_last.is_some(),
+            };
+            if remove {
+                removed.push(c.imgref);
+            }
+        }
+    }
+    removed.sort_by_key(|i| i.to_string());
+    removed
+}
+
+/// Parse a duration such as `30d` or `12h`; the supported units are `s`, `m`, `h`,
+/// `d` and `w`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(unit_start);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration: {s}"))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid duration: {s}"),
+    };
+    Ok(Duration::from_secs(n.saturating_mul(secs)))
+}
+
+/// Prune the system repository in the same way as `ostree admin cleanup`, keeping the
+/// objects of all deployments.  Only the pruned objects which were reachable from one of
+/// `previous_refs` are counted in the returned size; others were already unreferenced.
+#[context("Pruning repository")]
+fn prune_repo(sysroot: &SysrootLock, previous_refs: &HashMap<String, String>) -> Result<u64> {
+    let cancellable = gio::Cancellable::NONE;
+    let repo = &sysroot.repo();
+    let current_refs = repo.list_refs(None, cancellable)?;
+    let mut removed_objects = HashSet::new();
+    for (name, commit) in previous_refs {
+        if current_refs.get(name) != Some(commit) {
+            removed_objects.extend(repo.traverse_commit(commit, 0, cancellable)?);
+        }
+    }
+    let retained_commits = current_refs
+        .values()
+        .cloned()
+        .chain(sysroot.deployments().iter().map(|d| d.csum().to_string()))
+        .collect::<HashSet<_>>();
+    for commit in retained_commits {
+        for obj in repo.traverse_commit(&commit, 0, cancellable)? {
+            removed_objects.remove(&obj);
+        }
+    }
+    let mut size = 0;
+    for obj in removed_objects {
+        size += repo.query_object_storage_size(obj.object_type(), obj.checksum(), cancellable)?;
+    }
+    crate::ostree_manual::sysroot_cleanup_prune_repo(
+        sysroot,
+        ostree::RepoPruneFlags::REFS_ONLY,
+        cancellable,
+    )?;
+    Ok(size)
+}
+
+/// Remove stored images according to the retention policy, along with their layers,
+/// and prune the objects which are no longer referenced.
+#[context("Pruning images")]
+pub fn prune_images(sysroot: &SysrootLock, policy: &RetentionPolicy) -> Result<PruneReport> {
+    if policy.keep_last.is_none() && policy.older_than.is_none() {
+        anyhow::bail!("No retention policy specified");
+    }
+    let repo = &sysroot.repo();
+    let deployed = super::deploy::deployed_images(sysroot)?;
+    let mut candidates = Vec::new();
+    for img in store::list_images(repo)? {
+        let imgref = ImageReference::try_from(img.as_str())?;
+        let state = match store::query_image_ref(repo, &imgref)? {
+            Some(s) => s,
+            None => continue,
+        };
+        let config = state.configuration.unwrap_or_default();
+        let created = store::timestamp_of_manifest_or_config(&state.manifest, &config);
+        let protected = deployed.contains(&imgref) || store::image_is_pinned(repo, &imgref)?;
+        candidates.push(Candidate {
+            imgref,
+            created,
+            protected,
+        });
+    }
+    let now = chrono::offset::Utc::now().timestamp() as u64;
+    let removed = select(candidates, policy, now);
+    let previous_refs = repo.list_refs(None, gio::Cancellable::NONE)?;
+    store::remove_images(repo, removed.iter())?;
+    let layers_removed = store::gc_image_layers(repo)?;
+    let bytes_freed = if !removed.is_empty() || layers_removed > 0 {
+        prune_repo(sysroot, &previous_refs)?
+    } else {
+        0
+    };
+    Ok(PruneReport {
+        removed,
+        layers_removed,
+        bytes_freed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn candidate(name: &str, created: Option<u64>, protected: bool) -> Candidate {
+        Candidate {
+            imgref: ImageReference::try_from(format!("registry:{name}").as_str()).unwrap(),
+            created,
+            protected,
+        }
+    }
+
+    fn candidates() -> Vec<Candidate> {
+        vec![
+            candidate("quay.io/exampleos/os:1", Some(DAY), false),
+            candidate("quay.io/exampleos/os:2", Some(2 * DAY), true),
+            candidate("quay.io/exampleos/os:3", Some(3 * DAY), false),
+            candidate("quay.io/exampleos/os:4", Some(4 * DAY), false),
+            candidate("quay.io/exampleos/os:5", None, false),
+            candidate("quay.io/exampleos/other:1", Some(DAY), false),
+        ]
+    }
+
+    fn names(v: Vec<ImageReference>) -> Vec<String> {
+        v.into_iter().map(|v| v.name).collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let now = 10 * DAY;
+        let mut policy = RetentionPolicy::default();
+        assert!(select(candidates(), &policy, now).is_empty());
+
+        policy.keep_last = Some(1);
+        assert_eq!(
+            names(select(candidates(), &policy, now)),
+            ["quay.io/exampleos/os:1", "quay.io/exampleos/os:3"]
+        );
+
+        policy.keep_last = None;
+        policy.older_than = Some(Duration::from_secs(6 * DAY));
+        assert_eq!(
+            names(select(candidates(), &policy, now)),
+            [
+                "quay.io/exampleos/os:1",
+                "quay.io/exampleos/os:3",
+                "quay.io/exampleos/other:1"
+            ]
+        );
+
+        policy.keep_last = Some(2);
+        assert_eq!(
+            names(select(candidates(), &policy, now)),
+            ["quay.io/exampleos/os:1"]
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        for (s, v) in [
+            ("0s", 0),
+            ("90s", 90),
+            ("5m", 300),
+            ("2h", 7200),
+            ("30d", 30 * DAY),
+        ] {
+            assert_eq!(parse_duration(s).unwrap(), Duration::from_secs(v));
+        }
+        assert_eq!(parse_duration("1w").unwrap(), Duration::from_secs(7 * DAY));
+        for s in ["", "d", "30", "30x", "-1d", "1.5d", "30 d"] {
+            assert!(parse_duration(s).is_err(), "{s}");
+        }
+    }
+}
@@ -4,7 +4,7 @@ use super::ImageReference;
 use anyhow::{Context, Result};
 use serde::Deserialize;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use tokio::process::Command;
 
@@ -38,16 +38,41 @@ impl ContainerPolicy {
     }
 }
 
-pub(crate) fn container_policy_is_default_insecure() -> Result<bool> {
-    let r = std::io::BufReader::new(std::fs::File::open(POLICY_PATH)?);
+/// Check whether the default of the policy at `path`, or the system policy if unset,
+/// is to accept any image.
+pub(crate) fn container_policy_is_default_insecure(path: Option<&Path>) -> Result<bool> {
+    let path = path.unwrap_or(Path::new(POLICY_PATH));
+    let r = std::io::BufReader::new(
+        std::fs::File::open(path).with_context(|| format!("Opening {}", path.display()))?,
+    );
     let policy: ContainerPolicy = serde_json::from_reader(r)?;
     Ok(policy.is_default_insecure())
 }
 
+/// Find the policy passed to a skopeo command via `--policy`, if any.
+pub(crate) fn policy_path_of(cmd: &std::process::Command) -> Option<PathBuf> {
+    let mut args = cmd.get_args();
+    while let Some(arg) = args.next() {
+        if arg == "--policy" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(v) = arg.to_str().and_then(|a| a.strip_prefix("--policy=")) {
+            return Some(v.into());
+        }
+    }
+    None
+}
+
+/// Create a synchronous Command builder for skopeo.
+pub(crate) fn new_std_cmd() -> std::process::Command {
+    let mut cmd = std::process::Command::new("skopeo");
+    cmd.stdin(Stdio::null());
+    cmd
+}
+
 /// Create a Command builder for skopeo.
 pub(crate) fn new_cmd() -> tokio::process::Command {
-    let mut cmd = Command::new("skopeo");
-    cmd.stdin(Stdio::null());
+    let mut cmd = Command::from(new_std_cmd());
     cmd.kill_on_drop(true);
     cmd
 }
@@ -58,15 +83,20 @@ pub(crate) fn spawn(mut cmd: Command) -> Result<tokio::process::Child> {
     cmd.spawn().context("Failed to exec skopeo")
 }
 
-/// Use skopeo to copy a container image.
+/// Use skopeo to copy a container image.  If `all` is set, all images referenced
+/// by an image index are copied, not just the one for the host platform.
 pub(crate) async fn copy(
     src: &ImageReference,
     dest: &ImageReference,
     authfile: Option<&Path>,
+    all: bool,
 ) -> Result<String> {
     let digestfile = tempfile::NamedTempFile::new()?;
     let mut cmd = new_cmd();
     cmd.stdout(std::process::Stdio::null()).arg("copy");
+    if all {
+        cmd.arg("--all");
+    }
     cmd.arg("--digestfile");
     cmd.arg(digestfile.path());
     if let Some(authfile) = authfile {
@@ -86,6 +116,46 @@ pub(crate) async fn copy(
     Ok(r.trim().to_string())
 }
 
+/// Synchronously use skopeo to copy a single container image, for use
+/// outside of an async context.
+pub(crate) fn copy_blocking(
+    src: &ImageReference,
+    dest: &ImageReference,
+    authfile: Option<&Path>,
+) -> Result<()> {
+    let mut cmd = new_std_cmd();
+    cmd.stdout(Stdio::null()).arg("copy");
+    if let Some(authfile) = authfile {
+        cmd.arg("--authfile");
+        cmd.arg(authfile);
+    }
+    cmd.args(&[src.to_string(), dest.to_string()]);
+    let output = cmd.output().context("Failed to exec skopeo")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("skopeo failed: {}\n", stderr));
+    }
+    Ok(())
+}
+
+/// Use skopeo to delete a container image.
+pub(crate) async fn delete(imgref: &ImageReference, authfile: Option<&Path>) -> Result<()> {
+    let mut cmd = new_cmd();
+    cmd.stdout(std::process::Stdio::null()).arg("delete");
+    if let Some(authfile) = authfile {
+        cmd.arg("--authfile");
+        cmd.arg(authfile);
+    }
+    cmd.arg(imgref.to_string());
+    let proc = super::skopeo::spawn(cmd)?;
+    let output = proc.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("skopeo failed: {}\n", stderr));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,4 +208,21 @@ mod tests {
             assert!(!p.is_default_insecure());
         }
     }
+
+    #[test]
+    fn test_policy_path_of() {
+        let mut cmd = std::process::Command::new("skopeo");
+        assert_eq!(policy_path_of(&cmd), None);
+        cmd.args(["--override-arch", "arm64", "--policy", "/etc/foo.json"]);
+        assert_eq!(policy_path_of(&cmd).unwrap(), Path::new("/etc/foo.json"));
+        let mut cmd = std::process::Command::new("setpriv");
+        cmd.args([
+            "--pdeathsig",
+            "TERM",
+            "--",
+            "skopeo",
+            "--policy=/etc/bar.json",
+        ]);
+        assert_eq!(policy_path_of(&cmd).unwrap(), Path::new("/etc/bar.json"));
+    }
 }
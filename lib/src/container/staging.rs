@@ -0,0 +1,314 @@
+//! On-disk staging of layer blobs, allowing interrupted fetches to be resumed.
+//!
+//! When enabled via [`super::store::ImageImporter::enable_blob_staging`], each layer
+//! blob is first written in full to `tmp/ostree-ext-blobs` in the repository,
+//! and only imported once its digest has been verified.  While a blob is being
+//! fetched it is periodically synced to disk, and the synced length is recorded
+//! alongside it; a later fetch of the same blob (e.g. after a reboot or a network
+//! failure) continues from that offset.  Staged blobs are removed once imported.
+//!
+//! Resuming requires reading the blob from an offset, which is only supported for
+//! images read in process (see [`super::store::ImageImporter::new_in_process`]).
+//! The containers-image-proxy does not support ranged fetches, so resuming is not
+//! implemented for other sources, including all registries: no checkpoints are
+//! written, a partially fetched blob is discarded and fetched again from the start,
+//! and only a blob that was completely staged is not fetched again.
+//!
+//! The partial blob is locked while it is fetched, so concurrent fetches of the same
+//! blob (in this or another process) wait for each other rather than both writing it.
+
+use super::imgsource::ImageSource;
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::{Dir, OpenOptions};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use futures_util::future::Either;
+use rustix::fs::MetadataExt;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, SeekFrom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The directory in the repository holding staged blobs.
+pub(crate) const STAGING_DIR: &str = "tmp/ostree-ext-blobs";
+/// The suffix of a partially fetched blob.
+const PARTIAL_SUFFIX: &str = ".partial";
+/// The suffix of the bookkeeping file for a partially fetched blob.
+const CHECKPOINT_SUFFIX: &str = ".checkpoint";
+/// How much data is fetched between syncing a partial blob to disk.
+const CHECKPOINT_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// Bookkeeping for a partially fetched blob.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// The length of the partial blob which has been synced to disk.
+    offset: u64,
+}
+
+/// A directory of staged blobs.
+#[derive(Debug)]
+pub(crate) struct BlobStaging {
+    dir: Dir,
+}
+
+/// Return the file name used to stage a blob.
+fn staged_name(digest: &str) -> Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(v) if v.len() == 64 && v.bytes().all(|c| c.is_ascii_hexdigit()) => Ok(v),
+        _ => anyhow::bail!("Unsupported digest: {digest}"),
+    }
+}
+
+/// Hash the first `len` bytes of the file, leaving it positioned after them.
+fn hash_prefix(mut f: std::fs::File, len: u64) -> Result<(std::fs::File, openssl::sha::Sha256)> {
+    let mut hasher = openssl::sha::Sha256::new();
+    f.seek(SeekFrom::Start(0))?;
+    let mut r = (&mut f).take(len);
+    let mut buf = vec![0u8; 128 * 1024];
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok((f, hasher))
+}
+
+impl BlobStaging {
+    /// Open (creating if necessary) the staging directory of the repository.
+    #[context("Opening blob staging directory")]
+    pub(crate) fn open(repo: &ostree::Repo) -> Result<Self> {
+        let repodir = Dir::reopen_dir(&repo.dfd_borrow())?;
+        repodir.create_dir_all(STAGING_DIR)?;
+        let dir = repodir.open_dir(STAGING_DIR)?;
+        Ok(Self { dir })
+    }
+
+    /// Read the bookkeeping for a partial blob; any invalid state means starting over.
+    fn read_checkpoint(&self, name: &str) -> Result<Checkpoint> {
+        let path = format!("{name}{CHECKPOINT_SUFFIX}");
+        let f = match self.dir.open_optional(&path)? {
+            Some(f) => f,
+            None => return Ok(Checkpoint::default()),
+        };
+        match serde_json::from_reader(std::io::BufReader::new(f)) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                tracing::warn!("Ignoring invalid {path}: {e}");
+                Ok(Checkpoint::default())
+            }
+        }
+    }
+
+    /// Record the synced length of a partial blob, in a worker thread.
+    async fn write_checkpoint(&self, name: &str, offset: u64) -> Result<()> {
+        let path = format!("{name}{CHECKPOINT_SUFFIX}");
+        let buf = serde_json::to_vec(&Checkpoint { offset })?;
+        let dir = self.dir.try_clone()?;
+        tokio::task::spawn_blocking(move || dir.atomic_write(path, buf)).await??;
+        Ok(())
+    }
+
+    /// Open and lock the partial blob, waiting for any concurrent fetch of it to finish.
+    /// Returns `None` if the blob has been completely staged in the meantime.
+    fn lock_partial(&self, name: &str, size: u64) -> Result<Option<std::fs::File>> {
+        let partial = format!("{name}{PARTIAL_SUFFIX}");
+        loop {
+            if let Some(meta) = self.dir.metadata_optional(name)? {
+                if meta.len() == size {
+                    return Ok(None);
+                }
+            }
+            let f = self
+                .dir
+                .open_with(
+                    &partial,
+                    OpenOptions::new().read(true).write(true).create(true),
+                )?
+                .into_std();
+            rustix::fs::flock(&f, rustix::fs::FlockOperation::LockExclusive)?;
+            // The previous holder of the lock may have renamed or removed the file
+            let meta = f.metadata()?;
+            match self.dir.metadata_optional(&partial)? {
+                Some(m) if m.dev() == meta.dev() && m.ino() == meta.ino() => return Ok(Some(f)),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Remove any state for a blob.
+    fn remove_all(&self, name: &str) -> Result<()> {
+        for path in [
+            name.to_string(),
+            format!("{name}{PARTIAL_SUFFIX}"),
+            format!("{name}{CHECKPOINT_SUFFIX}"),
+        ] {
+            self.dir.remove_file_optional(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Ensure the blob is completely staged, fetching any missing content, and
+    /// open it for reading.  `progress` is invoked with the number of bytes staged.
+    pub(crate) async fn fetch(
+        &self,
+        source: &ImageSource,
+        digest: &str,
+        size: u64,
+        mut progress: impl FnMut(u64),
+    ) -> Result<std::fs::File> {
+        let name = staged_name(digest)?;
+        if let Some(f) = self.dir.open_optional(name)? {
+            if f.metadata()?.len() == size {
+                tracing::debug!("Using staged blob {digest}");
+                progress(size);
+                return Ok(f.into_std());
+            }
+            self.dir.remove_file(name)?;
+        }
+        let this = Self {
+            dir: self.dir.try_clone()?,
+        };
+        let lockname = name.to_string();
+        let f = tokio::task::spawn_blocking(move || this.lock_partial(&lockname, size)).await??;
+        let f = match f {
+            Some(f) => f,
+            None => {
+                tracing::debug!("Using blob {digest} staged concurrently");
+                progress(size);
+                return Ok(self.dir.open(name)?.into_std());
+            }
+        };
+        let partial = format!("{name}{PARTIAL_SUFFIX}");
+        let resumable = source.supports_ranged_fetch();
+        let mut offset = 0;
+        if resumable {
+            offset = self.read_checkpoint(name)?.offset;
+            if offset > size {
+                offset = 0;
+            }
+        } else {
+            self.dir
+                .remove_file_optional(format!("{name}{CHECKPOINT_SUFFIX}"))?;
+        }
+        // Discard anything written after the last checkpoint, which may not have been synced.
+        f.set_len(offset)?;
+        let (f, mut hasher) = tokio::task::spawn_blocking(move || hash_prefix(f, offset)).await??;
+        let (mut blob, driver) = if offset > 0 {
+            tracing::debug!("Resuming fetch of {digest} at offset {offset}");
+            let blob = source.get_blob_at(digest, size, offset)?;
+            (blob, Either::Left(futures_util::future::ok(())))
+        } else {
+            let (blob, driver) = source.get_blob(digest, size).await?;
+            (blob, Either::Right(driver))
+        };
+        progress(offset);
+
+        let mut f = tokio::fs::File::from_std(f);
+        let fetch = async {
+            let mut buf = vec![0u8; 128 * 1024];
+            let mut pos = offset;
+            let mut synced = offset;
+            loop {
+                let n = blob.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                let chunk = &buf[..n];
+                f.write_all(chunk).await?;
+                hasher.update(chunk);
+                pos += chunk.len() as u64;
+                progress(pos);
+                if resumable && pos - synced >= CHECKPOINT_INTERVAL {
+                    f.sync_data().await?;
+                    self.write_checkpoint(name, pos).await?;
+                    synced = pos;
+                }
+            }
+            drop(blob);
+            f.sync_all().await?;
+            Ok::<_, anyhow::Error>(pos)
+        };
+        let pos = super::unencapsulate::join_fetch(fetch, driver)
+            .await
+            .with_context(|| format!("Fetching {digest}"))?;
+        if pos != size {
+            self.remove_all(name)?;
+            anyhow::bail!("Blob {digest} has size {pos}, expected {size}");
+        }
+        let actual = hex::encode(hasher.finish());
+        if actual != name {
+            self.remove_all(name)?;
+            anyhow::bail!("Blob digest mismatch: expected {digest}, found sha256:{actual}");
+        }
+        // The lock on the partial blob is held until after it is renamed.
+        self.dir.rename(&partial, &self.dir, name)?;
+        self.dir
+            .remove_file_optional(format!("{name}{CHECKPOINT_SUFFIX}"))?;
+        let r = self.dir.open(name)?.into_std();
+        drop(f);
+        Ok(r)
+    }
+
+    /// Remove a staged blob once it has been imported.
+    pub(crate) fn remove(&self, digest: &str) -> Result<()> {
+        self.remove_all(staged_name(digest)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staged_name() {
+        let v = "a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7";
+        assert_eq!(staged_name(&format!("sha256:{v}")).unwrap(), v);
+        for bad in ["", v, "sha256:", "sha256:../foo", "sha512:abcd"] {
+            assert!(staged_name(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn test_hash_prefix() -> Result<()> {
+        let mut f = tempfile::tempfile()?;
+        std::io::Write::write_all(&mut f, b"hello world")?;
+        let (mut f, hasher) = hash_prefix(f, 5)?;
+        assert_eq!(hasher.finish(), openssl::sha::sha256(b"hello"));
+        assert_eq!(f.stream_position()?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_partial() -> Result<()> {
+        use std::io::Write;
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std_ext::cap_std::ambient_authority())?;
+        let staging = BlobStaging {
+            dir: td.try_clone()?,
+        };
+        let name = "a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7";
+        let mut f = staging.lock_partial(name, 5)?.unwrap();
+        // A concurrent fetch waits, and then finds the completed blob
+        let other = BlobStaging {
+            dir: td.try_clone()?,
+        };
+        let waiter = std::thread::spawn(move || other.lock_partial(name, 5).map(|f| f.is_none()));
+        f.write_all(b"hello")?;
+        staging
+            .dir
+            .rename(format!("{name}{PARTIAL_SUFFIX}"), &staging.dir, name)?;
+        drop(f);
+        assert!(waiter.join().unwrap()?);
+        // If the partial blob was discarded, the waiter starts over
+        staging.dir.remove_file(name)?;
+        let f = staging.lock_partial(name, 5)?.unwrap();
+        let other = BlobStaging {
+            dir: td.try_clone()?,
+        };
+        let waiter = std::thread::spawn(move || other.lock_partial(name, 5).map(|f| f.is_some()));
+        staging.remove_all(name)?;
+        drop(f);
+        assert!(waiter.join().unwrap()?);
+        Ok(())
+    }
+}
@@ -5,6 +5,8 @@
 //! This code supports ingesting arbitrary layered container images from an ostree-exported
 //! base.  See [`encapsulate`][`super::encapsulate()`] for more information on encaspulation of images.
 
+use super::imgsource::ImageSource;
+use super::staging::BlobStaging;
 use super::*;
 use crate::logging::system_repo_journal_print;
 use crate::refescape;
@@ -13,15 +15,18 @@ use crate::utils::ResultExt;
 use anyhow::{anyhow, Context};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
-use containers_image_proxy::{ImageProxy, OpenedImage};
 use fn_error_context::context;
-use futures_util::TryFutureExt;
+use futures_util::{StreamExt, TryFutureExt, TryStreamExt};
 use oci_spec::image::{self as oci_image, Descriptor, History, ImageConfiguration, ImageManifest};
 use ostree::prelude::{Cast, FileEnumeratorExt, FileExt, ToVariant};
 use ostree::{gio, glib};
 use rustix::fs::MetadataExt;
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet, HashMap};
 use std::iter::FromIterator;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::{Receiver, Sender};
 
 /// Configuration for the proxy.
@@ -34,6 +39,9 @@ pub use containers_image_proxy::ImageProxyConfig;
 const LAYER_PREFIX: &str = "ostree/container/blob";
 /// The ostree ref prefix for image references.
 const IMAGE_PREFIX: &str = "ostree/container/image";
+/// The ostree ref prefix for pinned images; these refer to the merge commit of the
+/// image at the time it was pinned.
+const PINNED_PREFIX: &str = "ostree/container/pinned";
 /// The ostree ref prefix for "base" image references that are used by derived images.
 /// If you maintain tooling which is locally building derived commits, write a ref
 /// with this prefix that is owned by your code.  It's a best practice to prefix the
@@ -51,6 +59,9 @@ pub const META_FILTERED: &str = "ostree.tar-filtered";
 /// The type used to store content filtering information with `META_FILTERED`.
 pub type MetaFilteredData = HashMap<String, HashMap<String, u32>>;
 
+/// The compression ratio assumed for layers which do not record their uncompressed size.
+const ESTIMATED_COMPRESSION_RATIO: u64 = 3;
+
 /// The ref prefixes which point to ostree deployments.  (TODO: Add an official API for this)
 const OSTREE_BASE_DEPLOYMENT_REFS: &[&str] = &["ostree/0", "ostree/1"];
 /// A layering violation we'll carry for a bit to band-aid over https://github.com/coreos/rpm-ostree/issues/4185
@@ -71,6 +82,11 @@ fn ref_for_image(l: &ImageReference) -> Result<String> {
     refescape::prefix_escape_for_ref(IMAGE_PREFIX, &l.to_string())
 }
 
+/// Convert an image reference into its pin ref, e.g. `/ostree/container/pinned/registry_3A...`.
+fn ref_for_pin(l: &ImageReference) -> Result<String> {
+    refescape::prefix_escape_for_ref(PINNED_PREFIX, &l.to_string())
+}
+
 /// Sent across a channel to track start and end of a container fetch.
 #[derive(Debug)]
 pub enum ImportProgress {
@@ -97,7 +113,7 @@ impl ImportProgress {
 }
 
 /// Sent across a channel to track the byte-level progress of a layer fetch.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LayerProgress {
     /// Index of the layer in the manifest
     pub layer_index: usize,
@@ -107,6 +123,222 @@ pub struct LayerProgress {
     pub total: u64,
 }
 
+/// A phase of importing an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportPhase {
+    /// Fetching layers.
+    Fetch,
+    /// Checking out the layers to merge them.
+    MergeCheckout,
+    /// Writing the merged content as a commit.
+    Commit,
+    /// Pruning unused layers.
+    Gc,
+    /// Deploying the merged commit.
+    Deploy,
+}
+
+/// A serializable progress event covering an entire image import, suitable for
+/// e.g. writing as JSON Lines.  Retrieve these via [`ImageImporter::request_progress_events`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum ProgressEvent {
+    /// The fetch of an image is starting.
+    Start {
+        /// The image being fetched
+        image: String,
+        /// The digest of the image manifest
+        manifest_digest: String,
+        /// The total number of layers in the image
+        layers_total: usize,
+        /// The number of layers which need to be fetched
+        layers_to_fetch: usize,
+        /// The total size of the layers which need to be fetched
+        bytes_to_fetch: u64,
+    },
+    /// A new phase of the import started.
+    Phase {
+        /// The phase
+        phase: ImportPhase,
+    },
+    /// Started fetching a layer.
+    LayerStarted {
+        /// Index of the layer in the manifest
+        layer_index: usize,
+        /// Digest of the layer
+        digest: String,
+        /// Size of the layer
+        size: u64,
+    },
+    /// Byte-level progress of fetching a layer.  These events are dropped rather than
+    /// queued if the receiver is not keeping up.
+    LayerBytes {
+        /// Index of the layer in the manifest
+        layer_index: usize,
+        /// Number of bytes of the layer fetched
+        fetched: u64,
+        /// Size of the layer
+        total: u64,
+        /// Number of bytes fetched over all layers
+        bytes_fetched: u64,
+        /// The total size of the layers which need to be fetched
+        bytes_to_fetch: u64,
+    },
+    /// Completed fetching a layer.
+    LayerCompleted {
+        /// Index of the layer in the manifest
+        layer_index: usize,
+        /// Digest of the layer
+        digest: String,
+        /// The number of layers fetched so far
+        layers_fetched: usize,
+        /// The number of layers which need to be fetched
+        layers_to_fetch: usize,
+    },
+    /// The image is stored as the given merge commit.
+    Imported {
+        /// The merge commit
+        merge_commit: String,
+    },
+    /// The image was deployed.
+    Deployed {
+        /// The stateroot of the deployment
+        stateroot: String,
+        /// The deployed commit
+        commit: String,
+    },
+}
+
+/// Running totals used to fill in [`ProgressEvent`]s.
+#[derive(Debug, Default)]
+struct ProgressTotals {
+    layers_to_fetch: usize,
+    layers_fetched: usize,
+    bytes_to_fetch: u64,
+    /// The size of the completely fetched layers
+    bytes_completed: u64,
+    /// Bytes fetched of layers in progress, by index
+    in_progress: HashMap<usize, u64>,
+}
+
+/// Sends [`ProgressEvent`]s; cloned to be shared between concurrent layer fetches.
+#[derive(Debug, Clone)]
+pub(crate) struct ProgressSender {
+    sender: Sender<ProgressEvent>,
+    totals: Arc<Mutex<ProgressTotals>>,
+}
+
+impl ProgressSender {
+    pub(crate) fn new(sender: Sender<ProgressEvent>) -> Self {
+        Self {
+            sender,
+            totals: Default::default(),
+        }
+    }
+
+    /// Send an event.  It's OK if the caller is no longer listening.
+    pub(crate) async fn send(&self, event: ProgressEvent) {
+        let _ = self.sender.send(event).await;
+    }
+
+    /// Send an event from a synchronous context outside of the async runtime.
+    pub(crate) fn blocking_send(&self, event: ProgressEvent) {
+        let _ = self.sender.blocking_send(event);
+    }
+
+    /// Record the layers to fetch, and send the start event.
+    pub(crate) async fn start(&self, import: &PreparedImport, image: &OstreeImageReference) {
+        let (layers_to_fetch, bytes_to_fetch) = import
+            .all_layers()
+            .filter(|l| l.commit.is_none())
+            .fold((0, 0), |(n, sz), l| (n + 1, sz + l.size()));
+        *self.totals.lock().unwrap() = ProgressTotals {
+            layers_to_fetch,
+            bytes_to_fetch,
+            ..Default::default()
+        };
+        self.send(ProgressEvent::Start {
+            image: image.to_string(),
+            manifest_digest: import.manifest_digest.clone(),
+            layers_total: import.manifest.layers().len(),
+            layers_to_fetch,
+            bytes_to_fetch,
+        })
+        .await;
+        self.send(ProgressEvent::Phase {
+            phase: ImportPhase::Fetch,
+        })
+        .await;
+    }
+
+    pub(crate) async fn layer_started(
+        &self,
+        manifest: &ImageManifest,
+        layer: &Descriptor,
+    ) -> Result<()> {
+        self.send(ProgressEvent::LayerStarted {
+            layer_index: layer_index(manifest, layer)?,
+            digest: layer.digest().to_string(),
+            size: layer.size() as u64,
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Update the byte-level progress of a layer.  This never blocks; if the receiver
+    /// is not keeping up, the event is dropped.
+    pub(crate) fn layer_bytes(&self, progress: &LayerProgress) {
+        let event = {
+            let mut totals = self.totals.lock().unwrap();
+            totals
+                .in_progress
+                .insert(progress.layer_index, progress.fetched);
+            ProgressEvent::LayerBytes {
+                layer_index: progress.layer_index,
+                fetched: progress.fetched,
+                total: progress.total,
+                bytes_fetched: totals.bytes_completed + totals.in_progress.values().sum::<u64>(),
+                bytes_to_fetch: totals.bytes_to_fetch,
+            }
+        };
+        let _ = self.sender.try_send(event);
+    }
+
+    pub(crate) async fn layer_completed(
+        &self,
+        manifest: &ImageManifest,
+        layer: &Descriptor,
+    ) -> Result<()> {
+        let layer_index = layer_index(manifest, layer)?;
+        let (layers_fetched, layers_to_fetch) = {
+            let mut totals = self.totals.lock().unwrap();
+            totals.in_progress.remove(&layer_index);
+            totals.bytes_completed += layer.size() as u64;
+            totals.layers_fetched += 1;
+            (totals.layers_fetched, totals.layers_to_fetch)
+        };
+        self.send(ProgressEvent::LayerCompleted {
+            layer_index,
+            digest: layer.digest().to_string(),
+            layers_fetched,
+            layers_to_fetch,
+        })
+        .await;
+        Ok(())
+    }
+}
+
+/// Return the index of a layer in the manifest.
+fn layer_index(manifest: &ImageManifest, layer: &Descriptor) -> Result<usize> {
+    manifest
+        .layers()
+        .iter()
+        .position(|l| l == layer)
+        .ok_or_else(|| anyhow!("Layer {} not found in manifest", layer.digest()))
+}
+
 /// State of an already pulled layered image.
 #[derive(Debug, PartialEq, Eq)]
 pub struct LayeredImageState {
@@ -158,9 +390,16 @@ pub struct CachedImageUpdate {
     pub config: ImageConfiguration,
     /// The digest of the manifest
     pub manifest_digest: String,
+    layers_fetched: bool,
 }
 
 impl CachedImageUpdate {
+    /// Whether all layers of the update have been fetched via [`ImageImporter::fetch_layers`],
+    /// so that it can be completed offline via [`finalize_cached_update`].
+    pub fn layers_fetched(&self) -> bool {
+        self.layers_fetched
+    }
+
     /// Retrieve the container image version.
     pub fn version(&self) -> Option<&str> {
         super::version_for_config(&self.config)
@@ -171,17 +410,29 @@ impl CachedImageUpdate {
 #[derive(Debug)]
 pub struct ImageImporter {
     repo: ostree::Repo,
-    pub(crate) proxy: ImageProxy,
+    pub(crate) source: ImageSource,
     imgref: OstreeImageReference,
     target_imgref: Option<OstreeImageReference>,
     no_imgref: bool,  // If true, do not write final image ref
     disable_gc: bool, // If true, don't prune unused image layers
     /// If true, require the image has the bootable flag
     require_bootable: bool,
-    pub(crate) proxy_img: OpenedImage,
+    /// If true, check that the repository has space for the image in prepare()
+    require_free_space: bool,
+    /// If set, the platform to select from an image index instead of the host's
+    platform: Option<oci_image::Platform>,
+    /// The `containers-policy.json` used by the proxy, if not the system default
+    policy_path: Option<PathBuf>,
+    /// The number of layers to fetch concurrently
+    jobs: NonZeroUsize,
+    /// If set, layer blobs are staged on disk before being imported
+    staging: Option<BlobStaging>,
+    /// How to handle content outside of `/usr` and `/etc` in derived layers
+    content_policy: crate::tar::ContentPolicy,
 
     layer_progress: Option<Sender<ImportProgress>>,
     layer_byte_progress: Option<tokio::sync::watch::Sender<Option<LayerProgress>>>,
+    progress_events: Option<ProgressSender>,
 }
 
 /// Result of invoking [`ImageImporter::prepare`].
@@ -213,6 +464,41 @@ impl ManifestLayerState {
     pub fn size(&self) -> u64 {
         self.layer.size() as u64
     }
+
+    /// The size of the uncompressed layer content, if recorded in the layer annotations.
+    pub fn uncompressed_size(&self) -> Option<u64> {
+        self.layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(UNCOMPRESSED_SIZE_ANNOTATION))
+            .and_then(|v| v.parse().ok())
+    }
+
+    /// Guess the size of the uncompressed layer content from the compressed size.
+    fn guessed_uncompressed_size(&self) -> u64 {
+        match self.layer.media_type() {
+            oci_image::MediaType::ImageLayer | oci_image::MediaType::ImageLayerNonDistributable => {
+                self.size()
+            }
+            _ => self.size().saturating_mul(ESTIMATED_COMPRESSION_RATIO),
+        }
+    }
+}
+
+/// An estimate of the disk space required to import an image, as returned
+/// by [`PreparedImport::space_estimate`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceEstimate {
+    /// The total (compressed) size of the layers to fetch.
+    pub fetch_size: u64,
+    /// The estimated size of the uncompressed content of the layers to fetch.  Content
+    /// which is already present in the repository is not stored again, so this is
+    /// generally an upper bound.
+    pub content_size: u64,
+    /// The number of layers whose uncompressed size is recorded neither in the layer
+    /// annotations nor in the image history, and was instead estimated from their
+    /// compressed size.
+    pub estimated_layers: u32,
 }
 
 /// Information about which layers need to be downloaded.
@@ -282,6 +568,41 @@ impl PreparedImport {
         })
     }
 
+    /// Estimate the disk space required to import the layers which are not present.
+    pub fn space_estimate(&self) -> SpaceEstimate {
+        // Layer annotations are lost when an image is converted to the Docker schema,
+        // but the history in the image configuration is preserved.
+        let history = self
+            .config
+            .history()
+            .iter()
+            .filter(|h| !h.empty_layer().unwrap_or_default());
+        let history_sizes: HashMap<&str, u64> = self
+            .manifest
+            .layers()
+            .iter()
+            .zip(history)
+            .filter_map(|(l, h)| Some((l.digest().as_str(), super::history_uncompressed_size(h)?)))
+            .collect();
+        self.all_layers().filter(|l| l.commit.is_none()).fold(
+            SpaceEstimate::default(),
+            |mut e, l| {
+                e.fetch_size += l.size();
+                let recorded = l
+                    .uncompressed_size()
+                    .or_else(|| history_sizes.get(l.digest()).copied());
+                e.content_size += match recorded {
+                    Some(size) => size,
+                    None => {
+                        e.estimated_layers += 1;
+                        l.guessed_uncompressed_size()
+                    }
+                };
+                e
+            },
+        )
+    }
+
     /// Common helper to format a string for the status
     pub(crate) fn format_layer_status(&self) -> Option<String> {
         let (stored, to_fetch, to_fetch_size) =
@@ -295,7 +616,8 @@ impl PreparedImport {
                 });
         (to_fetch > 0).then(|| {
             let size = crate::glib::format_size(to_fetch_size);
-            format!("layers already present: {stored}; layers needed: {to_fetch} ({size})")
+            let content_size = crate::glib::format_size(self.space_estimate().content_size);
+            format!("layers already present: {stored}; layers needed: {to_fetch} ({size}, ~{content_size} uncompressed)")
         })
     }
 }
@@ -441,7 +763,7 @@ pub(crate) fn parse_manifest_layout<'a>(
 }
 
 /// Find the timestamp of the manifest (or config), ignoring errors.
-fn timestamp_of_manifest_or_config(
+pub(crate) fn timestamp_of_manifest_or_config(
     manifest: &ImageManifest,
     config: &ImageConfiguration,
 ) -> Option<u64> {
@@ -468,43 +790,87 @@ impl ImageImporter {
     const CACHED_KEY_MANIFEST_DIGEST: &str = "ostree-ext.cached.manifest-digest";
     const CACHED_KEY_MANIFEST: &str = "ostree-ext.cached.manifest";
     const CACHED_KEY_CONFIG: &str = "ostree-ext.cached.config";
+    /// The metadata key for the content filtered out of the derived layers of a cached
+    /// update; this is only present once all layers have been fetched.
+    const CACHED_KEY_FILTERED: &str = "ostree-ext.cached.filtered";
 
     /// Create a new importer.
-    #[context("Creating importer")]
     pub async fn new(
         repo: &ostree::Repo,
         imgref: &OstreeImageReference,
-        mut config: ImageProxyConfig,
+        config: ImageProxyConfig,
     ) -> Result<Self> {
-        if imgref.imgref.transport == Transport::ContainerStorage {
-            // Fetching from containers-storage, may require privileges to read files
-            merge_default_container_proxy_opts_with_isolation(&mut config, None)?;
-        } else {
-            // Apply our defaults to the proxy config
-            merge_default_container_proxy_opts(&mut config)?;
-        }
-        let proxy = ImageProxy::new_with_config(config).await?;
+        Self::new_with_platform(repo, imgref, config, None).await
+    }
+
+    /// Create a new importer which, if the image is an image index, selects the
+    /// image for the provided platform instead of the one matching the host.
+    #[context("Creating importer")]
+    pub async fn new_with_platform(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        config: ImageProxyConfig,
+        platform: Option<&oci_image::Platform>,
+    ) -> Result<Self> {
+        let policy_path = config.skopeo_cmd.as_ref().and_then(skopeo::policy_path_of);
+        let source = ImageSource::open(imgref, config, platform).await?;
+        Ok(Self::new_with_source(
+            repo,
+            imgref,
+            source,
+            platform,
+            policy_path,
+        ))
+    }
 
+    /// Create a new importer which reads an image in a local OCI directory (`oci:`)
+    /// or archive (`oci-archive:`) in process, rather than via the containers-image-proxy;
+    /// this does not require skopeo to be installed.  The digest of every blob is verified.
+    ///
+    /// This returns an error for other transports, and for images which require
+    /// signature verification via `containers-policy.json`.
+    #[context("Creating in-process importer")]
+    pub async fn new_in_process(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        platform: Option<&oci_image::Platform>,
+    ) -> Result<Self> {
+        let source = ImageSource::open_in_process(imgref, platform).await?;
+        Ok(Self::new_with_source(repo, imgref, source, platform, None))
+    }
+
+    fn new_with_source(
+        repo: &ostree::Repo,
+        imgref: &OstreeImageReference,
+        source: ImageSource,
+        platform: Option<&oci_image::Platform>,
+        policy_path: Option<PathBuf>,
+    ) -> Self {
         system_repo_journal_print(
             repo,
             libsystemd::logging::Priority::Info,
             &format!("Fetching {}", imgref),
         );
 
-        let proxy_img = proxy.open_image(&imgref.imgref.to_string()).await?;
         let repo = repo.clone();
-        Ok(ImageImporter {
+        ImageImporter {
             repo,
-            proxy,
-            proxy_img,
+            source,
             target_imgref: None,
             no_imgref: false,
             disable_gc: false,
             require_bootable: false,
+            require_free_space: false,
+            content_policy: Default::default(),
+            platform: platform.cloned(),
+            policy_path,
+            jobs: NonZeroUsize::MIN,
+            staging: None,
             imgref: imgref.clone(),
             layer_progress: None,
             layer_byte_progress: None,
-        })
+            progress_events: None,
+        }
     }
 
     /// Write cached data as if the image came from this source.
@@ -524,11 +890,49 @@ impl ImageImporter {
         self.require_bootable = true;
     }
 
+    /// Make [`Self::prepare`] return an error if the filesystem holding the repository
+    /// does not have enough free space for the layers to be fetched, according to
+    /// [`PreparedImport::space_estimate`].
+    pub fn require_free_space(&mut self) {
+        self.require_free_space = true;
+    }
+
+    /// Set how content outside of `/usr` and `/etc` in derived (non-ostree) layers is
+    /// handled.  The default is [`crate::tar::ContentPolicy::Warn`].
+    pub fn set_content_policy(&mut self, policy: crate::tar::ContentPolicy) {
+        self.content_policy = policy;
+    }
+
     /// Do not prune image layers.
     pub fn disable_gc(&mut self) {
         self.disable_gc = true;
     }
 
+    /// Set the number of layers to fetch and import concurrently; the default is 1.
+    /// The resulting image is the same regardless of this value.
+    pub fn set_jobs(&mut self, jobs: NonZeroUsize) {
+        self.jobs = jobs;
+    }
+
+    /// Fetch each layer blob in full to a staging directory in the repository before
+    /// importing it.  If the fetch is interrupted, a later import of an image with
+    /// the same layer resumes it from the last point synced to disk, rather than
+    /// starting over.  Resuming is only supported for importers created with
+    /// [`Self::new_in_process`]; via the containers-image-proxy, an interrupted fetch
+    /// starts over, and only completely staged blobs are reused.
+    pub fn enable_blob_staging(&mut self) -> Result<()> {
+        self.staging = Some(BlobStaging::open(&self.repo)?);
+        Ok(())
+    }
+
+    /// Remove the staged copy of a layer blob once it has been imported.
+    fn remove_staged(&self, layer: &Descriptor) -> Result<()> {
+        match self.staging.as_ref() {
+            Some(staging) => staging.remove(layer.digest()),
+            None => Ok(()),
+        }
+    }
+
     /// Determine if there is a new manifest, and if so return its digest.
     /// This will also serialize the new manifest and configuration into
     /// metadata associated with the image, so that invocations of `[query_cached]`
@@ -556,8 +960,71 @@ impl ImageImporter {
         r
     }
 
+    /// Create a channel receiver that will get [`ProgressEvent`]s for all phases of the import.
+    pub fn request_progress_events(&mut self) -> Receiver<ProgressEvent> {
+        let (s, r) = tokio::sync::mpsc::channel(32);
+        self.set_progress_events(s);
+        r
+    }
+
+    /// Send [`ProgressEvent`]s for all phases of the import to the provided channel.
+    pub fn set_progress_events(&mut self, sender: Sender<ProgressEvent>) {
+        assert!(self.progress_events.is_none());
+        self.progress_events = Some(ProgressSender::new(sender));
+    }
+
+    /// Return a callback which distributes byte-level progress of layer fetches, if requested.
+    fn byte_progress(&self) -> Option<Box<dyn Fn(LayerProgress) + Send + Sync + '_>> {
+        if self.layer_byte_progress.is_none() && self.progress_events.is_none() {
+            return None;
+        }
+        let bytes = self.layer_byte_progress.as_ref();
+        let events = self.progress_events.as_ref();
+        Some(Box::new(move |p: LayerProgress| {
+            if let Some(events) = events {
+                events.layer_bytes(&p);
+            }
+            if let Some(bytes) = bytes {
+                bytes.send_replace(Some(p));
+            }
+        }))
+    }
+
+    /// Notify listeners that fetching a layer started.
+    async fn layer_started(
+        &self,
+        manifest: &ImageManifest,
+        layer: &Descriptor,
+        msg: fn(Descriptor) -> ImportProgress,
+    ) -> Result<()> {
+        if let Some(p) = self.layer_progress.as_ref() {
+            p.send(msg(layer.clone())).await?;
+        }
+        if let Some(p) = self.progress_events.as_ref() {
+            p.layer_started(manifest, layer).await?;
+        }
+        Ok(())
+    }
+
+    /// Notify listeners that fetching a layer completed.
+    async fn layer_completed(
+        &self,
+        manifest: &ImageManifest,
+        layer: &Descriptor,
+        msg: fn(Descriptor) -> ImportProgress,
+    ) -> Result<()> {
+        if let Some(p) = self.layer_progress.as_ref() {
+            p.send(msg(layer.clone())).await?;
+        }
+        if let Some(p) = self.progress_events.as_ref() {
+            p.layer_completed(manifest, layer).await?;
+        }
+        Ok(())
+    }
+
     /// Serialize the metadata about a pending fetch as detached metadata on the commit object,
-    /// so it can be retrieved later offline
+    /// so it can be retrieved later offline.  If all layers have been fetched, `filtered`
+    /// holds the content filtered out of the derived layers.
     #[context("Writing cached pending manifest")]
     pub(crate) async fn cache_pending(
         &self,
@@ -565,6 +1032,7 @@ impl ImageImporter {
         manifest_digest: &str,
         manifest: &ImageManifest,
         config: &ImageConfiguration,
+        filtered: Option<&MetaFilteredData>,
     ) -> Result<()> {
         let commitmeta = glib::VariantDict::new(None);
         commitmeta.insert(Self::CACHED_KEY_MANIFEST_DIGEST, manifest_digest);
@@ -572,6 +1040,9 @@ impl ImageImporter {
         commitmeta.insert(Self::CACHED_KEY_MANIFEST, cached_manifest);
         let cached_config = serde_json::to_string(config).context("Serializing config")?;
         commitmeta.insert(Self::CACHED_KEY_CONFIG, cached_config);
+        if let Some(filtered) = filtered {
+            commitmeta.insert_value(Self::CACHED_KEY_FILTERED, &filtered.to_variant());
+        }
         let commitmeta = commitmeta.to_variant();
         // Clone these to move into blocking method
         let commit = commit.to_string();
@@ -635,9 +1106,12 @@ impl ImageImporter {
     #[context("Fetching manifest")]
     pub(crate) async fn prepare_internal(&mut self, verify_layers: bool) -> Result<PrepareResult> {
         match &self.imgref.sigverify {
-            SignatureSource::ContainerPolicy if skopeo::container_policy_is_default_insecure()? => {
+            s if s.requires_container_policy()
+                && skopeo::container_policy_is_default_insecure(self.policy_path.as_deref())? =>
+            {
                 return Err(anyhow!("containers-policy.json specifies a default of `insecureAcceptAnything`; refusing usage"));
             }
+            // Derived layers are only covered by a container image signature
             SignatureSource::OstreeRemote(_) if verify_layers => {
                 return Err(anyhow!(
                     "Cannot currently verify layered containers via ostree remote"
@@ -646,7 +1120,7 @@ impl ImageImporter {
             _ => {}
         }
 
-        let (manifest_digest, manifest) = self.proxy.fetch_manifest(&self.proxy_img).await?;
+        let (manifest_digest, manifest) = self.source.fetch_manifest().await?;
         let new_imageid = manifest.config().digest().as_str();
 
         // Query for previous stored state
@@ -668,18 +1142,44 @@ impl ImageImporter {
                 (None, None)
             };
 
-        let config = self.proxy.fetch_config(&self.proxy_img).await?;
+        let config = self.source.fetch_config().await?;
+        if let Some(platform) = self.platform.as_ref() {
+            // The variant only needs to match if one was requested
+            let matches = config.os() == platform.os()
+                && config.architecture() == platform.architecture()
+                && (platform.variant().is_none() || config.variant() == platform.variant());
+            if !matches {
+                let fmt = |os, arch, variant: &Option<String>| match variant {
+                    Some(variant) => format!("{os}/{arch}/{variant}"),
+                    None => format!("{os}/{arch}"),
+                };
+                anyhow::bail!(
+                    "Image platform {} does not match requested {}",
+                    fmt(config.os(), config.architecture(), config.variant()),
+                    fmt(platform.os(), platform.architecture(), platform.variant())
+                );
+            }
+        }
 
         // If there is a currently fetched image, cache the new pending manifest+config
         // as detached commit metadata, so that future fetches can query it offline.
+        // If this update is already cached, keep the existing state, which may record
+        // that its layers were fetched.
         if let Some(previous_state) = previous_state.as_ref() {
-            self.cache_pending(
-                previous_state.merge_commit.as_str(),
-                manifest_digest.as_str(),
-                &manifest,
-                &config,
-            )
-            .await?;
+            let cached = previous_state
+                .cached_update
+                .as_ref()
+                .is_some_and(|c| c.manifest_digest == manifest_digest);
+            if !cached {
+                self.cache_pending(
+                    previous_state.merge_commit.as_str(),
+                    manifest_digest.as_str(),
+                    &manifest,
+                    &config,
+                    None,
+                )
+                .await?;
+            }
         }
 
         let imp = self.create_prepared_import(
@@ -689,9 +1189,38 @@ impl ImageImporter {
             previous_state,
             previous_imageid,
         )?;
+        if self.require_free_space {
+            self.check_free_space(&imp)?;
+        }
         Ok(PrepareResult::Ready(imp))
     }
 
+    /// Verify the filesystem holding the repository has enough space to import the image,
+    /// in addition to the space reserved via the repository's `min-free-space-*` options.
+    fn check_free_space(&self, import: &PreparedImport) -> Result<()> {
+        let estimate = import.space_estimate();
+        let mut required = estimate.content_size;
+        // Staged blobs are stored in addition to their imported content
+        if self.staging.is_some() {
+            required = required.saturating_add(estimate.fetch_size);
+        }
+        // Writing objects fails once only the reserved space is left
+        let reserved = self.repo.min_free_space_bytes()?;
+        required = required.saturating_add(reserved);
+        let st = rustix::fs::fstatvfs(self.repo.dfd_borrow())?;
+        let available = st.f_bavail.saturating_mul(st.f_frsize);
+        if required > available {
+            anyhow::bail!(
+                "Insufficient free space in repository: {} required for {} of layers and {} reserved, but only {} available",
+                glib::format_size(required),
+                glib::format_size(estimate.fetch_size),
+                glib::format_size(reserved),
+                glib::format_size(available),
+            );
+        }
+        Ok(())
+    }
+
     /// Extract the base ostree commit.
     #[context("Unencapsulating base")]
     pub(crate) async fn unencapsulate_base(
@@ -700,47 +1229,118 @@ impl ImageImporter {
         write_refs: bool,
     ) -> Result<()> {
         tracing::debug!("Fetching base");
-        if matches!(self.imgref.sigverify, SignatureSource::ContainerPolicy)
-            && skopeo::container_policy_is_default_insecure()?
+        if self.imgref.sigverify.requires_container_policy()
+            && skopeo::container_policy_is_default_insecure(self.policy_path.as_deref())?
         {
             return Err(anyhow!("containers-policy.json specifies a default of `insecureAcceptAnything`; refusing usage"));
         }
-        let remote = match &self.imgref.sigverify {
-            SignatureSource::OstreeRemote(remote) => Some(remote.clone()),
-            SignatureSource::ContainerPolicy | SignatureSource::ContainerPolicyAllowInsecure => {
-                None
-            }
-        };
-        let des_layers = self.proxy.get_layer_info(&self.proxy_img).await?;
-        for layer in import.ostree_layers.iter_mut() {
-            if layer.commit.is_some() {
-                continue;
-            }
-            if let Some(p) = self.layer_progress.as_ref() {
-                p.send(ImportProgress::OstreeChunkStarted(layer.layer.clone()))
-                    .await?;
-            }
+        let remote = self.imgref.sigverify.ostree_remote().map(ToOwned::to_owned);
+        let des_layers = self.source.get_layer_info().await?;
+        // Fetch the ostree chunks, which are independent of each other, concurrently
+        // if configured.  The commit layer is imported last, once all objects are present.
+        let this = &*self;
+        let manifest = &import.manifest;
+        let fetches = import
+            .ostree_layers
+            .iter_mut()
+            .filter(|layer| layer.commit.is_none())
+            .map(|layer| this.fetch_ostree_chunk(manifest, layer, des_layers.as_ref(), write_refs));
+        futures_util::stream::iter(fetches)
+            .buffered(self.jobs.get())
+            .try_collect::<()>()
+            .await?;
+        if import.ostree_commit_layer.commit.is_none() {
+            self.layer_started(
+                &import.manifest,
+                &import.ostree_commit_layer.layer,
+                ImportProgress::OstreeChunkStarted,
+            )
+            .await?;
+            let progress = self.byte_progress();
             let (blob, driver) = fetch_layer_decompress(
-                &mut self.proxy,
-                &self.proxy_img,
+                &self.source,
                 &import.manifest,
-                &layer.layer,
-                self.layer_byte_progress.as_ref(),
+                &import.ostree_commit_layer.layer,
+                progress.as_deref(),
                 des_layers.as_ref(),
                 self.imgref.imgref.transport,
+                self.staging.as_ref(),
             )
             .await?;
             let repo = self.repo.clone();
-            let target_ref = layer.ostree_ref.clone();
+            let target_ref = import.ostree_commit_layer.ostree_ref.clone();
             let import_task =
                 crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
                     let txn = repo.auto_transaction(Some(cancellable))?;
-                    let mut importer = crate::tar::Importer::new_for_object_set(&repo);
+                    let mut importer = crate::tar::Importer::new_for_commit(&repo, remote);
                     let blob = tokio_util::io::SyncIoBridge::new(blob);
                     let mut archive = tar::Archive::new(blob);
-                    importer.import_objects(&mut archive, Some(cancellable))?;
+                    importer.import_commit(&mut archive, Some(cancellable))?;
+                    let commit = importer.finish_import_commit();
+                    if write_refs {
+                        repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
+                        tracing::debug!("Wrote {} => {}", target_ref, commit);
+                    }
+                    repo.mark_commit_partial(&commit, false)?;
+                    txn.commit(Some(cancellable))?;
+                    Ok::<_, anyhow::Error>(commit)
+                });
+            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
+            self.remove_staged(&import.ostree_commit_layer.layer)?;
+            import.ostree_commit_layer.commit = Some(commit);
+            self.layer_completed(
+                &import.manifest,
+                &import.ostree_commit_layer.layer,
+                ImportProgress::OstreeChunkCompleted,
+            )
+            .await?;
+        };
+        Ok(())
+    }
+
+    /// Return a repository instance to import a layer.  Transactions are per-instance,
+    /// so when importing layers concurrently each needs its own.
+    fn repo_for_layer(&self) -> Result<ostree::Repo> {
+        if self.jobs.get() == 1 {
+            return Ok(self.repo.clone());
+        }
+        let repo = ostree::Repo::open_at_dir(self.repo.dfd_borrow(), ".")?;
+        repo.set_disable_fsync(self.repo.is_disable_fsync());
+        Ok(repo)
+    }
+
+    /// Fetch an ostree chunk layer, importing its objects.
+    async fn fetch_ostree_chunk(
+        &self,
+        manifest: &ImageManifest,
+        layer: &mut ManifestLayerState,
+        des_layers: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
+        write_refs: bool,
+    ) -> Result<()> {
+        self.layer_started(manifest, &layer.layer, ImportProgress::OstreeChunkStarted)
+            .await?;
+        let repo = self.repo_for_layer()?;
+        let target_ref = layer.ostree_ref.clone();
+        let commit = if let Some((blob, base, pos)) = self.open_layer_with_toc(&layer.layer)? {
+            let size = layer.size();
+            let (bytes_send, mut bytes_recv) = tokio::sync::watch::channel(0u64);
+            let import_task =
+                crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+                    let txn = repo.auto_transaction(Some(cancellable))?;
+                    let progress = |fetched| {
+                        bytes_send.send_replace(fetched);
+                    };
+                    let (commit, n) = super::toc::import_partial(
+                        &repo,
+                        &blob,
+                        base,
+                        size,
+                        &pos,
+                        progress,
+                        Some(cancellable),
+                    )?;
+                    tracing::debug!("Fetched {n} objects via table of contents");
                     let commit = if write_refs {
-                        let commit = importer.finish_import_object_set()?;
                         repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
                         tracing::debug!("Wrote {} => {}", target_ref, commit);
                         Some(commit)
@@ -749,62 +1349,149 @@ impl ImageImporter {
                     };
                     txn.commit(Some(cancellable))?;
                     Ok::<_, anyhow::Error>(commit)
-                })
-                .map_err(|e| e.context(format!("Layer {}", layer.digest())));
-            let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
-            layer.commit = commit;
-            if let Some(p) = self.layer_progress.as_ref() {
-                p.send(ImportProgress::OstreeChunkCompleted(layer.layer.clone()))
-                    .await?;
-            }
-        }
-        if import.ostree_commit_layer.commit.is_none() {
-            if let Some(p) = self.layer_progress.as_ref() {
-                p.send(ImportProgress::OstreeChunkStarted(
-                    import.ostree_commit_layer.layer.clone(),
-                ))
-                .await?;
-            }
+                });
+            let progress = self.byte_progress();
+            let layer_index = layer_index(manifest, &layer.layer)?;
+            let total = size;
+            let readproxy = async {
+                while let Ok(()) = bytes_recv.changed().await {
+                    let fetched = *bytes_recv.borrow_and_update();
+                    if let Some(progress) = progress.as_deref() {
+                        progress(LayerProgress {
+                            layer_index,
+                            fetched,
+                            total,
+                        });
+                    }
+                }
+            };
+            let (commit, ()) = tokio::join!(import_task, readproxy);
+            commit.map_err(|e| e.context(format!("Layer {}", layer.digest())))?
+        } else {
+            let progress = self.byte_progress();
             let (blob, driver) = fetch_layer_decompress(
-                &mut self.proxy,
-                &self.proxy_img,
-                &import.manifest,
-                &import.ostree_commit_layer.layer,
-                self.layer_byte_progress.as_ref(),
-                des_layers.as_ref(),
+                &self.source,
+                manifest,
+                &layer.layer,
+                progress.as_deref(),
+                des_layers,
                 self.imgref.imgref.transport,
+                self.staging.as_ref(),
             )
             .await?;
-            let repo = self.repo.clone();
-            let target_ref = import.ostree_commit_layer.ostree_ref.clone();
             let import_task =
                 crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
                     let txn = repo.auto_transaction(Some(cancellable))?;
-                    let mut importer = crate::tar::Importer::new_for_commit(&repo, remote);
+                    let mut importer = crate::tar::Importer::new_for_object_set(&repo);
                     let blob = tokio_util::io::SyncIoBridge::new(blob);
                     let mut archive = tar::Archive::new(blob);
-                    importer.import_commit(&mut archive, Some(cancellable))?;
-                    let commit = importer.finish_import_commit();
-                    if write_refs {
+                    importer.import_objects(&mut archive, Some(cancellable))?;
+                    let commit = if write_refs {
+                        let commit = importer.finish_import_object_set()?;
                         repo.transaction_set_ref(None, &target_ref, Some(commit.as_str()));
                         tracing::debug!("Wrote {} => {}", target_ref, commit);
-                    }
-                    repo.mark_commit_partial(&commit, false)?;
+                        Some(commit)
+                    } else {
+                        None
+                    };
                     txn.commit(Some(cancellable))?;
                     Ok::<_, anyhow::Error>(commit)
-                });
+                })
+                .map_err(|e| e.context(format!("Layer {}", layer.digest())));
             let commit = super::unencapsulate::join_fetch(import_task, driver).await?;
-            import.ostree_commit_layer.commit = Some(commit);
-            if let Some(p) = self.layer_progress.as_ref() {
-                p.send(ImportProgress::OstreeChunkCompleted(
-                    import.ostree_commit_layer.layer.clone(),
-                ))
-                .await?;
-            }
+            self.remove_staged(&layer.layer)?;
+            commit
         };
+        layer.commit = commit;
+        self.layer_completed(manifest, &layer.layer, ImportProgress::OstreeChunkCompleted)
+            .await?;
         Ok(())
     }
 
+    /// Fetch a derived (non-ostree) layer, writing it as a commit labeled using the
+    /// SELinux policy from the base.  Returns the commit and the content that was filtered out.
+    async fn fetch_derived_layer(
+        &self,
+        manifest: &ImageManifest,
+        layer: ManifestLayerState,
+        diff_id: &str,
+        base_commit: &str,
+        des_layers: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
+    ) -> Result<(String, HashMap<String, u32>)> {
+        if let Some(c) = layer.commit {
+            tracing::debug!("Reusing fetched commit {}", c);
+            return Ok((c, HashMap::new()));
+        }
+        self.layer_started(manifest, &layer.layer, ImportProgress::DerivedLayerStarted)
+            .await?;
+        let progress = self.byte_progress();
+        let (blob, driver) = super::unencapsulate::fetch_layer_decompress(
+            &self.source,
+            manifest,
+            &layer.layer,
+            progress.as_deref(),
+            des_layers,
+            self.imgref.imgref.transport,
+            self.staging.as_ref(),
+        )
+        .await?;
+        // An important aspect of this is that we SELinux label the derived layers using
+        // the base policy.
+        // The uncompressed content must match the configuration, which is covered by
+        // the manifest digest.
+        let opts = crate::tar::WriteTarOptions {
+            base: Some(base_commit.to_string()),
+            selinux: true,
+            expected_digest: Some(diff_id.to_string()),
+            content_policy: self.content_policy,
+        };
+        let r = crate::tar::write_tar(&self.repo, blob, layer.ostree_ref.as_str(), Some(opts));
+        let r = super::unencapsulate::join_fetch(r, driver)
+            .await
+            .with_context(|| format!("Parsing layer blob {}", layer.digest()))?;
+        self.remove_staged(&layer.layer)?;
+        self.layer_completed(
+            manifest,
+            &layer.layer,
+            ImportProgress::DerivedLayerCompleted,
+        )
+        .await?;
+        Ok((r.commit, HashMap::from_iter(r.filtered)))
+    }
+
+    /// If this layer has an embedded table of contents and the image source provides
+    /// random access to the blob, open it so that only missing objects need to be read.
+    /// This is currently only the case for images read in process; layers fetched via
+    /// the proxy, including from registries, are always fetched in full.
+    /// Returns the file holding the blob, the offset of the blob in it and the position
+    /// of the table of contents.
+    fn open_layer_with_toc(
+        &self,
+        layer: &Descriptor,
+    ) -> Result<Option<(std::fs::File, u64, super::toc::TocPosition)>> {
+        let pos = match layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(super::toc::TOC_ANNOTATION))
+        {
+            Some(pos) => super::toc::TocPosition::try_from(pos.as_str())?,
+            None => return Ok(None),
+        };
+        match self
+            .source
+            .open_blob_file(layer.digest(), layer.size() as u64)?
+        {
+            Some((blob, base)) => Ok(Some((blob, base, pos))),
+            None => {
+                tracing::debug!(
+                    "Image source does not support ranged fetches; fetching full layer {}",
+                    layer.digest()
+                );
+                Ok(None)
+            }
+        }
+    }
+
     /// Retrieve an inner ostree commit.
     ///
     /// This does not write cached references for each blob, and errors out if
@@ -823,7 +1510,7 @@ impl ImageImporter {
         self.unencapsulate_base(&mut prep, false).await?;
         // TODO change the imageproxy API to ensure this happens automatically when
         // the image reference is dropped
-        self.proxy.close_image(&self.proxy_img).await?;
+        self.source.close().await?;
         let ostree_commit = prep.ostree_commit_layer.commit.unwrap();
         let image_digest = prep.manifest_digest;
         Ok(Import {
@@ -833,196 +1520,343 @@ impl ImageImporter {
         })
     }
 
-    /// Import a layered container image.
-    ///
-    /// If enabled, this will also prune unused container image layers.
-    #[context("Importing")]
-    pub async fn import(
-        mut self,
-        mut import: Box<PreparedImport>,
-    ) -> Result<Box<LayeredImageState>> {
+    /// Fetch all layers, returning the commits of the derived layers in order and the
+    /// content that was filtered out of them.
+    async fn fetch_all_layers(
+        &mut self,
+        import: &mut PreparedImport,
+    ) -> Result<(Vec<String>, MetaFilteredData)> {
         if let Some(status) = import.format_layer_status() {
             system_repo_journal_print(&self.repo, libsystemd::logging::Priority::Info, &status);
         }
+        if let Some(p) = self.progress_events.as_ref() {
+            p.start(import, &self.imgref).await;
+        }
         // First download all layers for the base image (if necessary) - we need the SELinux policy
         // there to label all following layers.
-        self.unencapsulate_base(&mut import, true).await?;
-        let des_layers = self.proxy.get_layer_info(&self.proxy_img).await?;
-        let mut proxy = self.proxy;
-        let proxy_img = self.proxy_img;
-        let target_imgref = self.target_imgref.as_ref().unwrap_or(&self.imgref);
+        self.unencapsulate_base(import, true).await?;
+        let des_layers = self.source.get_layer_info().await?;
         let base_commit = import.ostree_commit_layer.commit.clone().unwrap();
 
-        let ostree_ref = ref_for_image(&target_imgref.imgref)?;
-
+        // Derived layers are fetched concurrently if configured, but merged in order.
+        let this = &*self;
+        let manifest = &import.manifest;
+        let diff_ids = import.config.rootfs().diff_ids();
+        let layers = std::mem::take(&mut import.layers)
+            .into_iter()
+            .map(|layer| {
+                let diff_id = manifest
+                    .layers()
+                    .iter()
+                    .position(|l| l == &layer.layer)
+                    .and_then(|i| diff_ids.get(i))
+                    .ok_or_else(|| anyhow!("Missing diff_id for layer {}", layer.digest()))?;
+                Ok((layer, diff_id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let fetches = layers.into_iter().map(|(layer, diff_id)| {
+            let digest = layer.digest().to_string();
+            this.fetch_derived_layer(manifest, layer, diff_id, &base_commit, des_layers.as_ref())
+                .map_ok(|(commit, filtered)| (digest, commit, filtered))
+        });
+        let fetched: Vec<_> = futures_util::stream::iter(fetches)
+            .buffered(self.jobs.get())
+            .try_collect()
+            .await?;
         let mut layer_commits = Vec::new();
         let mut layer_filtered_content: MetaFilteredData = HashMap::new();
-        for layer in import.layers {
-            if let Some(c) = layer.commit {
-                tracing::debug!("Reusing fetched commit {}", c);
-                layer_commits.push(c.to_string());
-            } else {
-                if let Some(p) = self.layer_progress.as_ref() {
-                    p.send(ImportProgress::DerivedLayerStarted(layer.layer.clone()))
-                        .await?;
-                }
-                let (blob, driver) = super::unencapsulate::fetch_layer_decompress(
-                    &mut proxy,
-                    &proxy_img,
-                    &import.manifest,
-                    &layer.layer,
-                    self.layer_byte_progress.as_ref(),
-                    des_layers.as_ref(),
-                    self.imgref.imgref.transport,
-                )
-                .await?;
-                // An important aspect of this is that we SELinux label the derived layers using
-                // the base policy.
-                let opts = crate::tar::WriteTarOptions {
-                    base: Some(base_commit.clone()),
-                    selinux: true,
-                };
-                let r =
-                    crate::tar::write_tar(&self.repo, blob, layer.ostree_ref.as_str(), Some(opts));
-                let r = super::unencapsulate::join_fetch(r, driver)
-                    .await
-                    .with_context(|| format!("Parsing layer blob {}", layer.digest()))?;
-                layer_commits.push(r.commit);
-                if !r.filtered.is_empty() {
-                    let filtered = HashMap::from_iter(r.filtered.into_iter());
-                    layer_filtered_content.insert(layer.digest().to_string(), filtered);
-                }
-                if let Some(p) = self.layer_progress.as_ref() {
-                    p.send(ImportProgress::DerivedLayerCompleted(layer.layer.clone()))
-                        .await?;
-                }
+        for (digest, commit, filtered) in fetched {
+            layer_commits.push(commit);
+            if !filtered.is_empty() {
+                layer_filtered_content.insert(digest, filtered);
             }
         }
+        Ok((layer_commits, layer_filtered_content))
+    }
 
-        // TODO change the imageproxy API to ensure this happens automatically when
-        // the image reference is dropped
-        proxy.close_image(&proxy_img).await?;
-
-        // We're done with the proxy, make sure it didn't have any errors.
-        proxy.finalize().await?;
-        tracing::debug!("finalized proxy");
-
-        let serialized_manifest = serde_json::to_string(&import.manifest)?;
-        let serialized_config = serde_json::to_string(&import.config)?;
-        let mut metadata = HashMap::new();
-        metadata.insert(META_MANIFEST_DIGEST, import.manifest_digest.to_variant());
-        metadata.insert(META_MANIFEST, serialized_manifest.to_variant());
-        metadata.insert(META_CONFIG, serialized_config.to_variant());
-        metadata.insert(
-            "ostree.importer.version",
-            env!("CARGO_PKG_VERSION").to_variant(),
-        );
-        let filtered = layer_filtered_content.to_variant();
-        metadata.insert(META_FILTERED, filtered);
-        let metadata = metadata.to_variant();
+    /// Import a layered container image.
+    ///
+    /// If enabled, this will also prune unused container image layers.
+    #[context("Importing")]
+    pub async fn import(
+        mut self,
+        mut import: Box<PreparedImport>,
+    ) -> Result<Box<LayeredImageState>> {
+        let (layer_commits, layer_filtered_content) = self.fetch_all_layers(&mut import).await?;
+        let target_imgref = self.target_imgref.as_ref().unwrap_or(&self.imgref);
+        let ostree_ref = (!self.no_imgref)
+            .then(|| ref_for_image(&target_imgref.imgref))
+            .transpose()?;
+        let base_commit = import.ostree_commit_layer.commit.clone().unwrap();
 
+        // We're done with the image source; if using the proxy, make sure it didn't have any errors.
+        self.source.finalize().await?;
+        tracing::debug!("finalized image source");
+
+        let metadata = merge_commit_metadata(
+            &import.manifest_digest,
+            &import.manifest,
+            &import.config,
+            &layer_filtered_content,
+        )?;
         let timestamp = timestamp_of_manifest_or_config(&import.manifest, &import.config)
             .unwrap_or_else(|| chrono::offset::Utc::now().timestamp() as u64);
         // Destructure to transfer ownership to thread
         let repo = self.repo;
-        let state = crate::tokio_util::spawn_blocking_cancellable_flatten(
-            move |cancellable| -> Result<Box<LayeredImageState>> {
-                use rustix::fd::AsRawFd;
-
-                let cancellable = Some(cancellable);
-                let repo = &repo;
-                let txn = repo.auto_transaction(cancellable)?;
-
-                let devino = ostree::RepoDevInoCache::new();
-                let repodir = Dir::reopen_dir(&repo.dfd_borrow())?;
-                let repo_tmp = repodir.open_dir("tmp")?;
-                let td = cap_std_ext::cap_tempfile::TempDir::new_in(&repo_tmp)?;
-
-                let rootpath = "root";
-                let checkout_mode = if repo.mode() == ostree::RepoMode::Bare {
-                    ostree::RepoCheckoutMode::None
-                } else {
-                    ostree::RepoCheckoutMode::User
-                };
-                let mut checkout_opts = ostree::RepoCheckoutAtOptions {
-                    mode: checkout_mode,
-                    overwrite_mode: ostree::RepoCheckoutOverwriteMode::UnionFiles,
-                    devino_to_csum_cache: Some(devino.clone()),
-                    no_copy_fallback: true,
-                    force_copy_zerosized: true,
-                    process_whiteouts: false,
-                    ..Default::default()
-                };
-                repo.checkout_at(
-                    Some(&checkout_opts),
-                    (*td).as_raw_fd(),
-                    rootpath,
-                    &base_commit,
-                    cancellable,
-                )
-                .context("Checking out base commit")?;
-
-                // Layer all subsequent commits
-                checkout_opts.process_whiteouts = true;
-                for commit in layer_commits {
-                    repo.checkout_at(
-                        Some(&checkout_opts),
-                        (*td).as_raw_fd(),
-                        rootpath,
-                        &commit,
-                        cancellable,
-                    )
-                    .with_context(|| format!("Checking out layer {commit}"))?;
-                }
+        let gc = !self.disable_gc;
+        let progress = self.progress_events;
+        let merge_progress = progress.clone();
+        let state = crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+            write_merge_commit(
+                &repo,
+                &base_commit,
+                layer_commits,
+                &metadata,
+                timestamp,
+                ostree_ref.as_deref(),
+                gc,
+                merge_progress.as_ref(),
+                cancellable,
+            )
+        })
+        .await?;
+        if let Some(p) = progress {
+            p.send(ProgressEvent::Imported {
+                merge_commit: state.merge_commit.clone(),
+            })
+            .await;
+        }
+        Ok(state)
+    }
 
-                let modifier =
-                    ostree::RepoCommitModifier::new(ostree::RepoCommitModifierFlags::CONSUME, None);
-                modifier.set_devino_cache(&devino);
+    /// Fetch all layers of an update to a previously pulled image, without writing the
+    /// merge commit or updating the image reference.  The update is recorded as the
+    /// [`LayeredImageState::cached_update`] of the current image, and can then be
+    /// completed without network access via [`finalize_cached_update`].
+    ///
+    /// The fetched layers are not pruned while the update is pending.
+    #[context("Fetching layers")]
+    pub async fn fetch_layers(mut self, mut import: Box<PreparedImport>) -> Result<()> {
+        let previous_commit = match import.previous_state.as_ref() {
+            Some(s) => s.merge_commit.clone(),
+            None => anyhow::bail!("Fetching layers only requires a previously pulled image"),
+        };
+        let (_, layer_filtered_content) = self.fetch_all_layers(&mut import).await?;
+        self.cache_pending(
+            &previous_commit,
+            &import.manifest_digest,
+            &import.manifest,
+            &import.config,
+            Some(&layer_filtered_content),
+        )
+        .await?;
+        self.source.finalize().await?;
+        Ok(())
+    }
+}
 
-                let mt = ostree::MutableTree::new();
-                repo.write_dfd_to_mtree(
-                    (*td).as_raw_fd(),
-                    rootpath,
-                    &mt,
-                    Some(&modifier),
-                    cancellable,
-                )
-                .context("Writing merged filesystem to mtree")?;
-
-                let merged_root = repo
-                    .write_mtree(&mt, cancellable)
-                    .context("Writing mtree")?;
-                let merged_root = merged_root.downcast::<ostree::RepoFile>().unwrap();
-                let merged_commit = repo
-                    .write_commit_with_time(
-                        None,
-                        None,
-                        None,
-                        Some(&metadata),
-                        &merged_root,
-                        timestamp,
-                        cancellable,
-                    )
-                    .context("Writing commit")?;
-                if !self.no_imgref {
-                    repo.transaction_set_ref(None, &ostree_ref, Some(merged_commit.as_str()));
-                }
-                txn.commit(cancellable)?;
+/// Build the metadata for an image merge commit.
+fn merge_commit_metadata(
+    manifest_digest: &str,
+    manifest: &ImageManifest,
+    config: &ImageConfiguration,
+    filtered: &MetaFilteredData,
+) -> Result<glib::Variant> {
+    let serialized_manifest = serde_json::to_string(manifest)?;
+    let serialized_config = serde_json::to_string(config)?;
+    let mut metadata = HashMap::new();
+    metadata.insert(META_MANIFEST_DIGEST, manifest_digest.to_variant());
+    metadata.insert(META_MANIFEST, serialized_manifest.to_variant());
+    metadata.insert(META_CONFIG, serialized_config.to_variant());
+    metadata.insert(
+        "ostree.importer.version",
+        env!("CARGO_PKG_VERSION").to_variant(),
+    );
+    metadata.insert(META_FILTERED, filtered.to_variant());
+    Ok(metadata.to_variant())
+}
 
-                if !self.disable_gc {
-                    let n: u32 = gc_image_layers_impl(repo, cancellable)?;
-                    tracing::debug!("pruned {n} layers");
-                }
+/// Write the merge commit of a base commit and the commits of the derived layers
+/// on top of it, and point `ostree_ref` (if provided) to it.
+#[allow(clippy::too_many_arguments)]
+fn write_merge_commit(
+    repo: &ostree::Repo,
+    base_commit: &str,
+    layer_commits: Vec<String>,
+    metadata: &glib::Variant,
+    timestamp: u64,
+    ostree_ref: Option<&str>,
+    gc: bool,
+    progress: Option<&ProgressSender>,
+    cancellable: &gio::Cancellable,
+) -> Result<Box<LayeredImageState>> {
+    use rustix::fd::AsRawFd;
+
+    let cancellable = Some(cancellable);
+    let txn = repo.auto_transaction(cancellable)?;
+
+    let devino = ostree::RepoDevInoCache::new();
+    let repodir = Dir::reopen_dir(&repo.dfd_borrow())?;
+    let repo_tmp = repodir.open_dir("tmp")?;
+    let td = cap_std_ext::cap_tempfile::TempDir::new_in(&repo_tmp)?;
 
-                // Here we re-query state just to run through the same code path,
-                // though it'd be cheaper to synthesize it from the data we already have.
-                let state = query_image_commit(repo, &merged_commit)?;
-                Ok(state)
-            },
+    let phase = |phase| {
+        if let Some(p) = progress {
+            p.blocking_send(ProgressEvent::Phase { phase });
+        }
+    };
+    phase(ImportPhase::MergeCheckout);
+
+    let rootpath = "root";
+    let checkout_mode = if repo.mode() == ostree::RepoMode::Bare {
+        ostree::RepoCheckoutMode::None
+    } else {
+        ostree::RepoCheckoutMode::User
+    };
+    let mut checkout_opts = ostree::RepoCheckoutAtOptions {
+        mode: checkout_mode,
+        overwrite_mode: ostree::RepoCheckoutOverwriteMode::UnionFiles,
+        devino_to_csum_cache: Some(devino.clone()),
+        no_copy_fallback: true,
+        force_copy_zerosized: true,
+        process_whiteouts: false,
+        ..Default::default()
+    };
+    repo.checkout_at(
+        Some(&checkout_opts),
+        (*td).as_raw_fd(),
+        rootpath,
+        base_commit,
+        cancellable,
+    )
+    .context("Checking out base commit")?;
+
+    // Layer all subsequent commits
+    checkout_opts.process_whiteouts = true;
+    for commit in layer_commits {
+        repo.checkout_at(
+            Some(&checkout_opts),
+            (*td).as_raw_fd(),
+            rootpath,
+            &commit,
+            cancellable,
         )
-        .await?;
-        Ok(state)
+        .with_context(|| format!("Checking out layer {commit}"))?;
+    }
+
+    phase(ImportPhase::Commit);
+    let modifier = ostree::RepoCommitModifier::new(ostree::RepoCommitModifierFlags::CONSUME, None);
+    modifier.set_devino_cache(&devino);
+
+    let mt = ostree::MutableTree::new();
+    repo.write_dfd_to_mtree(
+        (*td).as_raw_fd(),
+        rootpath,
+        &mt,
+        Some(&modifier),
+        cancellable,
+    )
+    .context("Writing merged filesystem to mtree")?;
+
+    let merged_root = repo
+        .write_mtree(&mt, cancellable)
+        .context("Writing mtree")?;
+    let merged_root = merged_root.downcast::<ostree::RepoFile>().unwrap();
+    let merged_commit = repo
+        .write_commit_with_time(
+            None,
+            None,
+            None,
+            Some(metadata),
+            &merged_root,
+            timestamp,
+            cancellable,
+        )
+        .context("Writing commit")?;
+    if let Some(ostree_ref) = ostree_ref {
+        repo.transaction_set_ref(None, ostree_ref, Some(merged_commit.as_str()));
+    }
+    txn.commit(cancellable)?;
+
+    if gc {
+        phase(ImportPhase::Gc);
+        let n: u32 = gc_image_layers_impl(repo, cancellable)?;
+        tracing::debug!("pruned {n} layers");
+    }
+
+    // Here we re-query state just to run through the same code path,
+    // though it'd be cheaper to synthesize it from the data we already have.
+    query_image_commit(repo, &merged_commit)
+}
+
+/// Complete an update of an image whose layers were previously fetched via
+/// [`ImageImporter::fetch_layers`], by writing its merge commit and updating the
+/// image reference.  This does not access the network.  Unused layers are pruned.
+#[context("Finalizing cached update of {imgref}")]
+pub async fn finalize_cached_update(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+) -> Result<Box<LayeredImageState>> {
+    let ostree_ref = ref_for_image(imgref)?;
+    let merge_rev = repo.require_rev(&ostree_ref)?;
+    let meta = repo
+        .read_commit_detached_metadata(&merge_rev, gio::Cancellable::NONE)?
+        .map(|v| glib::VariantDict::new(Some(&v)));
+    let cached = meta
+        .as_ref()
+        .map(parse_cached_update)
+        .transpose()?
+        .flatten()
+        .filter(|c| c.layers_fetched)
+        .ok_or_else(|| anyhow!("No fetched update for {imgref}"))?;
+    let filtered = meta
+        .as_ref()
+        .and_then(|m| {
+            m.lookup::<MetaFilteredData>(ImageImporter::CACHED_KEY_FILTERED)
+                .transpose()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let (commit_layer, component_layers, derived_layers) =
+        parse_manifest_layout(&cached.manifest, &cached.config)?;
+    // Verify all layers are still present
+    let layer_commit = |layer: &Descriptor| -> Result<String> {
+        query_layer(repo, layer.clone())?
+            .commit
+            .ok_or_else(|| anyhow!("Layer {} is not present", layer.digest()))
+    };
+    let base_commit = layer_commit(commit_layer)?;
+    for layer in component_layers {
+        layer_commit(layer)?;
     }
+    let layer_commits = derived_layers
+        .into_iter()
+        .map(&layer_commit)
+        .collect::<Result<Vec<_>>>()?;
+
+    let metadata = merge_commit_metadata(
+        &cached.manifest_digest,
+        &cached.manifest,
+        &cached.config,
+        &filtered,
+    )?;
+    let timestamp = timestamp_of_manifest_or_config(&cached.manifest, &cached.config)
+        .unwrap_or_else(|| chrono::offset::Utc::now().timestamp() as u64);
+    let repo = repo.clone();
+    crate::tokio_util::spawn_blocking_cancellable_flatten(move |cancellable| {
+        write_merge_commit(
+            &repo,
+            &base_commit,
+            layer_commits,
+            &metadata,
+            timestamp,
+            Some(&ostree_ref),
+            true,
+            None,
+            cancellable,
+        )
+    })
+    .await
 }
 
 /// List all images stored
@@ -1108,10 +1942,12 @@ fn parse_cached_update(meta: &glib::VariantDict) -> Result<Option<CachedImageUpd
                 ImageImporter::CACHED_KEY_CONFIG
             )
         })?;
+    let layers_fetched = meta.contains(ImageImporter::CACHED_KEY_FILTERED);
     Ok(Some(CachedImageUpdate {
         manifest,
         config,
         manifest_digest,
+        layers_fetched,
     }))
 }
 
@@ -1178,6 +2014,21 @@ fn manifest_for_image(repo: &ostree::Repo, imgref: &ImageReference) -> Result<Im
     Ok(manifest_data_from_commitmeta(commit_meta)?.0)
 }
 
+/// Return the manifest of the cached update for an image, if any.
+fn cached_update_manifest_for_image(
+    repo: &ostree::Repo,
+    imgref: &ImageReference,
+) -> Result<Option<ImageManifest>> {
+    let ostree_ref = ref_for_image(imgref)?;
+    let rev = repo.require_rev(&ostree_ref)?;
+    let meta = repo.read_commit_detached_metadata(&rev, gio::Cancellable::NONE)?;
+    let cached = meta
+        .map(|v| parse_cached_update(&glib::VariantDict::new(Some(&v))))
+        .transpose()?
+        .flatten();
+    Ok(cached.map(|c| c.manifest))
+}
+
 /// Copy a downloaded image from one repository to another.
 #[context("Copying image")]
 #[deprecated = "Use copy_as instead"]
@@ -1287,6 +2138,25 @@ fn list_container_deployment_manifests(
     Ok(r)
 }
 
+/// Return the manifests of the pinned images.
+fn list_pinned_manifests(
+    repo: &ostree::Repo,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<Vec<ImageManifest>> {
+    let refs = repo.list_refs_ext(
+        Some(PINNED_PREFIX),
+        ostree::RepoListRefsExtFlags::empty(),
+        cancellable,
+    )?;
+    refs.values()
+        .map(|commit| {
+            let commit_obj = repo.load_commit(commit)?.0;
+            let commit_meta = &glib::VariantDict::new(Some(&commit_obj.child_value(0)));
+            Ok(manifest_data_from_commitmeta(commit_meta)?.0)
+        })
+        .collect()
+}
+
 /// Garbage collect unused image layer references.
 ///
 /// This function assumes no transaction is active on the repository.
@@ -1303,13 +2173,15 @@ fn gc_image_layers_impl(
 ) -> Result<u32> {
     let all_images = list_images(repo)?;
     let deployment_commits = list_container_deployment_manifests(repo, cancellable)?;
-    let all_manifests = all_images
-        .into_iter()
-        .map(|img| {
-            ImageReference::try_from(img.as_str()).and_then(|ir| manifest_for_image(repo, &ir))
-        })
-        .chain(deployment_commits.into_iter().map(Ok))
-        .collect::<Result<Vec<_>>>()?;
+    let mut all_manifests = Vec::new();
+    for img in all_images {
+        let ir = ImageReference::try_from(img.as_str())?;
+        all_manifests.push(manifest_for_image(repo, &ir)?);
+        // Layers fetched for a pending update are also retained
+        all_manifests.extend(cached_update_manifest_for_image(repo, &ir)?);
+    }
+    all_manifests.extend(deployment_commits);
+    all_manifests.extend(list_pinned_manifests(repo, cancellable)?);
     tracing::debug!("Images found: {}", all_manifests.len());
     let mut referenced_layers = BTreeSet::new();
     for m in all_manifests.iter() {
@@ -1433,6 +2305,57 @@ pub fn remove_images<'a>(
     Ok(())
 }
 
+/// Pin an image, so that it is retained by [`gc_image_layers`] and
+/// [`super::deploy::remove_undeployed_images`].  The pin refers to the image as
+/// currently stored; if the image is updated later, the pinned version is also kept
+/// until the image is pinned again or unpinned.
+///
+/// This function assumes no transaction is active on the repository.
+#[context("Pinning {img}")]
+pub fn pin_image(repo: &ostree::Repo, img: &ImageReference) -> Result<()> {
+    let merge_rev = repo.require_rev(&ref_for_image(img)?)?;
+    repo.set_ref_immediate(
+        None,
+        &ref_for_pin(img)?,
+        Some(merge_rev.as_str()),
+        gio::Cancellable::NONE,
+    )?;
+    Ok(())
+}
+
+/// Unpin an image.  Returns `false` if the image was not pinned.
+///
+/// This function assumes no transaction is active on the repository.
+/// The underlying layers are *not* pruned; that requires a separate invocation
+/// of [`gc_image_layers`].
+#[context("Unpinning {img}")]
+pub fn unpin_image(repo: &ostree::Repo, img: &ImageReference) -> Result<bool> {
+    let pin_ref = &ref_for_pin(img)?;
+    let found = repo.resolve_rev(pin_ref, true)?.is_some();
+    if found {
+        repo.set_ref_immediate(None, pin_ref, None, gio::Cancellable::NONE)?;
+    }
+    Ok(found)
+}
+
+/// Returns `true` if the image is pinned.
+pub fn image_is_pinned(repo: &ostree::Repo, img: &ImageReference) -> Result<bool> {
+    Ok(repo.resolve_rev(&ref_for_pin(img)?, true)?.is_some())
+}
+
+/// List all pinned images.
+pub fn list_pinned_images(repo: &ostree::Repo) -> Result<Vec<String>> {
+    let cancellable = gio::Cancellable::NONE;
+    let refs = repo.list_refs_ext(
+        Some(PINNED_PREFIX),
+        ostree::RepoListRefsExtFlags::empty(),
+        cancellable,
+    )?;
+    refs.keys()
+        .map(|imgname| refescape::unprefix_unescape_ref(PINNED_PREFIX, imgname))
+        .collect()
+}
+
 #[derive(Debug, Default)]
 struct CompareState {
     verified: BTreeSet<Utf8PathBuf>,
@@ -0,0 +1,325 @@
+//! Tables of contents for chunked layers.
+//!
+//! When enabled at export time, each content layer is written as a sequence
+//! of independent zstd frames, one per ostree content object (along with its
+//! extended attributes and hardlinks).  A JSON table of contents mapping ostree
+//! content checksums to the compressed byte range holding them is appended to the
+//! blob in a zstd skippable frame, which standard decompressors ignore; its
+//! location and digest are recorded in a layer annotation.  This is similar
+//! in spirit to the `zstd:chunked` and eStargz formats.
+//!
+//! The extended attributes of all objects are written once, in a leading frame
+//! which is always read.
+//!
+//! At import time, this allows fetching only the objects which are missing
+//! from the local repository.  Note that this requires random access to the
+//! layer blob, which is only available when reading a local OCI directory or
+//! archive in process (see [`super::store::ImageImporter::new_in_process`]).
+//! The container image proxy does not support ranged blob fetches, so images
+//! fetched via the proxy, including all images from registries, fall back to
+//! fetching the full layer, which remains a valid zstd compressed tarball.
+//!
+//! A partial import never reads the full blob, so its digest cannot be verified.
+//! Instead, the table of contents is verified against the digest recorded in the
+//! annotation, which is covered by the (verified) manifest digest, and every content
+//! object is written with its expected checksum, which ostree verifies.
+
+use super::ocidir::{Layer, LayerCompression, OciDir};
+use crate::chunking::ChunkMapping;
+use crate::tar as ostree_tar;
+use anyhow::{anyhow, Context, Result};
+use fn_error_context::context;
+use ostree::gio;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+
+/// The name of the layer annotation holding the position of the table of contents.
+pub(crate) const TOC_ANNOTATION: &str = "ostree.toc";
+
+/// The current version of the table of contents format.
+const TOC_VERSION: u32 = 1;
+
+/// A table of contents for a layer.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct ContentToc {
+    /// The format version.
+    pub(crate) version: u32,
+    /// Length of the leading frame, which holds the extended attributes of all
+    /// objects and must be imported before any of them.
+    pub(crate) header_length: u64,
+    /// The content objects in this layer.
+    pub(crate) entries: Vec<TocEntry>,
+}
+
+/// The location of a single content object in a layer blob.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct TocEntry {
+    /// The ostree content object checksum.
+    pub(crate) checksum: String,
+    /// Offset of the compressed frame in the blob.
+    pub(crate) offset: u64,
+    /// Length of the compressed frame.
+    pub(crate) length: u64,
+}
+
+/// The location and digest of the table of contents in a layer blob; serialized into
+/// [`TOC_ANNOTATION`] as `<offset>:<length>:sha256:<digest>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TocPosition {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    pub(crate) digest: String,
+}
+
+impl TryFrom<&str> for TocPosition {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        let mut parts = value.splitn(3, ':');
+        let mut next = || {
+            parts
+                .next()
+                .ok_or_else(|| anyhow!("Invalid table of contents position: {value}"))
+        };
+        let offset = next()?.parse()?;
+        let length = next()?.parse()?;
+        let digest = next()?;
+        let digest = digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| anyhow!("Unsupported table of contents digest: {digest}"))?;
+        Ok(Self {
+            offset,
+            length,
+            digest: digest.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for TocPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:sha256:{}", self.offset, self.length, self.digest)
+    }
+}
+
+fn sha256_hex(buf: &[u8]) -> Result<String> {
+    let digest = openssl::hash::hash(openssl::hash::MessageDigest::sha256(), buf)?;
+    Ok(hex::encode(digest))
+}
+
+/// Export a chunk as a zstd compressed layer with an embedded table of contents.
+#[context("Exporting chunk with table of contents")]
+pub(crate) fn export_chunk_with_toc(
+    repo: &ostree::Repo,
+    commit: &str,
+    chunk: ChunkMapping,
+    ociw: &OciDir,
+    level: i32,
+) -> Result<(Layer, TocPosition)> {
+    let mut toc = ContentToc {
+        version: TOC_VERSION,
+        ..Default::default()
+    };
+    let mut pending: Option<(String, u64)> = None;
+    let mut w = ociw.create_layer_with(LayerCompression::Zstd(level))?;
+    ostree_tar::export_chunk_split(repo, commit, chunk, &mut w, |raw, next| {
+        let offset = raw.end_frame()?;
+        match pending.take() {
+            Some((checksum, start)) => toc.entries.push(TocEntry {
+                checksum,
+                offset: start,
+                length: offset - start,
+            }),
+            None => toc.header_length = offset,
+        }
+        pending = next.map(|checksum| (checksum.to_string(), offset));
+        Ok(())
+    })?;
+    let mut w = w.into_inner()?;
+    let buf = serde_json::to_vec(&toc)?;
+    let offset = w.write_skippable_frame(&buf)?;
+    let pos = TocPosition {
+        offset,
+        length: buf.len() as u64,
+        digest: sha256_hex(&buf)?,
+    };
+    Ok((w.complete()?, pos))
+}
+
+impl ContentToc {
+    /// Check that the entries are valid checksums with ordered, non-overlapping
+    /// ranges between the leading frame and the table of contents at `toc_offset`.
+    fn validate(&self, toc_offset: u64) -> Result<()> {
+        let mut end = self.header_length;
+        for entry in self.entries.iter() {
+            ostree::validate_checksum_string(&entry.checksum)?;
+            if entry.offset < end {
+                anyhow::bail!("Overlapping table of contents entry {}", entry.checksum);
+            }
+            end = entry
+                .offset
+                .checked_add(entry.length)
+                .ok_or_else(|| anyhow!("Invalid table of contents entry {}", entry.checksum))?;
+        }
+        if end > toc_offset {
+            anyhow::bail!("Table of contents entries exceed offset {toc_offset}");
+        }
+        Ok(())
+    }
+}
+
+/// Read and verify the table of contents from a layer blob of `size` bytes starting at
+/// `base` in `blob`.
+#[context("Reading table of contents")]
+pub(crate) fn read_toc(blob: &File, base: u64, size: u64, pos: &TocPosition) -> Result<ContentToc> {
+    if pos
+        .offset
+        .checked_add(pos.length)
+        .map_or(true, |end| end > size)
+    {
+        anyhow::bail!("Table of contents at {pos} exceeds blob size {size}");
+    }
+    let mut buf = vec![0u8; usize::try_from(pos.length)?];
+    blob.read_exact_at(&mut buf, base + pos.offset)?;
+    let digest = sha256_hex(&buf)?;
+    if digest != pos.digest {
+        anyhow::bail!(
+            "Table of contents digest mismatch; expected {} found {digest}",
+            pos.digest
+        );
+    }
+    let toc: ContentToc = serde_json::from_slice(&buf)?;
+    if toc.version != TOC_VERSION {
+        anyhow::bail!("Unsupported table of contents version {}", toc.version);
+    }
+    toc.validate(pos.offset)?;
+    Ok(toc)
+}
+
+/// A reader for a byte range of a file.
+struct RangeReader<'a> {
+    f: &'a File,
+    pos: u64,
+    end: u64,
+}
+
+impl<'a> Read for RangeReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = usize::try_from(self.end - self.pos).unwrap_or(usize::MAX);
+        let n = buf.len().min(remaining);
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = self.f.read_at(&mut buf[..n], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Import a layer of `size` bytes starting at `base` in `blob` into an object set commit
+/// using its table of contents, reading only the ranges of the blob for content objects
+/// missing from the repository.  The total number of bytes read so far is passed to
+/// `progress`.
+///
+/// Returns the commit and the number of objects that were fetched.
+#[context("Importing layer via table of contents")]
+pub(crate) fn import_partial(
+    repo: &ostree::Repo,
+    blob: &File,
+    base: u64,
+    size: u64,
+    pos: &TocPosition,
+    mut progress: impl FnMut(u64),
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<(String, usize)> {
+    let toc = read_toc(blob, base, size, pos)?;
+    let mut importer = ostree_tar::Importer::new_for_object_set(repo);
+    let read_range = |importer: &mut ostree_tar::Importer, offset: u64, length: u64| {
+        let range = RangeReader {
+            f: blob,
+            pos: base + offset,
+            end: base + offset + length,
+        };
+        let decoder = zstd::stream::read::Decoder::new(range)?;
+        let mut archive = tar::Archive::new(decoder);
+        importer.import_objects(&mut archive, cancellable)
+    };
+    let mut bytes_read = pos.length;
+    read_range(&mut importer, 0, toc.header_length).context("Importing extended attributes")?;
+    bytes_read += toc.header_length;
+    progress(bytes_read);
+    let mut present = Vec::new();
+    let mut fetched = 0;
+    for entry in toc.entries {
+        if repo.has_object(ostree::ObjectType::File, &entry.checksum, cancellable)? {
+            present.push(entry.checksum);
+            continue;
+        }
+        read_range(&mut importer, entry.offset, entry.length)
+            .with_context(|| format!("Importing object {}", entry.checksum))?;
+        if !importer.object_set_contains(&entry.checksum) {
+            anyhow::bail!("Object {} not found at expected location", entry.checksum);
+        }
+        bytes_read += entry.length;
+        progress(bytes_read);
+        fetched += 1;
+    }
+    importer.extend_object_set(present);
+    let commit = importer.finish_import_object_set()?;
+    Ok((commit, fetched))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toc_position() {
+        let digest = "a5b2b2c507a0944348e0303114d8d93aaaa081732b86451d9bce1f432a537bc7";
+        let s = format!("1234:56:sha256:{digest}");
+        let pos = TocPosition::try_from(s.as_str()).unwrap();
+        assert_eq!(
+            pos,
+            TocPosition {
+                offset: 1234,
+                length: 56,
+                digest: digest.to_string(),
+            }
+        );
+        assert_eq!(pos.to_string(), s);
+        for invalid in [
+            "",
+            "1234",
+            "1234:56",
+            "x:56:sha256:abc",
+            "1234:56:sha512:abc",
+        ] {
+            assert!(TocPosition::try_from(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_toc_validate() {
+        let entry = |c: char, offset, length| TocEntry {
+            checksum: std::iter::repeat(c).take(64).collect(),
+            offset,
+            length,
+        };
+        let mut toc = ContentToc {
+            version: TOC_VERSION,
+            header_length: 10,
+            entries: vec![entry('a', 10, 5), entry('b', 15, 5)],
+        };
+        toc.validate(20).unwrap();
+        assert!(toc.validate(19).is_err());
+        toc.entries[1].offset = 14;
+        assert!(toc.validate(100).is_err());
+        toc.entries[1] = entry('b', 15, u64::MAX);
+        assert!(toc.validate(u64::MAX).is_err());
+        toc.entries[1] = entry('x', 15, 5);
+        assert!(toc.validate(100).is_err());
+        toc.entries = vec![entry('a', 5, 5)];
+        assert!(toc.validate(100).is_err());
+    }
+}
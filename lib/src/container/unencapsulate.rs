@@ -20,6 +20,10 @@
 //! Additionally, the proxy "upconverts" manifests into OCI, so we don't need to care
 //! about parsing the Docker manifest format (as used by most registries still).
 //!
+//! The exception is [`ImageImporter::new_in_process`], which can read images
+//! in a local OCI directory or archive without the proxy installed.
+//!
+//! [`ImageImporter::new_in_process`]: [`super::store::ImageImporter::new_in_process`]
 //! [`encapsulate`]: [`super::encapsulate()`]
 
 // # Implementation
@@ -33,15 +37,17 @@
 
 use crate::container::store::LayerProgress;
 
+use super::imgsource::ImageSource;
+use super::staging::BlobStaging;
 use super::*;
-use containers_image_proxy::{ImageProxy, OpenedImage};
+use containers_image_proxy::ImageProxy;
 use fn_error_context::context;
 use futures_util::{Future, FutureExt};
 use oci_spec::image as oci_image;
 use std::sync::{Arc, Mutex};
 use tokio::{
     io::{AsyncBufRead, AsyncRead},
-    sync::watch::{Receiver, Sender},
+    sync::watch::Receiver,
 };
 use tracing::instrument;
 
@@ -112,6 +118,17 @@ pub async fn fetch_manifest(
     fetch_manifest_impl(&mut proxy, imgref).await
 }
 
+/// Download the manifest for a target image and its sha256 digest, using the provided
+/// proxy configuration (e.g. to set an authfile).
+#[context("Fetching manifest")]
+pub async fn fetch_manifest_with_config(
+    imgref: &OstreeImageReference,
+    config: containers_image_proxy::ImageProxyConfig,
+) -> Result<(oci_spec::image::ImageManifest, String)> {
+    let mut proxy = ImageProxy::new_with_config(config).await?;
+    fetch_manifest_impl(&mut proxy, imgref).await
+}
+
 /// Download the manifest for a target image and its sha256 digest, as well as the image configuration.
 #[context("Fetching manifest and config")]
 pub async fn fetch_manifest_and_config(
@@ -193,6 +210,12 @@ fn new_async_decompressor<'a>(
         oci_image::MediaType::ImageLayerGzip => Ok(Box::new(tokio::io::BufReader::new(
             async_compression::tokio::bufread::GzipDecoder::new(src),
         ))),
+        oci_image::MediaType::ImageLayerZstd => {
+            let mut decoder = async_compression::tokio::bufread::ZstdDecoder::new(src);
+            // Layers may be split into multiple frames, e.g. when they have a table of contents
+            decoder.multiple_members(true);
+            Ok(Box::new(tokio::io::BufReader::new(decoder)))
+        }
         oci_image::MediaType::ImageLayer => Ok(Box::new(src)),
         o => Err(anyhow::anyhow!("Unhandled layer type: {}", o)),
     }
@@ -200,13 +223,13 @@ fn new_async_decompressor<'a>(
 
 /// A wrapper for [`get_blob`] which fetches a layer and decompresses it.
 pub(crate) async fn fetch_layer_decompress<'a>(
-    proxy: &'a mut ImageProxy,
-    img: &OpenedImage,
+    source: &'a ImageSource,
     manifest: &oci_image::ImageManifest,
     layer: &'a oci_image::Descriptor,
-    progress: Option<&'a Sender<Option<store::LayerProgress>>>,
+    progress: Option<&'a (dyn Fn(LayerProgress) + Send + Sync)>,
     layer_info: Option<&Vec<containers_image_proxy::ConvertedLayerInfo>>,
     transport_src: Transport,
+    staging: Option<&'a BlobStaging>,
 ) -> Result<(
     Box<dyn AsyncBufRead + Send + Unpin>,
     impl Future<Output = Result<()>> + 'a,
@@ -226,15 +249,32 @@ pub(crate) async fn fetch_layer_decompress<'a>(
             })?;
             size = layer_blob.size;
             media_type = &layer_blob.media_type;
-            (blob, driver) = proxy
-                .get_blob(img, layer_blob.digest.as_str(), size as u64)
+            (blob, driver) = source
+                .get_blob(layer_blob.digest.as_str(), size as u64)
                 .await?;
         }
         _ => {
             size = layer.size();
             media_type = layer.media_type();
-            (blob, driver) = proxy
-                .get_blob(img, layer.digest().as_str(), size as u64)
+            if let Some(staging) = staging {
+                let f = staging
+                    .fetch(source, layer.digest(), size as u64, |fetched| {
+                        if let Some(progress) = progress {
+                            progress(LayerProgress {
+                                layer_index,
+                                fetched,
+                                total: size as u64,
+                            });
+                        }
+                    })
+                    .await?;
+                let blob = tokio::io::BufReader::new(tokio::fs::File::from_std(f));
+                let blob = new_async_decompressor(media_type, blob)?;
+                let driver = futures_util::future::ok(());
+                return Ok((blob, Either::Right(Either::Right(driver))));
+            }
+            (blob, driver) = source
+                .get_blob(layer.digest().as_str(), size as u64)
                 .await?;
         }
     };
@@ -250,7 +290,7 @@ pub(crate) async fn fetch_layer_decompress<'a>(
                     fetched: *fetched,
                     total: size as u64,
                 };
-                progress.send_replace(Some(status));
+                progress(status);
             }
         };
         let reader = new_async_decompressor(media_type, readprogress)?;
@@ -258,6 +298,6 @@ pub(crate) async fn fetch_layer_decompress<'a>(
         Ok((reader, Either::Left(driver)))
     } else {
         let blob = new_async_decompressor(media_type, blob)?;
-        Ok((blob, Either::Right(driver)))
+        Ok((blob, Either::Right(Either::Left(driver))))
     }
 }
@@ -6,7 +6,9 @@ use camino::Utf8Path;
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
 use containers_image_proxy::oci_spec;
-use std::io::{BufReader, BufWriter};
+use oci_spec::image::MediaType;
+use ocidir::LayerCompression;
+use std::io::{BufReader, BufWriter, Read};
 
 /// Given an OSTree container image reference, update the detached metadata (e.g. GPG signature)
 /// while preserving all other container image metadata.
@@ -29,7 +31,7 @@ pub async fn update_detached_metadata(
     };
 
     // Full copy of the source image
-    let pulled_digest: String = skopeo::copy(src, &tempsrc_ref, None)
+    let pulled_digest: String = skopeo::copy(src, &tempsrc_ref, None, false)
         .await
         .context("Creating temporary copy to OCI dir")?;
 
@@ -72,9 +74,20 @@ pub async fn update_detached_metadata(
         // Create a new layer
         let out_layer = {
             // Create tar streams for source and destination
+            // Preserve the compression type of the original layer
             let src_layer = BufReader::new(tempsrc.read_blob(commit_layer)?);
-            let mut src_layer = flate2::read::GzDecoder::new(src_layer);
-            let mut out_layer = BufWriter::new(tempsrc.create_raw_layer(None)?);
+            let (mut src_layer, compression): (Box<dyn Read>, _) = match commit_layer.media_type() {
+                MediaType::ImageLayerZstd => (
+                    Box::new(zstd::stream::read::Decoder::with_buffer(src_layer)?),
+                    LayerCompression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL),
+                ),
+                MediaType::ImageLayer => (Box::new(src_layer), LayerCompression::None),
+                _ => (
+                    Box::new(flate2::read::GzDecoder::new(src_layer)),
+                    LayerCompression::default(),
+                ),
+            };
+            let mut out_layer = BufWriter::new(tempsrc.create_raw_layer_with(compression)?);
 
             // Process the tar stream and inject our new detached metadata
             crate::tar::update_detached_metadata(
@@ -92,11 +105,7 @@ pub async fn update_detached_metadata(
         };
         // Get the diffid and descriptor for our new tar layer
         let out_layer_diffid = format!("sha256:{}", out_layer.uncompressed_sha256);
-        let out_layer_descriptor = out_layer
-            .descriptor()
-            .media_type(oci_spec::image::MediaType::ImageLayerGzip)
-            .build()
-            .unwrap(); // SAFETY: We pass all required fields
+        let out_layer_descriptor = out_layer.descriptor().build().unwrap(); // SAFETY: We pass all required fields
 
         // Splice it into both the manifest and config
         manifest.layers_mut()[commit_layer_idx] = out_layer_descriptor;
@@ -124,7 +133,7 @@ pub async fn update_detached_metadata(
 
     // Finally, copy the mutated image back to the target.  For chunked images,
     // because we only changed one layer, skopeo should know not to re-upload shared blobs.
-    crate::container::skopeo::copy(&tempsrc_ref, dest, None)
+    crate::container::skopeo::copy(&tempsrc_ref, dest, None, false)
         .await
         .context("Copying to destination")
 }
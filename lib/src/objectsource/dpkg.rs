@@ -0,0 +1,197 @@
+//! Content metadata from the dpkg database.
+
+use super::{find_file, Package};
+use crate::objectsource::ObjectSourceMeta;
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
+use ostree::gio;
+use std::rc::Rc;
+
+/// Locations of the dpkg database, relative to the root.  Besides the default,
+/// it may have been relocated to `/usr`, or have been translated along with
+/// the rest of `/var` to the tmpfiles.d factory directory.
+const DPKG_DIRS: &[&str] = &[
+    "usr/lib/sysimage/dpkg",
+    "usr/share/factory/var/lib/dpkg",
+    "var/lib/dpkg",
+];
+
+/// An installed package from the status file.
+#[derive(Debug, PartialEq, Eq)]
+struct DpkgPackage<'a> {
+    name: &'a str,
+    version: &'a str,
+    arch: &'a str,
+    /// The source package and its version
+    source: (&'a str, &'a str),
+}
+
+/// Parse the installed packages from the contents of the status file.
+fn parse_status(status: &str) -> Result<Vec<DpkgPackage<'_>>> {
+    let mut r = Vec::new();
+    for paragraph in status.split("\n\n") {
+        let mut fields = std::collections::HashMap::new();
+        for line in paragraph.lines() {
+            // Skip continuation lines of multi-line fields
+            if line.starts_with([' ', '\t']) || line.is_empty() {
+                continue;
+            }
+            let (k, v) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid line in status: {line}"))?;
+            fields.insert(k, v.trim());
+        }
+        let name = match fields.get("Package") {
+            Some(&v) => v,
+            None => continue,
+        };
+        let installed = fields
+            .get("Status")
+            .is_some_and(|s| s.split_whitespace().last() == Some("installed"));
+        if !installed {
+            continue;
+        }
+        let field = |k: &str| {
+            fields
+                .get(k)
+                .copied()
+                .ok_or_else(|| anyhow!("Missing {k} for {name}"))
+        };
+        let version = field("Version")?;
+        let arch = field("Architecture")?;
+        // The source may include its version if it differs, e.g. `Source: foo (1.2-1)`
+        let source = match fields.get("Source") {
+            Some(s) => match s.split_once(' ') {
+                Some((src, srcversion)) => (
+                    src,
+                    srcversion.trim_start_matches('(').trim_end_matches(')'),
+                ),
+                None => (*s, version),
+            },
+            None => (name, version),
+        };
+        r.push(DpkgPackage {
+            name,
+            version,
+            arch,
+            source,
+        });
+    }
+    Ok(r)
+}
+
+/// Read the packages from the dpkg database in the tree, if present.
+pub(super) fn packages(root: &gio::File) -> Result<Option<Vec<Package>>> {
+    let (dir, status) = match DPKG_DIRS
+        .iter()
+        .find_map(|&dir| find_file(root, &[&format!("{dir}/status")]).map(|f| (dir, f)))
+    {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let status = crate::ostree_manual::repo_file_read_to_string(&status)
+        .with_context(|| format!("Reading {dir}/status"))?;
+    let pkgs = parse_status(&status).with_context(|| format!("Parsing {dir}/status"))?;
+    let mut r = Vec::new();
+    for pkg in pkgs {
+        let DpkgPackage {
+            name,
+            version,
+            arch,
+            source: (src, srcversion),
+        } = pkg;
+        // Packages which are Multi-Arch: same have the architecture in the name
+        let list = find_file(
+            root,
+            &[
+                &format!("{dir}/info/{name}:{arch}.list"),
+                &format!("{dir}/info/{name}.list"),
+            ],
+        );
+        let paths = match list {
+            Some(f) => crate::ostree_manual::repo_file_read_to_string(&f)?
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(Utf8PathBuf::from)
+                .collect(),
+            None => Vec::new(),
+        };
+        r.push(Package {
+            meta: ObjectSourceMeta {
+                identifier: Rc::from(format!("{name}_{version}_{arch}")),
+                name: Rc::from(name),
+                srcid: Rc::from(format!("{src}_{srcversion}")),
+                // The database has no information about when packages changed
+                change_time_offset: 0,
+                change_frequency: 1,
+            },
+            paths,
+        });
+    }
+    Ok(Some(r))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "Package: bash
+Essential: yes
+Status: install ok installed
+Priority: required
+Architecture: amd64
+Version: 5.2.15-2+b2
+Description: GNU Bourne Again SHell
+ Bash is an sh-compatible command language interpreter.
+ .
+ Source: not a field
+
+Package: libc6
+Status: install ok installed
+Architecture: amd64
+Multi-Arch: same
+Source: glibc
+Version: 2.36-9
+
+Package: libssl3
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 3.0.9-1
+
+Package: libbz2-1.0
+Status: install ok installed
+Architecture: amd64
+Source: bzip2 (1.0.8-5)
+Version: 1.0.8-5+b1
+";
+
+    #[test]
+    fn test_parse_status() {
+        let pkgs = parse_status(STATUS).unwrap();
+        assert_eq!(
+            pkgs,
+            [
+                DpkgPackage {
+                    name: "bash",
+                    version: "5.2.15-2+b2",
+                    arch: "amd64",
+                    source: ("bash", "5.2.15-2+b2"),
+                },
+                DpkgPackage {
+                    name: "libc6",
+                    version: "2.36-9",
+                    arch: "amd64",
+                    source: ("glibc", "2.36-9"),
+                },
+                DpkgPackage {
+                    name: "libbz2-1.0",
+                    version: "1.0.8-5+b1",
+                    arch: "amd64",
+                    source: ("bzip2", "1.0.8-5"),
+                },
+            ]
+        );
+        assert!(parse_status("Package: foo\nStatus: install ok installed\n").is_err());
+        assert!(parse_status("garbage\n").is_err());
+    }
+}
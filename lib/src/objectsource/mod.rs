@@ -0,0 +1,347 @@
+//! Metadata about the source of an object: a component or package.
+//!
+//! This is used to help split up containers into distinct layers.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+use ostree::gio;
+use ostree::prelude::*;
+use serde::{Deserialize, Serialize, Serializer};
+
+mod dpkg;
+#[cfg(feature = "rpmdb")]
+mod rpm;
+
+/// The identifier of the component holding content which is not owned by any package.
+pub const UNPACKAGED_ID: &str = "ostree-ext-unpackaged";
+
+mod rcstr_serialize {
+    use serde::Deserializer;
+
+    use super::*;
+
+    pub(crate) fn serialize<S>(v: &Rc<str>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(v)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Rc<str>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        Ok(Rc::from(v.into_boxed_str()))
+    }
+}
+
+mod rcmap_serialize {
+    use serde::{Deserializer, Serializer};
+
+    use super::*;
+
+    pub(crate) fn serialize<S>(v: &ObjectMetaMap, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(v.iter().map(|(k, v)| (k, &**v)))
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<ObjectMetaMap, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = BTreeMap::<String, String>::deserialize(deserializer)?;
+        Ok(v.into_iter()
+            .map(|(k, v)| (k, Rc::from(v.into_boxed_str())))
+            .collect())
+    }
+}
+
+/// Identifier for content (e.g. package/layer).  Not necessarily human readable.
+/// For example in RPMs, this may be a full "NEVRA" i.e. name-epoch:version-release.architecture e.g. kernel-6.2-2.fc38.aarch64
+/// But that's not strictly required as this string should only live in memory and not be persisted.
+pub type ContentID = Rc<str>;
+
+/// Metadata about a component/package.
+#[derive(Debug, Eq, Deserialize, Serialize)]
+pub struct ObjectSourceMeta {
+    /// Unique identifier, does not need to be human readable, but can be.
+    #[serde(with = "rcstr_serialize")]
+    pub identifier: ContentID,
+    /// Just the name of the package (no version), needs to be human readable.
+    #[serde(with = "rcstr_serialize")]
+    pub name: Rc<str>,
+    /// Identifier for the *source* of this content; for example, if multiple binary
+    /// packages derive from a single git repository or source package.
+    #[serde(with = "rcstr_serialize")]
+    pub srcid: Rc<str>,
+    /// Unitless, relative offset of last change time.
+    /// One suggested way to generate this number is to have it be in units of hours or days
+    /// since the earliest changed item.
+    pub change_time_offset: u32,
+    /// Change frequency
+    pub change_frequency: u32,
+}
+
+impl PartialEq for ObjectSourceMeta {
+    fn eq(&self, other: &Self) -> bool {
+        *self.identifier == *other.identifier
+    }
+}
+
+impl Hash for ObjectSourceMeta {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.identifier.hash(state);
+    }
+}
+
+impl Borrow<str> for ObjectSourceMeta {
+    fn borrow(&self) -> &str {
+        &self.identifier
+    }
+}
+
+/// Maps from e.g. "bash" or "kernel" to metadata about that content
+pub type ObjectMetaSet = HashSet<ObjectSourceMeta>;
+
+/// Maps from an ostree content object digest to the `ContentSet` key.
+pub type ObjectMetaMap = BTreeMap<String, ContentID>;
+
+/// Grouping of metadata about an object.
+///
+/// This can be serialized, e.g. as JSON of the form
+/// `{"set": [{"identifier": "bash-5.2.15-3.fc38.x86_64", "name": "bash", ...}], "map": {"<checksum>": "bash-5.2.15-3.fc38.x86_64"}}`;
+/// use [`ObjectMeta::from_json`] to also validate it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ObjectMeta {
+    /// The set of object sources with their metadata.
+    pub set: ObjectMetaSet,
+    /// Mapping from content object to source.
+    #[serde(with = "rcmap_serialize")]
+    pub map: ObjectMetaMap,
+}
+
+impl ObjectMeta {
+    /// Parse serialized JSON metadata, verifying that every content object is
+    /// mapped to a source in the set.
+    pub fn from_json(r: impl std::io::Read) -> anyhow::Result<Self> {
+        let mut meta: Self = serde_json::from_reader(r)?;
+        for (checksum, contentid) in meta.map.iter_mut() {
+            let source = meta.set.get(&**contentid).ok_or_else(|| {
+                anyhow::anyhow!("Object {checksum} has unknown source {contentid}")
+            })?;
+            // Share the identifier with the set
+            *contentid = Rc::clone(&source.identifier);
+        }
+        Ok(meta)
+    }
+}
+
+/// A package found in a package database in a commit.
+#[derive(Debug)]
+struct Package {
+    meta: ObjectSourceMeta,
+    /// The absolute paths of the files owned by the package
+    paths: Vec<Utf8PathBuf>,
+}
+
+/// Find a regular file in the tree of a commit.
+fn find_file(root: &gio::File, paths: &[&str]) -> Option<ostree::RepoFile> {
+    paths.iter().find_map(|path| {
+        let f = root.resolve_relative_path(path);
+        let ty = f.query_file_type(
+            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+            gio::Cancellable::NONE,
+        );
+        (ty == gio::FileType::Regular).then(|| f.downcast::<ostree::RepoFile>().unwrap())
+    })
+}
+
+/// Find the symbolic links in the root directory of a commit, such as `/bin` on systems
+/// with a merged `/usr`, and return their targets relative to the root.
+fn root_symlinks(root: &gio::File) -> Result<HashMap<String, Utf8PathBuf>> {
+    let e = root.enumerate_children(
+        "standard::name,standard::type,standard::symlink-target",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        gio::Cancellable::NONE,
+    )?;
+    let mut r = HashMap::new();
+    for info in e {
+        let info = info?;
+        if info.file_type() != gio::FileType::SymbolicLink {
+            continue;
+        }
+        let name = info.name();
+        let target = info.symlink_target();
+        if let (Some(name), Some(target)) =
+            (name.to_str(), target.as_deref().and_then(|t| t.to_str()))
+        {
+            r.insert(
+                name.to_string(),
+                Utf8PathBuf::from(target.trim_start_matches('/')),
+            );
+        }
+    }
+    Ok(r)
+}
+
+/// Return the path in an ostree commit of a file installed by a package.  Files in
+/// `/etc` are stored in `/usr/etc`, and paths below a symbolic link in the root such as
+/// `/bin/bash` are resolved through it, as package databases may use either form.
+fn path_in_commit(root_symlinks: &HashMap<String, Utf8PathBuf>, path: &Utf8Path) -> Utf8PathBuf {
+    let mut components = path.strip_prefix("/").unwrap_or(path).components();
+    let first = match components.next() {
+        Some(c) => c.as_str(),
+        None => return path.to_owned(),
+    };
+    let top = match first {
+        "etc" => Utf8Path::new("usr/etc"),
+        name => match root_symlinks.get(name) {
+            Some(target) => target.as_path(),
+            None => return path.to_owned(),
+        },
+    };
+    let mut r = Utf8PathBuf::from("/");
+    r.push(top);
+    let rest = components.as_path();
+    if !rest.as_str().is_empty() {
+        r.push(rest);
+    }
+    r
+}
+
+impl ObjectMeta {
+    /// Generate metadata from the package database (RPM or dpkg) in the commit.
+    /// Reading the RPM database requires the `rpmdb` feature.
+    /// Content which is not owned by any package is assigned to [`UNPACKAGED_ID`].
+    #[context("Generating content metadata for {rev}")]
+    pub fn from_commit(repo: &ostree::Repo, rev: &str) -> Result<Self> {
+        let root = repo.read_commit(rev, gio::Cancellable::NONE)?.0;
+        #[cfg(feature = "rpmdb")]
+        let packages = rpm::packages(&root)?;
+        #[cfg(not(feature = "rpmdb"))]
+        let packages = None;
+        let packages = match packages {
+            Some(p) => p,
+            None => match dpkg::packages(&root)? {
+                Some(p) => p,
+                None if cfg!(feature = "rpmdb") => {
+                    anyhow::bail!("No supported package database found")
+                }
+                None => anyhow::bail!(
+                    "No supported package database found; reading the RPM database requires the rpmdb feature"
+                ),
+            },
+        };
+        Self::from_packages(repo, rev, packages)
+    }
+
+    /// Map the content objects of the commit to the packages owning their paths.
+    fn from_packages(repo: &ostree::Repo, rev: &str, packages: Vec<Package>) -> Result<Self> {
+        let root = repo.read_commit(rev, gio::Cancellable::NONE)?.0;
+        let root_symlinks = root_symlinks(&root)?;
+        let content = crate::chunking::Chunking::new(repo, rev)?.remainder.content;
+        let objects: HashMap<&Utf8Path, &str> = content
+            .iter()
+            .flat_map(|(checksum, (_, paths))| paths.iter().map(|p| (p.as_path(), &**checksum)))
+            .collect();
+        let mut ret = ObjectMeta::default();
+        for pkg in packages {
+            for path in pkg.paths.iter() {
+                if let Some(&checksum) = objects.get(path_in_commit(&root_symlinks, path).as_path())
+                {
+                    // If multiple packages provide the same content, the first one wins.
+                    ret.map
+                        .entry(checksum.to_string())
+                        .or_insert_with(|| Rc::clone(&pkg.meta.identifier));
+                }
+            }
+            ret.set.insert(pkg.meta);
+        }
+        let unpackaged: ContentID = Rc::from(UNPACKAGED_ID);
+        let mut has_unpackaged = false;
+        for checksum in content.keys() {
+            if !ret.map.contains_key(&**checksum) {
+                ret.map.insert(checksum.to_string(), Rc::clone(&unpackaged));
+                has_unpackaged = true;
+            }
+        }
+        if has_unpackaged {
+            ret.set.insert(ObjectSourceMeta {
+                identifier: Rc::clone(&unpackaged),
+                name: Rc::clone(&unpackaged),
+                srcid: unpackaged,
+                change_time_offset: u32::MAX,
+                change_frequency: u32::MAX,
+            });
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_meta_json() {
+        let meta = ObjectMeta {
+            set: [ObjectSourceMeta {
+                identifier: Rc::from("bash-5.2.15-3.fc38.x86_64"),
+                name: Rc::from("bash"),
+                srcid: Rc::from("bash-5.2.15-3.fc38.src"),
+                change_time_offset: 1,
+                change_frequency: 2,
+            }]
+            .into_iter()
+            .collect(),
+            map: [(
+                "41af286d".to_string(),
+                Rc::from("bash-5.2.15-3.fc38.x86_64"),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let buf = serde_json::to_vec(&meta).unwrap();
+        let parsed = ObjectMeta::from_json(buf.as_slice()).unwrap();
+        assert_eq!(parsed.set, meta.set);
+        assert_eq!(parsed.map, meta.map);
+
+        let bad = br#"{"set": [], "map": {"41af286d": "bash"}}"#;
+        assert!(ObjectMeta::from_json(&bad[..]).is_err());
+    }
+
+    #[test]
+    fn test_path_in_commit() {
+        let root_symlinks = [("bin", "usr/bin"), ("lib64", "usr/lib64")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), Utf8PathBuf::from(v)))
+            .collect();
+        for (path, expected) in [
+            ("/usr/bin/bash", "/usr/bin/bash"),
+            ("/etc/passwd", "/usr/etc/passwd"),
+            ("/etc", "/usr/etc"),
+            ("/etcfoo", "/etcfoo"),
+            ("/bin/bash", "/usr/bin/bash"),
+            ("/bin", "/usr/bin"),
+            ("/lib64/libc.so.6", "/usr/lib64/libc.so.6"),
+            ("/lib/libc.so.6", "/lib/libc.so.6"),
+            ("/binfoo", "/binfoo"),
+            ("/", "/"),
+        ] {
+            assert_eq!(
+                path_in_commit(&root_symlinks, Utf8Path::new(path)),
+                expected,
+                "{path}"
+            );
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Content metadata from the RPM database.
+//!
+//! The sqlite database stores each package as a header blob; this parses the
+//! small subset of the header format we need.
+
+use super::{find_file, Package};
+use crate::objectsource::ObjectSourceMeta;
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8PathBuf;
+use ostree::gio;
+use ostree::prelude::InputStreamExtManual;
+use rusqlite::OpenFlags;
+use std::rc::Rc;
+
+/// Locations of the sqlite RPM database, relative to the root.
+const RPMDB_PATHS: &[&str] = &[
+    "usr/lib/sysimage/rpm/rpmdb.sqlite",
+    "usr/share/rpm/rpmdb.sqlite",
+];
+
+const RPMTAG_NAME: u32 = 1000;
+const RPMTAG_VERSION: u32 = 1001;
+const RPMTAG_RELEASE: u32 = 1002;
+const RPMTAG_EPOCH: u32 = 1003;
+const RPMTAG_BUILDTIME: u32 = 1006;
+const RPMTAG_ARCH: u32 = 1022;
+const RPMTAG_SOURCERPM: u32 = 1044;
+const RPMTAG_CHANGELOGTIME: u32 = 1080;
+const RPMTAG_DIRINDEXES: u32 = 1116;
+const RPMTAG_BASENAMES: u32 = 1117;
+const RPMTAG_DIRNAMES: u32 = 1118;
+
+const RPM_INT32_TYPE: u32 = 4;
+const RPM_STRING_TYPE: u32 = 6;
+const RPM_STRING_ARRAY_TYPE: u32 = 8;
+const RPM_I18NSTRING_TYPE: u32 = 9;
+
+/// An entry in the index of a header.
+#[derive(Debug)]
+struct IndexEntry {
+    tag: u32,
+    ty: u32,
+    offset: usize,
+    count: usize,
+}
+
+/// A header blob: an index of tags, followed by the data they refer to.
+#[derive(Debug)]
+struct Header<'a> {
+    index: Vec<IndexEntry>,
+    data: &'a [u8],
+}
+
+fn be_u32(buf: &[u8], pos: usize) -> Result<u32> {
+    pos.checked_add(4)
+        .and_then(|end| buf.get(pos..end))
+        .map(|v| u32::from_be_bytes(v.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("Truncated header"))
+}
+
+impl<'a> Header<'a> {
+    fn parse(blob: &'a [u8]) -> Result<Self> {
+        let il = be_u32(blob, 0)? as usize;
+        let dl = be_u32(blob, 4)? as usize;
+        let data_start = il
+            .checked_mul(16)
+            .and_then(|v| v.checked_add(8))
+            .ok_or_else(|| anyhow!("Invalid header index length {il}"))?;
+        let data = data_start
+            .checked_add(dl)
+            .and_then(|end| blob.get(data_start..end))
+            .ok_or_else(|| anyhow!("Truncated header"))?;
+        let index = (0..il)
+            .map(|i| {
+                let pos = 8 + i * 16;
+                Ok(IndexEntry {
+                    tag: be_u32(blob, pos)?,
+                    ty: be_u32(blob, pos + 4)?,
+                    offset: be_u32(blob, pos + 8)? as usize,
+                    count: be_u32(blob, pos + 12)? as usize,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { index, data })
+    }
+
+    fn entry(&self, tag: u32, types: &[u32]) -> Result<Option<&IndexEntry>> {
+        match self.index.iter().find(|e| e.tag == tag) {
+            Some(e) if types.contains(&e.ty) => Ok(Some(e)),
+            Some(e) => anyhow::bail!("Unexpected type {} for tag {tag}", e.ty),
+            None => Ok(None),
+        }
+    }
+
+    fn strings(&self, tag: u32, types: &[u32]) -> Result<Vec<&'a str>> {
+        let e = match self.entry(tag, types)? {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+        let mut pos = e.offset;
+        (0..e.count)
+            .map(|_| {
+                let rest = self
+                    .data
+                    .get(pos..)
+                    .ok_or_else(|| anyhow!("Truncated header"))?;
+                let len = rest
+                    .iter()
+                    .position(|&c| c == 0)
+                    .ok_or_else(|| anyhow!("Unterminated string in header"))?;
+                pos += len + 1;
+                std::str::from_utf8(&rest[..len]).with_context(|| format!("Tag {tag}"))
+            })
+            .collect()
+    }
+
+    fn string(&self, tag: u32) -> Result<Option<&'a str>> {
+        let v = self.strings(tag, &[RPM_STRING_TYPE, RPM_I18NSTRING_TYPE])?;
+        Ok(v.into_iter().next())
+    }
+
+    fn u32s(&self, tag: u32) -> Result<Vec<u32>> {
+        let e = match self.entry(tag, &[RPM_INT32_TYPE])? {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+        (0..e.count)
+            .map(|i| be_u32(self.data, e.offset + i * 4))
+            .collect()
+    }
+
+    fn u32(&self, tag: u32) -> Result<Option<u32>> {
+        Ok(self.u32s(tag)?.into_iter().next())
+    }
+}
+
+/// A package parsed from a header.
+#[derive(Debug)]
+struct RpmPackage {
+    nevra: String,
+    name: String,
+    sourcerpm: Option<String>,
+    buildtime: u32,
+    changelogs: usize,
+    paths: Vec<Utf8PathBuf>,
+}
+
+impl RpmPackage {
+    fn from_header(h: &Header) -> Result<Self> {
+        let name = h
+            .string(RPMTAG_NAME)?
+            .ok_or_else(|| anyhow!("Missing package name"))?;
+        let version = h.string(RPMTAG_VERSION)?.unwrap_or_default();
+        let release = h.string(RPMTAG_RELEASE)?.unwrap_or_default();
+        let epoch = h
+            .u32(RPMTAG_EPOCH)?
+            .map(|e| format!("{e}:"))
+            .unwrap_or_default();
+        let mut nevra = format!("{name}-{epoch}{version}-{release}");
+        if let Some(arch) = h.string(RPMTAG_ARCH)? {
+            nevra.push('.');
+            nevra.push_str(arch);
+        }
+        let dirnames = h.strings(RPMTAG_DIRNAMES, &[RPM_STRING_ARRAY_TYPE])?;
+        let basenames = h.strings(RPMTAG_BASENAMES, &[RPM_STRING_ARRAY_TYPE])?;
+        let dirindexes = h.u32s(RPMTAG_DIRINDEXES)?;
+        if basenames.len() != dirindexes.len() {
+            anyhow::bail!("Mismatched file lists in {nevra}");
+        }
+        let paths = basenames
+            .iter()
+            .zip(dirindexes)
+            .map(|(base, i)| {
+                let dir = dirnames
+                    .get(i as usize)
+                    .ok_or_else(|| anyhow!("Invalid directory index {i} in {nevra}"))?;
+                Ok(Utf8PathBuf::from(format!("{dir}{base}")))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            name: name.to_string(),
+            sourcerpm: h.string(RPMTAG_SOURCERPM)?.map(ToOwned::to_owned),
+            buildtime: h.u32(RPMTAG_BUILDTIME)?.unwrap_or_default(),
+            changelogs: h.u32s(RPMTAG_CHANGELOGTIME)?.len(),
+            nevra,
+            paths,
+        })
+    }
+}
+
+/// Convert parsed packages; the change time is the build time in hours since
+/// the oldest package was built, and the change frequency is the number of
+/// changelog entries.
+fn to_packages(pkgs: Vec<RpmPackage>) -> Vec<Package> {
+    let oldest = pkgs.iter().map(|p| p.buildtime).min().unwrap_or_default();
+    pkgs.into_iter()
+        .map(|p| {
+            let identifier: Rc<str> = Rc::from(p.nevra);
+            let srcid = p
+                .sourcerpm
+                .as_deref()
+                .map(|s| Rc::from(s.trim_end_matches(".rpm")))
+                .unwrap_or_else(|| Rc::clone(&identifier));
+            Package {
+                meta: ObjectSourceMeta {
+                    identifier,
+                    name: Rc::from(p.name),
+                    srcid,
+                    change_time_offset: (p.buildtime - oldest) / (60 * 60),
+                    change_frequency: p.changelogs.max(1).try_into().unwrap_or(u32::MAX),
+                },
+                paths: p.paths,
+            }
+        })
+        .collect()
+}
+
+/// Read the packages from the RPM database in the tree, if present.
+pub(super) fn packages(root: &gio::File) -> Result<Option<Vec<Package>>> {
+    let f = match find_file(root, RPMDB_PATHS) {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    // sqlite needs a real file
+    let mut tmpf = tempfile::NamedTempFile::new()?;
+    let mut src = crate::ostree_manual::repo_file_read(&f)?.into_read();
+    std::io::copy(&mut src, &mut tmpf).context("Copying rpmdb")?;
+    let uri = format!("file:{}?immutable=1", tmpf.path().display());
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI;
+    let conn = rusqlite::Connection::open_with_flags(uri, flags).context("Opening rpmdb")?;
+    let mut stmt = conn.prepare("SELECT blob FROM Packages ORDER BY hnum")?;
+    let mut rows = stmt.query([])?;
+    let mut pkgs = Vec::new();
+    while let Some(row) = rows.next()? {
+        let blob = row.get_ref(0)?.as_blob()?;
+        let header = Header::parse(blob)?;
+        pkgs.push(RpmPackage::from_header(&header)?);
+    }
+    Ok(Some(to_packages(pkgs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a header blob from (tag, type, count, data) entries.
+    fn build_header(entries: &[(u32, u32, u32, &[u8])]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut data = Vec::new();
+        for &(tag, ty, count, v) in entries {
+            // Integers are aligned
+            while ty == RPM_INT32_TYPE && data.len() % 4 != 0 {
+                data.push(0);
+            }
+            for n in [tag, ty, data.len() as u32, count] {
+                index.extend_from_slice(&n.to_be_bytes());
+            }
+            data.extend_from_slice(v);
+        }
+        let mut r = Vec::new();
+        r.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        r.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        r.extend(index);
+        r.extend(data);
+        r
+    }
+
+    #[test]
+    fn test_parse_header() -> Result<()> {
+        let blob = build_header(&[
+            (RPMTAG_NAME, RPM_STRING_TYPE, 1, b"bash\0"),
+            (RPMTAG_VERSION, RPM_STRING_TYPE, 1, b"5.2.15\0"),
+            (RPMTAG_RELEASE, RPM_STRING_TYPE, 1, b"3.fc38\0"),
+            (RPMTAG_EPOCH, RPM_INT32_TYPE, 1, &1u32.to_be_bytes()),
+            (RPMTAG_BUILDTIME, RPM_INT32_TYPE, 1, &7200u32.to_be_bytes()),
+            (RPMTAG_ARCH, RPM_STRING_TYPE, 1, b"x86_64\0"),
+            (
+                RPMTAG_SOURCERPM,
+                RPM_STRING_TYPE,
+                1,
+                b"bash-5.2.15-3.fc38.src.rpm\0",
+            ),
+            (
+                RPMTAG_DIRNAMES,
+                RPM_STRING_ARRAY_TYPE,
+                2,
+                b"/etc/\0/usr/bin/\0",
+            ),
+            (
+                RPMTAG_BASENAMES,
+                RPM_STRING_ARRAY_TYPE,
+                3,
+                b"bashrc\0bash\0sh\0",
+            ),
+            (
+                RPMTAG_DIRINDEXES,
+                RPM_INT32_TYPE,
+                3,
+                &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1],
+            ),
+        ]);
+        let header = Header::parse(&blob)?;
+        let pkg = RpmPackage::from_header(&header)?;
+        assert_eq!(pkg.nevra, "bash-1:5.2.15-3.fc38.x86_64");
+        assert_eq!(pkg.buildtime, 7200);
+        assert_eq!(pkg.changelogs, 0);
+        assert_eq!(pkg.paths, ["/etc/bashrc", "/usr/bin/bash", "/usr/bin/sh"]);
+
+        let blob = build_header(&[
+            (RPMTAG_NAME, RPM_STRING_TYPE, 1, b"filesystem\0"),
+            (RPMTAG_BUILDTIME, RPM_INT32_TYPE, 1, &0u32.to_be_bytes()),
+        ]);
+        let other = RpmPackage::from_header(&Header::parse(&blob)?)?;
+        assert_eq!(other.nevra, "filesystem--");
+        let pkgs = to_packages(vec![pkg, other]);
+        assert_eq!(&*pkgs[0].meta.srcid, "bash-5.2.15-3.fc38.src");
+        assert_eq!(pkgs[0].meta.change_time_offset, 2);
+        assert_eq!(pkgs[0].meta.change_frequency, 1);
+        assert_eq!(pkgs[1].meta.srcid, pkgs[1].meta.identifier);
+
+        // Wrong types and truncated data are errors
+        let blob = build_header(&[(RPMTAG_NAME, RPM_INT32_TYPE, 1, &0u32.to_be_bytes())]);
+        assert!(RpmPackage::from_header(&Header::parse(&blob)?).is_err());
+        let blob = build_header(&[(RPMTAG_NAME, RPM_STRING_TYPE, 1, b"bash")]);
+        assert!(RpmPackage::from_header(&Header::parse(&blob)?).is_err());
+        assert!(Header::parse(&blob[..blob.len() - 1]).is_err());
+        Ok(())
+    }
+}
@@ -32,3 +32,43 @@ pub fn repo_file_read_to_string(f: &ostree::RepoFile) -> anyhow::Result<String>
     s.read_to_string(&mut r)?;
     Ok(r)
 }
+
+/// Equivalent of `ostree_sysroot_cleanup_prune_repo()`, which is not bound.  This prunes
+/// the objects of the system repository which are not reachable from a ref or a deployment,
+/// returning the total number of objects, the number pruned and their size.
+#[allow(unsafe_code)]
+pub fn sysroot_cleanup_prune_repo(
+    sysroot: &ostree::Sysroot,
+    flags: ostree::RepoPruneFlags,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<(i32, i32, u64), glib::Error> {
+    use glib::translate::*;
+    unsafe {
+        let reachable = ostree::ffi::ostree_repo_traverse_new_reachable();
+        let mut options = ostree::ffi::OstreeRepoPruneOptions {
+            flags: flags.into_glib(),
+            reachable,
+            unused_bools: [0; 6],
+            unused_ints: [0; 6],
+            unused_ptrs: [ptr::null_mut(); 7],
+        };
+        let mut objects_total = 0;
+        let mut objects_pruned = 0;
+        let mut pruned_size = 0;
+        let mut error = ptr::null_mut();
+        ostree::ffi::ostree_sysroot_cleanup_prune_repo(
+            sysroot.to_glib_none().0,
+            &mut options,
+            &mut objects_total,
+            &mut objects_pruned,
+            &mut pruned_size,
+            cancellable.to_glib_none().0,
+            &mut error,
+        );
+        glib::ffi::g_hash_table_unref(reachable);
+        if !error.is_null() {
+            return Err(from_glib_full(error));
+        }
+        Ok((objects_total, objects_pruned, pruned_size))
+    }
+}
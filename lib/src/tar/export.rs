@@ -331,9 +331,9 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         Ok(())
     }
 
-    /// Export xattrs to the tar stream, return whether content was written.
-    #[context("Writing xattrs")]
-    fn append_xattrs(&mut self, checksum: &str, xattrs: &glib::Variant) -> Result<bool> {
+    /// Write xattrs content into a separate `.file-xattrs` object if not already
+    /// written, returning its path.
+    fn append_xattrs_content(&mut self, xattrs: &glib::Variant) -> Result<Utf8PathBuf> {
         let xattrs_data = xattrs.data_as_bytes();
         let xattrs_data = xattrs_data.as_ref();
 
@@ -343,12 +343,18 @@ impl<'a, W: std::io::Write> OstreeTarWriter<'a, W> {
         };
 
         let path = v1_xattrs_object_path(&xattrs_checksum);
-        // Write xattrs content into a separate `.file-xattrs` object.
         if !self.wrote_xattrs.contains(&xattrs_checksum) {
             let inserted = self.wrote_xattrs.insert(xattrs_checksum);
             debug_assert!(inserted);
             self.append_default_data(&path, xattrs_data)?;
         }
+        Ok(path)
+    }
+
+    /// Export xattrs to the tar stream, return whether content was written.
+    #[context("Writing xattrs")]
+    fn append_xattrs(&mut self, checksum: &str, xattrs: &glib::Variant) -> Result<bool> {
+        let path = self.append_xattrs_content(xattrs)?;
         // Write a `.file-xattrs-link` which links the file object to
         // the corresponding detached xattrs.
         {
@@ -625,6 +631,38 @@ pub(crate) fn export_chunk<W: std::io::Write>(
     write_chunk(writer, chunk)
 }
 
+/// Output a chunk to a tar stream, invoking `boundary` on the underlying writer before
+/// each content object (with its checksum) and once more at the end (with `None`).
+///
+/// The extended attributes of all objects are written before the first object, so
+/// that the stream can be split at these points and, once the leading piece has been
+/// imported, each other piece imported independently.
+pub(crate) fn export_chunk_split<W: std::io::Write>(
+    repo: &ostree::Repo,
+    commit: &str,
+    chunk: chunking::ChunkMapping,
+    out: &mut tar::Builder<W>,
+    mut boundary: impl FnMut(&mut W, Option<&str>) -> Result<()>,
+) -> Result<()> {
+    let opts = ExportOptions;
+    let writer = &mut OstreeTarWriter::new(repo, commit, out, opts)?;
+    writer.write_repo_structure()?;
+    for checksum in chunk.keys() {
+        let (_, _, xattrs) = repo.load_file(checksum, gio::Cancellable::NONE)?;
+        writer.append_xattrs_content(&xattrs)?;
+    }
+    for (checksum, (_size, paths)) in chunk.into_iter() {
+        boundary(writer.out.get_mut(), Some(checksum.borrow()))?;
+        let (objpath, h) = writer.append_content(checksum.borrow())?;
+        for path in paths.iter() {
+            let path = path_for_tar_v1(path);
+            let h = h.clone();
+            writer.append_content_hardlink(&objpath, h, path)?;
+        }
+    }
+    boundary(writer.out.get_mut(), None)
+}
+
 /// Output the last chunk in a chunking.
 #[context("Exporting final chunk")]
 pub(crate) fn export_final_chunk<W: std::io::Write>(
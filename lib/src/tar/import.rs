@@ -755,6 +755,22 @@ impl Importer {
         ostree::create_directory_metadata(&finfo, None)
     }
 
+    /// Record objects which are already present in the repository as part of the object set.
+    pub(crate) fn extend_object_set(&mut self, objects: impl IntoIterator<Item = String>) {
+        match &mut self.data {
+            ImporterMode::ObjectSet(s) => s.extend(objects),
+            ImporterMode::Commit(_) => unreachable!(),
+        }
+    }
+
+    /// Return whether the given content object was imported as part of the object set.
+    pub(crate) fn object_set_contains(&self, checksum: &str) -> bool {
+        match &self.data {
+            ImporterMode::ObjectSet(s) => s.contains(checksum),
+            ImporterMode::Commit(_) => unreachable!(),
+        }
+    }
+
     pub(crate) fn finish_import_object_set(self) -> Result<String> {
         let objset = match self.data {
             ImporterMode::Commit(_) => unreachable!(),
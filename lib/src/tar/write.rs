@@ -62,8 +62,58 @@ pub struct WriteTarOptions {
     /// Enable SELinux labeling from the base commit
     /// Requires the `base` option.
     pub selinux: bool,
+    /// If set, the expected digest (of the form `sha256:<hex>`) of the tar stream, such
+    /// as the `diff_id` of a container image layer.  On a mismatch an error is returned,
+    /// and the ref is not written.
+    pub expected_digest: Option<String>,
+    /// How to handle content outside of `/usr` and `/etc`.
+    pub content_policy: ContentPolicy,
 }
 
+/// How to handle content in a tar stream outside of `/usr` and `/etc`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ContentPolicy {
+    /// Fail, listing the offending paths.
+    Error,
+    /// Discard the content; the number of discarded paths is recorded in
+    /// [`WriteTarResult::filtered`].
+    #[default]
+    Warn,
+    /// Translate content in `/var` into a seed tree in `/usr/share/factory/var`,
+    /// along with `systemd-tmpfiles` entries in `/usr/lib/tmpfiles.d` which create
+    /// it on boot.  Any other content is discarded as with [`ContentPolicy::Warn`].
+    Translate,
+}
+
+impl TryFrom<&str> for ContentPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        Ok(match value {
+            "error" => Self::Error,
+            "warn" => Self::Warn,
+            "translate" => Self::Translate,
+            o => return Err(anyhow!("Unknown content policy '{}'", o)),
+        })
+    }
+}
+
+impl std::fmt::Display for ContentPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ContentPolicy::Error => "error",
+            ContentPolicy::Warn => "warn",
+            ContentPolicy::Translate => "translate",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The directory holding the seed tree for translated `/var` content.
+const VAR_FACTORY_DIR: &str = "usr/share/factory/var";
+/// The maximum number of offending paths listed by [`ContentPolicy::Error`].
+const MAX_LISTED_PATHS: usize = 20;
+
 /// The result of writing a tar stream.
 ///
 /// This includes some basic data on the number of files that were filtered
@@ -148,9 +198,96 @@ fn normalize_validate_path(path: &Utf8Path) -> Result<NormalizedPathResult<'_>>
     Ok(NormalizedPathResult::Normal(ret))
 }
 
+/// Return a (validated) path relative to its toplevel directory; e.g. `/var/lib/foo` => `lib/foo`.
+fn path_in_toplevel(path: &Utf8Path) -> Utf8PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Utf8Component::Normal(_)))
+        .skip(1)
+        .collect()
+}
+
+/// Escape a path for use in a `tmpfiles.d` entry.
+fn tmpfiles_escape(path: &Utf8Path) -> String {
+    let mut r = String::new();
+    for c in path.as_str().chars() {
+        match c {
+            '%' => r.push_str("%%"),
+            '\\' => r.push_str("\\x5c"),
+            c if c.is_whitespace() || c.is_control() => {
+                let mut buf = [0u8; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    r.push_str(&format!("\\x{b:02x}"));
+                }
+            }
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+/// Translates content in `/var`; see [`ContentPolicy::Translate`].
+#[derive(Debug, Default)]
+struct VarTranslator {
+    /// The generated `tmpfiles.d` entries
+    tmpfiles: String,
+}
+
+impl VarTranslator {
+    /// Return the path in the seed tree for a path in `/var`, recording a `tmpfiles.d`
+    /// entry for it.  Returns `None` if the entry cannot be translated.
+    fn translate(
+        &mut self,
+        header: &tar::Header,
+        path: &Utf8Path,
+        link_target: Option<&Utf8Path>,
+    ) -> Result<Option<Utf8PathBuf>> {
+        use std::fmt::Write;
+        let rel = path_in_toplevel(path);
+        let varpath = tmpfiles_escape(&Utf8Path::new("/var").join(&rel));
+        let factorypath = Utf8Path::new(".").join(VAR_FACTORY_DIR).join(&rel);
+        match header.entry_type() {
+            tar::EntryType::Directory => {
+                let mode = header.mode()? & 0o7777;
+                let (uid, gid) = (header.uid()?, header.gid()?);
+                writeln!(self.tmpfiles, "d {varpath} {mode:04o} {uid} {gid} - -")?;
+            }
+            tar::EntryType::Regular | tar::EntryType::Link => {
+                let src = Utf8Path::new("/").join(VAR_FACTORY_DIR).join(&rel);
+                let src = tmpfiles_escape(&src);
+                writeln!(self.tmpfiles, "C {varpath} - - - - {src}")?;
+            }
+            tar::EntryType::Symlink => {
+                let target = link_target.ok_or_else(|| anyhow!("Invalid symlink {path}"))?;
+                let target = tmpfiles_escape(target);
+                writeln!(self.tmpfiles, "L {varpath} - - - - {target}")?;
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(factorypath))
+    }
+
+    /// Write the generated `tmpfiles.d` entries, if any.
+    fn finish(self, dest: &mut tar::Builder<impl std::io::Write>) -> Result<()> {
+        if self.tmpfiles.is_empty() {
+            return Ok(());
+        }
+        // Layers may each have translated content, so name the file by its content.
+        let digest = hex::encode(openssl::sha::sha256(self.tmpfiles.as_bytes()));
+        let path = format!("./usr/lib/tmpfiles.d/ostree-ext-var-{}.conf", &digest[..16]);
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_mode(0o644);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_size(self.tmpfiles.len() as u64);
+        dest.append_data(&mut h, path, self.tmpfiles.as_bytes())?;
+        Ok(())
+    }
+}
+
 /// Perform various filtering on imported tar archives.
 ///  - Move /etc to /usr/etc
-///  - Entirely drop files not in /usr
+///  - Handle files not in /usr according to the [`ContentPolicy`]
 ///
 /// This also acts as a Rust "pre-parser" of the tar archive, hopefully
 /// catching anything corrupt that might be exploitable from the C libarchive side.
@@ -160,12 +297,15 @@ fn normalize_validate_path(path: &Utf8Path) -> Result<NormalizedPathResult<'_>>
 pub(crate) fn filter_tar(
     src: impl std::io::Read,
     dest: impl std::io::Write,
+    policy: ContentPolicy,
 ) -> Result<BTreeMap<String, u32>> {
     let src = std::io::BufReader::new(src);
     let mut src = tar::Archive::new(src);
     let dest = BufWriter::new(dest);
     let mut dest = tar::Builder::new(dest);
     let mut filtered = BTreeMap::new();
+    let mut rejected = Vec::new();
+    let mut translator = VarTranslator::default();
 
     let ents = src.entries()?;
 
@@ -237,7 +377,45 @@ pub(crate) fn filter_tar(
         }
 
         let normalized = match normalize_validate_path(path)? {
-            NormalizedPathResult::Filtered(path) => {
+            NormalizedPathResult::Filtered("var") if policy == ContentPolicy::Translate => {
+                // `/var` itself always exists
+                if path_in_toplevel(path).as_str().is_empty() {
+                    continue;
+                }
+                let link_target = entry.link_name()?;
+                let link_target = link_target
+                    .as_deref()
+                    .map(Utf8Path::from_path)
+                    .map(|p| p.ok_or_else(|| anyhow!("Invalid non-UTF8 link target of {path}")))
+                    .transpose()?;
+                let translated = translator.translate(header, path, link_target)?;
+                match (translated, header.entry_type()) {
+                    (Some(translated), tar::EntryType::Link) => {
+                        // Hardlinks within /var must refer to the seed tree
+                        let target = link_target.ok_or_else(|| anyhow!("Invalid link {path}"))?;
+                        let target = match normalize_validate_path(target)? {
+                            NormalizedPathResult::Filtered("var") => Utf8Path::new(".")
+                                .join(VAR_FACTORY_DIR)
+                                .join(path_in_toplevel(target)),
+                            NormalizedPathResult::Filtered(_) => continue,
+                            NormalizedPathResult::Normal(t) => t,
+                        };
+                        let mut header = header.clone();
+                        dest.append_link(&mut header, translated, target)?;
+                        continue;
+                    }
+                    (Some(translated), _) => translated,
+                    (None, _) => {
+                        *filtered.entry("var".to_string()).or_default() += 1;
+                        continue;
+                    }
+                }
+            }
+            NormalizedPathResult::Filtered(prefix) => {
+                if policy == ContentPolicy::Error {
+                    rejected.push(path.to_string());
+                }
+                let path = prefix;
                 if let Some(v) = filtered.get_mut(path) {
                     *v += 1;
                 } else {
@@ -250,24 +428,80 @@ pub(crate) fn filter_tar(
 
         copy_entry(entry, &mut dest, Some(normalized.as_std_path()))?;
     }
+    if !rejected.is_empty() {
+        let n = rejected.len();
+        let mut msg = rejected
+            .into_iter()
+            .take(MAX_LISTED_PATHS)
+            .collect::<Vec<_>>()
+            .join(", ");
+        if n > MAX_LISTED_PATHS {
+            msg.push_str(&format!(" (and {} more)", n - MAX_LISTED_PATHS));
+        }
+        anyhow::bail!("Found {n} paths outside of /usr and /etc: {msg}");
+    }
+    translator.finish(&mut dest)?;
     dest.into_inner()?.flush()?;
     Ok(filtered)
 }
 
-/// Asynchronous wrapper for filter_tar()
+/// A reader which computes the SHA-256 digest of the data read through it.
+struct DigestReader<R> {
+    inner: R,
+    hasher: openssl::sha::Sha256,
+}
+
+impl<R: std::io::Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: openssl::sha::Sha256::new(),
+        }
+    }
+
+    /// Read any remaining data, and verify the digest of the full stream.
+    fn verify(&mut self, expected: &str) -> Result<()> {
+        std::io::copy(self, &mut std::io::sink())?;
+        let found = hex::encode(self.hasher.clone().finish());
+        match expected.strip_prefix("sha256:") {
+            Some(v) if v == found => Ok(()),
+            _ => Err(anyhow!(
+                "Tar stream digest mismatch: expected {expected}, found sha256:{found}"
+            )),
+        }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Asynchronous wrapper for filter_tar(), optionally verifying the digest of the input.
 async fn filter_tar_async(
     src: impl AsyncRead + Send + 'static,
     mut dest: impl AsyncWrite + Send + Unpin,
+    expected_digest: Option<String>,
+    policy: ContentPolicy,
 ) -> Result<BTreeMap<String, u32>> {
     let (tx_buf, mut rx_buf) = tokio::io::duplex(8192);
     // The source must be moved to the heap so we know it is stable for passing to the worker thread
     let src = Box::pin(src);
     let tar_transformer = tokio::task::spawn_blocking(move || {
-        let mut src = tokio_util::io::SyncIoBridge::new(src);
+        let mut src = DigestReader::new(tokio_util::io::SyncIoBridge::new(src));
         let dest = tokio_util::io::SyncIoBridge::new(tx_buf);
-        let r = filter_tar(&mut src, dest);
+        let r = filter_tar(&mut src, dest, policy).and_then(|filtered| {
+            // Note the tar parser may not read the padding after the end of the archive
+            if let Some(expected) = expected_digest.as_deref() {
+                src.verify(expected)?;
+            }
+            Ok(filtered)
+        });
         // Pass ownership of the input stream back to the caller - see below.
-        (r, src)
+        (r, src.inner)
     });
     let copier = tokio::io::copy(&mut rx_buf, &mut dest);
     let (r, v) = tokio::join!(tar_transformer, copier);
@@ -304,6 +538,9 @@ pub async fn write_tar(
     } else {
         None
     };
+    // Also verify the content is accepted by the policy before writing the ref
+    let verify =
+        options.expected_digest.is_some() || options.content_policy == ContentPolicy::Error;
     let mut c = std::process::Command::new("ostree");
     let repofd = repo.dfd_as_file()?;
     let repofd: Arc<io_lifetimes::OwnedFd> = Arc::new(repofd.into());
@@ -327,9 +564,13 @@ pub async fn write_tar(
             "--no-bindings",
             "--tar-autocreate-parents",
             "--tree=tar=/proc/self/fd/0",
-            "--branch",
-            refname,
         ]);
+        // If verifying the input, we only write the ref once that has succeeded
+        if verify {
+            c.arg("--orphan");
+        } else {
+            c.args(["--branch", refname]);
+        }
     }
     let mut c = tokio::process::Command::from(c);
     c.kill_on_drop(true);
@@ -340,7 +581,12 @@ pub async fn write_tar(
     let mut child_stdout = r.stdout.take().unwrap();
     let mut child_stderr = r.stderr.take().unwrap();
     // Copy the filtered tar stream to child stdin
-    let filtered_result = filter_tar_async(src, child_stdin);
+    let filtered_result = filter_tar_async(
+        src,
+        child_stdin,
+        options.expected_digest,
+        options.content_policy,
+    );
     let output_copier = async move {
         // Gather stdout/stderr to buffers
         let mut child_stdout_buf = String::new();
@@ -383,6 +629,9 @@ pub async fn write_tar(
     tracing::trace!("tar written successfully");
     // TODO: trim string in place
     let s = child_stdout.trim();
+    if verify {
+        repo.set_ref_immediate(None, refname, Some(s), gio::Cancellable::NONE)?;
+    }
     Ok(WriteTarResult {
         commit: s.to_string(),
         filtered: filtered_result,
@@ -448,13 +697,115 @@ mod tests {
         let _ = rootfs_tar.into_inner()?;
         let mut dest = Vec::new();
         let src = tokio::io::BufReader::new(tokio::fs::File::open(rootfs_tar_path).await?);
-        filter_tar_async(src, &mut dest).await?;
+        filter_tar_async(src, &mut dest, None, ContentPolicy::Warn).await?;
         let dest = dest.as_slice();
         let mut final_tar = tar::Archive::new(Cursor::new(dest));
         let destdir = &tempd.path().join("destdir");
         final_tar.unpack(destdir)?;
         assert!(destdir.join("usr/etc/systemd/system/foo.service").exists());
         assert!(!destdir.join("blah").exists());
+
+        // Verify the digest of the input
+        let digest = openssl::sha::sha256(&std::fs::read(rootfs_tar_path)?);
+        let digest = format!("sha256:{}", hex::encode(digest));
+        let src = tokio::io::BufReader::new(tokio::fs::File::open(rootfs_tar_path).await?);
+        filter_tar_async(src, tokio::io::sink(), Some(digest), ContentPolicy::Warn).await?;
+        let src = tokio::io::BufReader::new(tokio::fs::File::open(rootfs_tar_path).await?);
+        let wrong = format!("sha256:{}", "0".repeat(64));
+        let r = filter_tar_async(src, tokio::io::sink(), Some(wrong), ContentPolicy::Warn).await;
+        assert!(format!("{:#}", r.unwrap_err()).contains("digest mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tmpfiles_escape() {
+        for (k, v) in [
+            ("/var/lib/foo", "/var/lib/foo"),
+            ("/var/lib/foo bar", "/var/lib/foo\\x20bar"),
+            ("/var/lib/50%", "/var/lib/50%%"),
+            ("/var/lib/a\\b", "/var/lib/a\\x5cb"),
+        ] {
+            assert_eq!(tmpfiles_escape(k.into()), v);
+        }
+    }
+
+    #[test]
+    fn tar_filter_policy() -> Result<()> {
+        let mut src = tar::Builder::new(Vec::new());
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Directory);
+        h.set_mode(0o750);
+        h.set_uid(42);
+        h.set_gid(42);
+        h.set_size(0);
+        src.append_data(&mut h.clone(), "var/lib/foo", std::io::empty())?;
+        src.append_data(&mut h, "usr/lib/foo", std::io::empty())?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_mode(0o644);
+        h.set_size(4);
+        src.append_data(&mut h.clone(), "var/lib/foo/data", &b"data"[..])?;
+        src.append_data(&mut h, "boot/vmlinuz", &b"kern"[..])?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Symlink);
+        h.set_size(0);
+        src.append_link(&mut h, "var/lib/foo/link", "data")?;
+        let mut h = tar::Header::new_gnu();
+        h.set_entry_type(tar::EntryType::Link);
+        h.set_size(0);
+        src.append_link(&mut h, "var/lib/foo/hardlink", "var/lib/foo/data")?;
+        let src = src.into_inner()?;
+
+        let r = filter_tar(src.as_slice(), std::io::sink(), ContentPolicy::Warn)?;
+        assert_eq!(r.get("var"), Some(&4));
+        assert_eq!(r.get("boot"), Some(&1));
+
+        let e = filter_tar(src.as_slice(), std::io::sink(), ContentPolicy::Error).unwrap_err();
+        let e = format!("{e:#}");
+        assert!(e.contains("Found 5 paths"));
+        assert!(e.contains("var/lib/foo/data"));
+        assert!(e.contains("boot/vmlinuz"));
+
+        let mut dest = Vec::new();
+        let r = filter_tar(src.as_slice(), &mut dest, ContentPolicy::Translate)?;
+        assert!(!r.contains_key("var"));
+        assert_eq!(r.get("boot"), Some(&1));
+        let mut dest = tar::Archive::new(dest.as_slice());
+        let mut tmpfiles = None;
+        let mut paths = Vec::new();
+        for entry in dest.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_str().unwrap().to_string();
+            if path.starts_with("./usr/lib/tmpfiles.d/") {
+                let mut buf = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut buf)?;
+                tmpfiles = Some(buf);
+            } else if entry.header().entry_type() == tar::EntryType::Link {
+                let target = entry.link_name()?.unwrap();
+                assert_eq!(
+                    target.to_str().unwrap(),
+                    "./usr/share/factory/var/lib/foo/data"
+                );
+            }
+            paths.push(path);
+        }
+        assert_eq!(
+            &paths[..5],
+            [
+                "./usr/share/factory/var/lib/foo",
+                "./usr/lib/foo",
+                "./usr/share/factory/var/lib/foo/data",
+                "./usr/share/factory/var/lib/foo/link",
+                "./usr/share/factory/var/lib/foo/hardlink",
+            ]
+        );
+        assert_eq!(
+            tmpfiles.unwrap(),
+            "d /var/lib/foo 0750 42 42 - -\n\
+             C /var/lib/foo/data - - - - /usr/share/factory/var/lib/foo/data\n\
+             L /var/lib/foo/link - - - - data\n\
+             C /var/lib/foo/hardlink - - - - /usr/share/factory/var/lib/foo/hardlink\n"
+        );
         Ok(())
     }
 }
@@ -8,15 +8,18 @@ use once_cell::sync::Lazy;
 use ostree_ext::chunking::ObjectMetaSized;
 use ostree_ext::container::{store, ManifestDiff};
 use ostree_ext::container::{
-    Config, ExportOpts, ImageReference, OstreeImageReference, SignatureSource, Transport,
+    Config, ExportCompression, ExportOpts, ImageReference, OstreeImageReference, SignatureSource,
+    Transport,
 };
 use ostree_ext::ocidir;
 use ostree_ext::prelude::{Cast, FileExt};
+use ostree_ext::sysroot::SysrootLock;
 use ostree_ext::tar::TarImportOptions;
 use ostree_ext::{gio, glib};
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Cursor, Read};
+use std::os::fd::AsFd;
 use std::os::unix::fs::DirBuilderExt;
 use std::process::Command;
 use std::time::SystemTime;
@@ -25,6 +28,8 @@ use xshell::cmd;
 use ostree_ext::fixture::{FileDef, Fixture, CONTENTS_CHECKSUM_V0, LAYERS_V0_LEN, PKGS_V0_LEN};
 
 const EXAMPLE_TAR_LAYER: &[u8] = include_bytes!("fixtures/hlinks.tar.gz");
+/// An RPM database with packages owning some of the files of the fixture
+const EXAMPLE_RPMDB: &[u8] = include_bytes!("fixtures/rpmdb.sqlite");
 const TEST_REGISTRY_DEFAULT: &str = "localhost:5000";
 
 #[track_caller]
@@ -381,6 +386,7 @@ async fn test_tar_write() -> Result<()> {
     let tmptar = "testlayer.tar";
     cmd!(sh, "tar cf {tmptar} -C tmproot .").run()?;
     let src = fixture.dir.open(tmptar)?;
+    let buf = fixture.dir.read(tmptar)?;
     fixture.dir.remove_file(tmptar)?;
     let src = tokio::fs::File::from_std(src.into_std());
     let r = ostree_ext::tar::write_tar(fixture.destrepo(), src, "layer", None).await?;
@@ -395,6 +401,48 @@ async fn test_tar_write() -> Result<()> {
     assert_eq!(*r.filtered.get("var").unwrap(), 4);
     assert_eq!(*r.filtered.get("boot").unwrap(), 1);
 
+    // Reject content outside of /usr and /etc
+    let mut opts = ostree_ext::tar::WriteTarOptions::default();
+    opts.content_policy = ostree_ext::tar::ContentPolicy::Error;
+    let r = ostree_ext::tar::write_tar(
+        fixture.destrepo(),
+        Cursor::new(buf.clone()),
+        "layer-rejected",
+        Some(opts),
+    )
+    .await;
+    assert_err_contains(r, "var/log/foo.log");
+    assert!(fixture
+        .destrepo()
+        .resolve_rev("layer-rejected", true)?
+        .is_none());
+
+    // Translate content in /var
+    let mut opts = ostree_ext::tar::WriteTarOptions::default();
+    opts.content_policy = ostree_ext::tar::ContentPolicy::Translate;
+    let r = ostree_ext::tar::write_tar(
+        fixture.destrepo(),
+        Cursor::new(buf),
+        "layer-translated",
+        Some(opts),
+    )
+    .await?;
+    assert_eq!(r.filtered.len(), 1);
+    assert_eq!(*r.filtered.get("boot").unwrap(), 1);
+    let layer_commit = r.commit.as_str();
+    cmd!(
+        sh,
+        "ostree --repo=dest/repo ls {layer_commit} /usr/share/factory/var/log/foo.log"
+    )
+    .ignore_stdout()
+    .run()?;
+    let tmpfiles = cmd!(
+        sh,
+        "ostree --repo=dest/repo ls {layer_commit} /usr/lib/tmpfiles.d"
+    )
+    .read()?;
+    assert!(tmpfiles.contains("ostree-ext-var-"));
+
     Ok(())
 }
 
@@ -405,6 +453,31 @@ async fn test_tar_write_tar_layer() -> Result<()> {
         async_compression::tokio::bufread::GzipDecoder::new(EXAMPLE_TAR_LAYER),
     );
     ostree_ext::tar::write_tar(fixture.destrepo(), uncompressed_tar, "test", None).await?;
+
+    // Verify the digest of the uncompressed stream
+    let mut buf = Vec::new();
+    flate2::read::GzDecoder::new(EXAMPLE_TAR_LAYER).read_to_end(&mut buf)?;
+    let digest = format!("sha256:{}", hex::encode(openssl::sha::sha256(&buf)));
+    let mut opts = ostree_ext::tar::WriteTarOptions::default();
+    opts.expected_digest = Some(format!("sha256:{}", "0".repeat(64)));
+    let r = ostree_ext::tar::write_tar(
+        fixture.destrepo(),
+        Cursor::new(buf.clone()),
+        "verified",
+        Some(opts),
+    )
+    .await;
+    assert_err_contains(r, "digest mismatch");
+    assert!(fixture.destrepo().resolve_rev("verified", true)?.is_none());
+    let mut opts = ostree_ext::tar::WriteTarOptions::default();
+    opts.expected_digest = Some(digest);
+    let r =
+        ostree_ext::tar::write_tar(fixture.destrepo(), Cursor::new(buf), "verified", Some(opts))
+            .await?;
+    assert_eq!(
+        fixture.destrepo().require_rev("verified")?.as_str(),
+        r.commit.as_str()
+    );
     Ok(())
 }
 
@@ -595,6 +668,96 @@ async fn impl_test_container_import_export(chunked: bool) -> Result<()> {
     Ok(())
 }
 
+/// A derived image can be pulled while verifying its ostree commit via a remote, if
+/// the image is also verified via `containers-policy.json`.
+#[tokio::test]
+async fn test_container_derived_remote_signed() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let sh = fixture.new_shell()?;
+    let testrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    let (imgref, _) = fixture.export_container().await?;
+    let srcpath = imgref.name.as_str();
+    let temproot = &fixture.path.join("temproot");
+    std::fs::create_dir_all(temproot.join("usr/bin"))?;
+    std::fs::write(temproot.join("usr/bin/newderivedfile"), "newderivedfile v0")?;
+    let derived_tag = "derived";
+    ostree_ext::integrationtest::generate_derived_oci(srcpath, temproot, Some(derived_tag))?;
+    let derived = ImageReference {
+        transport: Transport::OciDir,
+        name: format!("{srcpath}:{derived_tag}"),
+    };
+
+    // A policy which is not insecure by default, but accepts OCI directories
+    let policy = &fixture.path.join("policy.json");
+    std::fs::write(
+        policy,
+        r#"{"default": [{"type": "reject"}], "transports": {"oci": {"": [{"type": "insecureAcceptAnything"}]}}}"#,
+    )?;
+    let proxy_config = || {
+        let mut cmd = std::process::Command::new("skopeo");
+        cmd.arg("--policy").arg(policy);
+        store::ImageProxyConfig {
+            skopeo_cmd: Some(cmd),
+            ..Default::default()
+        }
+    };
+
+    // One remote has the signing key, the other does not
+    for remote in ["myremote", "unkeyed"] {
+        let opts = glib::VariantDict::new(None);
+        opts.insert("gpg-verify", true);
+        opts.insert("custom-backend", "ostree-rs-ext");
+        fixture
+            .destrepo()
+            .remote_add(remote, None, Some(&opts.end()), gio::Cancellable::NONE)?;
+    }
+    cmd!(
+        sh,
+        "ostree --repo=dest/repo remote gpg-import --stdin myremote"
+    )
+    .stdin(sh.read_file("src/gpghome/key1.asc")?)
+    .run()?;
+
+    // The derived layer is not covered by the ostree signature
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::OstreeRemote("myremote".into()),
+        imgref: derived.clone(),
+    };
+    let mut imp = store::ImageImporter::new(fixture.destrepo(), &imgref, proxy_config()).await?;
+    assert_err_contains(
+        imp.prepare().await,
+        "Cannot currently verify layered containers via ostree remote",
+    );
+
+    // With the image verified via the policy, the commit is still verified via the remote
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::OstreeRemoteAndContainerPolicy("unkeyed".into()),
+        imgref: derived.clone(),
+    };
+    let mut imp = store::ImageImporter::new(fixture.destrepo(), &imgref, proxy_config()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    assert_eq!(prep.layers.len(), 1);
+    assert_err_contains(imp.import(prep).await, "public key not found");
+
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::OstreeRemoteAndContainerPolicy("myremote".into()),
+        imgref: derived,
+    };
+    let mut imp = store::ImageImporter::new(fixture.destrepo(), &imgref, proxy_config()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let import = imp.import(prep).await?;
+    assert_eq!(import.base_commit, testrev.as_str());
+    assert_ne!(import.merge_commit, import.base_commit);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_unencapsulate_unbootable() -> Result<()> {
     let fixture = {
@@ -682,6 +845,84 @@ fn validate_chunked_structure(oci_path: &Utf8Path) -> Result<()> {
     Ok(())
 }
 
+/// Verify the space estimate and free space check of an import
+#[tokio::test]
+async fn test_container_space_estimate() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let imgref = fixture.export_container().await?.0;
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let destrepo = fixture.destrepo();
+    let prepare = |imgref: OstreeImageReference| async move {
+        let mut imp = store::ImageImporter::new(destrepo, &imgref, Default::default()).await?;
+        imp.require_free_space();
+        match imp.prepare().await? {
+            store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+            store::PrepareResult::Ready(r) => Ok::<_, anyhow::Error>(r),
+        }
+    };
+
+    // All layers record their uncompressed size
+    let prep = prepare(imgref.clone()).await?;
+    let estimate = prep.space_estimate();
+    assert_eq!(estimate.estimated_layers, 0);
+    assert_eq!(
+        estimate.fetch_size,
+        prep.all_layers().map(|l| l.size()).sum::<u64>()
+    );
+    let content_size = prep
+        .all_layers()
+        .map(|l| l.uncompressed_size().unwrap())
+        .sum::<u64>();
+    assert_eq!(estimate.content_size, content_size);
+
+    // Without the layer annotations, the size is taken from the history
+    let derived_path = &fixture.path.join("derived.oci");
+    oci_clone(imgref.imgref.name.as_str(), derived_path).await?;
+    let d = Dir::open_ambient_dir(derived_path, cap_std::ambient_authority())?;
+    let d = ocidir::OciDir::open(&d)?;
+    let mut manifest = d.read_manifest()?;
+    for layer in manifest.layers_mut() {
+        layer.set_annotations(None);
+    }
+    d.replace_with_single_manifest(manifest, oci_spec::image::Platform::default())?;
+    let derived_imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: ImageReference {
+            transport: Transport::OciDir,
+            name: derived_path.to_string(),
+        },
+    };
+    let prep = prepare(derived_imgref.clone()).await?;
+    assert!(prep.all_layers().all(|l| l.uncompressed_size().is_none()));
+    let estimate = prep.space_estimate();
+    assert_eq!(estimate.estimated_layers, 0);
+    assert_eq!(estimate.content_size, content_size);
+
+    // A layer which records its size nowhere is estimated from its compressed size
+    let temproot = &fixture.path.join("temproot");
+    std::fs::create_dir_all(temproot.join("usr/share"))?;
+    std::fs::write(temproot.join("usr/share/somefile"), "somecontent")?;
+    ostree_ext::integrationtest::generate_derived_oci(derived_path, temproot, None)?;
+    let prep = prepare(derived_imgref).await?;
+    let estimate = prep.space_estimate();
+    assert_eq!(estimate.estimated_layers, 1);
+    assert!(estimate.content_size > content_size);
+
+    // The space reserved by the repository is also required
+    let config = fixture.destrepo().copy_config();
+    config.set_string("core", "min-free-space-size", "1000TB");
+    fixture.destrepo().write_config(&config)?;
+    assert_err_contains(
+        prepare(imgref).await,
+        "Insufficient free space in repository",
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_container_chunked() -> Result<()> {
     let nlayers = LAYERS_V0_LEN - 1;
@@ -806,7 +1047,58 @@ r usr/bin/bash bash-v0
 
     assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 1);
     let n = store::count_layer_references(fixture.destrepo())? as i64;
-    let _import = imp.import(prep).await.unwrap();
+    let mut events = imp.request_progress_events();
+    let collector = tokio::task::spawn(async move {
+        let mut r = Vec::new();
+        while let Some(event) = events.recv().await {
+            r.push(event);
+        }
+        r
+    });
+    let import = imp.import(prep).await.unwrap();
+    let events = collector.await?;
+    match &events[0] {
+        store::ProgressEvent::Start {
+            layers_to_fetch,
+            manifest_digest,
+            ..
+        } => {
+            assert_eq!(*layers_to_fetch, 2);
+            assert_eq!(manifest_digest.as_str(), expected_digest);
+        }
+        o => panic!("Unexpected event {o:?}"),
+    }
+    assert_eq!(serde_json::to_value(&events[0])?["event"], "start");
+    let phases = events
+        .iter()
+        .filter_map(|e| match e {
+            store::ProgressEvent::Phase { phase } => Some(*phase),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        phases,
+        [
+            store::ImportPhase::Fetch,
+            store::ImportPhase::MergeCheckout,
+            store::ImportPhase::Commit,
+            store::ImportPhase::Gc
+        ]
+    );
+    let completed = events
+        .iter()
+        .filter_map(|e| match e {
+            store::ProgressEvent::LayerCompleted { layers_fetched, .. } => Some(*layers_fetched),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(completed, [1, 2]);
+    assert_eq!(
+        events.last().unwrap(),
+        &store::ProgressEvent::Imported {
+            merge_commit: import.merge_commit.clone()
+        }
+    );
 
     assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 1);
 
@@ -834,113 +1126,1587 @@ r usr/bin/bash bash-v0
     let derived_tag = "derived";
     ostree_ext::integrationtest::generate_derived_oci(srcpath, temproot, Some(derived_tag))?;
 
-    let derived_imgref = OstreeImageReference {
+    let derived_imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: ImageReference {
+            transport: Transport::OciDir,
+            name: format!("{srcpath}:{derived_tag}"),
+        },
+    };
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &derived_imgref, Default::default()).await?;
+    let prep = match imp.prepare().await.unwrap() {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let to_fetch = prep.layers_to_fetch().collect::<Result<Vec<_>>>()?;
+    assert_eq!(to_fetch.len(), 1);
+    assert!(prep.ostree_commit_layer.commit.is_some());
+    assert_eq!(prep.ostree_layers.len(), nlayers);
+
+    // We want to test explicit layer pruning
+    imp.disable_gc();
+    let _import = imp.import(prep).await.unwrap();
+    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 2);
+
+    assert!(
+        store::image_filtered_content_warning(fixture.destrepo(), &derived_imgref.imgref)
+            .unwrap()
+            .is_none()
+    );
+
+    // Should only be new layers
+    let n_removed = store::gc_image_layers(fixture.destrepo())?;
+    assert_eq!(n_removed, 0);
+    // Also test idempotence
+    store::remove_image(fixture.destrepo(), &imgref.imgref).unwrap();
+    store::remove_image(fixture.destrepo(), &imgref.imgref).unwrap();
+    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 1);
+    // Still no removed layers after removing the base image
+    let n_removed = store::gc_image_layers(fixture.destrepo())?;
+    assert_eq!(n_removed, 0);
+    store::remove_images(fixture.destrepo(), [&derived_imgref.imgref]).unwrap();
+    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 0);
+    let n_removed = store::gc_image_layers(fixture.destrepo())?;
+    assert_eq!(n_removed, (LAYERS_V0_LEN + 1) as u32);
+
+    // Repo should be clean now
+    assert_eq!(store::count_layer_references(fixture.destrepo())?, 0);
+    assert_eq!(
+        fixture
+            .destrepo()
+            .list_refs(None, gio::Cancellable::NONE)
+            .unwrap()
+            .len(),
+        0
+    );
+
+    Ok(())
+}
+
+/// Create an empty sysroot in the fixture.  Its repository is `bare-user`, so that
+/// images can be imported into it unprivileged.
+async fn new_test_sysroot(fixture: &Fixture) -> Result<SysrootLock> {
+    fixture.dir.create_dir_all("sysroot/ostree")?;
+    ostree::Repo::create_at_dir(
+        fixture.dir.as_fd(),
+        "sysroot/ostree/repo",
+        ostree::RepoMode::BareUser,
+        None,
+    )?;
+    let sysroot = ostree::Sysroot::new(Some(&gio::File::for_path(fixture.path.join("sysroot"))));
+    sysroot.ensure_initialized(gio::Cancellable::NONE)?;
+    sysroot.load(gio::Cancellable::NONE)?;
+    SysrootLock::new_from_sysroot(&sysroot).await
+}
+
+/// Import an image into the repository, optionally storing it under another name.
+async fn import_image(
+    repo: &ostree::Repo,
+    imgref: &OstreeImageReference,
+    target: Option<&ImageReference>,
+) -> Result<Box<store::LayeredImageState>> {
+    let mut imp = store::ImageImporter::new(repo, imgref, Default::default()).await?;
+    if let Some(target) = target {
+        imp.set_target(&OstreeImageReference {
+            sigverify: imgref.sigverify.clone(),
+            imgref: target.clone(),
+        });
+    }
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    imp.import(prep).await
+}
+
+#[tokio::test]
+async fn test_container_pin() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let sysroot = &new_test_sysroot(&fixture).await?;
+    let repo = &sysroot.repo();
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: fixture.export_container().await?.0,
+    };
+    // Store the same image under a second name, which we pin
+    let pinned = ImageReference {
+        transport: Transport::Registry,
+        name: "quay.io/exampleos/pinned:latest".into(),
+    };
+    import_image(repo, &imgref, Some(&pinned)).await?;
+    import_image(repo, &imgref, None).await?;
+    assert_eq!(store::list_images(repo)?.len(), 2);
+
+    store::pin_image(repo, &pinned)?;
+    assert!(store::image_is_pinned(repo, &pinned)?);
+    assert!(!store::image_is_pinned(repo, &imgref.imgref)?);
+    assert_eq!(store::list_pinned_images(repo)?, [pinned.to_string()]);
+
+    // Neither image is deployed, but only the unpinned one is removed
+    let removed = ostree_ext::container::deploy::remove_undeployed_images(sysroot)?;
+    assert_eq!(removed, std::slice::from_ref(&imgref.imgref));
+    assert_eq!(store::list_images(repo)?, [pinned.to_string()]);
+    assert_eq!(store::gc_image_layers(repo)?, 0);
+
+    // A pinned image retains its layers, even once the image itself is removed
+    store::remove_images(repo, [&pinned])?;
+    assert_eq!(store::list_images(repo)?.len(), 0);
+    assert_eq!(store::gc_image_layers(repo)?, 0);
+    assert!(store::unpin_image(repo, &pinned)?);
+    assert!(!store::unpin_image(repo, &pinned)?);
+    assert!(store::list_pinned_images(repo)?.is_empty());
+    assert_eq!(store::gc_image_layers(repo)?, LAYERS_V0_LEN as u32);
+    assert_eq!(store::count_layer_references(repo)?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_prune_images() -> Result<()> {
+    use ostree_ext::container::retention::{prune_images, RetentionPolicy};
+
+    let fixture = Fixture::new_v1()?;
+    let sh = fixture.new_shell()?;
+    let sysroot = &new_test_sysroot(&fixture).await?;
+    let repo = &sysroot.repo();
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: fixture.export_container().await?.0,
+    };
+    let pinned = ImageReference {
+        transport: Transport::Registry,
+        name: "quay.io/exampleos/pinned:latest".into(),
+    };
+    import_image(repo, &imgref, Some(&pinned)).await?;
+    import_image(repo, &imgref, None).await?;
+    store::pin_image(repo, &pinned)?;
+
+    let srcpath = imgref.imgref.name.as_str();
+    fixture.dir.create_dir_all("temproot/usr/bin")?;
+    fixture
+        .dir
+        .write("temproot/usr/bin/newderivedfile", "newderivedfile v0")?;
+    let derived_tag = "derived";
+    ostree_ext::integrationtest::generate_derived_oci(
+        srcpath,
+        fixture.path.join("temproot"),
+        Some(derived_tag),
+    )?;
+    let derived_imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: ImageReference {
+            transport: Transport::OciDir,
+            name: format!("{srcpath}:{derived_tag}"),
+        },
+    };
+    import_image(repo, &derived_imgref, None).await?;
+    assert_eq!(store::list_images(repo)?.len(), 3);
+
+    // Content which is already unreferenced should not be counted as freed
+    let unreferenced_size = 1024 * 1024;
+    fixture.dir.create_dir_all("unreferenced/usr/share")?;
+    fixture.dir.write(
+        "unreferenced/usr/share/bigfile",
+        vec![0x42u8; unreferenced_size],
+    )?;
+    let unreferenced = cmd!(
+        sh,
+        "ostree --repo=sysroot/ostree/repo commit --orphan --tree=dir=unreferenced"
+    )
+    .read()?;
+
+    let mut policy = RetentionPolicy::default();
+    assert_err_contains(
+        prune_images(sysroot, &policy),
+        "No retention policy specified",
+    );
+    policy.older_than = Some(std::time::Duration::ZERO);
+    let report = prune_images(sysroot, &policy)?;
+    assert_eq!(
+        report.removed,
+        [imgref.imgref.clone(), derived_imgref.imgref.clone()]
+    );
+    // The layers of the pinned image are retained
+    assert_eq!(report.layers_removed, 1);
+    assert_eq!(store::list_images(repo)?, [pinned.to_string()]);
+    assert_eq!(store::count_layer_references(repo)?, LAYERS_V0_LEN as u32);
+    assert!(report.bytes_freed > 0);
+    assert!(report.bytes_freed < unreferenced_size as u64);
+    assert!(!repo.has_object(
+        ostree::ObjectType::Commit,
+        &unreferenced,
+        gio::Cancellable::NONE
+    )?);
+
+    // Nothing is left to remove
+    let report = prune_images(sysroot, &policy)?;
+    assert!(report.removed.is_empty());
+    assert_eq!(report.layers_removed, 0);
+    assert_eq!(report.bytes_freed, 0);
+
+    Ok(())
+}
+
+/// Importing with concurrent layer fetches should produce the same result
+#[tokio::test]
+async fn test_container_chunked_jobs() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+
+    let (imgref, expected_digest) = fixture.export_container().await.unwrap();
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let srcrev = fixture.srcrepo().require_rev(fixture.testref())?;
+
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    imp.set_jobs(std::num::NonZeroUsize::new(4).unwrap());
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    assert_eq!(prep.ostree_layers.len(), LAYERS_V0_LEN - 1);
+    let import = imp.import(prep).await?;
+    assert_eq!(import.manifest_digest.as_str(), expected_digest);
+    assert_eq!(import.merge_commit, srcrev.as_str());
+    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 1);
+
+    Ok(())
+}
+
+/// Fetching layers concurrently via the proxy must not block on a blob which
+/// has not yet been read.
+#[tokio::test]
+async fn test_container_derived_jobs() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let imgref = fixture.export_container().await?.0;
+    let derived_path = &fixture.path.join("derived.oci");
+    oci_clone(imgref.name.as_str(), derived_path).await?;
+    // Incompressible, and larger than a pipe buffer, so that the proxy
+    // blocks writing each blob until it is read.
+    let mut buf = vec![0u8; 4 * 1024 * 1024];
+    for i in 0..2 {
+        openssl::rand::rand_bytes(&mut buf)?;
+        ostree_ext::integrationtest::generate_derived_oci_from_tar(
+            derived_path,
+            |w| {
+                let mut tar = tar::Builder::new(w);
+                let mut h = tar::Header::new_gnu();
+                h.set_uid(0);
+                h.set_gid(0);
+                h.set_size(0);
+                h.set_entry_type(tar::EntryType::Directory);
+                h.set_mode(0o755);
+                for d in ["usr", "usr/share"] {
+                    tar.append_data(&mut h, d, std::io::empty())?;
+                }
+                h.set_entry_type(tar::EntryType::Regular);
+                h.set_mode(0o644);
+                h.set_size(buf.len() as u64);
+                tar.append_data(&mut h, format!("usr/share/big{i}"), buf.as_slice())?;
+                tar.finish()?;
+                Ok(())
+            },
+            None,
+        )?;
+    }
+    let derived_imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: ImageReference {
+            transport: Transport::OciDir,
+            name: derived_path.to_string(),
+        },
+    };
+
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &derived_imgref, Default::default()).await?;
+    imp.set_jobs(std::num::NonZeroUsize::new(2).unwrap());
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    assert_eq!(prep.layers.len(), 2);
+    let import = tokio::time::timeout(std::time::Duration::from_secs(120), imp.import(prep))
+        .await
+        .context("Timed out fetching layers concurrently")??;
+    let root = fixture
+        .destrepo()
+        .read_commit(&import.merge_commit, gio::Cancellable::NONE)?
+        .0;
+    for i in 0..2 {
+        let f = root.resolve_relative_path(format!("usr/share/big{i}"));
+        let info = f.query_info(
+            "standard::size",
+            gio::FileQueryInfoFlags::NONE,
+            gio::Cancellable::NONE,
+        )?;
+        assert_eq!(info.size() as usize, buf.len());
+    }
+
+    Ok(())
+}
+
+/// Fetch the layers of an update, and complete it later
+#[tokio::test]
+async fn test_container_fetch_then_finalize() -> Result<()> {
+    let mut fixture = Fixture::new_v1()?;
+    let (imgref, digest) = fixture.export_container().await?;
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+
+    // Fetching layers only is not supported for the initial pull
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    assert_err_contains(imp.fetch_layers(prep).await, "previously pulled image");
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let initial = imp.import(prep).await?;
+    assert_eq!(initial.manifest_digest, digest);
+
+    fixture
+        .update(
+            FileDef::iter_from("r usr/bin/bash bash-v0\n"),
+            std::iter::empty(),
+        )
+        .context("Failed to update")?;
+    let expected_digest = fixture.export_container().await?.1;
+    assert_ne!(digest, expected_digest);
+
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    imp.fetch_layers(prep).await?;
+
+    // The image is unchanged, but the update is ready
+    let state = store::query_image_ref(fixture.destrepo(), &imgref.imgref)?.unwrap();
+    assert_eq!(state.merge_commit, initial.merge_commit);
+    let cached = state.cached_update.unwrap();
+    assert_eq!(cached.manifest_digest, expected_digest);
+    assert!(cached.layers_fetched());
+
+    // Preparing the same update again finds all layers present, and keeps the cached state
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    assert_eq!(prep.layers_to_fetch().count(), 0);
+    let state = store::query_image_ref(fixture.destrepo(), &imgref.imgref)?.unwrap();
+    assert!(state.cached_update.unwrap().layers_fetched());
+
+    let state = store::finalize_cached_update(fixture.destrepo(), &imgref.imgref).await?;
+    assert_eq!(state.manifest_digest, expected_digest);
+    assert_ne!(state.merge_commit, initial.merge_commit);
+    assert!(state.cached_update.is_none());
+    assert_err_contains(
+        store::finalize_cached_update(fixture.destrepo(), &imgref.imgref).await,
+        "No fetched update",
+    );
+
+    // Nothing more to fetch
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    assert!(matches!(
+        imp.prepare().await?,
+        store::PrepareResult::AlreadyPresent(_)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_var_content() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+
+    let imgref = fixture.export_container().await.unwrap().0;
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+
+    // Build a derived image
+    let derived_path = &fixture.path.join("derived.oci");
+    let srcpath = imgref.imgref.name.as_str();
+    oci_clone(srcpath, derived_path).await.unwrap();
+    let temproot = &fixture.path.join("temproot");
+    || -> Result<_> {
+        std::fs::create_dir(temproot)?;
+        let temprootd = Dir::open_ambient_dir(temproot, cap_std::ambient_authority())?;
+        let mut db = DirBuilder::new();
+        db.mode(0o755);
+        db.recursive(true);
+        temprootd.create_dir_with("var/lib", &db)?;
+        temprootd.write("var/lib/foo", "junk var data")?;
+        Ok(())
+    }()
+    .context("generating temp content")?;
+    ostree_ext::integrationtest::generate_derived_oci(derived_path, temproot, None)?;
+
+    let derived_imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: ImageReference {
+            transport: Transport::OciDir,
+            name: derived_path.to_string(),
+        },
+    };
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &derived_imgref, Default::default()).await?;
+    let prep = match imp.prepare().await.unwrap() {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let _import = imp.import(prep).await.unwrap();
+
+    assert!(
+        store::image_filtered_content_warning(fixture.destrepo(), &derived_imgref.imgref)
+            .unwrap()
+            .is_some()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_zstd() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let testrev = fixture
+        .srcrepo()
+        .require_rev(fixture.testref())
+        .context("Failed to resolve ref")?;
+    let srcoci_path = &fixture.path.join("oci-zstd");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: srcoci_path.as_str().to_string(),
+    };
+    let contentmeta =
+        ObjectMetaSized::compute_sizes(fixture.srcrepo(), fixture.get_object_meta()?)?;
+    let mut opts = ExportOpts::default();
+    opts.compression = ExportCompression::Zstd;
+    opts.contentmeta = Some(&contentmeta);
+    ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        Some(opts),
+        &imgref,
+    )
+    .await
+    .context("exporting")?;
+
+    let d = Dir::open_ambient_dir(srcoci_path, cap_std::ambient_authority())?;
+    let manifest = ocidir::OciDir::open(&d)?.read_manifest()?;
+    assert!(manifest.layers().len() > 1);
+    for layer in manifest.layers() {
+        assert_eq!(
+            layer.media_type(),
+            &oci_spec::image::MediaType::ImageLayerZstd
+        );
+    }
+
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let import = imp.import(prep).await?;
+    assert_eq!(import.get_commit(), testrev.as_str());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_multiarch() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let sh = fixture.new_shell()?;
+    let testref = fixture.testref();
+    let mut revs = Vec::new();
+    for arch in ["x86_64", "aarch64"] {
+        let r = format!("{testref}-{arch}");
+        cmd!(sh, "ostree --repo=src/repo commit -b {r} --tree=ref={testref} --no-bindings --add-metadata-string=ostree.linux=6.5.6-300.fc39.{arch}").ignore_stdout().run()?;
+        revs.push(fixture.srcrepo().require_rev(&r)?.to_string());
+    }
+    let srcoci_path = &fixture.path.join("oci-multiarch");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: srcoci_path.as_str().to_string(),
+    };
+    let digest = ostree_ext::container::encapsulate_multiarch(
+        fixture.srcrepo(),
+        &revs,
+        &Config::default(),
+        None,
+        &imgref,
+    )
+    .await
+    .context("exporting")?;
+
+    let d = Dir::open_ambient_dir(srcoci_path, cap_std::ambient_authority())?;
+    let ocidir = ocidir::OciDir::open(&d)?;
+    let toplevel: oci_spec::image::ImageIndex =
+        serde_json::from_reader(BufReader::new(d.open("index.json")?))?;
+    let desc = match toplevel.manifests().as_slice() {
+        [desc] => desc,
+        o => panic!("Expected a single index entry, found {}", o.len()),
+    };
+    assert_eq!(desc.digest().as_str(), digest.as_str());
+    assert_eq!(desc.media_type(), &oci_spec::image::MediaType::ImageIndex);
+    let index = ocidir.read_index(desc)?;
+    let archs: Vec<_> = index
+        .manifests()
+        .iter()
+        .map(|m| m.platform().as_ref().unwrap().architecture().to_string())
+        .collect();
+    assert_eq!(archs, ["amd64", "arm64"]);
+
+    // Duplicate platforms are rejected
+    let dup = [revs[0].as_str(), revs[0].as_str()];
+    let dupref = ImageReference {
+        transport: Transport::OciDir,
+        name: fixture.path.join("oci-dup").to_string(),
+    };
+    let r = ostree_ext::container::encapsulate_multiarch(
+        fixture.srcrepo(),
+        &dup,
+        &Config::default(),
+        None,
+        &dupref,
+    )
+    .await;
+    assert_err_contains(r, "Duplicate platform");
+
+    // Explicitly select the non-default platform
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let platform = ostree_ext::cli::parse_platform("linux/arm64")?;
+    let mut imp = store::ImageImporter::new_with_platform(
+        fixture.destrepo(),
+        &imgref,
+        Default::default(),
+        Some(&platform),
+    )
+    .await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let import = imp.import(prep).await?;
+    assert_eq!(import.get_commit(), revs[1].as_str());
+
+    // The configuration of a single image must match all of the requested platform
+    let singlepath = &fixture.path.join("oci-single");
+    let singleref = ImageReference {
+        transport: Transport::OciDir,
+        name: singlepath.to_string(),
+    };
+    ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        &revs[0],
+        &Config::default(),
+        None,
+        &singleref,
+    )
+    .await?;
+    let singleref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: singleref,
+    };
+    for platform in ["linux/arm64", "freebsd/amd64", "linux/amd64/v2"] {
+        let platform = ostree_ext::cli::parse_platform(platform)?;
+        let mut imp =
+            store::ImageImporter::new_in_process(fixture.destrepo(), &singleref, Some(&platform))
+                .await?;
+        assert_err_contains(imp.prepare().await, "does not match requested");
+    }
+
+    Ok(())
+}
+
+async fn export_content_toc(fixture: &Fixture, path: &Utf8Path) -> Result<ImageReference> {
+    if path.exists() {
+        std::fs::remove_dir_all(path)?;
+    }
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: path.to_string(),
+    };
+    let contentmeta =
+        ObjectMetaSized::compute_sizes(fixture.srcrepo(), fixture.get_object_meta()?)?;
+    let mut opts = ExportOpts::default();
+    opts.content_toc = true;
+    opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+    opts.contentmeta = Some(&contentmeta);
+    ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        Some(opts),
+        &imgref,
+    )
+    .await
+    .context("exporting")?;
+    Ok(imgref)
+}
+
+#[tokio::test]
+async fn test_container_content_toc() -> Result<()> {
+    let mut fixture = Fixture::new_v1()?;
+    let srcoci_path = &fixture.path.join("oci-toc");
+    let imgref = export_content_toc(&fixture, srcoci_path).await?;
+
+    // Every content layer should have a table of contents, and still be a valid
+    // zstd compressed tarball for clients which fetch the full layer.
+    let d = Dir::open_ambient_dir(srcoci_path, cap_std::ambient_authority())?;
+    let d = ocidir::OciDir::open(&d)?;
+    let manifest = d.read_manifest()?;
+    assert_eq!(manifest.layers().len(), LAYERS_V0_LEN);
+    for layer in &manifest.layers()[1..] {
+        assert_eq!(
+            layer.media_type(),
+            &oci_spec::image::MediaType::ImageLayerZstd
+        );
+        assert!(layer
+            .annotations()
+            .as_ref()
+            .unwrap()
+            .contains_key("ostree.toc"));
+        let blob = zstd::stream::read::Decoder::new(d.read_blob(layer)?)?;
+        let n = tar::Archive::new(blob).entries()?.count();
+        assert!(n > 0);
+    }
+
+    // Via the proxy, the full layers are fetched
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let mut imp =
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let import = imp.import(prep).await?;
+    let testrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    assert_eq!(import.get_commit(), testrev.as_str());
+    fixture
+        .destrepo()
+        .read_commit(import.get_commit(), gio::Cancellable::NONE)?;
+
+    // Change a file; the layer containing it changes, but only the new object
+    // should need to be read.
+    const ADDITIONS: &str = indoc::indoc! { "
+r usr/bin/bash bash-v1
+"};
+    fixture.update(FileDef::iter_from(ADDITIONS), std::iter::empty())?;
+    let imgref = export_content_toc(&fixture, srcoci_path).await?;
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    // To verify that, corrupt the frames of all objects that are already present
+    let d = Dir::open_ambient_dir(srcoci_path, cap_std::ambient_authority())?;
+    let d = ocidir::OciDir::open(&d)?;
+    let manifest = d.read_manifest()?;
+    let mut corrupted = 0;
+    for layer in &manifest.layers()[1..] {
+        let pos = layer.annotations().as_ref().unwrap()["ostree.toc"].as_str();
+        let (offset, pos) = pos.split_once(':').unwrap();
+        let (length, _) = pos.split_once(':').unwrap();
+        let (offset, length): (usize, usize) = (offset.parse()?, length.parse()?);
+        let blobpath = format!(
+            "blobs/sha256/{}",
+            layer.digest().strip_prefix("sha256:").unwrap()
+        );
+        let mut blob = d.dir.read(&blobpath)?;
+        let toc: serde_json::Value = serde_json::from_slice(&blob[offset..offset + length])?;
+        for entry in toc["entries"].as_array().unwrap() {
+            let checksum = entry["checksum"].as_str().unwrap();
+            if !fixture.destrepo().has_object(
+                ostree::ObjectType::File,
+                checksum,
+                gio::Cancellable::NONE,
+            )? {
+                continue;
+            }
+            let offset = entry["offset"].as_u64().unwrap() as usize;
+            let length = entry["length"].as_u64().unwrap() as usize;
+            blob[offset + length / 2] ^= 0xff;
+            corrupted += 1;
+        }
+        d.dir.remove_file(&blobpath)?;
+        d.dir.write(&blobpath, blob)?;
+    }
+    assert!(corrupted > 0);
+    let mut imp = store::ImageImporter::new_in_process(fixture.destrepo(), &imgref, None).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let partial_layers = prep
+        .ostree_layers
+        .iter()
+        .filter(|l| l.commit.is_none())
+        .map(|l| {
+            manifest
+                .layers()
+                .iter()
+                .position(|m| m.digest().as_str() == l.digest())
+                .unwrap()
+        })
+        .collect::<HashSet<_>>();
+    assert!(!partial_layers.is_empty());
+    let mut events = imp.request_progress_events();
+    let collector = tokio::task::spawn(async move {
+        let mut r = Vec::new();
+        while let Some(event) = events.recv().await {
+            r.push(event);
+        }
+        r
+    });
+    let import = imp.import(prep).await?;
+    // Byte-level progress is reported for partially read layers
+    let events = collector.await?;
+    for i in partial_layers {
+        assert!(events.iter().any(|e| matches!(e,
+            store::ProgressEvent::LayerBytes { layer_index, fetched, .. }
+                if *layer_index == i && *fetched > 0
+        )));
+    }
+    let testrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    assert_eq!(import.get_commit(), testrev.as_str());
+    let root = fixture
+        .destrepo()
+        .read_commit(import.get_commit(), gio::Cancellable::NONE)?
+        .0;
+    let bash = root.resolve_relative_path("/usr/bin/bash");
+    let bash = bash.downcast_ref::<ostree::RepoFile>().unwrap();
+    assert_eq!(
+        ostree_ext::ostree_manual::repo_file_read_to_string(bash)?,
+        "bash-v1"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_export_jobs() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let contentmeta =
+        ObjectMetaSized::compute_sizes(fixture.srcrepo(), fixture.get_object_meta()?)?;
+    let mut layers = Vec::new();
+    for jobs in [1, 4] {
+        let path = &fixture.path.join(format!("oci-jobs{jobs}"));
+        let imgref = ImageReference {
+            transport: Transport::OciDir,
+            name: path.to_string(),
+        };
+        let mut opts = ExportOpts::default();
+        opts.jobs = std::num::NonZeroUsize::new(jobs);
+        opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+        opts.contentmeta = Some(&contentmeta);
+        ostree_ext::container::encapsulate(
+            fixture.srcrepo(),
+            fixture.testref(),
+            &Config::default(),
+            Some(opts),
+            &imgref,
+        )
+        .await?;
+        let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+        let manifest = ocidir::OciDir::open(&d)?.read_manifest()?;
+        layers.push(manifest.layers().clone());
+    }
+    // The layers should be identical, and in the same order
+    assert_eq!(layers[0].len(), LAYERS_V0_LEN);
+    assert_eq!(layers[0], layers[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_chunking_report() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    // Round trip the content metadata through its serialized form
+    let meta = serde_json::to_vec(&fixture.get_object_meta()?)?;
+    let meta = ostree_ext::objectsource::ObjectMeta::from_json(meta.as_slice())?;
+    let contentmeta = ObjectMetaSized::compute_sizes(fixture.srcrepo(), meta)?;
+    let path = &fixture.path.join("oci-report");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: path.to_string(),
+    };
+    let mut opts = ExportOpts::default();
+    opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+    opts.contentmeta = Some(&contentmeta);
+    let report =
+        ostree_ext::container::chunking_report(fixture.srcrepo(), fixture.testref(), Some(opts))?;
+    // One chunk per layer, plus the ostree layer
+    assert_eq!(report.chunks.len() + 1, LAYERS_V0_LEN);
+    assert!(report.moved.is_empty());
+    let total = report.chunks.iter().map(|c| c.size).sum::<u64>()
+        + report.metadata_size
+        + report.remainder.size;
+    assert_eq!(report.redownload_size, total);
+
+    let mut opts = ExportOpts::default();
+    opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+    opts.contentmeta = Some(&contentmeta);
+    ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        Some(opts),
+        &imgref,
+    )
+    .await?;
+    let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    let manifest = ocidir::OciDir::open(&d)?.read_manifest()?;
+
+    // Compared to an identical prior build, only the ostree layer changes
+    let mut opts = ExportOpts::default();
+    opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+    opts.contentmeta = Some(&contentmeta);
+    opts.prior_build = Some(&manifest);
+    let prior_report =
+        ostree_ext::container::chunking_report(fixture.srcrepo(), fixture.testref(), Some(opts))?;
+    assert_eq!(prior_report.chunks.len(), report.chunks.len());
+    assert!(prior_report.moved.is_empty());
+    assert_eq!(
+        prior_report.redownload_size,
+        report.metadata_size + report.remainder.size
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_chunk_by_path() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let path = &fixture.path.join("oci-bypath");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: path.to_string(),
+    };
+    let mut opts = ExportOpts::default();
+    opts.chunk_by_path = true;
+    ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        Some(opts),
+        &imgref,
+    )
+    .await?;
+    let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    let manifest = ocidir::OciDir::open(&d)?.read_manifest()?;
+    let mut components: Vec<_> = manifest
+        .layers()
+        .iter()
+        .skip(1)
+        .flat_map(|l| l.annotations().as_ref().unwrap()["ostree.components"].split(','))
+        .filter(|v| !v.is_empty())
+        .collect();
+    components.sort();
+    // The kernel, /usr/bin and the directories in /usr/lib each get a group; the ostree
+    // layer comes first.  The package database is the same object in both of its directories.
+    let groups = [
+        "usr/bin",
+        "usr/lib/modules/5.10.18-200.x86_64",
+        "usr/lib/pkgdb",
+    ];
+    assert_eq!(components, groups);
+
+    // Groups are packed by size; with fewer groups than layers, each gets a layer of
+    // its own, largest first, followed by an empty layer reserved for new groups.
+    let mut opts = ExportOpts::default();
+    opts.chunk_by_path = true;
+    let report =
+        ostree_ext::container::chunking_report(fixture.srcrepo(), fixture.testref(), Some(opts))?;
+    assert_eq!(report.components_provided, 3);
+    let (reserved, chunks) = report.chunks.split_last().unwrap();
+    assert_eq!((reserved.objects, reserved.size), (0, 0));
+    assert_eq!(chunks.len(), groups.len());
+    assert!(chunks.iter().all(|c| c.packages.len() == 1 && c.size > 0));
+    assert!(chunks.windows(2).all(|w| w[0].size >= w[1].size));
+    let kernel = report
+        .chunks
+        .iter()
+        .find(|c| c.packages == ["usr/lib/modules/5.10.18-200.x86_64"])
+        .unwrap();
+    let vmlinuz = content_checksum(
+        fixture.srcrepo(),
+        fixture.testref(),
+        "usr/lib/modules/5.10.18-200.x86_64/vmlinuz",
+    )?;
+    let (vmlinuz, _) = fixture
+        .srcrepo()
+        .query_file(&vmlinuz, gio::Cancellable::NONE)?;
+    assert!(kernel.size >= vmlinuz.size() as u64);
+
+    // Given the previous build, the same layout is kept
+    let mut opts = ExportOpts::default();
+    opts.chunk_by_path = true;
+    opts.prior_build = Some(&manifest);
+    let prior_report =
+        ostree_ext::container::chunking_report(fixture.srcrepo(), fixture.testref(), Some(opts))?;
+    assert!(prior_report.moved.is_empty());
+    assert_eq!(prior_report.chunks, report.chunks);
+
+    // Too few layers to pack into is an error, as with content metadata
+    let mut opts = ExportOpts::default();
+    opts.chunk_by_path = true;
+    opts.max_layers = std::num::NonZeroU32::new(1);
+    assert!(ostree_ext::container::chunking_report(
+        fixture.srcrepo(),
+        fixture.testref(),
+        Some(opts)
+    )
+    .is_err());
+    Ok(())
+}
+
+/// Check out the fixture commit, modify it and commit the result; returns the new commit.
+fn commit_modified_tree(fixture: &Fixture, f: impl FnOnce(&Dir) -> Result<()>) -> Result<String> {
+    let sh = fixture.new_shell()?;
+    let testref = fixture.testref();
+    cmd!(sh, "ostree --repo=src/repo checkout -U {testref} modified").run()?;
+    f(&fixture.dir.open_dir("modified")?)?;
+    let rev = cmd!(
+        sh,
+        "ostree --repo=src/repo commit --orphan --tree=dir=modified"
+    )
+    .read()?;
+    sh.remove_path("modified")?;
+    Ok(rev)
+}
+
+/// Look up the content object of a path in a commit.
+fn content_checksum(repo: &ostree::Repo, rev: &str, path: &str) -> Result<String> {
+    let root = repo.read_commit(rev, gio::Cancellable::NONE)?.0;
+    let f = root.resolve_relative_path(path);
+    let f = f.downcast_ref::<ostree::RepoFile>().unwrap();
+    Ok(f.checksum().to_string())
+}
+
+#[test]
+fn test_objectmeta_from_rpmdb() -> Result<()> {
+    use ostree_ext::objectsource::{ObjectMeta, UNPACKAGED_ID};
+
+    let fixture = Fixture::new_v1()?;
+    let rev = commit_modified_tree(&fixture, |d| {
+        d.create_dir_all("usr/lib/sysimage/rpm")?;
+        d.write("usr/lib/sysimage/rpm/rpmdb.sqlite", EXAMPLE_RPMDB)?;
+        Ok(())
+    })?;
+    let repo = fixture.srcrepo();
+    let meta = ObjectMeta::from_commit(repo, &rev)?;
+    let mut identifiers: Vec<_> = meta.set.iter().map(|v| &*v.identifier).collect();
+    identifiers.sort();
+    assert_eq!(
+        identifiers,
+        [
+            "bash-5.2.15-3.fc38.x86_64",
+            "kernel-core-6.2.9-300.fc38.x86_64",
+            UNPACKAGED_ID,
+            "someconfig-1.0-1.fc38.noarch",
+            "testlink-1.0-1.fc38.x86_64",
+        ]
+    );
+    let kernel = meta.set.get("kernel-core-6.2.9-300.fc38.x86_64").unwrap();
+    assert_eq!(&*kernel.name, "kernel-core");
+    assert_eq!(&*kernel.srcid, "kernel-6.2.9-300.fc38.src");
+    // Built 10 hours after the oldest package
+    assert_eq!(kernel.change_time_offset, 10);
+    let bash = meta.set.get("bash-5.2.15-3.fc38.x86_64").unwrap();
+    assert_eq!(bash.change_time_offset, 0);
+    assert_eq!(bash.change_frequency, 3);
+
+    for (path, owner) in [
+        ("/usr/bin/bash", "bash-5.2.15-3.fc38.x86_64"),
+        ("/usr/lib/emptyfile", "bash-5.2.15-3.fc38.x86_64"),
+        (
+            "/usr/lib/modules/5.10.18-200.x86_64/vmlinuz",
+            "kernel-core-6.2.9-300.fc38.x86_64",
+        ),
+        // Owned as /etc/someconfig.conf
+        ("/usr/etc/someconfig.conf", "someconfig-1.0-1.fc38.noarch"),
+        ("/usr/bin/hardlink-a", "testlink-1.0-1.fc38.x86_64"),
+        (
+            "/usr/lib/modules/5.10.18-200.x86_64/initramfs",
+            UNPACKAGED_ID,
+        ),
+        ("/usr/lib/sysimage/rpm/rpmdb.sqlite", UNPACKAGED_ID),
+    ] {
+        let checksum = content_checksum(repo, &rev, path)?;
+        assert_eq!(&*meta.map[&checksum], owner, "{path}");
+    }
+    // Every content object is mapped
+    let report = ostree_ext::chunking::Chunking::new(repo, &rev)?.report(None)?;
+    assert_eq!(meta.map.len(), report.remainder.objects);
+
+    // Without a package database, there is no metadata
+    assert_err_contains(
+        ObjectMeta::from_commit(repo, fixture.testref()),
+        "No supported package database found",
+    );
+    Ok(())
+}
+
+#[test]
+fn test_objectmeta_from_dpkg() -> Result<()> {
+    use ostree_ext::objectsource::{ObjectMeta, UNPACKAGED_ID};
+
+    let fixture = Fixture::new_v1()?;
+    // A merged /usr, where the package database lists paths via the root symlinks
+    let rev = commit_modified_tree(&fixture, |d| {
+        d.symlink("usr/bin", "bin")?;
+        d.symlink("usr/lib", "lib")?;
+        d.create_dir_all("var/lib/dpkg/info")?;
+        d.write(
+            "var/lib/dpkg/status",
+            indoc::indoc! { "
+                Package: bash
+                Status: install ok installed
+                Architecture: amd64
+                Version: 5.2.15-2+b2
+
+                Package: someconfig
+                Status: install ok installed
+                Architecture: all
+                Version: 1.0-1
+                Source: someconfig-src (1.0-0)
+            " },
+        )?;
+        d.write(
+            "var/lib/dpkg/info/bash.list",
+            "/.\n/bin\n/bin/bash\n/bin/sh\n/lib/emptyfile\n",
+        )?;
+        d.write(
+            "var/lib/dpkg/info/someconfig.list",
+            "/.\n/etc\n/etc/someconfig.conf\n",
+        )?;
+        Ok(())
+    })?;
+    let repo = fixture.srcrepo();
+    let meta = ObjectMeta::from_commit(repo, &rev)?;
+    let mut identifiers: Vec<_> = meta.set.iter().map(|v| &*v.identifier).collect();
+    identifiers.sort();
+    assert_eq!(
+        identifiers,
+        [
+            "bash_5.2.15-2+b2_amd64",
+            UNPACKAGED_ID,
+            "someconfig_1.0-1_all"
+        ]
+    );
+    let someconfig = meta.set.get("someconfig_1.0-1_all").unwrap();
+    assert_eq!(&*someconfig.srcid, "someconfig-src_1.0-0");
+    for (path, owner) in [
+        ("/usr/bin/bash", "bash_5.2.15-2+b2_amd64"),
+        ("/usr/bin/sh", "bash_5.2.15-2+b2_amd64"),
+        ("/usr/lib/emptyfile", "bash_5.2.15-2+b2_amd64"),
+        ("/usr/etc/someconfig.conf", "someconfig_1.0-1_all"),
+        ("/usr/bin/hardlink-a", UNPACKAGED_ID),
+        ("/var/lib/dpkg/status", UNPACKAGED_ID),
+    ] {
+        let checksum = content_checksum(repo, &rev, path)?;
+        assert_eq!(&*meta.map[&checksum], owner, "{path}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_reproducible() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let contentmeta =
+        ObjectMetaSized::compute_sizes(fixture.srcrepo(), fixture.get_object_meta()?)?;
+    let mut digests = Vec::new();
+    for i in 0..2 {
+        if i > 0 {
+            // Ensure the current time differs between the builds
+            std::thread::sleep(std::time::Duration::from_millis(1100));
+        }
+        let path = &fixture.path.join(format!("oci-reproducible{i}"));
+        let imgref = ImageReference {
+            transport: Transport::OciDir,
+            name: path.to_string(),
+        };
+        let mut opts = ExportOpts::default();
+        opts.reproducible = true;
+        opts.contentmeta = Some(&contentmeta);
+        let digest = ostree_ext::container::encapsulate(
+            fixture.srcrepo(),
+            fixture.testref(),
+            &Config::default(),
+            Some(opts),
+            &imgref,
+        )
+        .await?;
+        let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+        let ocidir = ocidir::OciDir::open(&d)?;
+        let manifest = ocidir.read_manifest()?;
+        let config: oci_spec::image::ImageConfiguration =
+            ocidir.read_json_blob(manifest.config())?;
+        // All timestamps are derived from the commit (or SOURCE_DATE_EPOCH)
+        if std::env::var_os("SOURCE_DATE_EPOCH").is_none() {
+            assert_eq!(config.created().as_deref(), Some("1997-08-29T18:30:42Z"));
+        }
+        for h in config.history() {
+            assert_eq!(h.created(), config.created());
+        }
+        digests.push((digest, manifest.config().digest().clone()));
+    }
+    assert_eq!(digests[0], digests[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_artifacts() -> Result<()> {
+    use ostree_ext::container::artifact;
+    let fixture = Fixture::new_v1()?;
+    let path = &fixture.path.join("oci-artifacts");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: format!("{path}:latest"),
+    };
+    let digest = ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        None,
+        &imgref,
+    )
+    .await?;
+
+    let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    let sbom_type = "application/spdx+json";
+    let sbom = br#"{"spdxVersion": "SPDX-2.3"}"#;
+    let sbom_digest = artifact::attach(
+        &d,
+        &digest,
+        sbom_type,
+        [(
+            oci_spec::image::MediaType::Other(sbom_type.into()),
+            &sbom[..],
+        )],
+        None,
+    )?;
+    assert!(artifact::attach(
+        &d,
+        "sha256:0000",
+        sbom_type,
+        [(
+            oci_spec::image::MediaType::Other(sbom_type.into()),
+            &sbom[..]
+        )],
+        None
+    )
+    .is_err());
+
+    let referrers = artifact::referrers(&d, &digest, None)?;
+    assert_eq!(referrers.len(), 1);
+    let referrer = &referrers[0];
+    assert_eq!(referrer.digest, sbom_digest);
+    assert_eq!(referrer.artifact_type, sbom_type);
+    assert!(artifact::referrers(&d, &digest, Some("application/other"))?.is_empty());
+
+    let manifest = artifact::fetch(&d, referrer)?;
+    // The subject does not include the tag annotation or platform of the index entry
+    let subject = manifest.subject.unwrap();
+    assert_eq!(subject.digest().as_str(), digest);
+    assert!(subject.annotations().is_none() && subject.platform().is_none());
+    let mut buf = Vec::new();
+    artifact::open_blob(&d, &manifest.layers[0])?.read_to_end(&mut buf)?;
+    assert_eq!(buf, sbom);
+
+    // Attaching the same artifact again is a no-op
+    let again = artifact::attach(
+        &d,
+        &digest,
+        sbom_type,
+        [(
+            oci_spec::image::MediaType::Other(sbom_type.into()),
+            &sbom[..],
+        )],
+        None,
+    )?;
+    assert_eq!(again, sbom_digest);
+    assert_eq!(artifact::referrers(&d, &digest, None)?.len(), 1);
+
+    // The tagged image is still pullable
+    let ocidir = ocidir::OciDir::open(&d)?;
+    assert_eq!(
+        ocidir.read_manifest_and_descriptor()?.1.digest().as_str(),
+        digest
+    );
+    let imgref = OstreeImageReference {
         sigverify: SignatureSource::ContainerPolicyAllowInsecure,
-        imgref: ImageReference {
-            transport: Transport::OciDir,
-            name: format!("{srcpath}:{derived_tag}"),
-        },
+        imgref,
     };
+    let (_, fetched_digest) = ostree_ext::container::fetch_manifest(&imgref).await?;
+    assert_eq!(fetched_digest, digest);
     let mut imp =
-        store::ImageImporter::new(fixture.destrepo(), &derived_imgref, Default::default()).await?;
-    let prep = match imp.prepare().await.unwrap() {
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    let prep = match imp.prepare().await? {
         store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
         store::PrepareResult::Ready(r) => r,
     };
-    let to_fetch = prep.layers_to_fetch().collect::<Result<Vec<_>>>()?;
-    assert_eq!(to_fetch.len(), 1);
-    assert!(prep.ostree_commit_layer.commit.is_some());
-    assert_eq!(prep.ostree_layers.len(), nlayers);
-
-    // We want to test explicit layer pruning
-    imp.disable_gc();
-    let _import = imp.import(prep).await.unwrap();
-    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 2);
-
-    assert!(
-        store::image_filtered_content_warning(fixture.destrepo(), &derived_imgref.imgref)
-            .unwrap()
-            .is_none()
+    let import = imp.import(prep).await?;
+    let testrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    assert_eq!(import.get_commit(), testrev.as_str());
+
+    // Artifacts cannot be attached to untagged images
+    let (untagged, untagged_digest) = fixture.export_container().await?;
+    let d = Dir::open_ambient_dir(&untagged.name, cap_std::ambient_authority())?;
+    let r = artifact::attach(
+        &d,
+        &untagged_digest,
+        sbom_type,
+        [(
+            oci_spec::image::MediaType::Other(sbom_type.into()),
+            &sbom[..],
+        )],
+        None,
     );
+    assert_err_contains(r, "is not tagged");
+    let untagged = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: untagged,
+    };
+    let (_, fetched_digest) = ostree_ext::container::fetch_manifest(&untagged).await?;
+    assert_eq!(fetched_digest, untagged_digest);
+    Ok(())
+}
 
-    // Should only be new layers
-    let n_removed = store::gc_image_layers(fixture.destrepo())?;
-    assert_eq!(n_removed, 0);
-    // Also test idempotence
-    store::remove_image(fixture.destrepo(), &imgref.imgref).unwrap();
-    store::remove_image(fixture.destrepo(), &imgref.imgref).unwrap();
-    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 1);
-    // Still no removed layers after removing the base image
-    let n_removed = store::gc_image_layers(fixture.destrepo())?;
-    assert_eq!(n_removed, 0);
-    store::remove_images(fixture.destrepo(), [&derived_imgref.imgref]).unwrap();
-    assert_eq!(store::list_images(fixture.destrepo()).unwrap().len(), 0);
-    let n_removed = store::gc_image_layers(fixture.destrepo())?;
-    assert_eq!(n_removed, (LAYERS_V0_LEN + 1) as u32);
+#[tokio::test]
+async fn test_container_local_source() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let testrev = fixture.srcrepo().require_rev(fixture.testref())?;
 
-    // Repo should be clean now
-    assert_eq!(store::count_layer_references(fixture.destrepo())?, 0);
+    // OCI archives can be read in process
+    let archivepath = &fixture.path.join("local.ociarchive");
+    let imgref = ImageReference {
+        transport: Transport::OciArchive,
+        name: archivepath.to_string(),
+    };
+    let digest = ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        fixture.testref(),
+        &Config::default(),
+        None,
+        &imgref,
+    )
+    .await?;
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let imp = store::ImageImporter::new_in_process(fixture.destrepo(), &imgref, None).await?;
+    let import = imp.unencapsulate().await?;
+    assert_eq!(import.ostree_commit, testrev.as_str());
+    assert_eq!(import.image_digest, digest);
+
+    // Other transports and signature verification via containers-policy.json are not supported
+    let unsupported = [
+        OstreeImageReference {
+            sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+            imgref: ImageReference {
+                transport: Transport::Registry,
+                name: "quay.io/exampleos/someos:latest".into(),
+            },
+        },
+        OstreeImageReference {
+            sigverify: SignatureSource::ContainerPolicy,
+            imgref: imgref.imgref.clone(),
+        },
+    ];
+    for imgref in unsupported {
+        assert!(
+            store::ImageImporter::new_in_process(fixture.destrepo(), &imgref, None)
+                .await
+                .is_err()
+        );
+    }
+
+    // Corrupted blobs are rejected, including ostree chunks whose import stops at the end
+    // of the tarball.  Flip a bit in the OS field of the gzip header, which is not covered
+    // by its checksum, so that only verifying the digest can detect it.
+    let (imgref, _) = fixture.export_container().await?;
+    let d = Dir::open_ambient_dir(&imgref.name, cap_std::ambient_authority())?;
+    let layer = ocidir::OciDir::open(&d)?.read_manifest()?.layers()[1].clone();
     assert_eq!(
-        fixture
-            .destrepo()
-            .list_refs(None, gio::Cancellable::NONE)
-            .unwrap()
-            .len(),
-        0
+        layer.media_type(),
+        &oci_spec::image::MediaType::ImageLayerGzip
     );
+    let blobpath = format!(
+        "blobs/sha256/{}",
+        layer.digest().strip_prefix("sha256:").unwrap()
+    );
+    let mut buf = d.read(&blobpath)?;
+    assert_eq!(buf[..2], [0x1f, 0x8b]);
+    buf[9] ^= 1;
+    d.remove_file(&blobpath)?;
+    d.write(&blobpath, buf)?;
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    fixture.clear_destrepo()?;
+    let mut imp = store::ImageImporter::new_in_process(fixture.destrepo(), &imgref, None).await?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let err = imp.import(prep).await.err().unwrap();
+    assert!(format!("{err:#}").contains("Digest mismatch"), "{err:#}");
 
     Ok(())
 }
 
+/// Simulate an interrupted fetch of a layer, and verify it is resumed
 #[tokio::test]
-async fn test_container_var_content() -> Result<()> {
+async fn test_container_staged_resume() -> Result<()> {
     let fixture = Fixture::new_v1()?;
-
-    let imgref = fixture.export_container().await.unwrap().0;
+    let srcrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    let (imgref, _) = fixture.export_container().await?;
     let imgref = OstreeImageReference {
         sigverify: SignatureSource::ContainerPolicyAllowInsecure,
         imgref,
     };
-
-    // Build a derived image
-    let derived_path = &fixture.path.join("derived.oci");
-    let srcpath = imgref.imgref.name.as_str();
-    oci_clone(srcpath, derived_path).await.unwrap();
-    let temproot = &fixture.path.join("temproot");
-    || -> Result<_> {
-        std::fs::create_dir(temproot)?;
-        let temprootd = Dir::open_ambient_dir(temproot, cap_std::ambient_authority())?;
-        let mut db = DirBuilder::new();
-        db.mode(0o755);
-        db.recursive(true);
-        temprootd.create_dir_with("var/lib", &db)?;
-        temprootd.write("var/lib/foo", "junk var data")?;
+    let d = Dir::open_ambient_dir(&imgref.imgref.name, cap_std::ambient_authority())?;
+    let layer = ocidir::OciDir::open(&d)?.read_manifest()?.layers()[0].clone();
+    let name = layer.digest().strip_prefix("sha256:").unwrap();
+    let blob = d.read(format!("blobs/sha256/{name}"))?;
+    let offset = blob.len() / 2;
+
+    let repodir = Dir::reopen_dir(&fixture.destrepo().dfd_borrow())?;
+    let write_partial = |prefix: &[u8]| -> Result<()> {
+        repodir.create_dir_all("tmp/ostree-ext-blobs")?;
+        // Content after the checkpoint was not synced, and must be discarded
+        let mut partial = prefix.to_vec();
+        partial.extend_from_slice(b"unsynced garbage");
+        repodir.write(format!("tmp/ostree-ext-blobs/{name}.partial"), partial)?;
+        repodir.write(
+            format!("tmp/ostree-ext-blobs/{name}.checkpoint"),
+            format!(r#"{{"offset": {}}}"#, prefix.len()),
+        )?;
         Ok(())
-    }()
-    .context("generating temp content")?;
-    ostree_ext::integrationtest::generate_derived_oci(derived_path, temproot, None)?;
+    };
 
-    let derived_imgref = OstreeImageReference {
+    // A corrupted partial blob is detected and discarded
+    let mut corrupted = blob[..offset].to_vec();
+    corrupted[0] = !corrupted[0];
+    write_partial(&corrupted)?;
+    let mut imp = store::ImageImporter::new_in_process(fixture.destrepo(), &imgref, None).await?;
+    imp.enable_blob_staging()?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    assert_err_contains(imp.import(prep).await, "Blob digest mismatch");
+    assert!(!repodir.try_exists(format!("tmp/ostree-ext-blobs/{name}.partial"))?);
+
+    // A valid partial blob is resumed
+    write_partial(&blob[..offset])?;
+    let mut imp = store::ImageImporter::new_in_process(fixture.destrepo(), &imgref, None).await?;
+    imp.enable_blob_staging()?;
+    let prep = match imp.prepare().await? {
+        store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
+        store::PrepareResult::Ready(r) => r,
+    };
+    let import = imp.import(prep).await?;
+    assert_eq!(import.merge_commit, srcrev.as_str());
+    // Staged blobs are removed once imported
+    assert_eq!(repodir.read_dir("tmp/ostree-ext-blobs")?.count(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_staged_proxy() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let srcrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    let (imgref, _) = fixture.export_container().await?;
+    let imgref = OstreeImageReference {
         sigverify: SignatureSource::ContainerPolicyAllowInsecure,
-        imgref: ImageReference {
-            transport: Transport::OciDir,
-            name: derived_path.to_string(),
-        },
+        imgref,
     };
+    let d = Dir::open_ambient_dir(&imgref.imgref.name, cap_std::ambient_authority())?;
+    let layer = ocidir::OciDir::open(&d)?.read_manifest()?.layers()[0].clone();
+    let name = layer.digest().strip_prefix("sha256:").unwrap();
+    let blob = d.read(format!("blobs/sha256/{name}"))?;
+    let mut corrupted = blob[..blob.len() / 2].to_vec();
+    corrupted[0] = !corrupted[0];
+
+    // The proxy cannot resume, so a partial blob and its checkpoint are discarded
+    // and the blob is fetched again from the start; even a corrupted one is not read.
+    let repodir = Dir::reopen_dir(&fixture.destrepo().dfd_borrow())?;
+    repodir.create_dir_all("tmp/ostree-ext-blobs")?;
+    repodir.write(format!("tmp/ostree-ext-blobs/{name}.partial"), &corrupted)?;
+    repodir.write(
+        format!("tmp/ostree-ext-blobs/{name}.checkpoint"),
+        format!(r#"{{"offset": {}}}"#, corrupted.len()),
+    )?;
     let mut imp =
-        store::ImageImporter::new(fixture.destrepo(), &derived_imgref, Default::default()).await?;
-    let prep = match imp.prepare().await.unwrap() {
+        store::ImageImporter::new(fixture.destrepo(), &imgref, Default::default()).await?;
+    imp.enable_blob_staging()?;
+    let prep = match imp.prepare().await? {
         store::PrepareResult::AlreadyPresent(_) => panic!("should not be already imported"),
         store::PrepareResult::Ready(r) => r,
     };
-    let _import = imp.import(prep).await.unwrap();
+    let import = imp.import(prep).await?;
+    assert_eq!(import.merge_commit, srcrev.as_str());
+    assert_eq!(repodir.read_dir("tmp/ostree-ext-blobs")?.count(), 0);
 
-    assert!(
-        store::image_filtered_content_warning(fixture.destrepo(), &derived_imgref.imgref)
-            .unwrap()
-            .is_some()
-    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_ociarchive() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let testrev = fixture.srcrepo().require_rev(fixture.testref())?;
+    let archivepath = &fixture.path.join("native.ociarchive");
+    let imgref = ImageReference {
+        transport: Transport::OciArchive,
+        name: format!("{archivepath}:latest"),
+    };
+    let mut digests = Vec::new();
+    let mut contents = Vec::new();
+    for _ in 0..2 {
+        let mut opts = ExportOpts::default();
+        opts.reproducible = true;
+        let digest = ostree_ext::container::encapsulate(
+            fixture.srcrepo(),
+            fixture.testref(),
+            &Config::default(),
+            Some(opts),
+            &imgref,
+        )
+        .await?;
+        digests.push(digest);
+        contents.push(std::fs::read(archivepath)?);
+    }
+    assert_eq!(digests[0], digests[1]);
+    assert!(contents[0] == contents[1]);
+
+    let mut archive = tar::Archive::new(contents[0].as_slice());
+    let names = archive
+        .entries()?
+        .map(|e| {
+            let path = e?
+                .path()?
+                .to_str()
+                .unwrap()
+                .trim_end_matches('/')
+                .to_string();
+            Ok(path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Layers are streamed into the archive, and the index is written last
+    assert_eq!(&names[..2], ["blobs", "blobs/sha256"]);
+    assert_eq!(&names[names.len() - 2..], ["index.json", "oci-layout"]);
 
+    let imgref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref,
+    };
+    let import = ostree_ext::container::unencapsulate(fixture.destrepo(), &imgref).await?;
+    assert_eq!(import.ostree_commit, testrev.as_str());
+    assert_eq!(import.image_digest, digests[0]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_container_runtime_config() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let sh = fixture.new_shell()?;
+    let testref = fixture.testref();
+    let r = format!("{testref}-runtime");
+    let meta = [
+        "--add-metadata=ostree.container-entrypoint=['/usr/bin/init']",
+        "--add-metadata=ostree.container-env=['FOO=bar']",
+        "--add-metadata=ostree.container-workdir='/var'",
+        "--add-metadata=ostree.container-annotations={'org.example.meta': 'yes'}",
+    ];
+    cmd!(
+        sh,
+        "ostree --repo=src/repo commit -b {r} --tree=ref={testref} --no-bindings {meta...}"
+    )
+    .ignore_stdout()
+    .run()?;
+    let path = &fixture.path.join("oci-runtime");
+    let imgref = ImageReference {
+        transport: Transport::OciDir,
+        name: path.to_string(),
+    };
+    // Explicit configuration overrides commit metadata
+    let config = Config {
+        working_dir: Some("/srv".into()),
+        user: Some("nobody".into()),
+        exposed_ports: Some(vec!["8080/tcp".into()]),
+        volumes: Some(vec!["/var/lib/data".into()]),
+        stop_signal: Some("SIGRTMIN+3".into()),
+        ..Default::default()
+    };
+    ostree_ext::container::encapsulate(fixture.srcrepo(), &r, &config, None, &imgref).await?;
+
+    let d = Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    let ocidir = ocidir::OciDir::open(&d)?;
+    let manifest = ocidir.read_manifest()?;
+    assert_eq!(
+        manifest
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get("org.example.meta"))
+            .map(|v| v.as_str()),
+        Some("yes")
+    );
+    let imgcfg: oci_spec::image::ImageConfiguration = ocidir.read_json_blob(manifest.config())?;
+    let ctrcfg = imgcfg.config().as_ref().unwrap();
+    assert_eq!(
+        ctrcfg.entrypoint().as_deref(),
+        Some(["/usr/bin/init".to_string()].as_slice())
+    );
+    assert_eq!(
+        ctrcfg.cmd().as_deref(),
+        Some(["/usr/bin/bash".to_string()].as_slice())
+    );
+    assert_eq!(
+        ctrcfg.env().as_deref(),
+        Some(["FOO=bar".to_string()].as_slice())
+    );
+    assert_eq!(ctrcfg.working_dir().as_deref(), Some("/srv"));
+    assert_eq!(ctrcfg.user().as_deref(), Some("nobody"));
+    assert_eq!(
+        ctrcfg.exposed_ports().as_deref(),
+        Some(["8080/tcp".to_string()].as_slice())
+    );
+    assert_eq!(
+        ctrcfg.volumes().as_deref(),
+        Some(["/var/lib/data".to_string()].as_slice())
+    );
+    assert_eq!(ctrcfg.stop_signal().as_deref(), Some("SIGRTMIN+3"));
     Ok(())
 }
 
@@ -1444,3 +3210,111 @@ fn test_manifest_diff() {
         "sha256:76b83eea62b7b93200a056b5e0201ef486c67f1eeebcf2c7678ced4d614cece2"
     );
 }
+
+#[ignore]
+#[tokio::test]
+// Like the above, but uploading each layer as it is generated.
+async fn test_container_incremental_push_registry() -> Result<()> {
+    let tr = &*TEST_REGISTRY;
+    let fixture = Fixture::new_v1()?;
+    let testref = fixture.testref();
+    let testrev = fixture.srcrepo().require_rev(testref)?;
+    let src_imgref = ImageReference {
+        transport: Transport::Registry,
+        name: format!("{}/exampleos:incremental", tr),
+    };
+    let mut opts = ExportOpts::default();
+    opts.incremental_push = true;
+    let digest = ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        testref,
+        &Config::default(),
+        Some(opts),
+        &src_imgref,
+    )
+    .await
+    .context("exporting to registry")?;
+    let import_ref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: ImageReference {
+            transport: Transport::Registry,
+            name: format!("{}/exampleos@{}", tr, digest),
+        },
+    };
+    let import = ostree_ext::container::unencapsulate(fixture.destrepo(), &import_ref)
+        .await
+        .context("importing")?;
+    assert_eq!(import.ostree_commit, testrev.as_str());
+
+    Ok(())
+}
+
+#[tokio::test]
+// Uploading each layer as it is generated, to an OCI directory.
+async fn test_container_incremental_push_ocidir() -> Result<()> {
+    let fixture = Fixture::new_v1()?;
+    let testref = fixture.testref();
+    let testrev = fixture.srcrepo().require_rev(testref)?;
+    let path = fixture.path.join("incremental.oci");
+    let dest = ImageReference {
+        transport: Transport::OciDir,
+        name: format!("{path}:latest"),
+    };
+    let contentmeta =
+        ObjectMetaSized::compute_sizes(fixture.srcrepo(), fixture.get_object_meta()?)?;
+    let mut opts = ExportOpts::default();
+    opts.incremental_push = true;
+    opts.max_layers = std::num::NonZeroU32::new(PKGS_V0_LEN as u32);
+    opts.contentmeta = Some(&contentmeta);
+    let digest = ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        testref,
+        &Config::default(),
+        Some(opts),
+        &dest,
+    )
+    .await
+    .context("exporting")?;
+
+    // Only the final image remains; the carrier images were removed
+    let d = Dir::open_ambient_dir(&path, cap_std::ambient_authority())?;
+    let ocidir = ocidir::OciDir::open(&d)?;
+    let (manifest, desc) = ocidir.read_manifest_and_descriptor()?;
+    assert_eq!(desc.digest().as_str(), digest);
+    assert_eq!(manifest.layers().len(), LAYERS_V0_LEN);
+    let index: oci_spec::image::ImageIndex = serde_json::from_reader(d.open("index.json")?)?;
+    assert_eq!(index.manifests().len(), 1);
+    // All layers are present, along with exactly one manifest and config
+    let blobs = d.read_dir("blobs/sha256")?.count();
+    assert_eq!(blobs, LAYERS_V0_LEN + 2);
+
+    let import_ref = OstreeImageReference {
+        sigverify: SignatureSource::ContainerPolicyAllowInsecure,
+        imgref: dest,
+    };
+    let import = ostree_ext::container::unencapsulate(fixture.destrepo(), &import_ref)
+        .await
+        .context("importing")?;
+    assert_eq!(import.ostree_commit, testrev.as_str());
+
+    // Incremental pushes are not supported for other transports
+    let mut opts = ExportOpts::default();
+    opts.incremental_push = true;
+    let dest = ImageReference {
+        transport: Transport::OciArchive,
+        name: fixture.path.join("incremental.ociarchive").to_string(),
+    };
+    let r = ostree_ext::container::encapsulate(
+        fixture.srcrepo(),
+        testref,
+        &Config::default(),
+        Some(opts),
+        &dest,
+    )
+    .await;
+    assert_err_contains(
+        r,
+        "Incremental push requires a registry or OCI directory destination",
+    );
+    Ok(())
+}